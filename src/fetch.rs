@@ -0,0 +1,782 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Prebuilt kernels and root filesystems, downloaded into a local cache.
+//!
+//! The base URL holds the artifacts, as `kernel/<version>/vmlinux` and
+//! `rootfs/<name>/rootfs.ext4`, and a `SHA256SUMS` manifest listing them with their SHA-256 sum
+//! in the `sha256sum` format. They are cached under `$XDG_CACHE_HOME/lumper` with the same
+//! layout, along with the manifest, so that a warm cache needs no network. Only `http://` URLs
+//! and local directories are supported. Redirects are followed to `http://` URLs only, a server
+//! redirecting to `https://` fails the download, so the base URL must be the final one.
+//!
+//! Plain HTTP does not protect the manifest, so its SHA-256 sum must be pinned, obtained out of
+//! band, for `http://` URLs. The artifacts are then checked against the pinned manifest.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use std::result;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Manifest listing the artifacts with their SHA-256 sum.
+const MANIFEST: &str = "SHA256SUMS";
+/// Link to the last kernel fetched, booted when no kernel is given.
+const DEFAULT_KERNEL: &str = "vmlinux";
+const MAX_REDIRECTS: usize = 5;
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Errors fetching the artifacts.
+#[derive(Debug)]
+pub enum Error {
+    /// Neither `--base-url` nor `LUMPER_FETCH_URL` is set.
+    NoBaseUrl,
+    /// Neither `XDG_CACHE_HOME` nor `HOME` is set.
+    NoCacheDir,
+    /// Unsupported URL, only `http://` ones and local paths are.
+    Url(String),
+    /// The base URL is an `http://` one, and the manifest SHA-256 sum is not pinned.
+    UnpinnedManifest(String),
+    /// Invalid manifest SHA-256 sum, as given.
+    ManifestSum(String),
+    /// Failed to download the URL.
+    Download(String, io::Error),
+    /// The URL redirects to this unsupported one, such as an `https://` one.
+    Redirect(String, String),
+    /// The server answered the URL with this status line.
+    Status(String, String),
+    /// Invalid manifest line.
+    Manifest(String),
+    /// The manifest does not list this artifact.
+    NotFound(String),
+    /// The artifact downloaded does not have the SHA-256 sum listed in the manifest.
+    Checksum(String),
+    /// Failed to write to the cache.
+    Cache(PathBuf, io::Error),
+}
+
+/// Dedicated Result type.
+pub type Result<T> = result::Result<T, Error>;
+
+/// Name of the kernel `version` in the manifest.
+pub fn kernel_name(version: &str) -> String {
+    format!("kernel/{}/vmlinux", version)
+}
+
+/// Name of the root filesystem `name` in the manifest.
+pub fn rootfs_name(name: &str) -> String {
+    format!("rootfs/{}/rootfs.ext4", name)
+}
+
+// Round constants of SHA-256.
+const SHA256_K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// SHA-256 of a stream of bytes.
+struct Sha256 {
+    state: [u32; 8],
+    block: [u8; 64],
+    block_len: usize,
+    len: u64,
+}
+
+impl Sha256 {
+    fn new() -> Self {
+        Sha256 {
+            state: [
+                0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+                0x5be0cd19,
+            ],
+            block: [0; 64],
+            block_len: 0,
+            len: 0,
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        self.len = self.len.wrapping_add(data.len() as u64);
+        while !data.is_empty() {
+            let count = (self.block.len() - self.block_len).min(data.len());
+            self.block[self.block_len..self.block_len + count].copy_from_slice(&data[..count]);
+            self.block_len += count;
+            data = &data[count..];
+
+            if self.block_len == self.block.len() {
+                self.compress();
+                self.block_len = 0;
+            }
+        }
+    }
+
+    fn compress(&mut self) {
+        let mut w = [0u32; 64];
+        for (word, bytes) in w.iter_mut().zip(self.block.chunks_exact(4)) {
+            *word = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = self.state;
+        for (k, w) in SHA256_K.iter().zip(w.iter()) {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(*k)
+                .wrapping_add(*w);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+
+        for (state, value) in self.state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *state = state.wrapping_add(value);
+        }
+    }
+
+    fn finish(mut self) -> [u8; 32] {
+        // Padded with a 1 bit, then 0 bits up to the length in bits on the last 8 bytes.
+        let len_bits = self.len.wrapping_mul(8);
+        self.update(&[0x80]);
+        while self.block_len != 56 {
+            self.update(&[0]);
+        }
+        self.update(&len_bits.to_be_bytes());
+
+        let mut sum = [0; 32];
+        for (bytes, word) in sum.chunks_exact_mut(4).zip(self.state.iter()) {
+            bytes.copy_from_slice(&word.to_be_bytes());
+        }
+        sum
+    }
+}
+
+/// Copy `reader` to `writer`, returning the SHA-256 sum of the bytes copied.
+fn copy_hashed<R: Read, W: Write>(reader: &mut R, writer: &mut W) -> io::Result<[u8; 32]> {
+    let mut sha256 = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let count = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(count) => count,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        sha256.update(&buf[..count]);
+        writer.write_all(&buf[..count])?;
+    }
+
+    Ok(sha256.finish())
+}
+
+/// SHA-256 sum of the file at `path`.
+fn file_sum(path: &Path) -> io::Result<[u8; 32]> {
+    copy_hashed(&mut File::open(path)?, &mut io::sink())
+}
+
+/// Parse a SHA-256 sum written in hexadecimal.
+fn parse_sum(hex: &str) -> Option<[u8; 32]> {
+    if hex.len() != 64 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    let mut sum = [0; 32];
+    for (i, byte) in sum.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).ok()?;
+    }
+    Some(sum)
+}
+
+/// Artifacts of the base URL, with their SHA-256 sum.
+#[derive(Debug, PartialEq)]
+pub struct Manifest(BTreeMap<String, [u8; 32]>);
+
+impl Manifest {
+    fn sum(&self, name: &str) -> Result<[u8; 32]> {
+        self.0
+            .get(name)
+            .copied()
+            .ok_or_else(|| Error::NotFound(name.to_string()))
+    }
+}
+
+impl FromStr for Manifest {
+    type Err = Error;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        s.lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| {
+                let invalid = || Error::Manifest(line.to_string());
+
+                // `<sum>  <name>`, the name starting with `*` for files hashed as binary.
+                let (sum, name) = line.split_once(char::is_whitespace).ok_or_else(invalid)?;
+                let name = name.trim_start().trim_start_matches('*');
+                // Names are paths in the cache, which they must not leave.
+                if name.is_empty() || name.starts_with('/') || name.split('/').any(|c| c == "..") {
+                    return Err(invalid());
+                }
+
+                Ok((name.to_string(), parse_sum(sum).ok_or_else(invalid)?))
+            })
+            .collect::<Result<_>>()
+            .map(Manifest)
+    }
+}
+
+enum Response {
+    Body(Box<dyn Read>),
+    Redirect(String),
+    Status(String),
+}
+
+/// Connect to `addr`, trying each of its addresses for at most [`TIMEOUT`].
+fn connect(addr: &str) -> io::Result<TcpStream> {
+    let mut last_error = None;
+    for addr in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = Some(e),
+        }
+    }
+
+    Err(last_error
+        .unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address to connect to")))
+}
+
+/// Send a GET request for `url`, without its `http://` prefix.
+fn http_get(url: &str) -> io::Result<Response> {
+    let (host, path) = match url.find('/') {
+        Some(i) => (&url[..i], &url[i..]),
+        None => (url, "/"),
+    };
+    let addr = if host.contains(':') {
+        host.to_string()
+    } else {
+        format!("{}:80", host)
+    };
+
+    let mut stream = connect(&addr)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    // HTTP/1.0 so that the body is neither chunked nor followed by another response.
+    write!(
+        stream,
+        "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: lumper\r\n\r\n",
+        path, host
+    )?;
+
+    let mut reader = BufReader::new(stream);
+    let mut status = String::new();
+    reader.read_line(&mut status)?;
+    let status = status.trim_end().to_string();
+
+    let mut location = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim_end().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("location") {
+                location = Some(value.trim().to_string());
+            }
+        }
+    }
+
+    Ok(match (status.split_whitespace().nth(1), location) {
+        (Some("200"), _) => Response::Body(Box::new(reader)),
+        (Some("301" | "302" | "303" | "307" | "308"), Some(location)) => {
+            if location.starts_with('/') {
+                Response::Redirect(format!("http://{}{}", host, location))
+            } else {
+                Response::Redirect(location)
+            }
+        }
+        _ => Response::Status(status),
+    })
+}
+
+/// Open `url`, an `http://` URL or a local path, for reading.
+fn open(url: &str) -> Result<Box<dyn Read>> {
+    let mut url = url.to_string();
+    for _ in 0..=MAX_REDIRECTS {
+        let http_url = match url.strip_prefix("http://") {
+            Some(http_url) => http_url,
+            None if url.contains("://") && !url.starts_with("file://") => {
+                return Err(Error::Url(url))
+            }
+            None => {
+                let path = url.trim_start_matches("file://");
+                return match File::open(path) {
+                    Ok(file) => Ok(Box::new(file)),
+                    Err(e) => Err(Error::Download(url, e)),
+                };
+            }
+        };
+
+        match http_get(http_url).map_err(|e| Error::Download(url.clone(), e))? {
+            Response::Body(body) => return Ok(body),
+            // Neither followed to `https://`, nor to a local file.
+            Response::Redirect(location) if !location.starts_with("http://") => {
+                return Err(Error::Redirect(url, location))
+            }
+            Response::Redirect(location) => url = location,
+            Response::Status(status) => return Err(Error::Status(url, status)),
+        }
+    }
+
+    Err(Error::Status(url, "too many redirects".to_string()))
+}
+
+/// Directory the artifacts are cached in.
+pub struct Cache {
+    dir: PathBuf,
+}
+
+impl Cache {
+    /// `$XDG_CACHE_HOME/lumper`, or `~/.cache/lumper`.
+    pub fn new() -> Result<Self> {
+        let cache_home = env::var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .filter(|dir| dir.is_absolute())
+            .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+            .ok_or(Error::NoCacheDir)?;
+
+        Ok(Cache {
+            dir: cache_home.join("lumper"),
+        })
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+
+    /// The last kernel fetched, if any.
+    pub fn default_kernel(&self) -> Option<PathBuf> {
+        fs::canonicalize(self.path(DEFAULT_KERNEL)).ok()
+    }
+}
+
+/// Downloads artifacts from the base URL into the cache.
+pub struct Fetcher {
+    base_url: String,
+    cache: Cache,
+    // Whether cached files are downloaded again.
+    refresh: bool,
+    // SHA-256 sum the manifest must have.
+    manifest_sum: Option<[u8; 32]>,
+}
+
+impl Fetcher {
+    /// Fetch from `base_url`, checking the manifest against `manifest_sum`, written in
+    /// hexadecimal, which `http://` URLs require.
+    pub fn new(
+        base_url: String,
+        cache: Cache,
+        refresh: bool,
+        manifest_sum: Option<String>,
+    ) -> Result<Self> {
+        let manifest_sum = match manifest_sum {
+            Some(sum) => Some(parse_sum(&sum).ok_or(Error::ManifestSum(sum))?),
+            None if base_url.starts_with("http://") => {
+                return Err(Error::UnpinnedManifest(base_url))
+            }
+            None => None,
+        };
+
+        Ok(Fetcher {
+            base_url,
+            cache,
+            refresh,
+            manifest_sum,
+        })
+    }
+
+    /// Whether `manifest` has the pinned SHA-256 sum, if any.
+    fn pinned(&self, manifest: &str) -> bool {
+        let mut sha256 = Sha256::new();
+        sha256.update(manifest.as_bytes());
+
+        self.manifest_sum
+            .map_or(true, |manifest_sum| sha256.finish() == manifest_sum)
+    }
+
+    fn url(&self, name: &str) -> String {
+        format!("{}/{}", self.base_url.trim_end_matches('/'), name)
+    }
+
+    /// The manifest of the base URL, the cached one unless refreshing or it is not the pinned
+    /// one.
+    pub fn manifest(&self) -> Result<Manifest> {
+        let path = self.cache.path(MANIFEST);
+        if !self.refresh {
+            match fs::read_to_string(&path) {
+                Ok(manifest) if self.pinned(&manifest) => return manifest.parse(),
+                _ => {}
+            }
+        }
+
+        let url = self.url(MANIFEST);
+        let mut manifest = String::new();
+        open(&url)?
+            .read_to_string(&mut manifest)
+            .map_err(|e| Error::Download(url, e))?;
+        if !self.pinned(&manifest) {
+            return Err(Error::Checksum(MANIFEST.to_string()));
+        }
+        let parsed = manifest.parse()?;
+
+        fs::create_dir_all(&self.cache.dir).map_err(|e| Error::Cache(self.cache.dir.clone(), e))?;
+        fs::write(&path, manifest).map_err(|e| Error::Cache(path, e))?;
+        Ok(parsed)
+    }
+
+    /// Path of the artifact `name` in the cache, downloaded first unless already there.
+    pub fn fetch(&self, manifest: &Manifest, name: &str) -> Result<PathBuf> {
+        let sum = manifest.sum(name)?;
+        let path = self.cache.path(name);
+        if !self.refresh && file_sum(&path).ok() == Some(sum) {
+            return Ok(path);
+        }
+
+        let dir = path.parent().unwrap_or(&self.cache.dir);
+        fs::create_dir_all(dir).map_err(|e| Error::Cache(dir.to_path_buf(), e))?;
+
+        // Downloaded next to the artifact, which is only replaced once checked.
+        let mut partial = path.clone().into_os_string();
+        partial.push(".part");
+        let partial = PathBuf::from(partial);
+        let mut file = File::create(&partial).map_err(|e| Error::Cache(partial.clone(), e))?;
+
+        let url = self.url(name);
+        let downloaded = open(&url).and_then(|mut body| {
+            copy_hashed(&mut body, &mut file).map_err(|e| Error::Download(url, e))
+        });
+        match downloaded {
+            Ok(downloaded) if downloaded == sum => {}
+            result => {
+                let _ = fs::remove_file(&partial);
+                result?;
+                return Err(Error::Checksum(name.to_string()));
+            }
+        }
+
+        fs::rename(&partial, &path).map_err(|e| Error::Cache(path.clone(), e))?;
+        Ok(path)
+    }
+
+    /// Fetch the kernel `version`, which becomes the default one.
+    pub fn fetch_kernel(&self, manifest: &Manifest, version: &str) -> Result<PathBuf> {
+        let path = self.fetch(manifest, &kernel_name(version))?;
+
+        let link = self.cache.path(DEFAULT_KERNEL);
+        let _ = fs::remove_file(&link);
+        symlink(&path, &link).map_err(|e| Error::Cache(link, e))?;
+
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::process;
+    use std::thread;
+
+    fn hex(sum: &[u8; 32]) -> String {
+        sum.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    fn sha256(data: &[u8]) -> [u8; 32] {
+        let mut sha256 = Sha256::new();
+        sha256.update(data);
+        sha256.finish()
+    }
+
+    // Serves `files` by path, redirecting `/redirect/<path>` to `/<path>`, and
+    // `/https/<path>` to `https://<host>/<path>`.
+    fn serve(files: BTreeMap<String, Vec<u8>>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(&stream);
+                let mut request = String::new();
+                reader.read_line(&mut request).unwrap();
+                // Read the headers, closing the connection with unread data would reset it.
+                let mut header = String::new();
+                while reader.read_line(&mut header).unwrap() > 2 {
+                    header.clear();
+                }
+                let path = request.split_whitespace().nth(1).unwrap_or("/");
+
+                let https = path
+                    .strip_prefix("/https")
+                    .map(|path| format!("https://{}{}", addr, path));
+                let redirect = path.strip_prefix("/redirect").map(str::to_string).or(https);
+
+                let _ = match (files.get(path), redirect) {
+                    (Some(body), _) => stream
+                        .write_all(b"HTTP/1.0 200 OK\r\n\r\n")
+                        .and_then(|_| stream.write_all(body)),
+                    (None, Some(path)) => {
+                        write!(stream, "HTTP/1.0 302 Found\r\nLocation: {}\r\n\r\n", path)
+                    }
+                    (None, None) => stream.write_all(b"HTTP/1.0 404 Not Found\r\n\r\n"),
+                };
+            }
+        });
+
+        format!("http://{}", addr)
+    }
+
+    struct TestCache(PathBuf);
+
+    impl TestCache {
+        fn new(name: &str) -> Self {
+            let dir = env::temp_dir().join(format!("lumper-fetch-{}-{}", name, process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            TestCache(dir)
+        }
+
+        fn cache(&self) -> Cache {
+            Cache {
+                dir: self.0.clone(),
+            }
+        }
+    }
+
+    impl Drop for TestCache {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn sha256_sums() {
+        assert_eq!(
+            hex(&sha256(b"")),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            hex(&sha256(b"abc")),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        // Two blocks once padded.
+        assert_eq!(
+            hex(&sha256(
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"
+            )),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+
+        // Fed in pieces.
+        let data = vec![0x5a; 1000];
+        let mut sha256_pieces = Sha256::new();
+        for piece in data.chunks(7) {
+            sha256_pieces.update(piece);
+        }
+        assert_eq!(sha256_pieces.finish(), sha256(&data));
+    }
+
+    #[test]
+    fn manifest() {
+        let sum = hex(&sha256(b"abc"));
+        let manifest: Manifest = format!(
+            "{}  kernel/6.6/vmlinux\n\n{} *rootfs/alpine/rootfs.ext4\n",
+            sum, sum
+        )
+        .parse()
+        .unwrap();
+        assert_eq!(manifest.sum("kernel/6.6/vmlinux").unwrap(), sha256(b"abc"));
+        assert_eq!(
+            manifest.sum("rootfs/alpine/rootfs.ext4").unwrap(),
+            sha256(b"abc")
+        );
+        assert!(matches!(
+            manifest.sum("kernel/5.10/vmlinux"),
+            Err(Error::NotFound(_))
+        ));
+
+        for invalid in [
+            "kernel/6.6/vmlinux".to_string(),
+            format!("{}  kernel/6.6/vmlinux", &sum[1..]),
+            format!("{}  /etc/passwd", sum),
+            format!("{}  kernel/../../vmlinux", sum),
+        ] {
+            assert!(
+                matches!(invalid.parse::<Manifest>(), Err(Error::Manifest(_))),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn fetch() {
+        let kernel = b"kernel image".to_vec();
+        let rootfs = b"root filesystem".to_vec();
+        let manifest = format!(
+            "{}  {}\n{}  {}\n{}  rootfs/corrupted/rootfs.ext4\n",
+            hex(&sha256(&kernel)),
+            kernel_name("6.6"),
+            hex(&sha256(&rootfs)),
+            rootfs_name("alpine"),
+            hex(&sha256(b"something else")),
+        );
+        let mut files = BTreeMap::new();
+        let manifest_sum = hex(&sha256(manifest.as_bytes()));
+        files.insert(format!("/{}", MANIFEST), manifest.into_bytes());
+        files.insert(format!("/{}", kernel_name("6.6")), kernel.clone());
+        files.insert(format!("/{}", rootfs_name("alpine")), rootfs.clone());
+        files.insert(format!("/{}", rootfs_name("corrupted")), rootfs.clone());
+        let base_url = serve(files);
+
+        let test_cache = TestCache::new("http");
+        let pinned = Some(manifest_sum.clone());
+        let fetcher = Fetcher::new(base_url.clone(), test_cache.cache(), false, pinned).unwrap();
+        let manifest = fetcher.manifest().unwrap();
+
+        // The cache mirrors the base URL layout.
+        let kernel_path = fetcher.fetch_kernel(&manifest, "6.6").unwrap();
+        assert_eq!(kernel_path, test_cache.0.join("kernel/6.6/vmlinux"));
+        assert_eq!(fs::read(&kernel_path).unwrap(), kernel);
+        assert_eq!(test_cache.cache().default_kernel(), Some(kernel_path));
+        let rootfs_path = fetcher.fetch(&manifest, &rootfs_name("alpine")).unwrap();
+        assert_eq!(rootfs_path, test_cache.0.join("rootfs/alpine/rootfs.ext4"));
+        assert_eq!(fs::read(&rootfs_path).unwrap(), rootfs);
+        assert!(test_cache.0.join(MANIFEST).is_file());
+
+        // Corrupted downloads are not kept.
+        assert!(matches!(
+            fetcher.fetch(&manifest, &rootfs_name("corrupted")),
+            Err(Error::Checksum(_))
+        ));
+        assert!(test_cache
+            .0
+            .join("rootfs/corrupted")
+            .read_dir()
+            .unwrap()
+            .next()
+            .is_none());
+        assert!(matches!(
+            fetcher.fetch(&manifest, &kernel_name("5.10")),
+            Err(Error::NotFound(_))
+        ));
+
+        // A warm cache needs no network.
+        let offline_url = "http://127.0.0.1:1".to_string();
+        let pinned = Some(manifest_sum.clone());
+        let offline = Fetcher::new(offline_url.clone(), test_cache.cache(), false, pinned).unwrap();
+        let manifest = offline.manifest().unwrap();
+        assert!(offline.fetch_kernel(&manifest, "6.6").is_ok());
+        let pinned = Some(manifest_sum.clone());
+        let offline = Fetcher::new(offline_url.clone(), test_cache.cache(), true, pinned).unwrap();
+        assert!(matches!(offline.manifest(), Err(Error::Download(_, _))));
+        // Unless the cached manifest is not the pinned one.
+        let pinned = Some(hex(&sha256(b"another manifest")));
+        let offline = Fetcher::new(offline_url, test_cache.cache(), false, pinned).unwrap();
+        assert!(matches!(offline.manifest(), Err(Error::Download(_, _))));
+
+        // Refreshing follows redirects.
+        let redirect_url = format!("{}/redirect", base_url);
+        let pinned = Some(manifest_sum);
+        let fetcher = Fetcher::new(redirect_url, test_cache.cache(), true, pinned).unwrap();
+        let manifest = fetcher.manifest().unwrap();
+        assert!(fetcher.fetch(&manifest, &rootfs_name("alpine")).is_ok());
+    }
+
+    #[test]
+    fn urls() {
+        assert!(matches!(
+            open("https://example.com/SHA256SUMS"),
+            Err(Error::Url(_))
+        ));
+
+        let base_url = serve(BTreeMap::new());
+        assert!(matches!(
+            open(&format!("{}/SHA256SUMS", base_url)),
+            Err(Error::Status(_, status)) if status.contains("404")
+        ));
+
+        // Redirects to `https://` are not followed.
+        assert!(matches!(
+            open(&format!("{}/https/SHA256SUMS", base_url)),
+            Err(Error::Redirect(_, location)) if location.starts_with("https://")
+        ));
+
+        // Local directories.
+        let test_cache = TestCache::new("local");
+        fs::create_dir_all(&test_cache.0).unwrap();
+        fs::write(test_cache.0.join(MANIFEST), "").unwrap();
+        let base_url = format!("file://{}", test_cache.0.display());
+        let fetcher = Fetcher::new(base_url, test_cache.cache(), true, None).unwrap();
+        assert_eq!(fetcher.manifest().unwrap(), Manifest(BTreeMap::new()));
+    }
+
+    #[test]
+    fn pinned_manifest() {
+        let manifest = format!("{}  {}\n", hex(&sha256(b"kernel")), kernel_name("6.6"));
+        let mut files = BTreeMap::new();
+        files.insert(format!("/{}", MANIFEST), manifest.clone().into_bytes());
+        let base_url = serve(files);
+        let test_cache = TestCache::new("pinned");
+
+        // Plain HTTP needs the manifest sum.
+        assert!(matches!(
+            Fetcher::new(base_url.clone(), test_cache.cache(), true, None),
+            Err(Error::UnpinnedManifest(_))
+        ));
+        assert!(matches!(
+            Fetcher::new(
+                base_url.clone(),
+                test_cache.cache(),
+                true,
+                Some("abc".to_string())
+            ),
+            Err(Error::ManifestSum(_))
+        ));
+
+        // A tampered manifest is neither used nor cached.
+        let pinned = Some(hex(&sha256(b"another manifest")));
+        let fetcher = Fetcher::new(base_url.clone(), test_cache.cache(), true, pinned).unwrap();
+        assert!(matches!(fetcher.manifest(), Err(Error::Checksum(_))));
+        assert!(!test_cache.0.join(MANIFEST).exists());
+
+        let pinned = Some(hex(&sha256(manifest.as_bytes())));
+        let fetcher = Fetcher::new(base_url, test_cache.cache(), true, pinned).unwrap();
+        assert!(fetcher.manifest().is_ok());
+    }
+}
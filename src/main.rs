@@ -1,57 +1,477 @@
+use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::u32;
 
-use clap::Parser;
-use vmm::VMM;
+use clap::{Args, Parser, Subcommand};
+use fetch::{Cache, Fetcher};
+use vmm::{
+    Cgroup, CgroupConfig, ConsoleConfig, ExitReporter, JailConfig, JailSupervisor, LogFilter,
+    Pidfile, ReadyNotifier, SystemdNotifier, VmConfigBuilder, VmExitReason, VMM,
+};
+
+mod fetch;
 
 #[derive(Parser)]
 #[clap(version = "0.1", author = "Polytech Montpellier - DevOps")]
 struct VMMOpts {
-    /// Linux kernel path
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    /// Linux kernel path, defaults to the last kernel fetched
     #[clap(short, long)]
-    kernel: String,
+    kernel: Option<String>,
 
     /// Number of virtual CPUs assigned to the guest
     #[clap(short, long, default_value = "1")]
     cpus: u8,
 
+    /// Processor brand string exposed to the guest (e.g. "lumper virtual CPU")
+    #[clap(long)]
+    cpu_brand: Option<String>,
+
+    /// CPU features to enable or disable for the guest (e.g. `+x2apic,-avx512f`)
+    #[clap(long)]
+    cpu_features: Option<String>,
+
+    /// TSC frequency (in kHz) exposed to the guest, defaults to the host one
+    #[clap(long)]
+    tsc_frequency: Option<u32>,
+
+    /// Inject a #GP when the guest accesses an MSR unknown to KVM and lumper, instead of reading
+    /// it as 0 and ignoring writes
+    #[clap(long)]
+    strict_msrs: bool,
+
+    /// System UUID read by the guest from the SMBIOS tables (e.g. in
+    /// `/sys/class/dmi/id/product_uuid`), a random one by default
+    #[clap(long)]
+    uuid: Option<String>,
+
+    /// System serial number and product name read by the guest from the SMBIOS tables, as
+    /// `serial=<serial>,product=<product>`
+    #[clap(long)]
+    smbios: Option<String>,
+
+    /// Arguments appended to the kernel command line (e.g. `init=/bin/sh`)
+    #[clap(long)]
+    cmdline: Option<String>,
+
+    /// Maximum size (in bytes) of the kernel command line, defaults to 2048
+    #[clap(long)]
+    cmdline_size: Option<usize>,
+
+    /// Protocol used to boot the kernel: `auto`, `linux` or `pvh`, `auto` picking PVH when the
+    /// kernel supports it
+    #[clap(long)]
+    boot_protocol: Option<String>,
+
     /// Memory amount (in MBytes) assigned to the guest
     #[clap(short, long, default_value = "512")]
     memory: u32,
 
+    /// Maximum memory amount (in MBytes) a guest may be assigned, defaults to the host memory
+    #[clap(long)]
+    memory_limit: Option<u32>,
+
+    /// Guest memory options, as `mlock=on|off`: `mlock=on` locks the guest memory in RAM, within
+    /// `RLIMIT_MEMLOCK` unless the VMM has `CAP_IPC_LOCK`
+    #[clap(long)]
+    memory_options: Option<String>,
+
+    /// Start the VM even if its memory exceeds the memory available on the host
+    #[clap(long)]
+    allow_overcommit: bool,
+
     /// A level of verbosity, and can be used multiple times
     #[clap(short, long, parse(from_occurrences))]
     verbose: i32,
 
     /// Stdout console file path
     #[clap(long)]
-    console: Option<String>
+    console: Option<String>,
+
+    /// Expose the console on this TCP address (e.g. `127.0.0.1:4444`) instead of stdout
+    #[clap(long, conflicts_with = "console")]
+    console_tcp: Option<String>,
+
+    /// Pin vCPU threads to host CPUs, as a list of `<vcpu>=<host cpu>` (e.g. `0=2,1=3`)
+    #[clap(long)]
+    cpu_affinity: Option<String>,
+
+    /// Pin the VMM event loop thread to this host CPU
+    #[clap(long)]
+    event_loop_cpu: Option<usize>,
+
+    /// Bind the guest memory and the vCPUs not pinned with `--cpu-affinity` to a host NUMA node,
+    /// as `node=<host node>[,srat=on|off]`: `srat=on` tells the guest it runs on a single NUMA node
+    #[clap(long)]
+    numa: Option<String>,
+
+    /// Size (in bytes) of the console output buffer, 0 to make the guest wait for the console
+    #[clap(long)]
+    console_buffer: Option<usize>,
+
+    /// Stop the VM after this many seconds, exiting with code 124
+    #[clap(long)]
+    timeout: Option<u64>,
+
+    /// Stop the VM with exit code 0 as soon as the guest prints this string
+    #[clap(long)]
+    expect_string: Option<String>,
+
+    /// Action taken when the guest crashes: `dump` its memory and registers, `pause` it, or
+    /// `shutdown`, exiting with code 3
+    #[clap(long)]
+    on_crash: Option<String>,
+
+    /// Watchdog exposed to the guest, as `action=<poweroff|reset|none>,timeout=<seconds>`: when
+    /// the guest stops pinging it, the VM is stopped with exit code 3, rebooted, or the expiry only
+    /// reported
+    #[clap(long)]
+    watchdog: Option<String>,
+
+    /// Memory shared with host processes, as `size=<size>,socket=<path>` (e.g.
+    /// `size=16M,socket=/run/shmem.sock`): processes connecting to the socket get the memfd, the
+    /// eventfd the guest doorbell signals and the eventfd raising the guest interrupt (IRQ 5)
+    #[clap(long)]
+    shmem: Option<String>,
+
+    /// Report the guest boot time once it writes 123 to this I/O port (e.g. `0x440`), see
+    /// `printf '\173' | dd of=/dev/port bs=1 count=1 seek=$((0x440))`
+    #[clap(long)]
+    boot_notifier: Option<String>,
+
+    /// Wait for gdb to connect on this address (e.g. `tcp::1234`) before booting the guest
+    #[clap(long)]
+    gdb: Option<String>,
+
+    /// Write why the VMM exited to this file, as JSON (`{"reason", "details", "uptime_ms"}`)
+    #[clap(long)]
+    exit_reason_file: Option<String>,
+
+    /// Run in the background once the VM started: exits with code 0 then, or with the VMM exit
+    /// code and error if the VM failed to start
+    #[clap(long)]
+    daemonize: bool,
+
+    /// File stdout, stderr and the console (unless set) are written to in the background,
+    /// discarded if unset
+    #[clap(long, requires = "daemonize")]
+    log_file: Option<String>,
+
+    /// VMM diagnostics logged to stderr, as `[<module>=]<level>` entries (e.g.
+    /// `info,vmm::cpu=trace`), defaults to `$LUMPER_LOG` then to warnings only
+    #[clap(long)]
+    log_filter: Option<String>,
+
+    /// When the VM is ready, for `--daemonize` and systemd (`Type=notify`): `vcpus` once the
+    /// vCPUs are running, or `console-string:<string>` once the guest printed `<string>` (e.g.
+    /// `console-string:login:`)
+    #[clap(long)]
+    ready_on: Option<String>,
+
+    /// Write the VMM process ID to this file, removed when the VMM exits
+    #[clap(long)]
+    pidfile: Option<String>,
+
+    /// Confine the VMM in this directory, in new mount, PID, network and UTS namespaces and as
+    /// `--jail-uid`/`--jail-gid`: files opened afterwards (e.g. the console) are inside it. The
+    /// host network namespace is kept for `--console-tcp` and `--gdb`
+    #[clap(long, requires_all = &["jail_uid", "jail_gid"])]
+    jail: Option<String>,
+
+    /// User the VMM runs as in the jail
+    #[clap(long, requires = "jail")]
+    jail_uid: Option<u32>,
+
+    /// Group the VMM runs as in the jail
+    #[clap(long, requires = "jail")]
+    jail_gid: Option<u32>,
+
+    /// Resource limits in the jail, as a list of `<resource>=<limit>` (e.g. `nofile=1024`), with
+    /// resources among `as`, `core`, `fsize`, `memlock`, `nofile` and `nproc`
+    #[clap(long, requires = "jail")]
+    rlimit: Option<String>,
+
+    /// Limit the VMM resources in a cgroup v2 created under `parent`, removed when the VMM exits,
+    /// as `parent=<path>[,cpu-max=<microseconds per 100ms>][,memory-max=<bytes>[K|M|G]]`
+    #[clap(long, conflicts_with = "jail")]
+    cgroup: Option<String>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Download a prebuilt kernel and root filesystem into the cache, printing their paths
+    Fetch(FetchOpts),
+}
+
+#[derive(Args)]
+struct FetchOpts {
+    /// Kernel version (e.g. `6.6`), booted when `--kernel` is omitted
+    #[clap(long)]
+    kernel: Option<String>,
+
+    /// Root filesystem (e.g. `alpine`)
+    #[clap(long)]
+    rootfs: Option<String>,
+
+    /// URL (`http://`) or directory holding the artifacts and their `SHA256SUMS` manifest,
+    /// defaults to `$LUMPER_FETCH_URL`
+    #[clap(long)]
+    base_url: Option<String>,
+
+    /// SHA-256 sum of the `SHA256SUMS` manifest, obtained out of band and required for
+    /// `http://` URLs, defaults to `$LUMPER_FETCH_MANIFEST_SHA256`
+    #[clap(long)]
+    manifest_sha256: Option<String>,
+
+    /// Download the manifest and artifacts again, even if cached
+    #[clap(long)]
+    refresh: bool,
 }
 
 #[derive(Debug)]
 pub enum Error {
+    NoKernel,
+
     VmmNew(vmm::Error),
 
     VmmConfigure(vmm::Error),
 
     VmmRun(vmm::Error),
+
+    Daemonize(std::io::Error),
+
+    Pidfile(std::io::Error),
+
+    SystemdNotify(std::io::Error),
 }
 
-fn main() -> Result<(), Error> {
-    let opts: VMMOpts = VMMOpts::parse();
+impl From<Error> for VmExitReason {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::NoKernel => VmExitReason::ConfigError(
+                "no kernel, set --kernel or run `lumper fetch --kernel <version>`".to_string(),
+            ),
+            Error::VmmConfigure(e) => VmExitReason::ConfigError(format!("{:?}", e)),
+            Error::VmmNew(e) | Error::VmmRun(e) => VmExitReason::InternalError(format!("{:?}", e)),
+            Error::Daemonize(e) => VmExitReason::InternalError(format!("{:?}", e)),
+            Error::Pidfile(e) => VmExitReason::ConfigError(format!("pidfile: {:?}", e)),
+            Error::SystemdNotify(e) => VmExitReason::ConfigError(format!("NOTIFY_SOCKET: {:?}", e)),
+        }
+    }
+}
 
-    // Create a new VMM
-    let mut vmm = VMM::new().map_err(Error::VmmNew)?;
+// Exit codes: 0 when the guest stopped cleanly, 1 on VMM errors, 2 on configuration errors, 3
+// when the guest crashed and 124 when it timed out.
+fn main() {
+    let mut opts: VMMOpts = VMMOpts::parse();
 
-    // Configure the VMM:
-    // * Number of virtual CPUs
-    // * Memory size (in MB)
-    // * Path to a Linux kernel
-    // * Optional path to console file
-    vmm.configure(opts.cpus, opts.memory, &opts.kernel, opts.console)
-        .map_err(Error::VmmConfigure)?;
+    if let Some(Command::Fetch(fetch_opts)) = opts.command.take() {
+        if let Err(e) = fetch(fetch_opts) {
+            eprintln!("Error: {:?}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
 
-    // Run the VMM
-    vmm.run().map_err(Error::VmmRun)?;
+    // Report why the VMM exits, panics included
+    let exit_reason_file = opts.exit_reason_file.clone().map(PathBuf::from);
+    let exit_reporter = Arc::new(ExitReporter::new(exit_reason_file));
+    exit_reporter.install_panic_hook();
+
+    let mut ready_notifier = None;
+    let result = daemonize(&opts).and_then(|notifier| {
+        ready_notifier = notifier.clone();
+        run(opts, &exit_reporter, notifier)
+    });
+    let reason = match result {
+        Ok(reason) => reason,
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            e.into()
+        }
+    };
+
+    // The foreground process is only told if the VM did not start
+    if let Some(ready_notifier) = ready_notifier {
+        let message = format!("{}: {}", reason.name(), reason.details());
+        let _ = ready_notifier.failed(reason.exit_code(), &message);
+    }
+
+    std::process::exit(exit_reporter.report(&reason));
+}
+
+// Fork into the background if asked to, before any thread is spawned or the VM is created.
+fn daemonize(opts: &VMMOpts) -> Result<Option<Arc<ReadyNotifier>>, Error> {
+    if !opts.daemonize {
+        return Ok(None);
+    }
+
+    let log_file = opts.log_file.as_ref().map(Path::new);
+    let ready_notifier = vmm::daemonize(log_file).map_err(Error::Daemonize)?;
+
+    Ok(Some(Arc::new(ready_notifier)))
+}
+
+// Download the artifacts asked for, or find them in the cache.
+fn fetch(opts: FetchOpts) -> fetch::Result<()> {
+    let base_url = opts
+        .base_url
+        .or_else(|| env::var("LUMPER_FETCH_URL").ok())
+        .ok_or(fetch::Error::NoBaseUrl)?;
+    let manifest_sum = opts
+        .manifest_sha256
+        .or_else(|| env::var("LUMPER_FETCH_MANIFEST_SHA256").ok());
+    let fetcher = Fetcher::new(base_url, Cache::new()?, opts.refresh, manifest_sum)?;
+    let manifest = fetcher.manifest()?;
+
+    if let Some(version) = opts.kernel {
+        println!("{}", fetcher.fetch_kernel(&manifest, &version)?.display());
+    }
+    if let Some(rootfs) = opts.rootfs {
+        let rootfs = fetch::rootfs_name(&rootfs);
+        println!("{}", fetcher.fetch(&manifest, &rootfs)?.display());
+    }
 
     Ok(())
 }
+
+fn run(
+    opts: VMMOpts,
+    exit_reporter: &ExitReporter,
+    ready_notifier: Option<Arc<ReadyNotifier>>,
+) -> Result<VmExitReason, Error> {
+    if let Some(filter) = opts.log_filter.or_else(|| env::var("LUMPER_LOG").ok()) {
+        let filter: LogFilter = filter.parse().map_err(Error::VmmConfigure)?;
+        vmm::init_logger(filter);
+    }
+
+    let pidfile = opts
+        .pidfile
+        .map(|pidfile| Pidfile::create(PathBuf::from(pidfile)))
+        .transpose()
+        .map_err(Error::Pidfile)?;
+
+    // Tell systemd how the VM is doing when run as a `Type=notify` service, connecting to it
+    // before entering the jail
+    let systemd_notifier = SystemdNotifier::from_env().map_err(Error::SystemdNotify)?;
+
+    // Move the VMM into its cgroup before any vCPU thread is spawned, threads spawned afterwards
+    // landing in it too
+    let _cgroup = match opts.cgroup {
+        Some(config) => {
+            let config: CgroupConfig = config.parse().map_err(Error::VmmConfigure)?;
+            let cgroup = Cgroup::create(&config).map_err(Error::VmmConfigure)?;
+            cgroup
+                .add_process(std::process::id())
+                .map_err(Error::VmmConfigure)?;
+            Some(cgroup)
+        }
+        None => None,
+    };
+
+    // Create a new VMM, confined with only the kernel and /dev/kvm if asked to
+    let mut kernel = match opts.kernel {
+        Some(kernel) => PathBuf::from(kernel),
+        None => Cache::new()
+            .ok()
+            .and_then(|cache| cache.default_kernel())
+            .ok_or(Error::NoKernel)?,
+    };
+    // sysfs is out of reach in the jail
+    let numa = opts
+        .numa
+        .map(|numa| vmm::numa_placement(&numa))
+        .transpose()
+        .map_err(Error::VmmConfigure)?;
+    let mut host_available_memory = None;
+    let mut vmm = match (opts.jail, opts.jail_uid, opts.jail_gid) {
+        (Some(root), Some(uid), Some(gid)) => {
+            // /proc is out of reach in the jail
+            if !opts.allow_overcommit {
+                host_available_memory =
+                    Some(vmm::host_available_memory().map_err(Error::VmmConfigure)?);
+            }
+            let jail = JailConfig {
+                root: PathBuf::from(root),
+                uid,
+                gid,
+                files: vec![kernel.clone()],
+                rlimits: opts
+                    .rlimit
+                    .unwrap_or_default()
+                    .parse()
+                    .map_err(Error::VmmConfigure)?,
+                host_network: opts.console_tcp.is_some() || opts.gdb.is_some(),
+            };
+            kernel = JailConfig::jailed_path(&kernel).map_err(Error::VmmConfigure)?;
+
+            // The process left outside of the jail removes the pidfile, and hands the systemd
+            // main process role over to the VMM
+            let supervisor = JailSupervisor {
+                systemd_notifier: systemd_notifier
+                    .as_ref()
+                    .map(SystemdNotifier::try_clone)
+                    .transpose()
+                    .map_err(Error::SystemdNotify)?,
+                pidfile,
+            };
+            let kvm = vmm::enter_jail(&jail, supervisor).map_err(Error::VmmConfigure)?;
+            VMM::with_kvm_file(kvm).map_err(Error::VmmNew)?
+        }
+        _ => {
+            if let Some(pidfile) = pidfile {
+                exit_reporter.remove_on_exit(pidfile);
+            }
+            VMM::new().map_err(Error::VmmNew)?
+        }
+    };
+
+    // Console: optional file path or TCP address, string marking a successful boot, output
+    // buffer size and whether stdin is detached
+    let mut console = ConsoleConfig {
+        path: opts.console,
+        tcp: opts.console_tcp,
+        expect_string: opts.expect_string,
+        detached: opts.daemonize,
+        ..Default::default()
+    };
+    if let Some(buffer_size) = opts.console_buffer {
+        console.buffer_size = buffer_size;
+    }
+
+    // Configure the VMM as a whole
+    VmConfigBuilder::new()
+        .vcpus(opts.cpus)
+        .memory(opts.memory, opts.memory_limit)
+        .memory_options(opts.memory_options)
+        .allow_overcommit(opts.allow_overcommit)
+        .host_available_memory(host_available_memory)
+        .kernel(kernel)
+        .cmdline(opts.cmdline)
+        .cmdline_size(opts.cmdline_size)
+        .boot_protocol(opts.boot_protocol)
+        .cpu_model(opts.cpu_brand, opts.cpu_features)
+        .smbios(opts.uuid, opts.smbios)
+        .tsc_frequency(opts.tsc_frequency)
+        .strict_msrs(opts.strict_msrs)
+        .console(console)
+        .timeout(opts.timeout)
+        .on_crash(opts.on_crash)
+        .watchdog(opts.watchdog)
+        .shmem(opts.shmem)
+        .boot_notifier(opts.boot_notifier)
+        .affinity(opts.cpu_affinity, opts.event_loop_cpu)
+        .numa(numa)
+        .gdb(opts.gdb)
+        .ready_notifier(ready_notifier)
+        .ready_on(opts.ready_on)
+        .systemd_notifier(systemd_notifier)
+        .configure(&mut vmm)
+        .map_err(Error::VmmConfigure)?;
+
+    // Run the VMM
+    vmm.run().map_err(Error::VmmRun)
+}
@@ -0,0 +1,721 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! ACPI tables describing the machine to the guest.
+//!
+//! The RSDP is written in the BIOS read-only memory area, where the guest looks for it, and the
+//! other tables right after it. See the ACPI specification 6.4, chapter 5.2.
+
+use std::result;
+
+use vm_memory::{
+    Address, ByteValued, Bytes, GuestAddress, GuestMemory, GuestMemoryError, GuestMemoryMmap,
+    GuestMemoryRegion,
+};
+
+use crate::devices::acpi_pm::{
+    ACPI_PM_PORT_BASE, PM1_CNT_LEN, PM1_CNT_OFFSET, PM1_EVT_LEN, PM1_EVT_OFFSET, SLP_TYP_S5,
+};
+use crate::devices::cmos::RTC_CENTURY;
+use crate::devices::i8042::I8042_PORT_BASE;
+use crate::devices::pvpanic::PVPANIC_PORT;
+use crate::devices::serial::SERIAL_PORT_BASE;
+
+/// Address of the RSDP, in the BIOS area scanned by the guest.
+pub const RSDP_START: u64 = 0x000e_0000;
+// The tables must fit below the SMBIOS tables, at the end of the BIOS area.
+const ACPI_END: u64 = crate::smbios::SMBIOS_START;
+const TABLE_ALIGNMENT: u64 = 16;
+
+const OEM_ID: [u8; 6] = *b"LUMPER";
+const OEM_TABLE_ID: [u8; 8] = *b"LUMPERVM";
+const OEM_REVISION: u32 = 1;
+const CREATOR_ID: [u8; 4] = *b"LMPR";
+const CREATOR_REVISION: u32 = 1;
+
+// Size of the header common to all system description tables.
+const SDT_HEADER_LEN: usize = 36;
+const SDT_LENGTH_OFFSET: usize = 4;
+const SDT_CHECKSUM_OFFSET: usize = 9;
+
+// ACPI 2.0 RSDP, pointing to the XSDT. The first checksum only covers the ACPI 1.0 fields.
+const RSDP_REVISION: u8 = 2;
+const RSDP_LEN: usize = 36;
+const RSDP_V1_LEN: usize = 20;
+const RSDP_CHECKSUM_OFFSET: usize = 8;
+const RSDP_EXTENDED_CHECKSUM_OFFSET: usize = 32;
+
+// FADT fields, ACPI 6.4 table 5.9.
+const FADT_LEN: usize = 276;
+const FADT_REVISION: u8 = 6;
+const FADT_MINOR_REVISION: u8 = 4;
+const FADT_DSDT_OFFSET: usize = 40;
+const FADT_SCI_INT_OFFSET: usize = 46;
+const FADT_PM1A_EVT_BLK_OFFSET: usize = 56;
+const FADT_PM1A_CNT_BLK_OFFSET: usize = 64;
+const FADT_PM1_EVT_LEN_OFFSET: usize = 88;
+const FADT_PM1_CNT_LEN_OFFSET: usize = 89;
+const FADT_CENTURY_OFFSET: usize = 108;
+const FADT_IAPC_BOOT_ARCH_OFFSET: usize = 109;
+const FADT_FLAGS_OFFSET: usize = 112;
+const FADT_RESET_REG_OFFSET: usize = 116;
+const FADT_RESET_VALUE_OFFSET: usize = 128;
+const FADT_MINOR_REVISION_OFFSET: usize = 131;
+const FADT_X_DSDT_OFFSET: usize = 140;
+
+// The SCI is wired to the legacy ISA interrupt 9, like on most PCs.
+const SCI_INT: u16 = 9;
+// IA-PC boot architecture flags: an i8042 controller is present.
+const IAPC_BOOT_ARCH_8042: u16 = 1 << 1;
+// Fixed feature flags: WBINVD works, the power button is a fixed feature but there is no sleep
+// button, and the machine is reset through the reset register.
+const FADT_WBINVD: u32 = 1 << 0;
+const FADT_SLP_BUTTON: u32 = 1 << 5;
+const FADT_RESET_REG_SUP: u32 = 1 << 10;
+// Pulsing the CPU reset line of the i8042 controller resets the machine.
+const RESET_VALUE: u8 = 0xfe;
+const I8042_COMMAND_PORT: u16 = I8042_PORT_BASE + 0x4;
+
+// Generic address structure, ACPI 6.4 table 5.1.
+const GAS_SYSTEM_IO: u8 = 1;
+const GAS_ACCESS_BYTE: u8 = 1;
+
+// MADT fields, ACPI 6.4 table 5.43.
+const MADT_REVISION: u8 = 5;
+const APIC_DEFAULT_PHYS_BASE: u32 = 0xfee0_0000;
+const IO_APIC_DEFAULT_PHYS_BASE: u32 = 0xfec0_0000;
+// The machine also has dual 8259 PICs.
+const MADT_PCAT_COMPAT: u32 = 1 << 0;
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_LOCAL_APIC_LEN: u8 = 8;
+const MADT_LOCAL_APIC_ENABLED: u32 = 1 << 0;
+const MADT_IO_APIC: u8 = 1;
+const MADT_IO_APIC_LEN: u8 = 12;
+
+// SRAT fields, ACPI 6.4 table 5.64.
+const SRAT_REVISION: u8 = 3;
+// Reserved field, set to 1 for backward compatibility.
+const SRAT_TABLE_REVISION: u32 = 1;
+const SRAT_LOCAL_APIC_AFFINITY: u8 = 0;
+const SRAT_LOCAL_APIC_AFFINITY_LEN: u8 = 16;
+const SRAT_MEMORY_AFFINITY: u8 = 1;
+const SRAT_MEMORY_AFFINITY_LEN: u8 = 40;
+const SRAT_ENABLED: u32 = 1 << 0;
+
+const XSDT_REVISION: u8 = 1;
+const DSDT_REVISION: u8 = 2;
+
+// AML encoding, ACPI 6.4 chapter 20.
+const AML_ZERO_OP: u8 = 0x00;
+const AML_NAME_OP: u8 = 0x08;
+const AML_BYTE_PREFIX: u8 = 0x0a;
+const AML_DWORD_PREFIX: u8 = 0x0c;
+const AML_STRING_PREFIX: u8 = 0x0d;
+const AML_SCOPE_OP: u8 = 0x10;
+const AML_BUFFER_OP: u8 = 0x11;
+const AML_PACKAGE_OP: u8 = 0x12;
+const AML_DEVICE_OP: [u8; 2] = [0x5b, 0x82];
+
+// Resource descriptors, ACPI 6.4 chapter 6.4.
+const IO_DESCRIPTOR: u8 = 0x47;
+const IO_DECODE_16: u8 = 0x01;
+const IRQ_NO_FLAGS_DESCRIPTOR: u8 = 0x22;
+const END_TAG_DESCRIPTOR: u8 = 0x79;
+
+// The COM1 UART, a 16550 compatible device.
+const COM1_PNP_ID: (&[u8; 3], u16) = (b"PNP", 0x0501);
+const COM1_IRQ: u8 = 4;
+const COM1_PORT_LEN: u8 = 8;
+
+// The pvpanic device, as exposed by QEMU.
+const PVPANIC_HID: &[u8] = b"QEMU0001";
+
+/// Errors associated with the ACPI tables.
+#[derive(Debug)]
+pub enum Error {
+    /// The tables do not fit in the BIOS area.
+    NotEnoughMemory,
+    /// Failed to write a table to guest memory.
+    WriteTable(GuestMemoryError),
+}
+
+/// Dedicated Result type.
+pub type Result<T> = result::Result<T, Error>;
+
+pub(crate) fn checksum(data: &[u8]) -> u8 {
+    let sum = data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    0u8.wrapping_sub(sum)
+}
+
+/// A system description table: the common header, followed by the table specific fields.
+struct Sdt {
+    data: Vec<u8>,
+}
+
+impl Sdt {
+    fn new(signature: &[u8; 4], length: usize, revision: u8) -> Self {
+        let mut sdt = Sdt {
+            data: vec![0u8; length],
+        };
+
+        sdt.data[0..4].copy_from_slice(signature);
+        sdt.data[8] = revision;
+        sdt.data[10..16].copy_from_slice(&OEM_ID);
+        sdt.data[16..24].copy_from_slice(&OEM_TABLE_ID);
+        sdt.write(24, OEM_REVISION);
+        sdt.data[28..32].copy_from_slice(&CREATOR_ID);
+        sdt.write(32, CREATOR_REVISION);
+        sdt.update();
+
+        sdt
+    }
+
+    fn write<T: ByteValued>(&mut self, offset: usize, value: T) {
+        self.data[offset..offset + value.as_slice().len()].copy_from_slice(value.as_slice());
+        self.update();
+    }
+
+    fn append<T: ByteValued>(&mut self, value: T) {
+        self.append_slice(value.as_slice());
+    }
+
+    fn append_slice(&mut self, data: &[u8]) {
+        self.data.extend_from_slice(data);
+        self.update();
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        &self.data
+    }
+
+    // Keep the length and checksum fields in sync with the table content.
+    fn update(&mut self) {
+        let length = (self.data.len() as u32).to_le_bytes();
+        self.data[SDT_LENGTH_OFFSET..SDT_LENGTH_OFFSET + 4].copy_from_slice(&length);
+        self.data[SDT_CHECKSUM_OFFSET] = 0;
+        self.data[SDT_CHECKSUM_OFFSET] = checksum(&self.data);
+    }
+}
+
+fn align(addr: u64) -> u64 {
+    (addr + TABLE_ALIGNMENT - 1) & !(TABLE_ALIGNMENT - 1)
+}
+
+/// Root System Description Pointer, the entry point to the tables.
+fn create_rsdp(xsdt_addr: u64) -> [u8; RSDP_LEN] {
+    let mut rsdp = [0u8; RSDP_LEN];
+
+    rsdp[0..8].copy_from_slice(b"RSD PTR ");
+    rsdp[9..15].copy_from_slice(&OEM_ID);
+    rsdp[15] = RSDP_REVISION;
+    // There is no RSDT, only the 64-bit XSDT.
+    rsdp[20..24].copy_from_slice(&(RSDP_LEN as u32).to_le_bytes());
+    rsdp[24..32].copy_from_slice(&xsdt_addr.to_le_bytes());
+    rsdp[RSDP_CHECKSUM_OFFSET] = checksum(&rsdp[..RSDP_V1_LEN]);
+    rsdp[RSDP_EXTENDED_CHECKSUM_OFFSET] = checksum(&rsdp);
+
+    rsdp
+}
+
+/// Extended System Description Table, listing the other tables but the DSDT.
+fn create_xsdt(tables: &[u64]) -> Sdt {
+    let mut xsdt = Sdt::new(b"XSDT", SDT_HEADER_LEN, XSDT_REVISION);
+    for table in tables {
+        xsdt.append(*table);
+    }
+
+    xsdt
+}
+
+/// Encode the length of an AML package, which counts its own bytes.
+fn aml_pkg_length(len: usize) -> Vec<u8> {
+    // A single byte holds lengths up to 63, two bytes up to 4095, which is enough here.
+    if len + 1 < 1 << 6 {
+        vec![(len + 1) as u8]
+    } else {
+        let len = len + 2;
+        vec![(1 << 6) | (len & 0xf) as u8, (len >> 4) as u8]
+    }
+}
+
+/// Encode an AML package: `op`, the package length, then `header` and `body`.
+fn aml_pkg(op: &[u8], header: &[u8], body: &[u8]) -> Vec<u8> {
+    let mut pkg = op.to_vec();
+    pkg.extend(aml_pkg_length(header.len() + body.len()));
+    pkg.extend_from_slice(header);
+    pkg.extend_from_slice(body);
+    pkg
+}
+
+/// Encode `Name (name, value)`.
+fn aml_name(name: &[u8; 4], value: &[u8]) -> Vec<u8> {
+    let mut aml = vec![AML_NAME_OP];
+    aml.extend_from_slice(name);
+    aml.extend_from_slice(value);
+    aml
+}
+
+/// Encode a string, which must not hold any NUL byte.
+fn aml_string(value: &[u8]) -> Vec<u8> {
+    let mut aml = vec![AML_STRING_PREFIX];
+    aml.extend_from_slice(value);
+    aml.push(0);
+    aml
+}
+
+/// Encode `EisaId ("<vendor><product>")`, a compressed PNP identifier.
+fn aml_eisa_id(vendor: &[u8; 3], product: u16) -> Vec<u8> {
+    let vendor = vendor
+        .iter()
+        .fold(0u16, |id, c| (id << 5) | u16::from((c - b'@') & 0x1f));
+
+    let mut aml = vec![AML_DWORD_PREFIX];
+    aml.extend_from_slice(&vendor.to_be_bytes());
+    aml.extend_from_slice(&product.to_be_bytes());
+    aml
+}
+
+/// Resources of a device decoding `len` I/O ports from `port`, and raising `irq` if any.
+fn io_resources(port: u16, len: u8, irq: Option<u8>) -> Vec<u8> {
+    let port = port.to_le_bytes();
+    // IO (Decode16, port, port, 0x00, len)
+    let mut resources = vec![
+        IO_DESCRIPTOR,
+        IO_DECODE_16,
+        port[0],
+        port[1],
+        port[0],
+        port[1],
+        0x00,
+        len,
+    ];
+    if let Some(irq) = irq {
+        // IRQNoFlags () { irq }
+        let irq_mask = (1u16 << irq).to_le_bytes();
+        resources.extend_from_slice(&[IRQ_NO_FLAGS_DESCRIPTOR, irq_mask[0], irq_mask[1]]);
+    }
+    // End tag, without checksum.
+    resources.extend_from_slice(&[END_TAG_DESCRIPTOR, 0x00]);
+
+    // Buffer (size) { resources }
+    aml_pkg(
+        &[AML_BUFFER_OP],
+        &[AML_BYTE_PREFIX, resources.len() as u8],
+        &resources,
+    )
+}
+
+/// Differentiated System Description Table, advertising the soft off sleep state, the COM1 UART
+/// and the pvpanic device.
+///
+/// The power button is a fixed feature, described by the FADT.
+fn create_dsdt() -> Sdt {
+    let mut dsdt = Sdt::new(b"DSDT", SDT_HEADER_LEN, DSDT_REVISION);
+
+    // Name (_S5, Package (0x02) { SLP_TYP_S5, SLP_TYP_S5 }), one per PM1 control block.
+    let s5 = aml_pkg(
+        &[AML_PACKAGE_OP],
+        &[0x02],
+        &[
+            AML_BYTE_PREFIX,
+            SLP_TYP_S5 as u8,
+            AML_BYTE_PREFIX,
+            SLP_TYP_S5 as u8,
+        ],
+    );
+    dsdt.append_slice(&aml_name(b"_S5_", &s5));
+
+    // Scope (\_SB) { Device (COM1) { _HID, _UID, _CRS } Device (PEVT) { _HID, _CRS } }
+    let com1 = [
+        aml_name(b"_HID", &aml_eisa_id(COM1_PNP_ID.0, COM1_PNP_ID.1)),
+        aml_name(b"_UID", &[AML_ZERO_OP]),
+        aml_name(
+            b"_CRS",
+            &io_resources(SERIAL_PORT_BASE, COM1_PORT_LEN, Some(COM1_IRQ)),
+        ),
+    ]
+    .concat();
+    let pvpanic = [
+        aml_name(b"_HID", &aml_string(PVPANIC_HID)),
+        aml_name(b"_CRS", &io_resources(PVPANIC_PORT, 1, None)),
+    ]
+    .concat();
+    let devices = [
+        aml_pkg(&AML_DEVICE_OP, b"COM1", &com1),
+        aml_pkg(&AML_DEVICE_OP, b"PEVT", &pvpanic),
+    ]
+    .concat();
+    dsdt.append_slice(&aml_pkg(&[AML_SCOPE_OP], b"\\_SB_", &devices));
+
+    dsdt
+}
+
+/// Write a generic address structure, describing a register in the I/O port space.
+fn write_io_register(sdt: &mut Sdt, offset: usize, port: u16, bit_width: u8) {
+    sdt.write(offset, GAS_SYSTEM_IO);
+    sdt.write(offset + 1, bit_width);
+    sdt.write(offset + 2, 0u8);
+    sdt.write(offset + 3, GAS_ACCESS_BYTE);
+    sdt.write(offset + 4, u64::from(port));
+}
+
+/// Fixed ACPI Description Table, describing the power management hardware.
+fn create_fadt(dsdt_addr: u32) -> Sdt {
+    let mut fadt = Sdt::new(b"FACP", FADT_LEN, FADT_REVISION);
+
+    fadt.write(FADT_DSDT_OFFSET, dsdt_addr);
+    fadt.write(FADT_X_DSDT_OFFSET, u64::from(dsdt_addr));
+    fadt.write(FADT_MINOR_REVISION_OFFSET, FADT_MINOR_REVISION);
+
+    // The machine is powered off by entering S5 through the PM1a control register. There is no
+    // SMI command port, the machine is always in ACPI mode.
+    fadt.write(FADT_SCI_INT_OFFSET, SCI_INT);
+    fadt.write(
+        FADT_PM1A_EVT_BLK_OFFSET,
+        u32::from(ACPI_PM_PORT_BASE + PM1_EVT_OFFSET),
+    );
+    fadt.write(
+        FADT_PM1A_CNT_BLK_OFFSET,
+        u32::from(ACPI_PM_PORT_BASE + PM1_CNT_OFFSET),
+    );
+    fadt.write(FADT_PM1_EVT_LEN_OFFSET, PM1_EVT_LEN);
+    fadt.write(FADT_PM1_CNT_LEN_OFFSET, PM1_CNT_LEN);
+
+    // The machine is reset through the i8042 controller.
+    write_io_register(&mut fadt, FADT_RESET_REG_OFFSET, I8042_COMMAND_PORT, 8);
+    fadt.write(FADT_RESET_VALUE_OFFSET, RESET_VALUE);
+
+    fadt.write(FADT_CENTURY_OFFSET, RTC_CENTURY);
+    fadt.write(FADT_IAPC_BOOT_ARCH_OFFSET, IAPC_BOOT_ARCH_8042);
+    fadt.write(
+        FADT_FLAGS_OFFSET,
+        FADT_WBINVD | FADT_SLP_BUTTON | FADT_RESET_REG_SUP,
+    );
+
+    fadt
+}
+
+/// Multiple APIC Description Table, with one local APIC per vCPU and the IOAPIC.
+fn create_madt(num_cpus: u8) -> Sdt {
+    let mut madt = Sdt::new(b"APIC", SDT_HEADER_LEN, MADT_REVISION);
+    madt.append(APIC_DEFAULT_PHYS_BASE);
+    madt.append(MADT_PCAT_COMPAT);
+
+    for cpu_id in 0..num_cpus {
+        // Processor UID and APIC ID both match the vCPU index, as in the MP table.
+        madt.append_slice(&[MADT_LOCAL_APIC, MADT_LOCAL_APIC_LEN, cpu_id, cpu_id]);
+        madt.append(MADT_LOCAL_APIC_ENABLED);
+    }
+
+    // The IOAPIC ID follows the one used by the MP table.
+    madt.append_slice(&[MADT_IO_APIC, MADT_IO_APIC_LEN, num_cpus + 1, 0]);
+    madt.append(IO_APIC_DEFAULT_PHYS_BASE);
+    // First global system interrupt.
+    madt.append(0u32);
+
+    madt
+}
+
+/// System Resource Affinity Table, putting every vCPU and memory range in proximity domain 0.
+fn create_srat(num_cpus: u8, memory_ranges: &[(u64, u64)]) -> Sdt {
+    let mut srat = Sdt::new(b"SRAT", SDT_HEADER_LEN, SRAT_REVISION);
+    srat.append(SRAT_TABLE_REVISION);
+    srat.append(0u64);
+
+    for cpu_id in 0..num_cpus {
+        // Proximity domain bits 0-7, APIC ID, flags, SAPIC EID, domain bits 8-31, clock domain.
+        srat.append_slice(&[
+            SRAT_LOCAL_APIC_AFFINITY,
+            SRAT_LOCAL_APIC_AFFINITY_LEN,
+            0,
+            cpu_id,
+        ]);
+        srat.append(SRAT_ENABLED);
+        srat.append_slice(&[0; 8]);
+    }
+
+    for (base, length) in memory_ranges {
+        // Proximity domain, reserved, base, length, reserved, flags, reserved.
+        srat.append_slice(&[SRAT_MEMORY_AFFINITY, SRAT_MEMORY_AFFINITY_LEN]);
+        srat.append(0u32);
+        srat.append(0u16);
+        srat.append(*base);
+        srat.append(*length);
+        srat.append(0u32);
+        srat.append(SRAT_ENABLED);
+        srat.append(0u64);
+    }
+
+    srat
+}
+
+/// Write `data` at `addr`, returning the address where the next table can be written.
+fn write_table(mem: &GuestMemoryMmap, addr: GuestAddress, data: &[u8]) -> Result<GuestAddress> {
+    let end = addr.raw_value() + data.len() as u64;
+    if end > ACPI_END {
+        return Err(Error::NotEnoughMemory);
+    }
+
+    mem.write_slice(data, addr).map_err(Error::WriteTable)?;
+
+    Ok(GuestAddress(align(end)))
+}
+
+/// Write the ACPI tables for a machine with `num_cpus` vCPUs, returning the RSDP address.
+///
+/// With `srat`, the guest is told it runs on a single NUMA node.
+pub fn setup_acpi(mem: &GuestMemoryMmap, num_cpus: u8, srat: bool) -> Result<GuestAddress> {
+    let rsdp_addr = GuestAddress(RSDP_START);
+    let dsdt_addr = GuestAddress(align(RSDP_START + RSDP_LEN as u64));
+
+    let dsdt = create_dsdt();
+    let fadt_addr = write_table(mem, dsdt_addr, dsdt.as_slice())?;
+
+    let fadt = create_fadt(dsdt_addr.raw_value() as u32);
+    let madt_addr = write_table(mem, fadt_addr, fadt.as_slice())?;
+
+    let madt = create_madt(num_cpus);
+    let mut next_addr = write_table(mem, madt_addr, madt.as_slice())?;
+    let mut tables = vec![fadt_addr.raw_value(), madt_addr.raw_value()];
+
+    if srat {
+        let memory_ranges: Vec<(u64, u64)> = mem
+            .iter()
+            .map(|region| (region.start_addr().raw_value(), region.len()))
+            .collect();
+        let srat_table = create_srat(num_cpus, &memory_ranges);
+        tables.push(next_addr.raw_value());
+        next_addr = write_table(mem, next_addr, srat_table.as_slice())?;
+    }
+
+    let xsdt_addr = next_addr;
+    let xsdt = create_xsdt(&tables);
+    write_table(mem, xsdt_addr, xsdt.as_slice())?;
+
+    write_table(mem, rsdp_addr, &create_rsdp(xsdt_addr.raw_value()))?;
+
+    Ok(rsdp_addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn guest_memory() -> GuestMemoryMmap {
+        GuestMemoryMmap::from_ranges(&[(GuestAddress(0), ACPI_END as usize)]).unwrap()
+    }
+
+    fn read_u32(mem: &GuestMemoryMmap, addr: u64) -> u32 {
+        mem.read_obj(GuestAddress(addr)).unwrap()
+    }
+
+    fn read_u64(mem: &GuestMemoryMmap, addr: u64) -> u64 {
+        mem.read_obj(GuestAddress(addr)).unwrap()
+    }
+
+    // Read a whole table from guest memory, checking its signature.
+    fn read_table(mem: &GuestMemoryMmap, addr: u64, signature: &[u8; 4]) -> Vec<u8> {
+        let length = read_u32(mem, addr + SDT_LENGTH_OFFSET as u64) as usize;
+        let mut table = vec![0u8; length];
+        mem.read_slice(&mut table, GuestAddress(addr)).unwrap();
+
+        assert_eq!(&table[0..4], signature);
+        table
+    }
+
+    #[test]
+    fn checksums() {
+        let mem = guest_memory();
+        let rsdp_addr = setup_acpi(&mem, 2, false).unwrap();
+
+        let mut rsdp = [0u8; RSDP_LEN];
+        mem.read_slice(&mut rsdp, rsdp_addr).unwrap();
+        assert_eq!(&rsdp[0..8], b"RSD PTR ");
+        assert_eq!(checksum(&rsdp[..RSDP_V1_LEN]), 0);
+        assert_eq!(checksum(&rsdp), 0);
+
+        let xsdt_addr = read_u64(&mem, rsdp_addr.raw_value() + 24);
+        let xsdt = read_table(&mem, xsdt_addr, b"XSDT");
+        assert_eq!(checksum(&xsdt), 0);
+        assert_eq!(xsdt.len(), SDT_HEADER_LEN + 2 * 8);
+
+        let fadt_addr = read_u64(&mem, xsdt_addr + SDT_HEADER_LEN as u64);
+        let fadt = read_table(&mem, fadt_addr, b"FACP");
+        assert_eq!(checksum(&fadt), 0);
+
+        let dsdt_addr = read_u64(&mem, fadt_addr + FADT_X_DSDT_OFFSET as u64);
+        let dsdt = read_table(&mem, dsdt_addr, b"DSDT");
+        assert_eq!(checksum(&dsdt), 0);
+
+        let madt_addr = read_u64(&mem, xsdt_addr + SDT_HEADER_LEN as u64 + 8);
+        let madt = read_table(&mem, madt_addr, b"APIC");
+        assert_eq!(checksum(&madt), 0);
+    }
+
+    #[test]
+    fn rsdp_layout() {
+        let expected = [
+            0x52, 0x53, 0x44, 0x20, 0x50, 0x54, 0x52, 0x20, 0x0a, 0x4c, 0x55, 0x4d, //
+            0x50, 0x45, 0x52, 0x02, 0x00, 0x00, 0x00, 0x00, 0x24, 0x00, 0x00, 0x00, //
+            0x00, 0x02, 0x0e, 0x00, 0x00, 0x00, 0x00, 0x00, 0xcc, 0x00, 0x00, 0x00, //
+        ];
+
+        assert_eq!(create_rsdp(0xe_0200), expected);
+    }
+
+    #[test]
+    fn xsdt_layout() {
+        let expected = [
+            0x58, 0x53, 0x44, 0x54, 0x34, 0x00, 0x00, 0x00, 0x01, 0xe1, 0x4c, 0x55, //
+            0x4d, 0x50, 0x45, 0x52, 0x4c, 0x55, 0x4d, 0x50, 0x45, 0x52, 0x56, 0x4d, //
+            0x01, 0x00, 0x00, 0x00, 0x4c, 0x4d, 0x50, 0x52, 0x01, 0x00, 0x00, 0x00, //
+            0x70, 0x00, 0x0e, 0x00, 0x00, 0x00, 0x00, 0x00, 0x90, 0x01, 0x0e, 0x00, //
+            0x00, 0x00, 0x00, 0x00, //
+        ];
+
+        assert_eq!(create_xsdt(&[0xe_0070, 0xe_0190]).as_slice(), expected);
+    }
+
+    #[test]
+    fn madt_layout() {
+        // Local APICs 0 and 1, then IOAPIC 3.
+        let expected = [
+            0x41, 0x50, 0x49, 0x43, 0x48, 0x00, 0x00, 0x00, 0x05, 0x4b, 0x4c, 0x55, //
+            0x4d, 0x50, 0x45, 0x52, 0x4c, 0x55, 0x4d, 0x50, 0x45, 0x52, 0x56, 0x4d, //
+            0x01, 0x00, 0x00, 0x00, 0x4c, 0x4d, 0x50, 0x52, 0x01, 0x00, 0x00, 0x00, //
+            0x00, 0x00, 0xe0, 0xfe, 0x01, 0x00, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00, //
+            0x01, 0x00, 0x00, 0x00, 0x00, 0x08, 0x01, 0x01, 0x01, 0x00, 0x00, 0x00, //
+            0x01, 0x0c, 0x03, 0x00, 0x00, 0x00, 0xc0, 0xfe, 0x00, 0x00, 0x00, 0x00, //
+        ];
+
+        assert_eq!(create_madt(2).as_slice(), expected);
+    }
+
+    #[test]
+    fn madt_cpus() {
+        let madt = create_madt(4);
+        let entries = &madt.as_slice()[SDT_HEADER_LEN + 8..];
+
+        // One local APIC per vCPU, then the IOAPIC.
+        assert_eq!(entries.len(), 4 * 8 + 12);
+        for (cpu_id, entry) in entries.chunks(8).take(4).enumerate() {
+            assert_eq!(
+                entry,
+                &[MADT_LOCAL_APIC, 8, cpu_id as u8, cpu_id as u8, 1, 0, 0, 0]
+            );
+        }
+        assert_eq!(&entries[32..36], &[MADT_IO_APIC, 12, 5, 0]);
+    }
+
+    #[test]
+    fn dsdt_layout() {
+        // Disassembled:
+        //
+        // Name (_S5, Package (0x02) { 0x05, 0x05 })
+        // Scope (\_SB) {
+        //     Device (COM1) {
+        //         Name (_HID, EisaId ("PNP0501"))
+        //         Name (_UID, Zero)
+        //         Name (_CRS, ResourceTemplate () {
+        //             IO (Decode16, 0x03F8, 0x03F8, 0x00, 0x08)
+        //             IRQNoFlags () {4}
+        //         })
+        //     }
+        //     Device (PEVT) {
+        //         Name (_HID, "QEMU0001")
+        //         Name (_CRS, ResourceTemplate () {
+        //             IO (Decode16, 0x0505, 0x0505, 0x00, 0x01)
+        //         })
+        //     }
+        // }
+        let expected = [
+            0x44, 0x53, 0x44, 0x54, 0x8e, 0x00, 0x00, 0x00, 0x02, 0x0c, 0x4c, 0x55, //
+            0x4d, 0x50, 0x45, 0x52, 0x4c, 0x55, 0x4d, 0x50, 0x45, 0x52, 0x56, 0x4d, //
+            0x01, 0x00, 0x00, 0x00, 0x4c, 0x4d, 0x50, 0x52, 0x01, 0x00, 0x00, 0x00, //
+            0x08, 0x5f, 0x53, 0x35, 0x5f, 0x12, 0x06, 0x02, 0x0a, 0x05, 0x0a, 0x05, //
+            0x10, 0x4d, 0x05, 0x5c, 0x5f, 0x53, 0x42, 0x5f, 0x5b, 0x82, 0x2b, 0x43, //
+            0x4f, 0x4d, 0x31, 0x08, 0x5f, 0x48, 0x49, 0x44, 0x0c, 0x41, 0xd0, 0x05, //
+            0x01, 0x08, 0x5f, 0x55, 0x49, 0x44, 0x00, 0x08, 0x5f, 0x43, 0x52, 0x53, //
+            0x11, 0x10, 0x0a, 0x0d, 0x47, 0x01, 0xf8, 0x03, 0xf8, 0x03, 0x00, 0x08, //
+            0x22, 0x10, 0x00, 0x79, 0x00, 0x5b, 0x82, 0x27, 0x50, 0x45, 0x56, 0x54, //
+            0x08, 0x5f, 0x48, 0x49, 0x44, 0x0d, 0x51, 0x45, 0x4d, 0x55, 0x30, 0x30, //
+            0x30, 0x31, 0x00, 0x08, 0x5f, 0x43, 0x52, 0x53, 0x11, 0x0d, 0x0a, 0x0a, //
+            0x47, 0x01, 0x05, 0x05, 0x05, 0x05, 0x00, 0x01, 0x79, 0x00, //
+        ];
+
+        assert_eq!(create_dsdt().as_slice(), expected);
+    }
+
+    #[test]
+    fn aml_pkg_lengths() {
+        assert_eq!(aml_pkg_length(0), [0x01]);
+        assert_eq!(aml_pkg_length(62), [0x3f]);
+        // 63 bytes of content and two bytes of length.
+        assert_eq!(aml_pkg_length(63), [0x41, 0x04]);
+    }
+
+    #[test]
+    fn fadt_power_management() {
+        let fadt = create_fadt(0x1234);
+        let data = fadt.as_slice();
+
+        assert_eq!(data.len(), FADT_LEN);
+        assert_eq!(
+            &data[FADT_DSDT_OFFSET..FADT_DSDT_OFFSET + 4],
+            &[0x34, 0x12, 0, 0]
+        );
+        // PM1a control block, where the guest writes the S5 sleep type.
+        assert_eq!(
+            &data[FADT_PM1A_CNT_BLK_OFFSET..FADT_PM1A_CNT_BLK_OFFSET + 4],
+            &[0x04, 0x06, 0, 0]
+        );
+        assert_eq!(data[FADT_PM1_CNT_LEN_OFFSET], 2);
+        // Reset register, the i8042 command port.
+        assert_eq!(
+            &data[FADT_RESET_REG_OFFSET..FADT_RESET_REG_OFFSET + 5],
+            &[GAS_SYSTEM_IO, 8, 0, GAS_ACCESS_BYTE, 0x64]
+        );
+        assert_eq!(data[FADT_RESET_VALUE_OFFSET], RESET_VALUE);
+        // The power button is a fixed feature.
+        assert_eq!(data[FADT_FLAGS_OFFSET] & (1 << 4), 0);
+    }
+
+    #[test]
+    fn tables_fit_for_max_cpus() {
+        let mem = guest_memory();
+        assert!(setup_acpi(&mem, 254, true).is_ok());
+    }
+
+    #[test]
+    fn srat_layout() {
+        // Local APIC 0, then 2 GiB of memory from 0, both in proximity domain 0.
+        let expected = [
+            0x53, 0x52, 0x41, 0x54, 0x68, 0x00, 0x00, 0x00, 0x03, 0x15, 0x4c, 0x55, //
+            0x4d, 0x50, 0x45, 0x52, 0x4c, 0x55, 0x4d, 0x50, 0x45, 0x52, 0x56, 0x4d, //
+            0x01, 0x00, 0x00, 0x00, 0x4c, 0x4d, 0x50, 0x52, 0x01, 0x00, 0x00, 0x00, //
+            0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, //
+            0x00, 0x10, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, //
+            0x00, 0x00, 0x00, 0x00, 0x01, 0x28, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, //
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x80, //
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, //
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, //
+        ];
+
+        assert_eq!(create_srat(1, &[(0, 2 << 30)]).as_slice(), expected);
+    }
+
+    #[test]
+    fn srat_in_xsdt() {
+        let mem = guest_memory();
+        let rsdp_addr = setup_acpi(&mem, 2, true).unwrap();
+
+        let xsdt_addr = read_u64(&mem, rsdp_addr.raw_value() + 24);
+        let xsdt = read_table(&mem, xsdt_addr, b"XSDT");
+        assert_eq!(xsdt.len(), SDT_HEADER_LEN + 3 * 8);
+
+        let srat_addr = read_u64(&mem, xsdt_addr + SDT_HEADER_LEN as u64 + 16);
+        let srat = read_table(&mem, srat_addr, b"SRAT");
+        assert_eq!(checksum(&srat), 0);
+        // Header, two local APICs and the single guest memory region.
+        assert_eq!(srat.len(), SDT_HEADER_LEN + 12 + 2 * 16 + 40);
+    }
+}
@@ -0,0 +1,595 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Assembling a VM from its whole configuration.
+//!
+//! The builder checks the configuration as a whole, then configures the VMM in the order its
+//! `configure_*` methods expect. It is the recommended way to create a VM, the `configure_*`
+//! methods stay available for finer control.
+
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
+use std::result;
+use std::sync::Arc;
+
+use crate::cpu::mptable::MAX_SUPPORTED_CPUS;
+use crate::daemon::ReadyNotifier;
+use crate::devices::shmem::{ShmemConfig, SHMEM_IRQ};
+use crate::devices::{self, boot_notifier};
+use crate::memory;
+use crate::{ConsoleConfig, NumaPlacement, SystemdNotifier, VMM};
+
+/// Errors found by checking the configuration as a whole.
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// No kernel image was given.
+    NoKernel,
+    /// The kernel image is not a file.
+    KernelNotFound(PathBuf),
+    /// The VM has no vCPU.
+    NoVcpus,
+    /// The guest cannot have that many vCPUs.
+    TooManyVcpus(u8),
+    /// The console cannot be written to a file and exposed over TCP at the same time.
+    ConsoleConflict,
+    /// The guest memory does not fit in its limit or in the guest physical address space.
+    Memory(memory::Error),
+    /// Invalid shared memory configuration.
+    Shmem(String),
+    /// Invalid I/O port.
+    Port(String),
+    /// The I/O port belongs to another device.
+    PortConflict(u16),
+    /// The interrupt is raised by another device.
+    IrqConflict(u32),
+    /// Invalid GDB server address.
+    GdbAddress(String),
+    /// The console and the GDB server listen on the same address.
+    AddressConflict(String),
+}
+
+/// Configuration of a VM, turned into a VMM ready to run.
+pub struct VmConfigBuilder {
+    num_vcpus: u8,
+    mem_size_mb: u32,
+    mem_limit_mb: Option<u32>,
+    dirty_log: bool,
+    memory_options: Option<String>,
+    allow_overcommit: bool,
+    host_available_memory: Option<u64>,
+    kernel_path: Option<PathBuf>,
+    cmdline: Vec<String>,
+    cmdline_size: Option<usize>,
+    boot_protocol: Option<String>,
+    cpu_brand: Option<String>,
+    cpu_features: Option<String>,
+    tsc_khz: Option<u32>,
+    strict_msrs: bool,
+    uuid: Option<String>,
+    smbios: Option<String>,
+    console: ConsoleConfig,
+    timeout: Option<u64>,
+    on_crash: Option<String>,
+    watchdog: Option<String>,
+    shmem: Option<String>,
+    boot_notifier: Option<String>,
+    vcpu_affinity: Option<String>,
+    event_loop_cpu: Option<usize>,
+    numa: Option<NumaPlacement>,
+    gdb: Option<String>,
+    ready_notifier: Option<Arc<ReadyNotifier>>,
+    ready_on: Option<String>,
+    systemd_notifier: Option<SystemdNotifier>,
+}
+
+impl Default for VmConfigBuilder {
+    fn default() -> Self {
+        VmConfigBuilder {
+            num_vcpus: 1,
+            mem_size_mb: 512,
+            mem_limit_mb: None,
+            dirty_log: false,
+            memory_options: None,
+            allow_overcommit: false,
+            host_available_memory: None,
+            kernel_path: None,
+            cmdline: Vec::new(),
+            cmdline_size: None,
+            boot_protocol: None,
+            cpu_brand: None,
+            cpu_features: None,
+            tsc_khz: None,
+            strict_msrs: false,
+            uuid: None,
+            smbios: None,
+            console: ConsoleConfig::default(),
+            timeout: None,
+            on_crash: None,
+            watchdog: None,
+            shmem: None,
+            boot_notifier: None,
+            vcpu_affinity: None,
+            event_loop_cpu: None,
+            numa: None,
+            gdb: None,
+            ready_notifier: None,
+            ready_on: None,
+            systemd_notifier: None,
+        }
+    }
+}
+
+impl VmConfigBuilder {
+    /// A VM with 1 vCPU and 512 MB of memory, only the kernel must be set.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of vCPUs.
+    pub fn vcpus(mut self, num_vcpus: u8) -> Self {
+        self.num_vcpus = num_vcpus;
+        self
+    }
+
+    /// Memory size, in MB, and the maximum allowed (the host memory if `None`).
+    pub fn memory(mut self, mem_size_mb: u32, mem_limit_mb: Option<u32>) -> Self {
+        self.mem_size_mb = mem_size_mb;
+        self.mem_limit_mb = mem_limit_mb;
+        self
+    }
+
+    /// Track the guest pages written, see [`VMM::dirty_pages`].
+    pub fn dirty_log(mut self, enabled: bool) -> Self {
+        self.dirty_log = enabled;
+        self
+    }
+
+    /// How the guest memory is allocated, as `mlock=on|off`, see
+    /// [`VMM::configure_memory_options`].
+    pub fn memory_options(mut self, options: Option<String>) -> Self {
+        self.memory_options = options;
+        self
+    }
+
+    /// Allow the guest memory to exceed the memory available on the host.
+    pub fn allow_overcommit(mut self, allowed: bool) -> Self {
+        self.allow_overcommit = allowed;
+        self
+    }
+
+    /// Memory available on the host, in bytes, read beforehand, see
+    /// [`VMM::configure_host_available_memory`].
+    pub fn host_available_memory(mut self, available: Option<u64>) -> Self {
+        self.host_available_memory = available;
+        self
+    }
+
+    /// Kernel image, either an ELF `vmlinux` or a bzImage.
+    pub fn kernel<P: Into<PathBuf>>(mut self, kernel_path: P) -> Self {
+        self.kernel_path = Some(kernel_path.into());
+        self
+    }
+
+    /// Append `args` to the kernel command line, may be called several times.
+    pub fn cmdline(mut self, args: Option<String>) -> Self {
+        self.cmdline.extend(args);
+        self
+    }
+
+    /// Maximum size of the kernel command line, see [`VMM::configure_cmdline_size`].
+    pub fn cmdline_size(mut self, size: Option<usize>) -> Self {
+        self.cmdline_size = size;
+        self
+    }
+
+    /// Protocol used to enter the kernel: `auto`, `linux` or `pvh`.
+    pub fn boot_protocol(mut self, boot_protocol: Option<String>) -> Self {
+        self.boot_protocol = boot_protocol;
+        self
+    }
+
+    /// Brand string and features of the CPU exposed to the guest, see
+    /// [`VMM::configure_cpu_model`].
+    pub fn cpu_model(mut self, brand: Option<String>, features: Option<String>) -> Self {
+        self.cpu_brand = brand;
+        self.cpu_features = features;
+        self
+    }
+
+    /// System UUID and `serial=<serial>,product=<product>` strings read by the guest from the
+    /// SMBIOS tables, see [`VMM::configure_smbios`].
+    pub fn smbios(mut self, uuid: Option<String>, strings: Option<String>) -> Self {
+        self.uuid = uuid;
+        self.smbios = strings;
+        self
+    }
+
+    /// TSC frequency of the vCPUs in kHz, the host one if `None`.
+    pub fn tsc_frequency(mut self, tsc_khz: Option<u32>) -> Self {
+        self.tsc_khz = tsc_khz;
+        self
+    }
+
+    /// Inject a #GP on accesses to unknown MSRs, see [`VMM::configure_strict_msrs`].
+    pub fn strict_msrs(mut self, strict: bool) -> Self {
+        self.strict_msrs = strict;
+        self
+    }
+
+    /// Where the console is written to and read from.
+    pub fn console(mut self, console: ConsoleConfig) -> Self {
+        self.console = console;
+        self
+    }
+
+    /// Stop the VM if it is still running after `timeout` seconds.
+    pub fn timeout(mut self, timeout: Option<u64>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// What happens when the guest crashes: `dump`, `pause` or `shutdown`.
+    pub fn on_crash(mut self, on_crash: Option<String>) -> Self {
+        self.on_crash = on_crash;
+        self
+    }
+
+    /// Watchdog exposed to the guest, as `action=<action>,timeout=<seconds>`.
+    pub fn watchdog(mut self, watchdog: Option<String>) -> Self {
+        self.watchdog = watchdog;
+        self
+    }
+
+    /// Memory shared with host processes, as `size=<size>,socket=<path>`, see
+    /// [`VMM::configure_shmem`].
+    pub fn shmem(mut self, shmem: Option<String>) -> Self {
+        self.shmem = shmem;
+        self
+    }
+
+    /// I/O port the guest writes to once it booted.
+    pub fn boot_notifier(mut self, port: Option<String>) -> Self {
+        self.boot_notifier = port;
+        self
+    }
+
+    /// Host CPUs the vCPU threads and the event loop thread are pinned to, see
+    /// [`VMM::configure_affinity`].
+    pub fn affinity(
+        mut self,
+        vcpu_affinity: Option<String>,
+        event_loop_cpu: Option<usize>,
+    ) -> Self {
+        self.vcpu_affinity = vcpu_affinity;
+        self.event_loop_cpu = event_loop_cpu;
+        self
+    }
+
+    /// Host NUMA node the VM is bound to, as resolved by [`crate::numa_placement`], see
+    /// [`VMM::configure_numa`].
+    pub fn numa(mut self, numa: Option<NumaPlacement>) -> Self {
+        self.numa = numa;
+        self
+    }
+
+    /// Address the GDB remote protocol is served on (e.g. `tcp::1234`).
+    pub fn gdb(mut self, addr: Option<String>) -> Self {
+        self.gdb = addr;
+        self
+    }
+
+    /// Told once the VM is ready.
+    pub fn ready_notifier(mut self, ready_notifier: Option<Arc<ReadyNotifier>>) -> Self {
+        self.ready_notifier = ready_notifier;
+        self
+    }
+
+    /// When the VM is ready, see [`VMM::configure_ready_on`].
+    pub fn ready_on(mut self, ready_on: Option<String>) -> Self {
+        self.ready_on = ready_on;
+        self
+    }
+
+    /// Told how the VM is doing, when the VMM runs as a systemd service.
+    pub fn systemd_notifier(mut self, systemd_notifier: Option<SystemdNotifier>) -> Self {
+        self.systemd_notifier = systemd_notifier;
+        self
+    }
+
+    /// Check the parts of the configuration that do not need a VM.
+    fn validate(&self) -> result::Result<&PathBuf, Error> {
+        let kernel_path = self.kernel_path.as_ref().ok_or(Error::NoKernel)?;
+        if !kernel_path.is_file() {
+            return Err(Error::KernelNotFound(kernel_path.clone()));
+        }
+
+        if self.num_vcpus == 0 {
+            return Err(Error::NoVcpus);
+        }
+        if u32::from(self.num_vcpus) > MAX_SUPPORTED_CPUS {
+            return Err(Error::TooManyVcpus(self.num_vcpus));
+        }
+
+        if self.console.path.is_some() && self.console.tcp.is_some() {
+            return Err(Error::ConsoleConflict);
+        }
+
+        // The host memory is only read when the VM is configured, without an explicit limit.
+        let mem_limit = self
+            .mem_limit_mb
+            .map_or(u64::MAX, |mem_limit_mb| u64::from(mem_limit_mb) << 20);
+        memory::guest_memory_regions(u64::from(self.mem_size_mb) << 20, mem_limit)
+            .map_err(Error::Memory)?;
+
+        if let Some(shmem) = &self.shmem {
+            shmem
+                .parse::<ShmemConfig>()
+                .map_err(|_| Error::Shmem(shmem.clone()))?;
+            if devices::irq_in_use(SHMEM_IRQ) {
+                return Err(Error::IrqConflict(SHMEM_IRQ));
+            }
+        }
+
+        if let Some(port) = &self.boot_notifier {
+            let port = boot_notifier::parse_port(port).map_err(|_| Error::Port(port.clone()))?;
+            if devices::port_in_use(port) {
+                return Err(Error::PortConflict(port));
+            }
+        }
+
+        if let Some(gdb) = &self.gdb {
+            let addr =
+                crate::gdb::parse_address(gdb).map_err(|_| Error::GdbAddress(gdb.clone()))?;
+            if let Some(console_tcp) = &self.console.tcp {
+                if addresses_conflict(console_tcp, &addr) {
+                    return Err(Error::AddressConflict(addr));
+                }
+            }
+        }
+
+        Ok(kernel_path)
+    }
+
+    /// Create the VM, ready to run.
+    pub fn build(self) -> crate::Result<VMM> {
+        let mut vmm = VMM::new()?;
+        self.configure(&mut vmm)?;
+
+        Ok(vmm)
+    }
+
+    /// Configure a VM just created with [`VMM::new`], for callers telling its creation errors
+    /// apart from configuration ones.
+    pub fn configure(self, vmm: &mut VMM) -> crate::Result<()> {
+        let kernel_path = self
+            .validate()
+            .map_err(crate::Error::VmConfig)?
+            .to_string_lossy()
+            .into_owned();
+
+        // Settings used while configuring the vCPUs and loading the kernel.
+        vmm.configure_cpu_model(self.cpu_brand, self.cpu_features)?;
+        vmm.configure_tsc_frequency(self.tsc_khz);
+        vmm.configure_strict_msrs(self.strict_msrs);
+        vmm.configure_smbios(self.uuid, self.smbios)?;
+        vmm.configure_boot_protocol(self.boot_protocol)?;
+        vmm.configure_cmdline_size(self.cmdline_size)?;
+        for args in self.cmdline {
+            vmm.configure_cmdline(Some(args))?;
+        }
+        vmm.configure_on_crash(self.on_crash)?;
+        vmm.configure_watchdog(self.watchdog)?;
+        vmm.configure_shmem(self.shmem)?;
+        vmm.configure_boot_notifier(self.boot_notifier)?;
+        vmm.configure_ready_notifier(self.ready_notifier);
+        vmm.configure_ready_on(self.ready_on)?;
+        vmm.configure_systemd_notifier(self.systemd_notifier)?;
+        vmm.configure_dirty_log(self.dirty_log);
+        vmm.configure_memory_options(self.memory_options)?;
+        vmm.configure_overcommit(self.allow_overcommit);
+        vmm.configure_host_available_memory(self.host_available_memory);
+        vmm.configure_numa(self.numa);
+
+        vmm.configure(
+            self.num_vcpus,
+            self.mem_size_mb,
+            self.mem_limit_mb,
+            &kernel_path,
+            self.console,
+            self.timeout,
+        )?;
+
+        // Settings applied to the configured vCPUs.
+        vmm.configure_affinity(self.vcpu_affinity, self.event_loop_cpu)?;
+        vmm.configure_gdb(self.gdb)?;
+
+        Ok(())
+    }
+}
+
+/// Whether listening on both addresses takes the same port, an unspecified host listening on
+/// every host. Addresses that do not resolve are left for the listeners to report.
+fn addresses_conflict(a: &str, b: &str) -> bool {
+    let resolve = |addr: &str| -> Vec<SocketAddr> {
+        addr.to_socket_addrs()
+            .map(|addrs| addrs.collect())
+            .unwrap_or_default()
+    };
+    let b = resolve(b);
+
+    resolve(a).iter().any(|a| {
+        b.iter().any(|b| {
+            a.port() == b.port()
+                && (a.ip() == b.ip() || a.ip().is_unspecified() || b.ip().is_unspecified())
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    use vmm_sys_util::tempfile::TempFile;
+
+    // Kernel image passing the checks, removed with the returned file.
+    fn kernel() -> (TempFile, PathBuf) {
+        let kernel = TempFile::new_with_prefix(env::temp_dir().join("vmlinux")).unwrap();
+        let kernel_path = kernel.as_path().to_path_buf();
+
+        (kernel, kernel_path)
+    }
+
+    #[test]
+    fn validate() {
+        let (_kernel, kernel_path) = kernel();
+
+        let builder = VmConfigBuilder::new();
+        assert_eq!(builder.validate(), Err(Error::NoKernel));
+
+        let builder = VmConfigBuilder::new().kernel("/nonexistent/vmlinux");
+        assert_eq!(
+            builder.validate(),
+            Err(Error::KernelNotFound(PathBuf::from("/nonexistent/vmlinux")))
+        );
+
+        let builder = VmConfigBuilder::new().kernel(&kernel_path).vcpus(4);
+        assert_eq!(builder.validate(), Ok(&kernel_path));
+
+        let builder = VmConfigBuilder::new().kernel(&kernel_path).vcpus(0);
+        assert_eq!(builder.validate(), Err(Error::NoVcpus));
+
+        let builder = VmConfigBuilder::new().kernel(&kernel_path).vcpus(255);
+        assert_eq!(builder.validate(), Err(Error::TooManyVcpus(255)));
+
+        let builder = VmConfigBuilder::new()
+            .kernel(&kernel_path)
+            .console(ConsoleConfig {
+                path: Some("console.log".to_string()),
+                tcp: Some("127.0.0.1:4444".to_string()),
+                ..Default::default()
+            });
+        assert_eq!(builder.validate(), Err(Error::ConsoleConflict));
+    }
+
+    #[test]
+    fn validate_memory() {
+        let (_kernel, kernel_path) = kernel();
+
+        let builder = VmConfigBuilder::new()
+            .kernel(&kernel_path)
+            .memory(4096, Some(4096));
+        assert_eq!(builder.validate(), Ok(&kernel_path));
+
+        let builder = VmConfigBuilder::new().kernel(&kernel_path).memory(0, None);
+        assert_eq!(builder.validate(), Err(Error::Memory(memory::Error::Empty)));
+
+        let builder = VmConfigBuilder::new()
+            .kernel(&kernel_path)
+            .memory(2048, Some(1024));
+        assert_eq!(
+            builder.validate(),
+            Err(Error::Memory(memory::Error::ExceedsLimit(2 << 30, 1 << 30)))
+        );
+    }
+
+    #[test]
+    fn validate_shmem() {
+        let (_kernel, kernel_path) = kernel();
+
+        let shmem = "size=16M,socket=/run/shmem.sock".to_string();
+        let builder = VmConfigBuilder::new()
+            .kernel(&kernel_path)
+            .shmem(Some(shmem));
+        assert_eq!(builder.validate(), Ok(&kernel_path));
+
+        // Larger than the window left to it in the MMIO gap.
+        let shmem = "size=1G,socket=/run/shmem.sock".to_string();
+        let builder = VmConfigBuilder::new()
+            .kernel(&kernel_path)
+            .shmem(Some(shmem.clone()));
+        assert_eq!(builder.validate(), Err(Error::Shmem(shmem)));
+    }
+
+    #[test]
+    fn validate_ports() {
+        let (_kernel, kernel_path) = kernel();
+
+        let builder = VmConfigBuilder::new()
+            .kernel(&kernel_path)
+            .boot_notifier(Some("0x440".to_string()));
+        assert_eq!(builder.validate(), Ok(&kernel_path));
+
+        let builder = VmConfigBuilder::new()
+            .kernel(&kernel_path)
+            .boot_notifier(Some("0x3f8".to_string()));
+        assert_eq!(builder.validate(), Err(Error::PortConflict(0x3f8)));
+
+        let builder = VmConfigBuilder::new()
+            .kernel(&kernel_path)
+            .boot_notifier(Some("0x10000".to_string()));
+        assert_eq!(builder.validate(), Err(Error::Port("0x10000".to_string())));
+    }
+
+    #[test]
+    fn validate_addresses() {
+        let (_kernel, kernel_path) = kernel();
+        let console = || ConsoleConfig {
+            tcp: Some("127.0.0.1:1234".to_string()),
+            ..Default::default()
+        };
+
+        let builder = VmConfigBuilder::new()
+            .kernel(&kernel_path)
+            .console(console())
+            .gdb(Some("tcp::1235".to_string()));
+        assert_eq!(builder.validate(), Ok(&kernel_path));
+
+        let builder = VmConfigBuilder::new()
+            .kernel(&kernel_path)
+            .console(console())
+            .gdb(Some("tcp::1234".to_string()));
+        assert_eq!(
+            builder.validate(),
+            Err(Error::AddressConflict("127.0.0.1:1234".to_string()))
+        );
+
+        // Listening on every host takes the port on the loopback one too.
+        let builder = VmConfigBuilder::new()
+            .kernel(&kernel_path)
+            .console(ConsoleConfig {
+                tcp: Some("0.0.0.0:1234".to_string()),
+                ..Default::default()
+            })
+            .gdb(Some("tcp::1234".to_string()));
+        assert_eq!(
+            builder.validate(),
+            Err(Error::AddressConflict("127.0.0.1:1234".to_string()))
+        );
+
+        let builder = VmConfigBuilder::new()
+            .kernel(&kernel_path)
+            .console(console())
+            .gdb(Some("tcp:localhost:1234".to_string()));
+        assert_eq!(
+            builder.validate(),
+            Err(Error::AddressConflict("localhost:1234".to_string()))
+        );
+
+        let builder = VmConfigBuilder::new()
+            .kernel(&kernel_path)
+            .gdb(Some("1234".to_string()));
+        assert_eq!(
+            builder.validate(),
+            Err(Error::GdbAddress("1234".to_string()))
+        );
+    }
+
+    #[test]
+    fn cmdline_args() {
+        let builder = VmConfigBuilder::new()
+            .cmdline(Some("quiet".to_string()))
+            .cmdline(None)
+            .cmdline(Some("init=/bin/sh".to_string()));
+        assert_eq!(builder.cmdline, vec!["quiet", "init=/bin/sh"]);
+    }
+}
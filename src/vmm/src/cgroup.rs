@@ -0,0 +1,289 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Limiting the host resources of the VMM with a cgroup v2.
+//!
+//! A child cgroup is created under the given parent, with the CPU and memory limits written to
+//! it, and the whole VMM process is moved into it: threads spawned afterwards, vCPUs included,
+//! land in it too. The process moves back to its original cgroup and the child cgroup is removed
+//! when dropped.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::result;
+use std::str::FromStr;
+
+use crate::memory::parse_size;
+
+/// Where the cgroup v2 hierarchy is mounted.
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// Period of the CPU quota, in microseconds.
+const CPU_PERIOD_US: u64 = 100_000;
+
+/// Errors limiting the VMM resources.
+#[derive(Debug)]
+pub enum Error {
+    /// Invalid cgroup configuration, as given.
+    Config(String),
+    /// The parent is not a cgroup v2 directory (cgroup v1 host, or wrong path).
+    NotCgroup2(PathBuf),
+    /// The controller is not available in the parent cgroup, it must be enabled in the
+    /// `cgroup.subtree_control` of its own parent.
+    Controller(&'static str, PathBuf),
+    /// The VMM may not write to the cgroup, it must own the parent cgroup or run as root.
+    PermissionDenied(PathBuf),
+    /// Failed to access the cgroup file.
+    Io(PathBuf, io::Error),
+}
+
+/// Dedicated Result type.
+pub type Result<T> = result::Result<T, Error>;
+
+/// Cgroup of the VMM, as a list of `<key>=<value>` (e.g.
+/// `parent=/sys/fs/cgroup/lumper,cpu-max=200000,memory-max=3G`).
+#[derive(Clone, Debug, PartialEq)]
+pub struct CgroupConfig {
+    /// Cgroup the VMM one is created in.
+    pub parent: PathBuf,
+    /// CPU time the VMM may use per 100ms, in microseconds (e.g. 200000 for 2 CPUs).
+    pub cpu_max: Option<u64>,
+    /// Memory the VMM may use, in bytes.
+    pub memory_max: Option<u64>,
+}
+
+impl FromStr for CgroupConfig {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        let invalid = || crate::Error::Cgroup(Error::Config(s.to_string()));
+
+        let mut parent = None;
+        let mut cpu_max = None;
+        let mut memory_max = None;
+        for option in s.split(',').filter(|option| !option.is_empty()) {
+            let mut option = option.splitn(2, '=');
+            match (option.next(), option.next()) {
+                (Some("parent"), Some(path)) => parent = Some(PathBuf::from(path)),
+                (Some("cpu-max"), Some(quota)) => {
+                    cpu_max = Some(quota.parse().ok().filter(|&q| q > 0).ok_or_else(invalid)?)
+                }
+                (Some("memory-max"), Some(size)) => {
+                    memory_max = Some(parse_size(size).ok_or_else(invalid)?)
+                }
+                _ => return Err(invalid()),
+            }
+        }
+
+        Ok(CgroupConfig {
+            parent: parent.ok_or_else(invalid)?,
+            cpu_max,
+            memory_max,
+        })
+    }
+}
+
+fn write(path: &Path, value: &str) -> Result<()> {
+    fs::write(path, value).map_err(|e| match e.kind() {
+        io::ErrorKind::PermissionDenied => Error::PermissionDenied(path.to_path_buf()),
+        _ => Error::Io(path.to_path_buf(), e),
+    })
+}
+
+fn read(path: &Path) -> Result<String> {
+    fs::read_to_string(path).map_err(|e| Error::Io(path.to_path_buf(), e))
+}
+
+/// Cgroup the process is in, from `/proc/self/cgroup`.
+fn current_cgroup() -> Option<PathBuf> {
+    let cgroups = fs::read_to_string("/proc/self/cgroup").ok()?;
+    // The cgroup v2 entry is `0::<path>`.
+    let path = cgroups.lines().find_map(|line| line.strip_prefix("0::"))?;
+
+    Some(Path::new(CGROUP_ROOT).join(path.trim_start_matches('/')))
+}
+
+/// Cgroup created for the VMM, removed when dropped.
+pub struct Cgroup {
+    path: PathBuf,
+    // Cgroup the process is moved back to, so that this one can be removed.
+    origin: Option<PathBuf>,
+}
+
+impl Cgroup {
+    /// Create the VMM cgroup, named after the process, and write its limits.
+    pub fn create(config: &CgroupConfig) -> crate::Result<Self> {
+        let name = format!("lumper-{}", process::id());
+        Self::create_in(config, &name, current_cgroup()).map_err(crate::Error::Cgroup)
+    }
+
+    fn create_in(config: &CgroupConfig, name: &str, origin: Option<PathBuf>) -> Result<Self> {
+        // Only cgroup v2 directories have this file.
+        let controllers = config.parent.join("cgroup.controllers");
+        if !controllers.is_file() {
+            return Err(Error::NotCgroup2(config.parent.clone()));
+        }
+        let controllers = read(&controllers)?;
+
+        // The limits of a cgroup need its parent to enable the controllers for its children.
+        let subtree_control = config.parent.join("cgroup.subtree_control");
+        let enabled = read(&subtree_control)?;
+        for (controller, needed) in [
+            ("cpu", config.cpu_max.is_some()),
+            ("memory", config.memory_max.is_some()),
+        ]
+        .iter()
+        {
+            if !needed || enabled.split_whitespace().any(|c| c == *controller) {
+                continue;
+            }
+            if !controllers.split_whitespace().any(|c| c == *controller) {
+                return Err(Error::Controller(controller, config.parent.clone()));
+            }
+            write(&subtree_control, &format!("+{}", controller))?;
+        }
+
+        let path = config.parent.join(name);
+        fs::create_dir(&path).map_err(|e| match e.kind() {
+            io::ErrorKind::PermissionDenied => Error::PermissionDenied(path.clone()),
+            _ => Error::Io(path.clone(), e),
+        })?;
+        let cgroup = Cgroup { path, origin };
+
+        if let Some(quota) = config.cpu_max {
+            write(
+                &cgroup.path.join("cpu.max"),
+                &format!("{} {}", quota, CPU_PERIOD_US),
+            )?;
+        }
+        if let Some(size) = config.memory_max {
+            write(&cgroup.path.join("memory.max"), &size.to_string())?;
+        }
+
+        Ok(cgroup)
+    }
+
+    /// Move the process `pid`, with all its threads, into the cgroup.
+    pub fn add_process(&self, pid: u32) -> crate::Result<()> {
+        write(&self.path.join("cgroup.procs"), &pid.to_string()).map_err(crate::Error::Cgroup)
+    }
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        // A cgroup holding processes cannot be removed.
+        if let Some(origin) = &self.origin {
+            let _ = fs::write(origin.join("cgroup.procs"), process::id().to_string());
+        }
+        let _ = fs::remove_dir(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    use vmm_sys_util::tempdir::TempDir;
+
+    #[test]
+    fn config() {
+        assert_eq!(
+            "parent=/sys/fs/cgroup/lumper,cpu-max=200000,memory-max=3G"
+                .parse::<CgroupConfig>()
+                .unwrap(),
+            CgroupConfig {
+                parent: PathBuf::from("/sys/fs/cgroup/lumper"),
+                cpu_max: Some(200_000),
+                memory_max: Some(3 << 30),
+            }
+        );
+        assert_eq!(
+            "parent=/sys/fs/cgroup,memory-max=4096"
+                .parse::<CgroupConfig>()
+                .unwrap(),
+            CgroupConfig {
+                parent: PathBuf::from("/sys/fs/cgroup"),
+                cpu_max: None,
+                memory_max: Some(4096),
+            }
+        );
+
+        for invalid in [
+            "cpu-max=200000",
+            "parent=/sys/fs/cgroup,cpu-max=0",
+            "parent=/sys/fs/cgroup,memory-max=3X",
+            "parent=/sys/fs/cgroup,io-max=1",
+        ]
+        .iter()
+        {
+            assert!(invalid.parse::<CgroupConfig>().is_err(), "{}", invalid);
+        }
+    }
+
+    // A fake cgroup v2 directory, the cgroup files being regular files.
+    fn fake_parent(controllers: &str, subtree_control: &str) -> TempDir {
+        let dir = TempDir::new_with_prefix(env::temp_dir().join("lumper")).unwrap();
+        fs::write(dir.as_path().join("cgroup.controllers"), controllers).unwrap();
+        fs::write(
+            dir.as_path().join("cgroup.subtree_control"),
+            subtree_control,
+        )
+        .unwrap();
+        dir
+    }
+
+    #[test]
+    fn limits() {
+        let parent = fake_parent("cpuset cpu io memory pids\n", "memory\n");
+        let config = CgroupConfig {
+            parent: parent.as_path().to_path_buf(),
+            cpu_max: Some(200_000),
+            memory_max: Some(3 << 30),
+        };
+
+        let cgroup = Cgroup::create_in(&config, "vm", None).unwrap();
+        let path = parent.as_path().join("vm");
+        assert_eq!(
+            fs::read_to_string(path.join("cpu.max")).unwrap(),
+            "200000 100000"
+        );
+        assert_eq!(
+            fs::read_to_string(path.join("memory.max")).unwrap(),
+            "3221225472"
+        );
+        // Only the missing controller is enabled.
+        assert_eq!(
+            fs::read_to_string(parent.as_path().join("cgroup.subtree_control")).unwrap(),
+            "+cpu"
+        );
+
+        cgroup.add_process(42).unwrap();
+        assert_eq!(fs::read_to_string(path.join("cgroup.procs")).unwrap(), "42");
+    }
+
+    #[test]
+    fn unsupported() {
+        let config = CgroupConfig {
+            parent: PathBuf::from("/nonexistent/cgroup"),
+            cpu_max: None,
+            memory_max: None,
+        };
+        assert!(matches!(
+            Cgroup::create_in(&config, "vm", None),
+            Err(Error::NotCgroup2(_))
+        ));
+
+        let parent = fake_parent("cpuset io pids\n", "");
+        let config = CgroupConfig {
+            parent: parent.as_path().to_path_buf(),
+            cpu_max: Some(100_000),
+            memory_max: None,
+        };
+        assert!(matches!(
+            Cgroup::create_in(&config, "vm", None),
+            Err(Error::Controller("cpu", _))
+        ));
+    }
+}
@@ -0,0 +1,168 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::BTreeMap;
+use std::{io, mem, result};
+
+/// Errors associated with pinning threads to host CPUs.
+#[derive(Debug)]
+pub enum Error {
+    /// Entry not following the `<vcpu>=<host cpu>` syntax.
+    InvalidEntry(String),
+    /// The vCPU does not exist.
+    InvalidVcpu(u8),
+    /// The vCPU is pinned more than once.
+    DuplicateVcpu(u8),
+    /// The host CPU does not exist or is not usable by the VMM.
+    InvalidHostCpu(usize),
+    /// Failed to read the host CPUs usable by the VMM.
+    GetAffinity(io::Error),
+    /// Failed to pin a thread.
+    SetAffinity(io::Error),
+}
+
+/// Dedicated Result type.
+pub type Result<T> = result::Result<T, Error>;
+
+/// Host CPUs the VMM is allowed to run on.
+pub fn host_cpus() -> Result<Vec<usize>> {
+    // Safe because cpu_set_t is plain data, and the kernel writes at most its size.
+    let mut set: libc::cpu_set_t = unsafe { mem::zeroed() };
+    let ret = unsafe { libc::sched_getaffinity(0, mem::size_of::<libc::cpu_set_t>(), &mut set) };
+    if ret < 0 {
+        return Err(Error::GetAffinity(io::Error::last_os_error()));
+    }
+
+    Ok((0..libc::CPU_SETSIZE as usize)
+        .filter(|cpu| unsafe { libc::CPU_ISSET(*cpu, &set) })
+        .collect())
+}
+
+/// Pin the calling thread to the `cpu` host CPU.
+pub fn pin_current_thread(cpu: usize) -> Result<()> {
+    pin_current_thread_to(&[cpu])
+}
+
+/// Restrict the calling thread to the `cpus` host CPUs.
+pub fn pin_current_thread_to(cpus: &[usize]) -> Result<()> {
+    // Safe because cpu_set_t is plain data and only read by the kernel.
+    let mut set: libc::cpu_set_t = unsafe { mem::zeroed() };
+    for cpu in cpus {
+        unsafe { libc::CPU_SET(*cpu, &mut set) };
+    }
+
+    let ret = unsafe { libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), &set) };
+    if ret < 0 {
+        return Err(Error::SetAffinity(io::Error::last_os_error()));
+    }
+
+    Ok(())
+}
+
+/// Parse a vCPU to host CPU mapping, such as `0=2,1=3`.
+///
+/// # Arguments
+///
+/// * `affinity` - comma separated list of `<vcpu>=<host cpu>` entries, empty for no pinning.
+/// * `num_vcpus` - number of vCPUs of the VM.
+/// * `host_cpus` - host CPUs the VMM may use.
+pub fn parse_affinity(
+    affinity: &str,
+    num_vcpus: u8,
+    host_cpus: &[usize],
+) -> Result<BTreeMap<u8, usize>> {
+    let mut mapping = BTreeMap::new();
+
+    for entry in affinity.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let (vcpu, cpu) = entry
+            .split_once('=')
+            .and_then(|(vcpu, cpu)| {
+                Some((
+                    vcpu.trim().parse::<u8>().ok()?,
+                    cpu.trim().parse::<usize>().ok()?,
+                ))
+            })
+            .ok_or_else(|| Error::InvalidEntry(entry.to_string()))?;
+
+        if vcpu >= num_vcpus {
+            return Err(Error::InvalidVcpu(vcpu));
+        }
+        if !host_cpus.contains(&cpu) {
+            return Err(Error::InvalidHostCpu(cpu));
+        }
+        if mapping.insert(vcpu, cpu).is_some() {
+            return Err(Error::DuplicateVcpu(vcpu));
+        }
+    }
+
+    Ok(mapping)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOST_CPUS: [usize; 4] = [0, 1, 2, 3];
+
+    #[test]
+    fn parse_mapping() {
+        let mapping = parse_affinity("0=2, 1=3", 2, &HOST_CPUS).unwrap();
+        assert_eq!(
+            mapping.into_iter().collect::<Vec<_>>(),
+            vec![(0, 2), (1, 3)]
+        );
+
+        // Not every vCPU has to be pinned.
+        let mapping = parse_affinity("1=0", 4, &HOST_CPUS).unwrap();
+        assert_eq!(mapping.into_iter().collect::<Vec<_>>(), vec![(1, 0)]);
+
+        assert!(parse_affinity("", 2, &HOST_CPUS).unwrap().is_empty());
+    }
+
+    #[test]
+    fn parse_invalid_mapping() {
+        assert!(matches!(
+            parse_affinity("0:2", 2, &HOST_CPUS),
+            Err(Error::InvalidEntry(e)) if e == "0:2"
+        ));
+        assert!(matches!(
+            parse_affinity("0=a", 2, &HOST_CPUS),
+            Err(Error::InvalidEntry(_))
+        ));
+        assert!(matches!(
+            parse_affinity("2=1", 2, &HOST_CPUS),
+            Err(Error::InvalidVcpu(2))
+        ));
+        assert!(matches!(
+            parse_affinity("0=4", 2, &HOST_CPUS),
+            Err(Error::InvalidHostCpu(4))
+        ));
+        assert!(matches!(
+            parse_affinity("0=1,0=2", 2, &HOST_CPUS),
+            Err(Error::DuplicateVcpu(0))
+        ));
+    }
+
+    #[test]
+    fn pin_thread() {
+        let cpu = host_cpus().unwrap()[0];
+
+        std::thread::spawn(move || {
+            pin_current_thread(cpu).unwrap();
+            assert_eq!(host_cpus().unwrap(), vec![cpu]);
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn pin_thread_to_cpus() {
+        let cpus: Vec<usize> = host_cpus().unwrap().into_iter().take(2).collect();
+
+        std::thread::spawn(move || {
+            pin_current_thread_to(&cpus).unwrap();
+            assert_eq!(host_cpus().unwrap(), cpus);
+        })
+        .join()
+        .unwrap();
+    }
+}
@@ -0,0 +1,337 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Pausing and resuming the vCPU threads, so that the VM can be rebooted in place, and stopping
+//! them for good.
+//!
+//! vCPU threads check whether the VM is paused before entering the guest. Threads running the
+//! guest are kicked out of it with a signal, until all of them are parked. When the VM stops, KVM
+//! is also told not to enter the guest anymore, in case a thread misses the signal.
+
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use kvm_ioctls::VcpuFd;
+use libc::{c_int, c_void, siginfo_t};
+use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::signal::{register_signal_handler, Killable, SIGRTMIN};
+
+use super::KvmRun;
+use crate::kernel::KernelEntry;
+
+/// Offset from `SIGRTMIN` of the signal kicking vCPU threads out of the guest.
+const VCPU_KICK_SIGNAL_OFFSET: c_int = 0;
+
+/// Interval at which vCPU threads not parked yet are kicked again.
+///
+/// A thread may receive the signal right before entering the guest, and miss it.
+const VCPU_KICK_INTERVAL: Duration = Duration::from_millis(1);
+
+extern "C" fn handle_kick(_: c_int, _: *mut siginfo_t, _: *mut c_void) {
+    // Receiving the signal is enough to interrupt KVM_RUN.
+}
+
+/// Let the kick signal interrupt vCPU threads running the guest, instead of terminating the VMM.
+pub fn register_kick_handler() -> io::Result<()> {
+    register_signal_handler(SIGRTMIN() + VCPU_KICK_SIGNAL_OFFSET, handle_kick)
+        .map_err(|e| io::Error::from_raw_os_error(e.errno()))
+}
+
+/// Kick the vCPU `threads` out of the guest.
+fn kick<T>(threads: &[JoinHandle<T>]) {
+    for thread in threads {
+        // Threads that already stopped cannot be signaled.
+        let _ = thread.kill(SIGRTMIN() + VCPU_KICK_SIGNAL_OFFSET);
+    }
+}
+
+struct ControlState {
+    paused: bool,
+    // The VM stopped, vCPU threads must not enter the guest anymore.
+    stopping: bool,
+    // vCPU threads still running, parked or not.
+    running: usize,
+    parked: usize,
+    // Incremented each time the vCPUs are resumed.
+    generation: u64,
+    // Entry point the vCPUs are reset to when they are resumed, if any.
+    reset: Option<KernelEntry>,
+}
+
+/// Shared by the event loop, pausing and resuming the VM, and the vCPU threads.
+pub(crate) struct VcpuControl {
+    state: Mutex<ControlState>,
+    // Signaled when a vCPU parks or stops, and when the vCPUs are resumed.
+    changed: Condvar,
+    // Signaled when a vCPU asks for the VM to be rebooted.
+    reset_evt: EventFd,
+    // Signaled once the VM stops, for vCPU threads blocked outside of the guest.
+    stop_evt: EventFd,
+    // vCPUs kept out of the guest once the VM stops.
+    kvm_runs: Mutex<Vec<KvmRun>>,
+}
+
+impl VcpuControl {
+    pub fn new(num_vcpus: usize, reset_evt: EventFd) -> io::Result<Self> {
+        Ok(VcpuControl {
+            state: Mutex::new(ControlState {
+                paused: false,
+                stopping: false,
+                running: num_vcpus,
+                parked: 0,
+                generation: 0,
+                reset: None,
+            }),
+            changed: Condvar::new(),
+            reset_evt,
+            stop_evt: EventFd::new(libc::EFD_NONBLOCK)?,
+            kvm_runs: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Keep the vCPU of `vcpu_fd` out of the guest once the VM stops.
+    pub fn add_vcpu(&self, vcpu_fd: &VcpuFd) -> io::Result<()> {
+        self.kvm_runs.lock().unwrap().push(KvmRun::new(vcpu_fd)?);
+        Ok(())
+    }
+
+    /// Pause the VM, kicking the vCPU `threads` out of the guest until all of them are parked.
+    pub fn pause<T>(&self, threads: &[JoinHandle<T>]) {
+        let mut state = self.state.lock().unwrap();
+        state.paused = true;
+
+        while state.parked < state.running {
+            kick(threads);
+            state = self
+                .changed
+                .wait_timeout(state, VCPU_KICK_INTERVAL)
+                .unwrap()
+                .0;
+        }
+    }
+
+    /// Resume the VM, resetting the vCPUs to enter the kernel at `reset` if set.
+    pub fn resume(&self, reset: Option<KernelEntry>) {
+        let mut state = self.state.lock().unwrap();
+        state.paused = false;
+        state.parked = 0;
+        state.generation += 1;
+        state.reset = reset;
+
+        self.changed.notify_all();
+    }
+
+    /// Park the calling vCPU thread if the VM is paused, until it is resumed.
+    ///
+    /// Returns the entry point the vCPU must be reset to, if any.
+    pub fn wait_if_paused(&self) -> Option<KernelEntry> {
+        let state = self.state.lock().unwrap();
+        if !state.paused {
+            return None;
+        }
+
+        self.wait_resume(state)
+    }
+
+    /// Park the calling vCPU thread until the VM is resumed, even if it is not paused yet.
+    pub fn park(&self) -> Option<KernelEntry> {
+        let state = self.state.lock().unwrap();
+
+        self.wait_resume(state)
+    }
+
+    fn wait_resume(&self, mut state: MutexGuard<ControlState>) -> Option<KernelEntry> {
+        let generation = state.generation;
+        state.parked += 1;
+        self.changed.notify_all();
+
+        while state.generation == generation && !state.stopping {
+            state = self.changed.wait(state).unwrap();
+        }
+
+        state.reset
+    }
+
+    /// Ask for the VM to be rebooted, on behalf of a vCPU.
+    pub fn request_reboot(&self) -> io::Result<()> {
+        self.reset_evt.write(1)
+    }
+
+    /// Stop the VM for good, kicking the vCPU `threads` out of the guest until all of them
+    /// stopped. The threads are then left to be joined.
+    pub fn stop<T>(&self, threads: &[JoinHandle<T>]) {
+        let mut state = self.state.lock().unwrap();
+        state.stopping = true;
+        // Never read, so that it stays readable.
+        let _ = self.stop_evt.write(1);
+        for kvm_run in self.kvm_runs.lock().unwrap().iter() {
+            kvm_run.set_immediate_exit();
+        }
+        // Parked threads stop too.
+        self.changed.notify_all();
+
+        while state.running > 0 {
+            kick(threads);
+            state = self
+                .changed
+                .wait_timeout(state, VCPU_KICK_INTERVAL)
+                .unwrap()
+                .0;
+        }
+    }
+
+    /// Whether the VM stopped, the calling vCPU thread must then stop too.
+    pub fn stopping(&self) -> bool {
+        self.state.lock().unwrap().stopping
+    }
+
+    /// Readable once the VM stopped, for vCPU threads to poll while blocked outside of the guest.
+    pub fn stop_fd(&self) -> RawFd {
+        self.stop_evt.as_raw_fd()
+    }
+
+    /// The calling vCPU thread stops for good, the VM is paused without waiting for it.
+    pub fn stopped(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.running -= 1;
+
+        self.changed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    use vm_memory::GuestAddress;
+
+    // Runs like a vCPU thread, until told to stop.
+    fn spawn_vcpu(
+        control: &Arc<VcpuControl>,
+        stop: &Arc<AtomicBool>,
+    ) -> JoinHandle<Vec<KernelEntry>> {
+        let control = Arc::clone(control);
+        let stop = Arc::clone(stop);
+
+        thread::spawn(move || {
+            let mut resets = Vec::new();
+            while !stop.load(Ordering::SeqCst) {
+                if let Some(entry) = control.wait_if_paused() {
+                    resets.push(entry);
+                }
+                thread::yield_now();
+            }
+            control.stopped();
+
+            resets
+        })
+    }
+
+    #[test]
+    fn pause_and_reset() {
+        register_kick_handler().unwrap();
+        let control = Arc::new(VcpuControl::new(2, EventFd::new(0).unwrap()).unwrap());
+        let stop = Arc::new(AtomicBool::new(false));
+        let threads = vec![spawn_vcpu(&control, &stop), spawn_vcpu(&control, &stop)];
+
+        // The VM reboots twice.
+        let entries = [GuestAddress(0x100_0000), GuestAddress(0x200_0000)];
+        for entry in entries.iter() {
+            control.pause(&threads);
+            assert_eq!(control.state.lock().unwrap().parked, 2);
+            control.resume(Some(KernelEntry::Linux(*entry)));
+        }
+
+        // Resuming without reset.
+        control.pause(&threads);
+        control.resume(None);
+
+        stop.store(true, Ordering::SeqCst);
+        for thread in threads {
+            assert_eq!(
+                thread.join().unwrap(),
+                vec![
+                    KernelEntry::Linux(entries[0]),
+                    KernelEntry::Linux(entries[1]),
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn pause_with_stopped_vcpus() {
+        register_kick_handler().unwrap();
+        let control = Arc::new(VcpuControl::new(2, EventFd::new(0).unwrap()).unwrap());
+        let stop = Arc::new(AtomicBool::new(true));
+        let threads = vec![spawn_vcpu(&control, &stop), spawn_vcpu(&control, &stop)];
+
+        // Both threads are gone, nothing is left to wait for.
+        control.pause(&threads);
+        control.resume(None);
+        for thread in threads {
+            assert!(thread.join().unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn park_until_resumed() {
+        let control = Arc::new(VcpuControl::new(1, EventFd::new(0).unwrap()).unwrap());
+
+        // A vCPU asks for a reboot, and parks before the VM is paused.
+        let vcpu = {
+            let control = Arc::clone(&control);
+            thread::spawn(move || {
+                control.request_reboot().unwrap();
+                control.park()
+            })
+        };
+
+        assert_eq!(control.reset_evt.read().unwrap(), 1);
+        control.pause(&[] as &[JoinHandle<()>]);
+        let entry = KernelEntry::Pvh(GuestAddress(0x100_0000));
+        control.resume(Some(entry));
+        assert_eq!(vcpu.join().unwrap(), Some(entry));
+    }
+
+    #[test]
+    fn stop_vcpus() {
+        register_kick_handler().unwrap();
+        let control = Arc::new(VcpuControl::new(2, EventFd::new(0).unwrap()).unwrap());
+
+        // One vCPU runs the guest, the other one is parked after a crash.
+        let running = {
+            let control = Arc::clone(&control);
+            thread::spawn(move || {
+                while !control.stopping() {
+                    thread::yield_now();
+                }
+                control.stopped();
+                None
+            })
+        };
+        let parked = {
+            let control = Arc::clone(&control);
+            thread::spawn(move || {
+                let entry = control.park();
+                control.stopped();
+                entry
+            })
+        };
+
+        let threads = vec![running, parked];
+        control.stop(&threads);
+        assert_eq!(control.state.lock().unwrap().running, 0);
+        for thread in threads {
+            assert_eq!(thread.join().unwrap(), None);
+        }
+
+        // vCPUs checking afterwards stop right away.
+        assert!(control.stopping());
+        assert_eq!(control.wait_if_paused(), None);
+    }
+}
@@ -2,7 +2,9 @@
 // Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
 
-use kvm_bindings::CpuId;
+use std::result;
+
+use kvm_bindings::{kvm_cpuid_entry2, CpuId};
 use kvm_ioctls::{Cap::TscDeadlineTimer, Kvm};
 
 // CPUID bits in ebx, ecx, and edx.
@@ -15,6 +17,270 @@ const ECX_TSC_DEADLINE_TIMER_SHIFT: u32 = 24; // TSC deadline mode of APIC timer
 const ECX_HYPERVISOR_SHIFT: u32 = 31; // Flag to be set when the cpu is running on a hypervisor.
 const EDX_HTT_SHIFT: u32 = 28; // Hyper Threading Enabled.
 
+// Leaves holding the processor brand string, 16 bytes each.
+const BRAND_STRING_LEAVES: [u32; 3] = [0x8000_0002, 0x8000_0003, 0x8000_0004];
+const BRAND_STRING_MAX_LEN: usize = 48;
+// Leaf describing the host processor frequencies.
+const FREQUENCY_LEAF: u32 = 0x16;
+// Leaf describing the power management features, including the invariant TSC.
+const POWER_MANAGEMENT_LEAF: u32 = 0x8000_0007;
+const EDX_INVARIANT_TSC_SHIFT: u32 = 8;
+
+// KVM paravirtual leaves. See Documentation/virt/kvm/cpuid.rst in the Linux sources.
+const KVM_CPUID_SIGNATURE: u32 = 0x4000_0000;
+const KVM_CPUID_FEATURES: u32 = 0x4000_0001;
+// "KVMKVMKVM\0\0\0", in ebx, ecx and edx.
+const KVM_SIGNATURE: [u32; 3] = [0x4b4d_564b, 0x564b_4d56, 0x0000_004d];
+const KVM_FEATURE_CLOCKSOURCE_SHIFT: u32 = 0;
+const KVM_FEATURE_CLOCKSOURCE2_SHIFT: u32 = 3;
+const KVM_FEATURE_CLOCKSOURCE_STABLE_BIT_SHIFT: u32 = 24;
+
+/// Register of a CPUID leaf.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Register {
+    Ebx,
+    Ecx,
+    Edx,
+}
+
+use Register::*;
+
+// Name, leaf, sub-leaf, register and bit of the features that can be overridden.
+const FEATURES: &[(&str, u32, u32, Register, u32)] = &[
+    ("sse3", 0x1, 0, Ecx, 0),
+    ("pclmulqdq", 0x1, 0, Ecx, 1),
+    ("vmx", 0x1, 0, Ecx, 5),
+    ("ssse3", 0x1, 0, Ecx, 9),
+    ("fma", 0x1, 0, Ecx, 12),
+    ("cx16", 0x1, 0, Ecx, 13),
+    ("pcid", 0x1, 0, Ecx, 17),
+    ("sse4_1", 0x1, 0, Ecx, 19),
+    ("sse4_2", 0x1, 0, Ecx, 20),
+    ("x2apic", 0x1, 0, Ecx, 21),
+    ("movbe", 0x1, 0, Ecx, 22),
+    ("popcnt", 0x1, 0, Ecx, 23),
+    ("tsc_deadline_timer", 0x1, 0, Ecx, 24),
+    ("aes", 0x1, 0, Ecx, 25),
+    ("xsave", 0x1, 0, Ecx, 26),
+    ("avx", 0x1, 0, Ecx, 28),
+    ("f16c", 0x1, 0, Ecx, 29),
+    ("rdrand", 0x1, 0, Ecx, 30),
+    ("hypervisor", 0x1, 0, Ecx, 31),
+    ("mtrr", 0x1, 0, Edx, 12),
+    ("pat", 0x1, 0, Edx, 16),
+    ("ht", 0x1, 0, Edx, 28),
+    ("fsgsbase", 0x7, 0, Ebx, 0),
+    ("bmi1", 0x7, 0, Ebx, 3),
+    ("hle", 0x7, 0, Ebx, 4),
+    ("avx2", 0x7, 0, Ebx, 5),
+    ("smep", 0x7, 0, Ebx, 7),
+    ("bmi2", 0x7, 0, Ebx, 8),
+    ("erms", 0x7, 0, Ebx, 9),
+    ("invpcid", 0x7, 0, Ebx, 10),
+    ("rtm", 0x7, 0, Ebx, 11),
+    ("avx512f", 0x7, 0, Ebx, 16),
+    ("avx512dq", 0x7, 0, Ebx, 17),
+    ("rdseed", 0x7, 0, Ebx, 18),
+    ("adx", 0x7, 0, Ebx, 19),
+    ("smap", 0x7, 0, Ebx, 20),
+    ("avx512cd", 0x7, 0, Ebx, 28),
+    ("sha_ni", 0x7, 0, Ebx, 29),
+    ("avx512bw", 0x7, 0, Ebx, 30),
+    ("avx512vl", 0x7, 0, Ebx, 31),
+    ("umip", 0x7, 0, Ecx, 2),
+    ("pku", 0x7, 0, Ecx, 3),
+    ("la57", 0x7, 0, Ecx, 16),
+    ("lahf_lm", 0x8000_0001, 0, Ecx, 0),
+    ("abm", 0x8000_0001, 0, Ecx, 5),
+    ("pdpe1gb", 0x8000_0001, 0, Edx, 26),
+    ("rdtscp", 0x8000_0001, 0, Edx, 27),
+];
+
+/// Errors associated with the CPU model configuration.
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// The brand string is longer than 48 bytes.
+    BrandTooLong(usize),
+    /// Feature override not starting with `+` or `-`.
+    InvalidFeature(String),
+    /// Unknown feature name, along with the known ones.
+    UnknownFeature(String, Vec<&'static str>),
+    /// No room left for the KVM leaves.
+    TooManyEntries,
+}
+
+/// Dedicated Result type.
+pub type Result<T> = result::Result<T, Error>;
+
+/// A CPU feature forced on or off.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct FeatureOverride {
+    leaf: u32,
+    subleaf: u32,
+    register: Register,
+    bit: u32,
+    enabled: bool,
+}
+
+/// Customization of the CPU exposed to the guest.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CpuModel {
+    brand: Option<String>,
+    features: Vec<FeatureOverride>,
+}
+
+impl CpuModel {
+    /// Build a CPU model.
+    ///
+    /// # Arguments
+    ///
+    /// * `brand` - processor brand string, at most 48 bytes.
+    /// * `features` - comma separated list of features to enable (`+x2apic`) or disable
+    ///                (`-avx512f`).
+    pub fn new(brand: Option<&str>, features: Option<&str>) -> Result<Self> {
+        if let Some(brand) = brand {
+            if brand.len() > BRAND_STRING_MAX_LEN {
+                return Err(Error::BrandTooLong(brand.len()));
+            }
+        }
+
+        let features = features
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|feature| !feature.is_empty())
+            .map(parse_feature)
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(CpuModel {
+            brand: brand.map(str::to_string),
+            features,
+        })
+    }
+}
+
+fn parse_feature(feature: &str) -> Result<FeatureOverride> {
+    let (enabled, name) = if let Some(name) = feature.strip_prefix('+') {
+        (true, name)
+    } else if let Some(name) = feature.strip_prefix('-') {
+        (false, name)
+    } else {
+        return Err(Error::InvalidFeature(feature.to_string()));
+    };
+
+    FEATURES
+        .iter()
+        .find(|(known, ..)| *known == name)
+        .map(|&(_, leaf, subleaf, register, bit)| FeatureOverride {
+            leaf,
+            subleaf,
+            register,
+            bit,
+            enabled,
+        })
+        .ok_or_else(|| {
+            Error::UnknownFeature(
+                name.to_string(),
+                FEATURES.iter().map(|(known, ..)| *known).collect(),
+            )
+        })
+}
+
+fn register_mut(entry: &mut kvm_cpuid_entry2, register: Register) -> &mut u32 {
+    match register {
+        Ebx => &mut entry.ebx,
+        Ecx => &mut entry.ecx,
+        Edx => &mut entry.edx,
+    }
+}
+
+/// Apply the brand string and feature overrides of `model`, and hide host specific leaves.
+pub(crate) fn apply_cpu_model(model: &CpuModel, cpuid: &mut CpuId) {
+    let mut brand = [0u8; BRAND_STRING_MAX_LEN];
+    if let Some(model_brand) = model.brand.as_ref() {
+        brand[..model_brand.len()].copy_from_slice(model_brand.as_bytes());
+    }
+
+    for entry in cpuid.as_mut_slice().iter_mut() {
+        if let Some(index) = BRAND_STRING_LEAVES
+            .iter()
+            .position(|l| *l == entry.function)
+        {
+            if model.brand.is_some() {
+                let words: Vec<u32> = brand[index * 16..(index + 1) * 16]
+                    .chunks(4)
+                    .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+                    .collect();
+                entry.eax = words[0];
+                entry.ebx = words[1];
+                entry.ecx = words[2];
+                entry.edx = words[3];
+            }
+        }
+
+        if entry.function == FREQUENCY_LEAF {
+            entry.eax = 0;
+            entry.ebx = 0;
+            entry.ecx = 0;
+            entry.edx = 0;
+        }
+
+        for feature in model.features.iter() {
+            if entry.function == feature.leaf && entry.index == feature.subleaf {
+                let register = register_mut(entry, feature.register);
+                if feature.enabled {
+                    *register |= 1 << feature.bit;
+                } else {
+                    *register &= !(1 << feature.bit);
+                }
+            }
+        }
+    }
+}
+
+/// Advertise the kvmclock paravirtual clocksource.
+///
+/// kvmclock readings are only flagged as stable when the host TSC is invariant.
+pub(crate) fn set_kvm_leaves(cpuid: &mut CpuId) -> Result<()> {
+    for function in [KVM_CPUID_SIGNATURE, KVM_CPUID_FEATURES] {
+        if !cpuid.as_slice().iter().any(|e| e.function == function) {
+            cpuid
+                .push(kvm_cpuid_entry2 {
+                    function,
+                    ..Default::default()
+                })
+                .map_err(|_| Error::TooManyEntries)?;
+        }
+    }
+
+    let invariant_tsc = cpuid.as_slice().iter().any(|e| {
+        e.function == POWER_MANAGEMENT_LEAF && e.edx & (1 << EDX_INVARIANT_TSC_SHIFT) != 0
+    });
+
+    for entry in cpuid.as_mut_slice().iter_mut() {
+        match entry.function {
+            KVM_CPUID_SIGNATURE => {
+                entry.eax = KVM_CPUID_FEATURES;
+                entry.ebx = KVM_SIGNATURE[0];
+                entry.ecx = KVM_SIGNATURE[1];
+                entry.edx = KVM_SIGNATURE[2];
+            }
+            KVM_CPUID_FEATURES => {
+                entry.eax |=
+                    (1 << KVM_FEATURE_CLOCKSOURCE_SHIFT) | (1 << KVM_FEATURE_CLOCKSOURCE2_SHIFT);
+                if invariant_tsc {
+                    entry.eax |= 1 << KVM_FEATURE_CLOCKSOURCE_STABLE_BIT_SHIFT;
+                } else {
+                    entry.eax &= !(1 << KVM_FEATURE_CLOCKSOURCE_STABLE_BIT_SHIFT);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    Ok(())
+}
+
 pub(crate) fn filter_cpuid(kvm: &Kvm, vcpu_id: usize, cpu_count: usize, cpuid: &mut CpuId) {
     for entry in cpuid.as_mut_slice().iter_mut() {
         match entry.function {
@@ -41,3 +307,186 @@ pub(crate) fn filter_cpuid(kvm: &Kvm, vcpu_id: usize, cpu_count: usize, cpuid: &
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(function: u32, index: u32, ecx: u32) -> kvm_cpuid_entry2 {
+        kvm_cpuid_entry2 {
+            function,
+            index,
+            ecx,
+            eax: 0x1234,
+            ..Default::default()
+        }
+    }
+
+    // A trimmed down snapshot of KVM_GET_SUPPORTED_CPUID.
+    fn snapshot() -> CpuId {
+        CpuId::from_entries(&[
+            entry(0x1, 0, 0x1 << 28),
+            kvm_cpuid_entry2 {
+                function: 0x7,
+                index: 0,
+                ebx: 1 << 16,
+                ..Default::default()
+            },
+            entry(FREQUENCY_LEAF, 0, 0),
+            entry(0x8000_0002, 0, 0),
+            entry(0x8000_0003, 0, 0),
+            entry(0x8000_0004, 0, 0),
+        ])
+        .unwrap()
+    }
+
+    #[test]
+    fn parse_model() {
+        let model = CpuModel::new(Some("lumper"), Some("+x2apic, -avx512f")).unwrap();
+        assert_eq!(
+            model.features,
+            vec![
+                FeatureOverride {
+                    leaf: 0x1,
+                    subleaf: 0,
+                    register: Ecx,
+                    bit: 21,
+                    enabled: true,
+                },
+                FeatureOverride {
+                    leaf: 0x7,
+                    subleaf: 0,
+                    register: Ebx,
+                    bit: 16,
+                    enabled: false,
+                },
+            ]
+        );
+
+        assert_eq!(CpuModel::new(None, Some("")).unwrap(), CpuModel::default());
+    }
+
+    #[test]
+    fn parse_invalid_model() {
+        assert_eq!(
+            CpuModel::new(None, Some("x2apic")),
+            Err(Error::InvalidFeature("x2apic".to_string()))
+        );
+
+        match CpuModel::new(None, Some("+x2apic,+avx1024")) {
+            Err(Error::UnknownFeature(name, known)) => {
+                assert_eq!(name, "avx1024");
+                assert!(known.contains(&"x2apic"));
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        let brand = "x".repeat(BRAND_STRING_MAX_LEN + 1);
+        assert_eq!(
+            CpuModel::new(Some(&brand), None),
+            Err(Error::BrandTooLong(BRAND_STRING_MAX_LEN + 1))
+        );
+    }
+
+    #[test]
+    fn features_override() {
+        let mut cpuid = snapshot();
+        let model = CpuModel::new(None, Some("+x2apic,-avx,-avx512f")).unwrap();
+
+        apply_cpu_model(&model, &mut cpuid);
+
+        let entries = cpuid.as_slice();
+        assert_eq!(entries[0].ecx, 1 << 21);
+        assert_eq!(entries[0].eax, 0x1234);
+        assert_eq!(entries[1].ebx, 0);
+    }
+
+    #[test]
+    fn brand_string() {
+        let mut cpuid = snapshot();
+        let model = CpuModel::new(Some("lumper virtual CPU"), None).unwrap();
+
+        apply_cpu_model(&model, &mut cpuid);
+
+        let brand: Vec<u8> = cpuid.as_slice()[3..6]
+            .iter()
+            .flat_map(|e| [e.eax, e.ebx, e.ecx, e.edx])
+            .flat_map(u32::to_le_bytes)
+            .collect();
+        assert_eq!(&brand[..18], b"lumper virtual CPU");
+        assert!(brand[18..].iter().all(|b| *b == 0));
+
+        // Host frequencies are hidden.
+        assert_eq!(cpuid.as_slice()[2].eax, 0);
+    }
+
+    #[test]
+    fn default_model_keeps_brand() {
+        let mut cpuid = snapshot();
+
+        apply_cpu_model(&CpuModel::default(), &mut cpuid);
+
+        assert_eq!(cpuid.as_slice()[3].eax, 0x1234);
+    }
+
+    fn kvm_leaf(cpuid: &CpuId, function: u32) -> kvm_cpuid_entry2 {
+        *cpuid
+            .as_slice()
+            .iter()
+            .find(|e| e.function == function)
+            .unwrap()
+    }
+
+    #[test]
+    fn kvm_leaves() {
+        let mut cpuid = snapshot();
+
+        set_kvm_leaves(&mut cpuid).unwrap();
+
+        let signature = kvm_leaf(&cpuid, KVM_CPUID_SIGNATURE);
+        assert_eq!(signature.eax, KVM_CPUID_FEATURES);
+        let name: Vec<u8> = [signature.ebx, signature.ecx, signature.edx]
+            .iter()
+            .flat_map(|r| r.to_le_bytes())
+            .collect();
+        assert_eq!(&name, b"KVMKVMKVM\0\0\0");
+
+        // Without an invariant TSC, kvmclock is not flagged as stable.
+        let features = kvm_leaf(&cpuid, KVM_CPUID_FEATURES);
+        assert_eq!(features.eax, (1 << 0) | (1 << 3));
+    }
+
+    #[test]
+    fn kvm_leaves_stable_clock() {
+        let mut cpuid = snapshot();
+        cpuid
+            .push(kvm_cpuid_entry2 {
+                function: POWER_MANAGEMENT_LEAF,
+                edx: 1 << EDX_INVARIANT_TSC_SHIFT,
+                ..Default::default()
+            })
+            .unwrap();
+        // KVM already reports some features, they are kept.
+        cpuid
+            .push(kvm_cpuid_entry2 {
+                function: KVM_CPUID_FEATURES,
+                eax: 1 << 1,
+                ..Default::default()
+            })
+            .unwrap();
+
+        set_kvm_leaves(&mut cpuid).unwrap();
+        set_kvm_leaves(&mut cpuid).unwrap();
+
+        let features = kvm_leaf(&cpuid, KVM_CPUID_FEATURES);
+        assert_eq!(features.eax, (1 << 0) | (1 << 1) | (1 << 3) | (1 << 24));
+        assert_eq!(
+            cpuid
+                .as_slice()
+                .iter()
+                .filter(|e| e.function == KVM_CPUID_FEATURES)
+                .count(),
+            1
+        );
+    }
+}
@@ -0,0 +1,63 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! The `kvm_run` structure of a vCPU, mapped a second time.
+//!
+//! kvm-ioctls keeps its own mapping private to the vCPU, while other threads must keep the vCPU
+//! out of the guest, and some exits it does not decode must be completed by the VMM.
+
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+
+use kvm_ioctls::VcpuFd;
+
+// Offsets in `struct kvm_run`, from linux/kvm.h. The structure fits in its first page.
+const KVM_RUN_IMMEDIATE_EXIT_OFFSET: usize = 1;
+const KVM_RUN_EXIT_OFFSET: usize = 32;
+const KVM_RUN_MAPPING_SIZE: usize = 0x1000;
+
+/// Mapping of the `kvm_run` structure of a vCPU.
+pub(crate) struct KvmRun {
+    // Host address of the mapping, as an integer so that it can be sent to other threads.
+    addr: u64,
+}
+
+impl KvmRun {
+    pub fn new(vcpu_fd: &VcpuFd) -> io::Result<Self> {
+        // Safe because the result is checked, and the mapping only unmapped when dropped.
+        let addr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                KVM_RUN_MAPPING_SIZE,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                vcpu_fd.as_raw_fd(),
+                0,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(KvmRun { addr: addr as u64 })
+    }
+
+    /// Have `KVM_RUN` return `EINTR` without entering the guest, from now on.
+    pub fn set_immediate_exit(&self) {
+        let immediate_exit = (self.addr as usize + KVM_RUN_IMMEDIATE_EXIT_OFFSET) as *mut u8;
+        // Safe because the field lies within the mapping, and KVM only reads it.
+        unsafe { ptr::write_volatile(immediate_exit, 1) };
+    }
+
+    /// The exit information, laid out as `T` for the exit the vCPU is on.
+    pub fn exit<T>(&self) -> *mut T {
+        (self.addr as usize + KVM_RUN_EXIT_OFFSET) as *mut T
+    }
+}
+
+impl Drop for KvmRun {
+    fn drop(&mut self) {
+        // Safe because the mapping is not used anymore.
+        unsafe { libc::munmap(self.addr as *mut libc::c_void, KVM_RUN_MAPPING_SIZE) };
+    }
+}
@@ -3,21 +3,45 @@
 
 use std::convert::TryInto;
 use std::io;
+use std::os::unix::io::AsRawFd;
 use std::sync::{Arc, Mutex};
+use std::time::Instant;
 use std::{result, u64};
 
-use kvm_bindings::{kvm_fpu, kvm_regs, CpuId};
+use kvm_bindings::{
+    kvm_fpu, kvm_guest_debug, kvm_lapic_state, kvm_mp_state, kvm_regs, kvm_sregs, kvm_translation,
+    CpuId, KVM_GUESTDBG_ENABLE, KVM_GUESTDBG_SINGLESTEP, KVM_GUESTDBG_USE_SW_BP,
+    KVM_MP_STATE_RUNNABLE, KVM_MP_STATE_UNINITIALIZED,
+};
 use kvm_ioctls::{VcpuExit, VcpuFd, VmFd};
 use vm_memory::{Address, Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
-use vmm_sys_util::terminal::Terminal;
 
+use crate::crash::{self, CrashAction, CrashHandler};
+use crate::devices;
+use crate::devices::acpi_pm::{AcpiPm, ACPI_PM_PORT_BASE, ACPI_PM_PORT_LAST_REGISTER};
+use crate::devices::boot_notifier::BootNotifier;
+use crate::devices::cmos::{Cmos, CMOS_PORT_BASE, CMOS_PORT_LAST_REGISTER};
+use crate::devices::i8042::{LumperI8042, I8042_PORT_BASE, I8042_PORT_LAST_REGISTER};
+use crate::devices::pvpanic::{self, PVPANIC_PORT};
 use crate::devices::serial::{LumperSerial, SERIAL_PORT_BASE, SERIAL_PORT_LAST_REGISTER};
-
+use crate::devices::shmem::{ShmemRegisters, SHMEM_REGS_ADDR};
+use crate::devices::watchdog::{Watchdog, WATCHDOG_START_PORT, WATCHDOG_STOP_PORT};
+use crate::exit::VmExitReason;
+use crate::gdb::{self, GdbStub, Resume};
+use crate::kernel::{KernelEntry, PVH_INFO_START, ZEROPG_START};
+use crate::msr::{MsrExits, MsrPolicy, KVM_EXIT_X86_RDMSR, KVM_EXIT_X86_WRMSR};
+use crate::GUEST_HALTED;
+
+pub(crate) mod affinity;
+pub(crate) mod control;
+use control::VcpuControl;
 pub(crate) mod cpuid;
 mod gdt;
 use gdt::*;
 mod interrupts;
 use interrupts::*;
+mod kvm_run;
+pub(crate) use kvm_run::KvmRun;
 pub(crate) mod mpspec;
 pub(crate) mod mptable;
 pub(crate) mod msr_index;
@@ -35,6 +59,16 @@ const X86_CR0_PE: u64 = 0x1;
 const X86_CR0_PG: u64 = 0x8000_0000;
 const X86_CR4_PAE: u64 = 0x20;
 
+// Initial rflags, only the reserved bit 1 is set.
+const BOOT_RFLAGS: u64 = 0x0000_0000_0000_0002;
+
+// _IO(KVMIO, 0xa2), not wrapped by kvm-ioctls.
+const KVM_SET_TSC_KHZ: libc::c_ulong = 0xaea2;
+// _IOWR(KVMIO, 0x85, struct kvm_translation).
+const KVM_TRANSLATE: libc::c_ulong = 0xc018_ae85;
+// _IOW(KVMIO, 0x9b, struct kvm_guest_debug).
+const KVM_SET_GUEST_DEBUG: libc::c_ulong = 0x4048_ae9b;
+
 /// Errors encountered during vCPU operation.
 #[derive(Debug)]
 pub enum Error {
@@ -50,11 +84,157 @@ pub enum Error {
     SetModelSpecificRegistersCount,
     /// Failed to configure MSRs.
     CreateMsr(msrs::Error),
+    /// A device failed to handle a guest access.
+    Device(devices::Error),
+    /// Failed to pin the vCPU thread.
+    Affinity(affinity::Error),
+    /// Failed to set the TSC frequency.
+    TscFrequency(io::Error),
+    /// Failed to configure guest debugging.
+    GuestDebug(io::Error),
+    /// Failed to write a crash dump.
+    CrashDump(crash::Error),
+    /// Failed to ask for the VM to be rebooted.
+    Reboot(io::Error),
+    /// Failed to map the MSR exits of the vCPU.
+    MsrExits(io::Error),
+    /// The VM must stop, for this reason.
+    Stop(VmExitReason),
 }
 
 /// Dedicated Result type.
 pub type Result<T> = result::Result<T, Error>;
 
+/// Format the general purpose and control registers of a vCPU, four per line.
+fn format_regs(regs: &kvm_regs, sregs: &kvm_sregs) -> String {
+    let values = [
+        ("rip", regs.rip),
+        ("rsp", regs.rsp),
+        ("rbp", regs.rbp),
+        ("rflags", regs.rflags),
+        ("rax", regs.rax),
+        ("rbx", regs.rbx),
+        ("rcx", regs.rcx),
+        ("rdx", regs.rdx),
+        ("rsi", regs.rsi),
+        ("rdi", regs.rdi),
+        ("r8", regs.r8),
+        ("r9", regs.r9),
+        ("r10", regs.r10),
+        ("r11", regs.r11),
+        ("r12", regs.r12),
+        ("r13", regs.r13),
+        ("r14", regs.r14),
+        ("r15", regs.r15),
+        ("cr0", sregs.cr0),
+        ("cr2", sregs.cr2),
+        ("cr3", sregs.cr3),
+        ("cr4", sregs.cr4),
+        ("efer", sregs.efer),
+    ];
+
+    values
+        .chunks(4)
+        .map(|line| {
+            line.iter()
+                .map(|(name, value)| format!("{:>6}={:016x}", name, value))
+                .collect::<Vec<_>>()
+                .join(" ")
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Initial general purpose registers to enter the kernel at `entry`.
+fn boot_regs(entry: KernelEntry) -> kvm_regs {
+    match entry {
+        KernelEntry::Linux(entry) => kvm_regs {
+            rflags: BOOT_RFLAGS,
+            rip: entry.raw_value(),
+            // Frame pointer. It gets a snapshot of the stack pointer (rsp) so that when adjustments are
+            // made to rsp (i.e. reserving space for local variables or pushing values on to the stack),
+            // local variables and function parameters are still accessible from a constant offset from rbp.
+            rsp: BOOT_STACK_POINTER,
+            // Starting stack pointer.
+            rbp: BOOT_STACK_POINTER,
+            // Must point to zero page address per Linux ABI. This is x86_64 specific.
+            rsi: ZEROPG_START,
+            ..Default::default()
+        },
+        KernelEntry::Pvh(entry) => kvm_regs {
+            rflags: BOOT_RFLAGS,
+            rip: entry.raw_value(),
+            // Must point to the start info structure per PVH ABI.
+            rbx: PVH_INFO_START,
+            ..Default::default()
+        },
+    }
+}
+
+/// Global descriptor table to enter the kernel at `entry` with.
+fn boot_gdt(entry: KernelEntry) -> [u64; BOOT_GDT_MAX] {
+    // The PVH entry point runs in 32-bit protected mode, the Linux one in 64-bit mode.
+    let code_flags = match entry {
+        KernelEntry::Linux(_) => 0xa09b,
+        KernelEntry::Pvh(_) => 0xc09b,
+    };
+
+    [
+        gdt_entry(0, 0, 0),                // NULL
+        gdt_entry(code_flags, 0, 0xfffff), // CODE
+        gdt_entry(0xc093, 0, 0xfffff),     // DATA
+        gdt_entry(0x808b, 0, 0xfffff),     // TSS
+    ]
+}
+
+/// Set the segments and the operating mode to enter the kernel at `entry` with.
+///
+/// Paging, only used by the Linux boot protocol, is enabled separately.
+fn set_boot_mode(sregs: &mut kvm_sregs, gdt_table: &[u64; BOOT_GDT_MAX], entry: KernelEntry) {
+    // KVM takes byte granular limits, which matter outside of 64-bit mode.
+    let segment = |index: usize| {
+        let mut segment = kvm_segment_from_gdt(gdt_table[index], index as u8);
+        if segment.g == 1 {
+            segment.limit = (segment.limit << 12) | 0xfff;
+        }
+        segment
+    };
+    let code_seg = segment(1);
+    let data_seg = segment(2);
+    let tss_seg = segment(3);
+
+    sregs.cs = code_seg;
+    sregs.ds = data_seg;
+    sregs.es = data_seg;
+    sregs.fs = data_seg;
+    sregs.gs = data_seg;
+    sregs.ss = data_seg;
+    sregs.tr = tss_seg;
+
+    match entry {
+        // 64-bit protected mode.
+        KernelEntry::Linux(_) => {
+            sregs.cr0 |= X86_CR0_PE;
+            sregs.efer |= (msr_index::EFER_LME | msr_index::EFER_LMA) as u64;
+        }
+        // 32-bit protected mode, without paging.
+        KernelEntry::Pvh(_) => {
+            sregs.cr0 = (sregs.cr0 | X86_CR0_PE) & !X86_CR0_PG;
+            sregs.cr4 &= !X86_CR4_PAE;
+            sregs.efer &= !((msr_index::EFER_LME | msr_index::EFER_LMA) as u64);
+        }
+    }
+}
+
+/// Devices shared by all the vCPUs, handling their port I/O.
+#[derive(Clone)]
+pub(crate) struct VcpuDevices {
+    pub serial: Arc<Mutex<LumperSerial>>,
+    pub i8042: Arc<Mutex<LumperI8042>>,
+    pub cmos: Arc<Mutex<Cmos>>,
+    pub acpi_pm: Arc<Mutex<AcpiPm>>,
+}
+
 /// Struct for interacting with vCPUs.
 ///
 /// This struct is a temporary (and quite terrible) placeholder until the
@@ -66,15 +246,65 @@ pub(crate) struct Vcpu {
     pub vcpu_fd: VcpuFd,
 
     serial: Arc<Mutex<LumperSerial>>,
+    i8042: Arc<Mutex<LumperI8042>>,
+    cmos: Arc<Mutex<Cmos>>,
+    acpi_pm: Arc<Mutex<AcpiPm>>,
+    gdb: Option<Arc<Mutex<GdbStub>>>,
+    // Guest crashes are ignored without a handler.
+    crash_handler: Option<Arc<CrashHandler>>,
+    // Watchdog ports are ignored without a watchdog.
+    watchdog: Option<Arc<Mutex<Watchdog>>>,
+    // Boot completion notifications are ignored without a notifier.
+    boot_notifier: Option<Arc<Mutex<BootNotifier>>>,
+    // MMIO accesses are not handled without shared memory.
+    shmem: Option<Arc<ShmemRegisters>>,
+    // KVM handles the MSRs itself without a policy.
+    msr_exits: Option<(MsrExits, Arc<MsrPolicy>)>,
+    // Pauses the vCPU while the VM reboots.
+    control: Arc<VcpuControl>,
+    guest_memory: GuestMemoryMmap,
+    // State the vCPU is created with, restored when the VM reboots.
+    boot_sregs: kvm_sregs,
+    boot_lapic: kvm_lapic_state,
 }
 
 impl Vcpu {
     /// Create a new vCPU.
-    pub fn new(vm_fd: &VmFd, index: u64, serial: Arc<Mutex<LumperSerial>>) -> Result<Self> {
+    pub fn new(
+        vm_fd: &VmFd,
+        index: u64,
+        devices: VcpuDevices,
+        control: Arc<VcpuControl>,
+        guest_memory: GuestMemoryMmap,
+    ) -> Result<Self> {
+        let vcpu_fd = vm_fd.create_vcpu(index).map_err(Error::KvmIoctl)?;
+        let boot_sregs = vcpu_fd.get_sregs().map_err(Error::KvmIoctl)?;
+        let boot_lapic = vcpu_fd.get_lapic().map_err(Error::KvmIoctl)?;
+
+        let VcpuDevices {
+            serial,
+            i8042,
+            cmos,
+            acpi_pm,
+        } = devices;
+
         Ok(Vcpu {
             index,
-            vcpu_fd: vm_fd.create_vcpu(index).map_err(Error::KvmIoctl)?,
+            vcpu_fd,
             serial,
+            i8042,
+            cmos,
+            acpi_pm,
+            gdb: None,
+            crash_handler: None,
+            watchdog: None,
+            boot_notifier: None,
+            shmem: None,
+            msr_exits: None,
+            control,
+            guest_memory,
+            boot_sregs,
+            boot_lapic,
         })
     }
 
@@ -98,39 +328,40 @@ impl Vcpu {
             })
     }
 
-    /// Configure regs.
-    pub fn configure_regs(&self, kernel_load: GuestAddress) -> Result<()> {
-        let regs = kvm_regs {
-            rflags: 0x0000_0000_0000_0002u64,
-            rip: kernel_load.raw_value(),
-            // Frame pointer. It gets a snapshot of the stack pointer (rsp) so that when adjustments are
-            // made to rsp (i.e. reserving space for local variables or pushing values on to the stack),
-            // local variables and function parameters are still accessible from a constant offset from rbp.
-            rsp: BOOT_STACK_POINTER,
-            // Starting stack pointer.
-            rbp: BOOT_STACK_POINTER,
-            // Must point to zero page address per Linux ABI. This is x86_64 specific.
-            rsi: crate::kernel::ZEROPG_START,
-            ..Default::default()
+    /// Set the TSC frequency, in kHz, so that it does not depend on the host.
+    pub fn configure_tsc_khz(&self, tsc_khz: u32) -> Result<()> {
+        // Safe because the ioctl takes its argument by value, and its result is checked.
+        let ret = unsafe {
+            libc::ioctl(
+                self.vcpu_fd.as_raw_fd(),
+                KVM_SET_TSC_KHZ,
+                libc::c_ulong::from(tsc_khz),
+            )
         };
-        self.vcpu_fd.set_regs(&regs).map_err(Error::KvmIoctl)
+        if ret < 0 {
+            return Err(Error::TscFrequency(io::Error::last_os_error()));
+        }
+
+        Ok(())
+    }
+
+    /// Configure regs.
+    pub fn configure_regs(&self, entry: KernelEntry) -> Result<()> {
+        self.vcpu_fd
+            .set_regs(&boot_regs(entry))
+            .map_err(Error::KvmIoctl)
     }
 
     /// Configure sregs.
-    pub fn configure_sregs(&self, guest_memory: &GuestMemoryMmap) -> Result<()> {
+    pub fn configure_sregs(
+        &self,
+        guest_memory: &GuestMemoryMmap,
+        entry: KernelEntry,
+    ) -> Result<()> {
         let mut sregs = self.vcpu_fd.get_sregs().map_err(Error::KvmIoctl)?;
 
         // Global descriptor tables.
-        let gdt_table: [u64; BOOT_GDT_MAX as usize] = [
-            gdt_entry(0, 0, 0),            // NULL
-            gdt_entry(0xa09b, 0, 0xfffff), // CODE
-            gdt_entry(0xc093, 0, 0xfffff), // DATA
-            gdt_entry(0x808b, 0, 0xfffff), // TSS
-        ];
-
-        let code_seg = kvm_segment_from_gdt(gdt_table[1], 1);
-        let data_seg = kvm_segment_from_gdt(gdt_table[2], 2);
-        let tss_seg = kvm_segment_from_gdt(gdt_table[3], 3);
+        let gdt_table = boot_gdt(entry);
 
         // Write segments to guest memory.
         write_gdt_table(&gdt_table[..], guest_memory).map_err(Error::GuestMemory)?;
@@ -141,17 +372,10 @@ impl Vcpu {
         sregs.idt.base = BOOT_IDT_OFFSET as u64;
         sregs.idt.limit = std::mem::size_of::<u64>() as u16 - 1;
 
-        sregs.cs = code_seg;
-        sregs.ds = data_seg;
-        sregs.es = data_seg;
-        sregs.fs = data_seg;
-        sregs.gs = data_seg;
-        sregs.ss = data_seg;
-        sregs.tr = tss_seg;
-
-        // 64-bit protected mode.
-        sregs.cr0 |= X86_CR0_PE;
-        sregs.efer |= (msr_index::EFER_LME | msr_index::EFER_LMA) as u64;
+        set_boot_mode(&mut sregs, &gdt_table, entry);
+        if let KernelEntry::Pvh(_) = entry {
+            return self.vcpu_fd.set_sregs(&sregs).map_err(Error::KvmIoctl);
+        }
 
         // Start page table configuration.
         // Puts PML4 right after zero page but aligned to 4k.
@@ -214,41 +438,286 @@ impl Vcpu {
         self.vcpu_fd.set_lapic(&klapic).map_err(Error::KvmIoctl)
     }
 
+    /// Put the vCPU back in its boot state, entering the kernel at `entry`.
+    pub fn reset(&self, entry: KernelEntry) -> Result<()> {
+        // Application processors wait for the boot one to start them again.
+        let mp_state = if self.index == 0 {
+            KVM_MP_STATE_RUNNABLE
+        } else {
+            KVM_MP_STATE_UNINITIALIZED
+        };
+        self.vcpu_fd
+            .set_mp_state(kvm_mp_state { mp_state })
+            .map_err(Error::KvmIoctl)?;
+
+        self.vcpu_fd
+            .set_sregs(&self.boot_sregs)
+            .map_err(Error::KvmIoctl)?;
+        self.vcpu_fd
+            .set_lapic(&self.boot_lapic)
+            .map_err(Error::KvmIoctl)?;
+
+        self.configure_msrs()?;
+        self.configure_regs(entry)?;
+        self.configure_sregs(&self.guest_memory, entry)?;
+        self.configure_fpu()?;
+        self.configure_lapic()
+    }
+
+    /// Park the vCPU while the VM is paused, resetting it if the VM rebooted meanwhile.
+    fn wait_if_paused(&self) -> Result<()> {
+        match self.control.wait_if_paused() {
+            Some(entry) => self.reset(entry),
+            None => Ok(()),
+        }
+    }
+
+    /// Park the vCPU until the VM resumes, resetting it if the VM rebooted meanwhile.
+    fn park(&self) -> Result<()> {
+        match self.control.park() {
+            Some(entry) => self.reset(entry),
+            None => Ok(()),
+        }
+    }
+
+    /// Hand the vCPU over to the debugger, trapping on software breakpoints from now on.
+    pub fn attach_gdb(&mut self, gdb: Arc<Mutex<GdbStub>>) -> Result<()> {
+        gdb::Target::set_single_step(self, false).map_err(Error::GuestDebug)?;
+        self.gdb = Some(gdb);
+
+        Ok(())
+    }
+
+    /// Let the debugger inspect the stopped vCPU, and resume it as told.
+    pub fn debug_stop(&mut self) -> Result<()> {
+        let gdb = match &self.gdb {
+            Some(gdb) => Arc::clone(gdb),
+            None => return Ok(()),
+        };
+
+        // Other vCPUs stopping wait for the debugger to be done with this one.
+        self.pause_watchdog();
+        let resume = gdb.lock().unwrap().stopped(self, self.index, &self.control);
+        self.resume_watchdog();
+        let step = match resume {
+            Resume::Step => true,
+            Resume::Continue | Resume::Detach => false,
+            // The VM stopped while the debugger was waited for.
+            Resume::Kill if self.control.stopping() => return Ok(()),
+            Resume::Kill => {
+                let reason = VmExitReason::Shutdown("killed by the debugger".to_string());
+                return Err(Error::Stop(reason));
+            }
+        };
+
+        gdb::Target::set_single_step(self, step).map_err(Error::GuestDebug)
+    }
+
+    /// The guest cannot ping the watchdog while this vCPU is stopped.
+    fn pause_watchdog(&self) {
+        if let Some(watchdog) = &self.watchdog {
+            watchdog.lock().unwrap().pause(Instant::now());
+        }
+    }
+
+    fn resume_watchdog(&self) {
+        if let Some(watchdog) = &self.watchdog {
+            watchdog.lock().unwrap().resume(Instant::now());
+        }
+    }
+
+    /// Handle guest crashes as configured, instead of ignoring them.
+    pub fn set_crash_handler(&mut self, crash_handler: Arc<CrashHandler>) {
+        self.crash_handler = Some(crash_handler);
+    }
+
+    /// Expose the watchdog to the guest.
+    pub fn set_watchdog(&mut self, watchdog: Arc<Mutex<Watchdog>>) {
+        self.watchdog = Some(watchdog);
+    }
+
+    /// Expose the shared memory registers to the guest.
+    pub fn set_shmem(&mut self, shmem: Arc<ShmemRegisters>) {
+        self.shmem = Some(shmem);
+    }
+
+    /// Handle the accesses to the MSRs KVM hands over following `policy`.
+    pub fn set_msr_policy(&mut self, policy: Arc<MsrPolicy>) -> Result<()> {
+        let msr_exits = MsrExits::new(&self.vcpu_fd).map_err(Error::MsrExits)?;
+        self.msr_exits = Some((msr_exits, policy));
+        Ok(())
+    }
+
+    /// Report the boot completion notified by the guest.
+    pub fn set_boot_notifier(&mut self, boot_notifier: Arc<Mutex<BootNotifier>>) {
+        self.boot_notifier = Some(boot_notifier);
+    }
+
+    /// Handle a guest write of `value` to `addr` if the boot notifier is there.
+    ///
+    /// Returns whether it is.
+    fn write_boot_notifier(&self, addr: u16, value: u8) -> bool {
+        let mut boot_notifier = match &self.boot_notifier {
+            Some(boot_notifier) => boot_notifier.lock().unwrap(),
+            None => return false,
+        };
+        if boot_notifier.port() != addr {
+            return false;
+        }
+
+        if let Some(boot_time) = boot_notifier.write(value, Instant::now()) {
+            info!("Guest booted in {} ms", boot_time.as_millis());
+        }
+
+        true
+    }
+
+    /// Carry the crash action out, if guest crashes are handled.
+    fn crash(&mut self, reason: &str) -> Result<()> {
+        let crash_handler = match &self.crash_handler {
+            Some(crash_handler) => Arc::clone(crash_handler),
+            None => return Ok(()),
+        };
+
+        let regs = self.regs_dump();
+        error!("vCPU {} crashed: {}\n{}", self.index, reason, regs);
+
+        match crash_handler
+            .crashed(self.index, &regs)
+            .map_err(Error::CrashDump)?
+        {
+            CrashAction::Dump | CrashAction::Shutdown => {
+                Err(Error::Stop(VmExitReason::GuestCrash(reason.to_string())))
+            }
+            CrashAction::Pause if self.gdb.is_some() => self.debug_stop(),
+            CrashAction::Pause => {
+                warn!("vCPU {} paused until the VM reboots", self.index);
+                // The reboot disarms the watchdog.
+                self.pause_watchdog();
+                self.park()
+            }
+        }
+    }
+
+    /// Report an exit the VMM does not know how to handle, with the vCPU state.
+    ///
+    /// Returns the error stopping the VM.
+    fn exit_unexpected(&self, reason: &str) -> Error {
+        error!(
+            "vCPU {} stopped: {}\n{}",
+            self.index,
+            reason,
+            self.regs_dump()
+        );
+
+        Error::Stop(VmExitReason::InternalError(format!(
+            "vCPU {}: {}",
+            self.index, reason
+        )))
+    }
+
+    /// Format the vCPU registers, for diagnostics.
+    fn regs_dump(&self) -> String {
+        match (self.vcpu_fd.get_regs(), self.vcpu_fd.get_sregs()) {
+            (Ok(regs), Ok(sregs)) => format_regs(&regs, &sregs),
+            (Err(e), _) | (_, Err(e)) => format!("Failed to read vCPU registers: {}", e),
+        }
+    }
+
+    fn handle_msr_exit(&self, write: bool) {
+        if let Some((msr_exits, policy)) = &self.msr_exits {
+            msr_exits.handle(policy, self.index, write);
+        }
+    }
+
     /// vCPU emulation loop.
-    pub fn run(&mut self) {
+    ///
+    /// Device failures are returned, the vCPU must not be run again afterwards.
+    pub fn run(&mut self) -> Result<()> {
+        self.wait_if_paused()?;
+        // The VM stopped while the vCPU was parked.
+        if self.control.stopping() {
+            return Ok(());
+        }
+
         // Call into KVM to launch (VMLAUNCH) or resume (VMRESUME) the virtual CPU.
         // This is a blocking function, it only returns for either an error or a
         // VM-Exit. In the latter case, we can inspect the exit reason.
         match self.vcpu_fd.run() {
             Ok(exit_reason) => match exit_reason {
-                // The VM stopped (Shutdown ot HLT).
-                VcpuExit::Shutdown | VcpuExit::Hlt => {
-                    println!("Guest shutdown: {:?}. Bye!", exit_reason);
-                    let stdin = io::stdin();
-                    let stdin_lock = stdin.lock();
-                    stdin_lock.set_canon_mode().unwrap();
-
-                    unsafe { libc::exit(0) };
+                // A triple fault, which resets the machine.
+                VcpuExit::Shutdown => {
+                    info!("vCPU {}: guest triple fault, rebooting", self.index);
+                    self.control.request_reboot().map_err(Error::Reboot)?;
+                    self.park()?;
+                }
+
+                // The VM stopped.
+                VcpuExit::Hlt => {
+                    return Err(Error::Stop(VmExitReason::Shutdown(
+                        GUEST_HALTED.to_string(),
+                    )));
                 }
 
                 // This is a PIO write, i.e. the guest is trying to write
                 // something to an I/O port.
                 VcpuExit::IoOut(addr, data) => match addr {
                     SERIAL_PORT_BASE..=SERIAL_PORT_LAST_REGISTER => {
-                        self.serial
+                        let offset = (addr - SERIAL_PORT_BASE)
+                            .try_into()
+                            .expect("Invalid serial register offset");
+                        let serial = &self.serial;
+                        devices::retry(|| {
+                            serial
+                                .lock()
+                                .unwrap()
+                                .serial
+                                .write(offset, data[0])
+                                .map_err(devices::Error::Serial)
+                        })
+                        .map_err(Error::Device)?;
+                    }
+                    I8042_PORT_BASE..=I8042_PORT_LAST_REGISTER => {
+                        let i8042 = &self.i8042;
+                        devices::retry(|| {
+                            i8042
+                                .lock()
+                                .unwrap()
+                                .i8042
+                                .write((addr - I8042_PORT_BASE) as u8, data[0])
+                                .map_err(devices::Error::I8042)
+                        })
+                        .map_err(Error::Device)?;
+                    }
+                    CMOS_PORT_BASE..=CMOS_PORT_LAST_REGISTER => {
+                        self.cmos
                             .lock()
                             .unwrap()
-                            .serial
-                            .write(
-                                (addr - SERIAL_PORT_BASE)
-                                    .try_into()
-                                    .expect("Invalid serial register offset"),
-                                data[0],
-                            )
-                            .unwrap();
+                            .write((addr - CMOS_PORT_BASE) as u8, data[0]);
+                    }
+                    ACPI_PM_PORT_BASE..=ACPI_PM_PORT_LAST_REGISTER => {
+                        self.acpi_pm
+                            .lock()
+                            .unwrap()
+                            .write(addr - ACPI_PM_PORT_BASE, data);
+                    }
+                    PVPANIC_PORT => {
+                        if pvpanic::write(data[0]) {
+                            self.crash("guest kernel panic")?;
+                        }
+                    }
+                    WATCHDOG_STOP_PORT | WATCHDOG_START_PORT => {
+                        if let Some(watchdog) = &self.watchdog {
+                            watchdog.lock().unwrap().write(addr, Instant::now());
+                        }
                     }
                     _ => {
-                        println!("Unsupported device write at {:x?}", addr);
+                        if !self.write_boot_notifier(addr, data[0]) {
+                            debug!(
+                                "vCPU {}: unsupported device write at {:#x}",
+                                self.index, addr
+                            );
+                        }
                     }
                 },
 
@@ -262,15 +731,206 @@ impl Vcpu {
                                 .expect("Invalid serial register offset"),
                         );
                     }
+                    I8042_PORT_BASE..=I8042_PORT_LAST_REGISTER => {
+                        data[0] = self
+                            .i8042
+                            .lock()
+                            .unwrap()
+                            .i8042
+                            .read((addr - I8042_PORT_BASE) as u8);
+                    }
+                    CMOS_PORT_BASE..=CMOS_PORT_LAST_REGISTER => {
+                        data[0] = self
+                            .cmos
+                            .lock()
+                            .unwrap()
+                            .read((addr - CMOS_PORT_BASE) as u8);
+                    }
+                    ACPI_PM_PORT_BASE..=ACPI_PM_PORT_LAST_REGISTER => {
+                        self.acpi_pm
+                            .lock()
+                            .unwrap()
+                            .read(addr - ACPI_PM_PORT_BASE, data);
+                    }
+                    PVPANIC_PORT => {
+                        data[0] = pvpanic::read();
+                    }
                     _ => {
-                        println!("Unsupported device read at {:x?}", addr);
+                        debug!(
+                            "vCPU {}: unsupported device read at {:#x}",
+                            self.index, addr
+                        );
+                    }
+                },
+
+                // KVM could not emulate the guest anymore.
+                VcpuExit::InternalError if self.crash_handler.is_some() => {
+                    self.crash("KVM internal error")?;
+                }
+
+                // A breakpoint was hit, or a single step completed.
+                VcpuExit::Debug(_) => self.debug_stop()?,
+
+                // Only the shared memory registers are backed by MMIO.
+                VcpuExit::MmioRead(addr, data) => match &self.shmem {
+                    Some(shmem) if shmem.contains(addr) => shmem.read(addr - SHMEM_REGS_ADDR, data),
+                    _ => debug!("vCPU {}: unsupported MMIO read at {:#x}", self.index, addr),
+                },
+                VcpuExit::MmioWrite(addr, data) => match &self.shmem {
+                    Some(shmem) if shmem.contains(addr) => {
+                        shmem.write(addr - SHMEM_REGS_ADDR, data)
                     }
+                    _ => debug!("vCPU {}: unsupported MMIO write at {:#x}", self.index, addr),
                 },
+
+                // An MSR KVM does not handle, only handed over with a policy.
+                VcpuExit::Unsupported(KVM_EXIT_X86_RDMSR) => self.handle_msr_exit(false),
+                VcpuExit::Unsupported(KVM_EXIT_X86_WRMSR) => self.handle_msr_exit(true),
+
                 _ => {
-                    eprintln!("Unhandled VM-Exit: {:?}", exit_reason);
+                    let reason = format!("unhandled VM-Exit {:?}", exit_reason);
+                    return Err(self.exit_unexpected(&reason));
                 }
             },
-            Err(e) => eprintln!("Emulation error: {}", e),
+            // Kicked out of the guest, so that the VM can be paused.
+            Err(e) if e.errno() == libc::EINTR => trace!("vCPU {}: kicked out", self.index),
+            Err(e) => return Err(self.exit_unexpected(&format!("emulation error {}", e))),
+        }
+
+        Ok(())
+    }
+}
+
+impl gdb::Target for Vcpu {
+    fn registers(&self) -> io::Result<(kvm_regs, kvm_sregs)> {
+        let regs = self.vcpu_fd.get_regs();
+        let sregs = self.vcpu_fd.get_sregs();
+        match (regs, sregs) {
+            (Ok(regs), Ok(sregs)) => Ok((regs, sregs)),
+            (Err(e), _) | (_, Err(e)) => Err(io::Error::from_raw_os_error(e.errno())),
+        }
+    }
+
+    fn set_registers(&self, regs: &kvm_regs) -> io::Result<()> {
+        self.vcpu_fd
+            .set_regs(regs)
+            .map_err(|e| io::Error::from_raw_os_error(e.errno()))
+    }
+
+    fn translate(&self, gva: u64) -> Option<u64> {
+        let mut translation = kvm_translation {
+            linear_address: gva,
+            ..Default::default()
+        };
+        // Safe because the kernel only writes within the struct, and the result is checked.
+        let ret = unsafe {
+            libc::ioctl(
+                self.vcpu_fd.as_raw_fd(),
+                KVM_TRANSLATE,
+                &mut translation as *mut kvm_translation,
+            )
+        };
+
+        if ret < 0 || translation.valid == 0 {
+            return None;
+        }
+        Some(translation.physical_address)
+    }
+
+    fn set_single_step(&self, step: bool) -> io::Result<()> {
+        let mut debug = kvm_guest_debug {
+            control: KVM_GUESTDBG_ENABLE | KVM_GUESTDBG_USE_SW_BP,
+            ..Default::default()
+        };
+        if step {
+            debug.control |= KVM_GUESTDBG_SINGLESTEP;
         }
+
+        // Safe because the kernel only reads the struct, and the result is checked.
+        let ret = unsafe {
+            libc::ioctl(
+                self.vcpu_fd.as_raw_fd(),
+                KVM_SET_GUEST_DEBUG,
+                &debug as *const kvm_guest_debug,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn regs_dump() {
+        let regs = kvm_regs {
+            rip: 0x100_0000,
+            rflags: 0x2,
+            ..Default::default()
+        };
+        let sregs = kvm_sregs {
+            cr2: 0xdead_beef,
+            ..Default::default()
+        };
+
+        let dump = format_regs(&regs, &sregs);
+        let lines: Vec<&str> = dump.lines().collect();
+
+        assert_eq!(lines.len(), 6);
+        assert_eq!(
+            lines[0],
+            "   rip=0000000001000000    rsp=0000000000000000    rbp=0000000000000000 rflags=0000000000000002"
+        );
+        assert!(lines[4].contains("cr2=00000000deadbeef"));
+    }
+
+    #[test]
+    fn linux_boot_state() {
+        let entry = KernelEntry::Linux(GuestAddress(0x100_0000));
+
+        let regs = boot_regs(entry);
+        assert_eq!(regs.rip, 0x100_0000);
+        assert_eq!(regs.rsi, ZEROPG_START);
+        assert_eq!(regs.rsp, BOOT_STACK_POINTER);
+        assert_eq!(regs.rflags, BOOT_RFLAGS);
+
+        let mut sregs = kvm_sregs::default();
+        set_boot_mode(&mut sregs, &boot_gdt(entry), entry);
+        assert_eq!((sregs.cs.l, sregs.cs.db), (1, 0));
+        assert_eq!(sregs.cr0 & X86_CR0_PE, X86_CR0_PE);
+        assert_eq!(
+            sregs.efer,
+            (msr_index::EFER_LME | msr_index::EFER_LMA) as u64
+        );
+    }
+
+    #[test]
+    fn pvh_boot_state() {
+        let entry = KernelEntry::Pvh(GuestAddress(0x100_0200));
+
+        let regs = boot_regs(entry);
+        assert_eq!(regs.rip, 0x100_0200);
+        assert_eq!(regs.rbx, PVH_INFO_START);
+        assert_eq!(regs.rflags, BOOT_RFLAGS);
+
+        // Leftovers from a previous mode are cleared.
+        let mut sregs = kvm_sregs {
+            cr0: X86_CR0_PG,
+            cr4: X86_CR4_PAE,
+            efer: msr_index::EFER_LME as u64,
+            ..Default::default()
+        };
+        set_boot_mode(&mut sregs, &boot_gdt(entry), entry);
+        assert_eq!((sregs.cs.l, sregs.cs.db), (0, 1));
+        assert_eq!((sregs.cs.base, sregs.cs.limit), (0, 0xffff_ffff));
+        assert_eq!(sregs.ds.selector, 2 << 3);
+        assert_eq!(sregs.cr0, X86_CR0_PE);
+        assert_eq!(sregs.cr4, 0);
+        assert_eq!(sregs.efer, 0);
     }
 }
@@ -11,6 +11,10 @@ use crate::cpu::msr_index::{
     MSR_STAR, MSR_SYSCALL_MASK,
 };
 
+// kvmclock MSR, written by the guest to register its per vCPU time structure.
+// See Documentation/virt/kvm/msr.rst in the Linux sources.
+const MSR_KVM_SYSTEM_TIME_NEW: u32 = 0x4b56_4d01;
+
 // Errors associated with operations on MSRs.
 #[derive(Debug, PartialEq)]
 pub enum Error {
@@ -39,6 +43,8 @@ pub fn create_boot_msr_entries() -> Result<Msrs> {
         msr_entry_default(MSR_LSTAR),
         // end of x86_64 specific code
         msr_entry_default(MSR_IA32_TSC),
+        // kvmclock is disabled until the guest enables it.
+        msr_entry_default(MSR_KVM_SYSTEM_TIME_NEW),
         kvm_msr_entry {
             index: MSR_IA32_MISC_ENABLE,
             data: u64::from(MSR_IA32_MISC_ENABLE_FAST_STRING),
@@ -0,0 +1,342 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Guest crash handling.
+//!
+//! Crashes are reported by the guest through the pvpanic device, or by KVM failing to run a vCPU.
+//! The vCPU that crashed carries the configured action out.
+//!
+//! Guest memory is dumped as an ELF core, with a loadable segment per memory region at its guest
+//! physical address. The vCPU registers are written next to it, as text.
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::result;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use vm_memory::{
+    Address, Bytes, GuestMemory, GuestMemoryError, GuestMemoryMmap, GuestMemoryRegion,
+};
+
+/// Errors encountered while writing a crash dump.
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to write the dump files.
+    IO(io::Error),
+    /// Failed to read guest memory.
+    GuestMemory(GuestMemoryError),
+}
+
+/// Dedicated Result type.
+pub type Result<T> = result::Result<T, Error>;
+
+// ELF64 constants, from elf.h.
+const ELF_HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+const ET_CORE: u16 = 4;
+const EM_X86_64: u16 = 62;
+const PT_LOAD: u32 = 1;
+const PF_RWX: u32 = 0x7;
+// Memory regions start on a page boundary in the core.
+const CORE_ALIGN: u64 = 0x1000;
+
+/// What to do when the guest crashes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CrashAction {
+    /// Write the guest memory and the vCPU registers to a directory, then stop the VM.
+    Dump,
+    /// Stop the crashed vCPU, handing it over to the debugger if one is attached.
+    Pause,
+    /// Stop the VM.
+    Shutdown,
+}
+
+impl FromStr for CrashAction {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s {
+            "dump" => Ok(CrashAction::Dump),
+            "pause" => Ok(CrashAction::Pause),
+            "shutdown" => Ok(CrashAction::Shutdown),
+            _ => Err(crate::Error::CrashAction(s.to_string())),
+        }
+    }
+}
+
+/// ELF header of a core with `phnum` program headers.
+fn elf_header(phnum: u16) -> Vec<u8> {
+    let mut header = Vec::with_capacity(ELF_HEADER_SIZE);
+    // Magic, 64-bit, little endian, current version, System V ABI, and padding.
+    header.extend_from_slice(b"\x7fELF");
+    header.extend_from_slice(&[2, 1, 1, 0]);
+    header.extend_from_slice(&[0; 8]);
+    header.extend_from_slice(&ET_CORE.to_le_bytes());
+    header.extend_from_slice(&EM_X86_64.to_le_bytes());
+    header.extend_from_slice(&1u32.to_le_bytes());
+    // No entry point nor section headers.
+    header.extend_from_slice(&0u64.to_le_bytes());
+    header.extend_from_slice(&(ELF_HEADER_SIZE as u64).to_le_bytes());
+    header.extend_from_slice(&0u64.to_le_bytes());
+    header.extend_from_slice(&0u32.to_le_bytes());
+    header.extend_from_slice(&(ELF_HEADER_SIZE as u16).to_le_bytes());
+    header.extend_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+    header.extend_from_slice(&phnum.to_le_bytes());
+    header.extend_from_slice(&[0; 6]);
+
+    header
+}
+
+/// Program header loading `len` bytes of guest memory at `gpa` from `offset` in the core.
+fn program_header(offset: u64, gpa: u64, len: u64) -> Vec<u8> {
+    let mut header = Vec::with_capacity(PROGRAM_HEADER_SIZE);
+    header.extend_from_slice(&PT_LOAD.to_le_bytes());
+    header.extend_from_slice(&PF_RWX.to_le_bytes());
+    header.extend_from_slice(&offset.to_le_bytes());
+    // Guest physical addresses, as virtual ones too.
+    header.extend_from_slice(&gpa.to_le_bytes());
+    header.extend_from_slice(&gpa.to_le_bytes());
+    header.extend_from_slice(&len.to_le_bytes());
+    header.extend_from_slice(&len.to_le_bytes());
+    header.extend_from_slice(&CORE_ALIGN.to_le_bytes());
+
+    header
+}
+
+/// Write `guest_memory` to `core` as an ELF core, streaming each region.
+fn write_core<W: Write>(guest_memory: &GuestMemoryMmap, core: &mut W) -> Result<()> {
+    let phnum = guest_memory.num_regions();
+    let headers_size = (ELF_HEADER_SIZE + phnum * PROGRAM_HEADER_SIZE) as u64;
+    let data_start = (headers_size + CORE_ALIGN - 1) / CORE_ALIGN * CORE_ALIGN;
+
+    let mut headers = elf_header(phnum as u16);
+    let mut offset = data_start;
+    for region in guest_memory.iter() {
+        headers.extend(program_header(
+            offset,
+            region.start_addr().raw_value(),
+            region.len(),
+        ));
+        offset += region.len();
+    }
+    headers.resize(data_start as usize, 0);
+    core.write_all(&headers).map_err(Error::IO)?;
+
+    for region in guest_memory.iter() {
+        guest_memory
+            .write_all_to(region.start_addr(), core, region.len() as usize)
+            .map_err(Error::GuestMemory)?;
+    }
+
+    Ok(())
+}
+
+/// Carries the configured action out, shared by the vCPUs.
+pub(crate) struct CrashHandler {
+    action: CrashAction,
+    guest_memory: GuestMemoryMmap,
+    // Crash dumps are written in timestamped directories under this one.
+    dump_dir: PathBuf,
+    // Directory of the crash dump the guest memory is still to be written to.
+    pending_dump: Mutex<Option<PathBuf>>,
+}
+
+impl CrashHandler {
+    pub fn new(action: CrashAction, guest_memory: GuestMemoryMmap, dump_dir: PathBuf) -> Self {
+        CrashHandler {
+            action,
+            guest_memory,
+            dump_dir,
+            pending_dump: Mutex::new(None),
+        }
+    }
+
+    /// Record the crash of vCPU `index`, described by its `regs` dump, and tell how the vCPU
+    /// proceeds.
+    ///
+    /// The guest memory is only dumped by [`dump_memory`](Self::dump_memory), once the vCPUs
+    /// are stopped.
+    pub fn crashed(&self, index: u64, regs: &str) -> Result<CrashAction> {
+        if self.action == CrashAction::Dump {
+            let mut pending_dump = self.pending_dump.lock().unwrap();
+            // vCPUs crashing together share the dump.
+            let dir = match pending_dump.take() {
+                Some(dir) => dir,
+                None => self.create_dump_dir()?,
+            };
+            fs::write(dir.join(format!("vcpu{}.txt", index)), regs).map_err(Error::IO)?;
+            *pending_dump = Some(dir);
+        }
+
+        Ok(self.action)
+    }
+
+    fn create_dump_dir(&self) -> Result<PathBuf> {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_secs());
+        let dir = self.dump_dir.join(format!("lumper-crash-{}", time));
+        fs::create_dir_all(&dir).map_err(Error::IO)?;
+
+        Ok(dir)
+    }
+
+    /// Write the guest memory of the pending crash dump, if any, as an ELF core.
+    ///
+    /// The vCPUs must be stopped, for the memory not to change while it is written.
+    pub fn dump_memory(&self) -> Result<()> {
+        let dir = match self.pending_dump.lock().unwrap().take() {
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+
+        let mut core =
+            io::BufWriter::new(File::create(dir.join("memory.core")).map_err(Error::IO)?);
+        write_core(&self.guest_memory, &mut core)?;
+        core.flush().map_err(Error::IO)?;
+
+        warn!("Crash dump written to {}", dir.display());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    use vm_memory::GuestAddress;
+    use vmm_sys_util::tempdir::TempDir;
+
+    fn handler(action: CrashAction, dump_dir: &TempDir) -> CrashHandler {
+        let guest_memory = GuestMemoryMmap::from_ranges(&[
+            (GuestAddress(0), 0x1000),
+            (GuestAddress(0x10_0000), 0x2000),
+        ])
+        .unwrap();
+
+        CrashHandler::new(action, guest_memory, dump_dir.as_path().to_path_buf())
+    }
+
+    #[test]
+    fn crash_action_names() {
+        assert_eq!("dump".parse::<CrashAction>().unwrap(), CrashAction::Dump);
+        assert_eq!("pause".parse::<CrashAction>().unwrap(), CrashAction::Pause);
+        assert_eq!(
+            "shutdown".parse::<CrashAction>().unwrap(),
+            CrashAction::Shutdown
+        );
+        assert!(matches!(
+            "reboot".parse::<CrashAction>(),
+            Err(crate::Error::CrashAction(name)) if name == "reboot"
+        ));
+    }
+
+    #[test]
+    fn dump() {
+        let dump_dir = TempDir::new_with_prefix(env::temp_dir().join("lumper")).unwrap();
+        let handler = handler(CrashAction::Dump, &dump_dir);
+        handler
+            .guest_memory
+            .write_slice(b"panic", GuestAddress(0x10_1000))
+            .unwrap();
+
+        assert_eq!(handler.crashed(1, "rip=0").unwrap(), CrashAction::Dump);
+        assert_eq!(handler.crashed(0, "rip=1").unwrap(), CrashAction::Dump);
+
+        let dirs: Vec<PathBuf> = fs::read_dir(dump_dir.as_path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(dirs.len(), 1);
+        let dir = &dirs[0];
+        assert!(dir
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .starts_with("lumper-crash-"));
+
+        assert_eq!(fs::read_to_string(dir.join("vcpu1.txt")).unwrap(), "rip=0");
+        assert_eq!(fs::read_to_string(dir.join("vcpu0.txt")).unwrap(), "rip=1");
+        // The memory is only written once the vCPUs are stopped.
+        assert!(!dir.join("memory.core").exists());
+
+        handler.dump_memory().unwrap();
+        let core = fs::read(dir.join("memory.core")).unwrap();
+        assert_eq!(core.len(), 0x1000 + 0x1000 + 0x2000);
+        assert_eq!(&core[0x2000 + 0x1000..0x2000 + 0x1005], b"panic");
+
+        // Once only.
+        fs::remove_file(dir.join("memory.core")).unwrap();
+        handler.dump_memory().unwrap();
+        assert!(!dir.join("memory.core").exists());
+    }
+
+    fn u16_at(data: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes([data[offset], data[offset + 1]])
+    }
+
+    fn u64_at(data: &[u8], offset: usize) -> u64 {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&data[offset..offset + 8]);
+        u64::from_le_bytes(bytes)
+    }
+
+    #[test]
+    fn core_headers() {
+        let dump_dir = TempDir::new_with_prefix(env::temp_dir().join("lumper")).unwrap();
+        let handler = handler(CrashAction::Dump, &dump_dir);
+        let pattern: Vec<u8> = (0..=255).collect();
+        handler
+            .guest_memory
+            .write_slice(&pattern, GuestAddress(0x10_0800))
+            .unwrap();
+
+        let mut core = Vec::new();
+        write_core(&handler.guest_memory, &mut core).unwrap();
+
+        assert_eq!(&core[..4], b"\x7fELF");
+        // 64-bit, little endian.
+        assert_eq!(&core[4..6], &[2, 1]);
+        assert_eq!(u16_at(&core, 16), ET_CORE);
+        assert_eq!(u16_at(&core, 18), EM_X86_64);
+        assert_eq!(u64_at(&core, 32), ELF_HEADER_SIZE as u64);
+        assert_eq!(u16_at(&core, 54), PROGRAM_HEADER_SIZE as u16);
+        assert_eq!(u16_at(&core, 56), 2);
+
+        // A loadable segment per region, as (offset, address, size).
+        let segments: Vec<(u64, u64, u64)> = (0..2)
+            .map(|i| {
+                let header = &core[ELF_HEADER_SIZE + i * PROGRAM_HEADER_SIZE..];
+                assert_eq!(header[..4], PT_LOAD.to_le_bytes());
+                assert_eq!(u64_at(header, 16), u64_at(header, 24));
+                assert_eq!(u64_at(header, 32), u64_at(header, 40));
+                (u64_at(header, 8), u64_at(header, 24), u64_at(header, 32))
+            })
+            .collect();
+        assert_eq!(
+            segments,
+            vec![(0x1000, 0, 0x1000), (0x2000, 0x10_0000, 0x2000)]
+        );
+        assert_eq!(core.len(), 0x4000);
+
+        // The pattern is found back through the segment holding its address.
+        let (offset, gpa, _) = segments[1];
+        let start = (offset + 0x10_0800 - gpa) as usize;
+        assert_eq!(&core[start..start + pattern.len()], pattern.as_slice());
+    }
+
+    #[test]
+    fn no_dump() {
+        let dump_dir = TempDir::new_with_prefix(env::temp_dir().join("lumper")).unwrap();
+
+        for action in [CrashAction::Pause, CrashAction::Shutdown].iter() {
+            let handler = handler(*action, &dump_dir);
+            assert_eq!(handler.crashed(0, "rip=0").unwrap(), *action);
+            handler.dump_memory().unwrap();
+        }
+        assert_eq!(fs::read_dir(dump_dir.as_path()).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,212 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Running the VMM in the background.
+//!
+//! The foreground process forks and waits on a pipe until the background process reports that
+//! the VM started, or why it failed to. The message is a status byte, 0 once the VM started or
+//! the exit code otherwise, followed by the error message.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Mutex;
+
+/// Status sent once the VM started.
+const READY: u8 = 0;
+
+/// Exit code of the foreground process when the background one stopped without a word.
+const NO_STATUS_EXIT_CODE: i32 = 1;
+
+/// Create a pipe, returning its read and write ends.
+fn pipe() -> io::Result<(File, File)> {
+    let mut fds = [0; 2];
+    // Safe because the array has room for both file descriptors, and the result is checked.
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // Safe because both file descriptors were just created, and are owned by nobody else.
+    Ok(unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) })
+}
+
+/// Reports to the foreground process whether the VM started, only the first report is sent.
+pub struct ReadyNotifier {
+    pipe: Mutex<Option<File>>,
+}
+
+impl ReadyNotifier {
+    fn new(pipe: File) -> Self {
+        ReadyNotifier {
+            pipe: Mutex::new(Some(pipe)),
+        }
+    }
+
+    /// The VM started, the foreground process exits with code 0.
+    pub fn ready(&self) -> io::Result<()> {
+        self.send(READY, "")
+    }
+
+    /// The VM did not start, the foreground process prints `message` and exits with `code`.
+    pub fn failed(&self, code: i32, message: &str) -> io::Result<()> {
+        self.send(code as u8, message)
+    }
+
+    fn send(&self, status: u8, message: &str) -> io::Result<()> {
+        // Closing the pipe once written lets the foreground process exit.
+        if let Some(mut pipe) = self.pipe.lock().unwrap().take() {
+            pipe.write_all(&[status])?;
+            pipe.write_all(message.as_bytes())?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Wait for the background process to report on `pipe`, returning the exit code of the
+/// foreground process and the error message.
+fn wait_ready<R: Read>(mut pipe: R) -> (i32, String) {
+    let mut report = Vec::new();
+    let _ = pipe.read_to_end(&mut report);
+
+    match report.split_first() {
+        Some((&READY, _)) => (0, String::new()),
+        Some((&code, message)) => (code as i32, String::from_utf8_lossy(message).into_owned()),
+        None => (
+            NO_STATUS_EXIT_CODE,
+            "the VMM stopped before the VM started".to_string(),
+        ),
+    }
+}
+
+/// Fork into the background, detached from the terminal, with stdout and stderr redirected to
+/// `log_path` (discarded if unset).
+///
+/// Only returns in the background process, the foreground one exits once it reported through
+/// the returned notifier. Must be called before any thread is spawned.
+pub fn daemonize(log_path: Option<&Path>) -> io::Result<ReadyNotifier> {
+    let log = match log_path {
+        Some(log_path) => OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(log_path)?,
+        None => OpenOptions::new().write(true).open("/dev/null")?,
+    };
+    let null = File::open("/dev/null")?;
+    let (reader, writer) = pipe()?;
+
+    // Safe because the process is still single-threaded.
+    match unsafe { libc::fork() } {
+        -1 => return Err(io::Error::last_os_error()),
+        0 => {}
+        _ => {
+            drop(writer);
+            let (code, message) = wait_ready(reader);
+            if !message.is_empty() {
+                eprintln!("Error: {}", message);
+            }
+            process::exit(code);
+        }
+    }
+    drop(reader);
+
+    // Safe because setsid and dup2 do not access memory, and the results are checked.
+    unsafe {
+        if libc::setsid() < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        for (file, fd) in [
+            (&null, libc::STDIN_FILENO),
+            (&log, libc::STDOUT_FILENO),
+            (&log, libc::STDERR_FILENO),
+        ]
+        .iter()
+        {
+            if libc::dup2(file.as_raw_fd(), *fd) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+    }
+
+    Ok(ReadyNotifier::new(writer))
+}
+
+/// File holding the VMM process ID, removed when dropped.
+pub struct Pidfile {
+    path: PathBuf,
+}
+
+impl Pidfile {
+    pub fn create(path: PathBuf) -> io::Result<Self> {
+        fs::write(&path, format!("{}\n", process::id()))?;
+
+        Ok(Pidfile { path })
+    }
+}
+
+impl Drop for Pidfile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    use vmm_sys_util::tempdir::TempDir;
+
+    #[test]
+    fn ready() {
+        let (reader, writer) = pipe().unwrap();
+        let notifier = ReadyNotifier::new(writer);
+
+        notifier.ready().unwrap();
+        // Only the first report is sent.
+        notifier.failed(2, "too late").unwrap();
+        assert_eq!(wait_ready(reader), (0, String::new()));
+    }
+
+    #[test]
+    fn failed() {
+        let (reader, writer) = pipe().unwrap();
+        let notifier = ReadyNotifier::new(writer);
+
+        notifier.failed(2, "config-error: BootProtocol").unwrap();
+        notifier.ready().unwrap();
+        assert_eq!(
+            wait_ready(reader),
+            (2, "config-error: BootProtocol".to_string())
+        );
+    }
+
+    #[test]
+    fn no_status() {
+        let (reader, writer) = pipe().unwrap();
+        drop(ReadyNotifier::new(writer));
+
+        let (code, message) = wait_ready(reader);
+        assert_eq!(code, NO_STATUS_EXIT_CODE);
+        assert!(!message.is_empty());
+    }
+
+    #[test]
+    fn pidfile() {
+        let dir = TempDir::new_with_prefix(env::temp_dir().join("lumper")).unwrap();
+        let path = dir.as_path().join("vm.pid");
+
+        let pidfile = Pidfile::create(path.clone()).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!("{}\n", process::id())
+        );
+
+        drop(pidfile);
+        assert!(!path.exists());
+
+        // The directory does not exist.
+        assert!(Pidfile::create(dir.as_path().join("run/vm.pid")).is_err());
+    }
+}
@@ -0,0 +1,160 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use std::io::Result;
+
+use vmm_sys_util::eventfd::EventFd;
+
+/// Base of the ACPI PM1a event and control blocks, as described by the FADT.
+pub const ACPI_PM_PORT_BASE: u16 = 0x600;
+pub const ACPI_PM_PORT_LAST_REGISTER: u16 = ACPI_PM_PORT_BASE + 0x5;
+
+/// Offset of the PM1a event block (status then enable registers) from the base port.
+pub const PM1_EVT_OFFSET: u16 = 0;
+pub const PM1_EVT_LEN: u8 = 4;
+/// Offset of the PM1a control register from the base port.
+pub const PM1_CNT_OFFSET: u16 = 4;
+pub const PM1_CNT_LEN: u8 = 2;
+
+/// Sleep type the guest writes to power the machine off, as advertised by the DSDT `\_S5` object.
+pub const SLP_TYP_S5: u16 = 5;
+
+// PM1 control register fields. See the ACPI specification, 4.8.3.2.1.
+const PM1_CNT_SCI_EN: u16 = 1 << 0;
+const PM1_CNT_SLP_TYP_SHIFT: u16 = 10;
+const PM1_CNT_SLP_TYP_MASK: u16 = 0x7 << PM1_CNT_SLP_TYP_SHIFT;
+const PM1_CNT_SLP_EN: u16 = 1 << 13;
+
+/// Minimal ACPI fixed hardware, through which the guest powers the machine off.
+///
+/// The machine is always in ACPI mode, and no fixed event is ever raised.
+pub(crate) struct AcpiPm {
+    status: u16,
+    enable: u16,
+    control: u16,
+    // Signaled when the guest enters the S5 (soft off) sleep state.
+    shutdown_evt: EventFd,
+}
+
+impl AcpiPm {
+    pub fn new() -> Result<Self> {
+        Ok(AcpiPm {
+            status: 0,
+            enable: 0,
+            control: PM1_CNT_SCI_EN,
+            shutdown_evt: EventFd::new(libc::EFD_NONBLOCK)?,
+        })
+    }
+
+    pub fn shutdown_eventfd(&self) -> Result<EventFd> {
+        self.shutdown_evt.try_clone()
+    }
+
+    /// Put the registers back in their power-on state.
+    pub fn reset(&mut self) {
+        self.status = 0;
+        self.enable = 0;
+        self.control = PM1_CNT_SCI_EN;
+    }
+
+    fn register(&mut self, offset: u16) -> Option<&mut u16> {
+        match offset / 2 {
+            0 => Some(&mut self.status),
+            1 => Some(&mut self.enable),
+            2 => Some(&mut self.control),
+            _ => None,
+        }
+    }
+
+    /// Read `data.len()` bytes starting at `offset` from the base port.
+    pub fn read(&mut self, offset: u16, data: &mut [u8]) {
+        for (i, byte) in data.iter_mut().enumerate() {
+            let offset = offset + i as u16;
+            *byte = self
+                .register(offset)
+                .map_or(0, |value| value.to_le_bytes()[(offset % 2) as usize]);
+        }
+    }
+
+    /// Write `data` starting at `offset` from the base port.
+    pub fn write(&mut self, offset: u16, data: &[u8]) {
+        for (i, byte) in data.iter().enumerate() {
+            let offset = offset + i as u16;
+            let shift = (offset % 2) * 8;
+            let value = u16::from(*byte) << shift;
+            let mask = 0xff << shift;
+
+            match offset / 2 {
+                // Status bits are cleared by writing ones.
+                0 => self.status &= !value,
+                1 => self.enable = (self.enable & !mask) | value,
+                2 => {
+                    // SLP_EN is write-only, and the guest can't leave ACPI mode.
+                    let control = (self.control & !mask) | value;
+                    self.control = (control & !PM1_CNT_SLP_EN) | PM1_CNT_SCI_EN;
+
+                    let slp_typ = (control & PM1_CNT_SLP_TYP_MASK) >> PM1_CNT_SLP_TYP_SHIFT;
+                    if control & PM1_CNT_SLP_EN != 0 && slp_typ == SLP_TYP_S5 {
+                        let _ = self.shutdown_evt.write(1);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sleep(slp_typ: u16) -> [u8; 2] {
+        (PM1_CNT_SLP_EN | slp_typ << PM1_CNT_SLP_TYP_SHIFT).to_le_bytes()
+    }
+
+    #[test]
+    fn soft_off() {
+        let mut pm = AcpiPm::new().unwrap();
+        let shutdown_evt = pm.shutdown_eventfd().unwrap();
+
+        // Other sleep states are not supported.
+        pm.write(PM1_CNT_OFFSET, &sleep(1));
+        assert!(shutdown_evt.read().is_err());
+
+        pm.write(PM1_CNT_OFFSET, &sleep(SLP_TYP_S5));
+        assert_eq!(shutdown_evt.read().unwrap(), 1);
+    }
+
+    #[test]
+    fn registers() {
+        let mut pm = AcpiPm::new().unwrap();
+        let mut data = [0u8; 2];
+
+        // The machine is in ACPI mode, and SLP_EN always reads as zero.
+        pm.write(PM1_CNT_OFFSET, &[0, 0]);
+        pm.read(PM1_CNT_OFFSET, &mut data);
+        assert_eq!(u16::from_le_bytes(data), PM1_CNT_SCI_EN);
+
+        // The enable register is plain storage, status bits are cleared by writing ones.
+        pm.write(PM1_EVT_OFFSET + 2, &[0x00, 0x01]);
+        pm.read(PM1_EVT_OFFSET + 2, &mut data);
+        assert_eq!(data, [0x00, 0x01]);
+
+        pm.status = 0x0101;
+        pm.write(PM1_EVT_OFFSET, &[0x01]);
+        pm.read(PM1_EVT_OFFSET, &mut data);
+        assert_eq!(data, [0x00, 0x01]);
+    }
+
+    #[test]
+    fn reset() {
+        let mut pm = AcpiPm::new().unwrap();
+        let mut data = [0u8; 2];
+
+        pm.write(PM1_EVT_OFFSET + 2, &[0xff, 0xff]);
+        pm.reset();
+        pm.read(PM1_EVT_OFFSET + 2, &mut data);
+        assert_eq!(u16::from_le_bytes(data), 0);
+        pm.read(PM1_CNT_OFFSET, &mut data);
+        assert_eq!(u16::from_le_bytes(data), PM1_CNT_SCI_EN);
+    }
+}
@@ -0,0 +1,122 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Boot completion notifications.
+//!
+//! The guest writes a magic value to the configured port once it is up, e.g. from an init script
+//! with port `0x440`:
+//!
+//! ```text
+//! printf '\173' | dd of=/dev/port bs=1 count=1 seek=$((0x440)) 2>/dev/null
+//! ```
+//!
+//! The VMM then reports the time the guest took to boot.
+
+use std::result;
+use std::time::{Duration, Instant};
+
+/// Value written by the guest once it booted.
+const BOOT_COMPLETE: u8 = 123;
+
+/// Parse an I/O port number, in decimal or in hexadecimal with a `0x` prefix.
+pub fn parse_port(s: &str) -> result::Result<u16, crate::Error> {
+    let port = match s.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+
+    port.map_err(|_| crate::Error::BootNotifierPort(s.to_string()))
+}
+
+pub(crate) struct BootNotifier {
+    port: u16,
+    // Time at which the VM started, or last rebooted.
+    start: Instant,
+    // Time the guest took to boot, once it notified it.
+    boot_time: Option<Duration>,
+}
+
+impl BootNotifier {
+    pub fn new(port: u16) -> Self {
+        BootNotifier {
+            port,
+            start: Instant::now(),
+            boot_time: None,
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// The VM starts, or reboots, at time `now`.
+    pub fn start(&mut self, now: Instant) {
+        self.start = now;
+        self.boot_time = None;
+    }
+
+    /// Handle a guest write of `value` at time `now`.
+    ///
+    /// Returns the time the guest took to boot, the first time it notifies it.
+    pub fn write(&mut self, value: u8, now: Instant) -> Option<Duration> {
+        if value != BOOT_COMPLETE || self.boot_time.is_some() {
+            return None;
+        }
+
+        self.boot_time = Some(now.saturating_duration_since(self.start));
+        self.boot_time
+    }
+
+    /// Time the guest took to boot, if it notified it.
+    pub fn boot_time(&self) -> Option<Duration> {
+        self.boot_time
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn port_numbers() {
+        assert_eq!(parse_port("0x440").unwrap(), 0x440);
+        assert_eq!(parse_port("1088").unwrap(), 0x440);
+
+        for port in ["0x10000", "-1", "0x", "port"].iter() {
+            assert!(matches!(
+                parse_port(port),
+                Err(crate::Error::BootNotifierPort(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn boot_complete() {
+        let start = Instant::now();
+        let mut notifier = BootNotifier::new(0x440);
+        notifier.start(start);
+
+        // Other values are ignored.
+        assert_eq!(notifier.write(0, start + Duration::from_millis(10)), None);
+        assert_eq!(notifier.boot_time(), None);
+
+        let boot_time = Duration::from_millis(150);
+        assert_eq!(
+            notifier.write(BOOT_COMPLETE, start + boot_time),
+            Some(boot_time)
+        );
+        assert_eq!(notifier.boot_time(), Some(boot_time));
+
+        // Only the first notification is reported.
+        assert_eq!(notifier.write(BOOT_COMPLETE, start + boot_time * 2), None);
+        assert_eq!(notifier.boot_time(), Some(boot_time));
+
+        // The boot time is measured again after a reboot.
+        let reboot = start + Duration::from_secs(10);
+        notifier.start(reboot);
+        assert_eq!(notifier.boot_time(), None);
+        assert_eq!(
+            notifier.write(BOOT_COMPLETE, reboot + boot_time),
+            Some(boot_time)
+        );
+    }
+}
@@ -0,0 +1,259 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use std::mem;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const CMOS_PORT_BASE: u16 = 0x70;
+pub const CMOS_PORT_LAST_REGISTER: u16 = CMOS_PORT_BASE + 0x1;
+
+// Offsets of the index and data ports from the base port.
+const INDEX_OFFSET: u8 = 0;
+const DATA_OFFSET: u8 = 1;
+
+// The index port also holds the NMI disable bit.
+const INDEX_MASK: u8 = 0x7f;
+const CMOS_SIZE: usize = 128;
+
+// RTC registers. See the MC146818 datasheet.
+const RTC_SECONDS: u8 = 0x00;
+const RTC_MINUTES: u8 = 0x02;
+const RTC_HOURS: u8 = 0x04;
+const RTC_DAY_OF_WEEK: u8 = 0x06;
+const RTC_DAY_OF_MONTH: u8 = 0x07;
+const RTC_MONTH: u8 = 0x08;
+const RTC_YEAR: u8 = 0x09;
+const RTC_STATUS_A: u8 = 0x0a;
+const RTC_STATUS_B: u8 = 0x0b;
+const RTC_STATUS_C: u8 = 0x0c;
+const RTC_STATUS_D: u8 = 0x0d;
+/// CMOS register holding the century, as advertised by the FADT.
+pub const RTC_CENTURY: u8 = 0x32;
+
+// Status A: 32.768kHz time base, 1.024kHz periodic rate. The update in progress bit is never set.
+const STATUS_A_DEFAULT: u8 = 0x26;
+// Status B: 24 hour mode, BCD values.
+const STATUS_B_DEFAULT: u8 = 0x02;
+const STATUS_B_24H: u8 = 0x02;
+const STATUS_B_BINARY: u8 = 0x04;
+// Status D: the RTC has power, its time is valid.
+const STATUS_D_VALID: u8 = 0x80;
+// Set in the hours register in 12 hour mode, after noon.
+const HOURS_PM: u8 = 0x80;
+
+fn host_time() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs() as i64)
+}
+
+fn to_bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
+
+/// Minimal MC146818 RTC and CMOS memory, serving the host wall-clock time.
+///
+/// The time can't be set by the guest, writes to the time registers are ignored.
+pub(crate) struct Cmos {
+    index: u8,
+    data: [u8; CMOS_SIZE],
+    // Seconds since the epoch.
+    clock: fn() -> i64,
+}
+
+impl Cmos {
+    pub fn new() -> Self {
+        Cmos::with_clock(host_time)
+    }
+
+    fn with_clock(clock: fn() -> i64) -> Self {
+        let mut data = [0u8; CMOS_SIZE];
+        data[RTC_STATUS_A as usize] = STATUS_A_DEFAULT;
+        data[RTC_STATUS_B as usize] = STATUS_B_DEFAULT;
+        data[RTC_STATUS_D as usize] = STATUS_D_VALID;
+
+        Cmos {
+            index: 0,
+            data,
+            clock,
+        }
+    }
+
+    /// Put the RTC and CMOS memory back in their power-on state.
+    pub fn reset(&mut self) {
+        *self = Cmos::with_clock(self.clock);
+    }
+
+    /// Encode `value` following the mode selected by the status B register.
+    fn encode(&self, value: u8) -> u8 {
+        if self.data[RTC_STATUS_B as usize] & STATUS_B_BINARY != 0 {
+            value
+        } else {
+            to_bcd(value)
+        }
+    }
+
+    fn encode_hours(&self, hours: u8) -> u8 {
+        if self.data[RTC_STATUS_B as usize] & STATUS_B_24H != 0 {
+            return self.encode(hours);
+        }
+
+        let pm = if hours >= 12 { HOURS_PM } else { 0 };
+        let hours = match hours % 12 {
+            0 => 12,
+            hours => hours,
+        };
+        self.encode(hours) | pm
+    }
+
+    fn read_time(&self, register: u8) -> u8 {
+        let time = (self.clock)() as libc::time_t;
+        // Safe because tm is plain data, and gmtime_r only writes to it.
+        let mut tm: libc::tm = unsafe { mem::zeroed() };
+        unsafe { libc::gmtime_r(&time, &mut tm) };
+
+        let year = tm.tm_year + 1900;
+        match register {
+            RTC_SECONDS => self.encode(tm.tm_sec as u8),
+            RTC_MINUTES => self.encode(tm.tm_min as u8),
+            RTC_HOURS => self.encode_hours(tm.tm_hour as u8),
+            // Sunday is 1.
+            RTC_DAY_OF_WEEK => self.encode(tm.tm_wday as u8 + 1),
+            RTC_DAY_OF_MONTH => self.encode(tm.tm_mday as u8),
+            RTC_MONTH => self.encode(tm.tm_mon as u8 + 1),
+            RTC_YEAR => self.encode((year % 100) as u8),
+            _ => self.encode((year / 100) as u8),
+        }
+    }
+
+    pub fn read(&mut self, offset: u8) -> u8 {
+        if offset != DATA_OFFSET {
+            return self.index;
+        }
+
+        match self.index {
+            RTC_SECONDS | RTC_MINUTES | RTC_HOURS | RTC_DAY_OF_WEEK | RTC_DAY_OF_MONTH
+            | RTC_MONTH | RTC_YEAR | RTC_CENTURY => self.read_time(self.index),
+            // No interrupt is ever pending, reading status C acknowledges them anyway.
+            RTC_STATUS_C => 0,
+            index => self.data[index as usize],
+        }
+    }
+
+    pub fn write(&mut self, offset: u8, value: u8) {
+        match offset {
+            INDEX_OFFSET => self.index = value & INDEX_MASK,
+            DATA_OFFSET => match self.index {
+                RTC_SECONDS | RTC_MINUTES | RTC_HOURS | RTC_DAY_OF_WEEK | RTC_DAY_OF_MONTH
+                | RTC_MONTH | RTC_YEAR | RTC_CENTURY | RTC_STATUS_A | RTC_STATUS_C
+                | RTC_STATUS_D => {}
+                index => self.data[index as usize] = value,
+            },
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2021-11-07 15:04:05 UTC, a Sunday.
+    fn fixed_clock() -> i64 {
+        1_636_297_445
+    }
+
+    fn read_register(cmos: &mut Cmos, register: u8) -> u8 {
+        cmos.write(INDEX_OFFSET, register);
+        cmos.read(DATA_OFFSET)
+    }
+
+    fn from_bcd(value: u8) -> u8 {
+        (value >> 4) * 10 + (value & 0xf)
+    }
+
+    #[test]
+    fn bcd_time() {
+        let mut cmos = Cmos::with_clock(fixed_clock);
+
+        assert_eq!(read_register(&mut cmos, RTC_SECONDS), 0x05);
+        assert_eq!(read_register(&mut cmos, RTC_MINUTES), 0x04);
+        assert_eq!(read_register(&mut cmos, RTC_HOURS), 0x15);
+        assert_eq!(read_register(&mut cmos, RTC_DAY_OF_WEEK), 0x01);
+        assert_eq!(read_register(&mut cmos, RTC_DAY_OF_MONTH), 0x07);
+        assert_eq!(read_register(&mut cmos, RTC_MONTH), 0x11);
+        assert_eq!(read_register(&mut cmos, RTC_YEAR), 0x21);
+        assert_eq!(read_register(&mut cmos, RTC_CENTURY), 0x20);
+    }
+
+    #[test]
+    fn binary_and_12h_time() {
+        let mut cmos = Cmos::with_clock(fixed_clock);
+
+        cmos.write(INDEX_OFFSET, RTC_STATUS_B);
+        cmos.write(DATA_OFFSET, STATUS_B_BINARY);
+
+        assert_eq!(read_register(&mut cmos, RTC_MINUTES), 4);
+        assert_eq!(read_register(&mut cmos, RTC_HOURS), HOURS_PM | 3);
+        assert_eq!(read_register(&mut cmos, RTC_MONTH), 11);
+        assert_eq!(read_register(&mut cmos, RTC_YEAR), 21);
+    }
+
+    #[test]
+    fn status_registers() {
+        let mut cmos = Cmos::with_clock(fixed_clock);
+
+        // Time can always be read, the update in progress bit is never set.
+        assert_eq!(read_register(&mut cmos, RTC_STATUS_A) & 0x80, 0);
+        assert_eq!(read_register(&mut cmos, RTC_STATUS_D), STATUS_D_VALID);
+
+        // The NMI disable bit is not part of the index.
+        cmos.write(INDEX_OFFSET, 0x80 | RTC_STATUS_B);
+        assert_eq!(cmos.read(DATA_OFFSET), STATUS_B_DEFAULT);
+
+        // Time registers are read-only, CMOS memory is not.
+        cmos.write(INDEX_OFFSET, RTC_MINUTES);
+        cmos.write(DATA_OFFSET, 0x42);
+        assert_eq!(cmos.read(DATA_OFFSET), 0x04);
+        cmos.write(INDEX_OFFSET, 0x40);
+        cmos.write(DATA_OFFSET, 0x42);
+        assert_eq!(cmos.read(DATA_OFFSET), 0x42);
+    }
+
+    #[test]
+    fn reset() {
+        let mut cmos = Cmos::with_clock(fixed_clock);
+
+        cmos.write(INDEX_OFFSET, RTC_STATUS_B);
+        cmos.write(DATA_OFFSET, STATUS_B_BINARY);
+        cmos.write(INDEX_OFFSET, 0x40);
+        cmos.write(DATA_OFFSET, 0x42);
+
+        cmos.reset();
+        assert_eq!(cmos.read(INDEX_OFFSET), 0);
+        assert_eq!(read_register(&mut cmos, RTC_STATUS_B), STATUS_B_DEFAULT);
+        assert_eq!(read_register(&mut cmos, 0x40), 0);
+        assert_eq!(read_register(&mut cmos, RTC_SECONDS), 0x05);
+    }
+
+    #[test]
+    fn host_date() {
+        let mut cmos = Cmos::new();
+        let now = host_time() as libc::time_t;
+        let mut tm: libc::tm = unsafe { mem::zeroed() };
+        unsafe { libc::gmtime_r(&now, &mut tm) };
+
+        let year = from_bcd(read_register(&mut cmos, RTC_CENTURY)) as i32 * 100
+            + from_bcd(read_register(&mut cmos, RTC_YEAR)) as i32;
+        let month = from_bcd(read_register(&mut cmos, RTC_MONTH)) as i32;
+        let day = from_bcd(read_register(&mut cmos, RTC_DAY_OF_MONTH)) as i32;
+
+        // Tolerate the test running across midnight.
+        let date = (year, month, day);
+        assert!(year >= 2021);
+        if date != (tm.tm_year + 1900, tm.tm_mon + 1, tm.tm_mday) {
+            let next = now + 60;
+            unsafe { libc::gmtime_r(&next, &mut tm) };
+            assert_eq!(date, (tm.tm_year + 1900, tm.tm_mon + 1, tm.tm_mday));
+        }
+    }
+}
@@ -0,0 +1,68 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use std::io::Result;
+
+use vm_superio::I8042Device;
+use vmm_sys_util::eventfd::EventFd;
+
+use crate::devices::serial::EventFdTrigger;
+
+pub const I8042_PORT_BASE: u16 = 0x60;
+pub const I8042_PORT_LAST_REGISTER: u16 = I8042_PORT_BASE + 0x4;
+
+pub(crate) struct LumperI8042 {
+    // reset_evt is signaled when the guest asks the controller to reset the CPU.
+    reset_evt: EventFdTrigger,
+
+    // i8042 is the actual keyboard controller, only used for its reset line.
+    pub i8042: I8042Device<EventFdTrigger>,
+}
+
+impl LumperI8042 {
+    pub fn new() -> Result<Self> {
+        let reset_evt = EventFdTrigger::new(libc::EFD_NONBLOCK)?;
+
+        Ok(LumperI8042 {
+            reset_evt: reset_evt.try_clone()?,
+            i8042: I8042Device::new(reset_evt),
+        })
+    }
+
+    pub fn reset_eventfd(&self) -> Result<EventFd> {
+        (*self.reset_evt).try_clone()
+    }
+
+    /// Put the controller back in its power-on state, keeping its reset line.
+    pub fn reset(&mut self) -> Result<()> {
+        self.i8042 = I8042Device::new(self.reset_evt.try_clone()?);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Offset of the command register from the base port.
+    const COMMAND_OFFSET: u8 = (I8042_PORT_LAST_REGISTER - I8042_PORT_BASE) as u8;
+    // Pulse the CPU reset line.
+    const CMD_RESET_CPU: u8 = 0xfe;
+
+    #[test]
+    fn reset_command() {
+        let mut i8042 = LumperI8042::new().unwrap();
+        let reset_evt = i8042.reset_eventfd().unwrap();
+
+        // Nothing is pending before the guest sends the command.
+        assert!(reset_evt.read().is_err());
+
+        i8042.i8042.write(COMMAND_OFFSET, CMD_RESET_CPU).unwrap();
+        assert_eq!(reset_evt.read().unwrap(), 1);
+
+        // The reset line is kept across resets.
+        i8042.reset().unwrap();
+        i8042.i8042.write(COMMAND_OFFSET, CMD_RESET_CPU).unwrap();
+        assert_eq!(reset_evt.read().unwrap(), 1);
+    }
+}
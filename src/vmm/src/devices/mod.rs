@@ -1,3 +1,152 @@
 // SPDX-License-Identifier: Apache-2.0
 
+use std::io::{self, ErrorKind};
+use std::{result, thread};
+
+use acpi_pm::{ACPI_PM_PORT_BASE, ACPI_PM_PORT_LAST_REGISTER};
+use cmos::{CMOS_PORT_BASE, CMOS_PORT_LAST_REGISTER};
+use i8042::{I8042_PORT_BASE, I8042_PORT_LAST_REGISTER};
+use pvpanic::PVPANIC_PORT;
+use serial::{SERIAL_IRQ, SERIAL_PORT_BASE, SERIAL_PORT_LAST_REGISTER};
+use watchdog::{WATCHDOG_START_PORT, WATCHDOG_STOP_PORT};
+
+pub(crate) mod acpi_pm;
+pub(crate) mod boot_notifier;
+pub(crate) mod cmos;
+pub(crate) mod i8042;
+pub(crate) mod pvpanic;
 pub(crate) mod serial;
+pub(crate) mod shmem;
+pub(crate) mod tcp_console;
+pub(crate) mod watchdog;
+
+/// Errors raised by devices while handling a guest access.
+#[derive(Debug)]
+pub enum Error {
+    /// Serial console failure.
+    Serial(vm_superio::serial::Error<io::Error>),
+    /// i8042 controller failure.
+    I8042(vm_superio::i8042::Error<io::Error>),
+}
+
+/// Dedicated Result type.
+pub type Result<T> = result::Result<T, Error>;
+
+impl Error {
+    /// Whether the access may succeed if attempted again.
+    pub fn is_recoverable(&self) -> bool {
+        let e = match self {
+            Error::Serial(vm_superio::serial::Error::Trigger(e))
+            | Error::Serial(vm_superio::serial::Error::IOError(e))
+            | Error::I8042(vm_superio::i8042::Error::Trigger(e)) => e,
+            _ => return false,
+        };
+
+        matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::Interrupted)
+    }
+}
+
+/// Whether `port` belongs to one of the devices at fixed ports.
+pub(crate) fn port_in_use(port: u16) -> bool {
+    [
+        (SERIAL_PORT_BASE, SERIAL_PORT_LAST_REGISTER),
+        (I8042_PORT_BASE, I8042_PORT_LAST_REGISTER),
+        (CMOS_PORT_BASE, CMOS_PORT_LAST_REGISTER),
+        (ACPI_PM_PORT_BASE, ACPI_PM_PORT_LAST_REGISTER),
+        (PVPANIC_PORT, PVPANIC_PORT),
+        (WATCHDOG_STOP_PORT, WATCHDOG_START_PORT),
+    ]
+    .iter()
+    .any(|(first, last)| (*first..=*last).contains(&port))
+}
+
+/// Whether `irq` is raised by one of the devices at fixed interrupts.
+pub(crate) fn irq_in_use(irq: u32) -> bool {
+    irq == SERIAL_IRQ
+}
+
+/// Perform a device access, retrying it as long as it fails with a transient error.
+pub(crate) fn retry<T>(mut access: impl FnMut() -> Result<T>) -> Result<T> {
+    loop {
+        match access() {
+            Err(e) if e.is_recoverable() => thread::yield_now(),
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    use serial::LumperSerial;
+
+    // Fails with `kind` the `failures` first times it is written to.
+    struct FailingWriter {
+        kind: ErrorKind,
+        failures: usize,
+    }
+
+    impl Write for FailingWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err(io::Error::from(self.kind));
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn serial_write(serial: &mut LumperSerial) -> Result<()> {
+        serial.serial.write(0, b'x').map_err(Error::Serial)
+    }
+
+    #[test]
+    fn ports_in_use() {
+        assert!(port_in_use(SERIAL_PORT_BASE));
+        assert!(port_in_use(SERIAL_PORT_LAST_REGISTER));
+        assert!(port_in_use(PVPANIC_PORT));
+        assert!(port_in_use(WATCHDOG_STOP_PORT + 1));
+
+        assert!(!port_in_use(SERIAL_PORT_BASE - 1));
+        assert!(!port_in_use(0x440));
+    }
+
+    #[test]
+    fn irqs_in_use() {
+        assert!(irq_in_use(SERIAL_IRQ));
+        assert!(!irq_in_use(shmem::SHMEM_IRQ));
+    }
+
+    #[test]
+    fn transient_errors_are_retried() {
+        let output = FailingWriter {
+            kind: ErrorKind::WouldBlock,
+            failures: 3,
+        };
+        let mut serial = LumperSerial::new(Box::new(output)).unwrap();
+
+        assert!(retry(|| serial_write(&mut serial)).is_ok());
+    }
+
+    #[test]
+    fn fatal_errors_are_reported() {
+        let output = FailingWriter {
+            kind: ErrorKind::BrokenPipe,
+            failures: 1,
+        };
+        let mut serial = LumperSerial::new(Box::new(output)).unwrap();
+
+        let error = retry(|| serial_write(&mut serial)).unwrap_err();
+        assert!(!error.is_recoverable());
+        assert!(matches!(
+            error,
+            Error::Serial(vm_superio::serial::Error::IOError(_))
+        ));
+    }
+}
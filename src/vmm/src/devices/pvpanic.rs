@@ -0,0 +1,39 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Guest crash notifications, through the QEMU pvpanic ISA device interface.
+//!
+//! The device has a single register: reading it returns the supported events, and the guest
+//! writes the event it reports.
+
+/// I/O port of the pvpanic register, as described by the DSDT.
+pub const PVPANIC_PORT: u16 = 0x505;
+
+// Events. See QEMU docs/specs/pvpanic.txt.
+const PVPANIC_PANICKED: u8 = 1 << 0;
+
+/// Supported events.
+///
+/// Crash kernel loads are not advertised, so that a guest booting one does not report it.
+pub fn read() -> u8 {
+    PVPANIC_PANICKED
+}
+
+/// Whether the event written by the guest reports a panic.
+pub fn write(value: u8) -> bool {
+    value & PVPANIC_PANICKED != 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn panic_events() {
+        assert_eq!(read(), PVPANIC_PANICKED);
+
+        assert!(write(PVPANIC_PANICKED));
+        // Crash kernel loaded.
+        assert!(!write(1 << 1));
+        assert!(!write(0));
+    }
+}
@@ -1,7 +1,12 @@
 // SPDX-License-Identifier: Apache-2.0
 
+use std::collections::VecDeque;
 use std::io::{Error, Result, Write};
 use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use vm_superio::serial::NoEvents;
 use vm_superio::{Serial, Trigger};
@@ -9,6 +14,17 @@ use vmm_sys_util::eventfd::EventFd;
 
 pub const SERIAL_PORT_BASE: u16 = 0x3f8;
 pub const SERIAL_PORT_LAST_REGISTER: u16 = SERIAL_PORT_BASE + 0x8;
+/// Interrupt raised by the serial device, the COM1 one.
+pub const SERIAL_IRQ: u32 = 4;
+
+/// Number of bytes of guest console output kept for diagnostics.
+pub const SERIAL_CAPTURE_SIZE: usize = 64 << 10;
+
+/// Default size of the buffer between the serial device and its output.
+pub const SERIAL_OUTPUT_BUFFER_SIZE: usize = 64 << 10;
+
+/// Longest time spent writing buffered output out when the VM stops.
+const SERIAL_FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
 
 pub struct EventFdTrigger(EventFd);
 
@@ -36,25 +52,620 @@ impl EventFdTrigger {
     }
 }
 
+/// Fixed-size buffer holding the most recent bytes written by the guest.
+pub(crate) struct SerialCapture {
+    buffer: VecDeque<u8>,
+    capacity: usize,
+}
+
+impl SerialCapture {
+    pub fn new(capacity: usize) -> Self {
+        SerialCapture {
+            buffer: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Append `data`, dropping the oldest bytes once the buffer is full.
+    ///
+    /// Returns the number of bytes dropped.
+    pub fn push(&mut self, data: &[u8]) -> usize {
+        let truncated = data.len().saturating_sub(self.capacity);
+        let data = &data[truncated..];
+        let overflow = (self.buffer.len() + data.len()).saturating_sub(self.capacity);
+        self.buffer.drain(..overflow);
+        self.buffer.extend(data);
+
+        truncated + overflow
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    /// Remove and return all the captured output.
+    pub fn take(&mut self) -> Vec<u8> {
+        self.buffer.drain(..).collect()
+    }
+
+    /// Return at most the `len` last bytes of captured output.
+    ///
+    /// The output is cut on a character boundary, invalid UTF-8 sequences are replaced.
+    pub fn tail(&self, len: usize) -> String {
+        let start = self.buffer.len().saturating_sub(len);
+        let bytes: Vec<u8> = self
+            .buffer
+            .iter()
+            .skip(start)
+            // Continuation bytes of a character cut by the start of the tail.
+            .skip_while(|byte| *byte & 0xc0 == 0x80)
+            .copied()
+            .collect();
+
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+
+    /// Return at most the `count` last lines of captured output.
+    pub fn last_lines(&self, count: usize) -> String {
+        let bytes: Vec<u8> = self.buffer.iter().copied().collect();
+        let text = String::from_utf8_lossy(&bytes);
+        let lines: Vec<&str> = text.lines().collect();
+
+        lines[lines.len().saturating_sub(count)..].join("\n")
+    }
+}
+
+/// Looks for a string in a stream of bytes, which may be split across several writes.
+pub(crate) struct StringMatcher {
+    pattern: Vec<u8>,
+    // Trailing bytes of the previous chunks, in case the pattern straddles two writes.
+    tail: Vec<u8>,
+}
+
+impl StringMatcher {
+    pub fn new(pattern: &str) -> Self {
+        StringMatcher {
+            pattern: pattern.as_bytes().to_vec(),
+            tail: Vec::new(),
+        }
+    }
+
+    /// Feed the next chunk of output, returns `true` once the pattern has been seen.
+    pub fn feed(&mut self, data: &[u8]) -> bool {
+        if self.pattern.is_empty() {
+            return true;
+        }
+
+        self.tail.extend_from_slice(data);
+        let found = self
+            .tail
+            .windows(self.pattern.len())
+            .any(|window| window == self.pattern.as_slice());
+
+        let keep = self.pattern.len() - 1;
+        let start = self.tail.len().saturating_sub(keep);
+        self.tail.drain(..start);
+
+        found
+    }
+}
+
+/// Serial output sink recording everything written by the guest before forwarding it.
+///
+/// The guest output is recorded and matched as soon as the guest writes it, whether or not it
+/// makes it to the output.
+pub(crate) struct CaptureWriter {
+    output: Box<dyn Write + Send>,
+    capture: Arc<Mutex<SerialCapture>>,
+    // Each signaled once its string has been printed by the guest.
+    matchers: Vec<(StringMatcher, EventFd)>,
+}
+
+impl CaptureWriter {
+    pub fn new(
+        output: Box<dyn Write + Send>,
+        capture: Arc<Mutex<SerialCapture>>,
+        matchers: Vec<(StringMatcher, EventFd)>,
+    ) -> Self {
+        CaptureWriter {
+            output,
+            capture,
+            matchers,
+        }
+    }
+}
+
+impl Write for CaptureWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.capture.lock().unwrap().push(buf);
+
+        let mut i = 0;
+        while i < self.matchers.len() {
+            if self.matchers[i].0.feed(buf) {
+                let (_, evt) = self.matchers.swap_remove(i);
+                evt.write(1)?;
+            } else {
+                i += 1;
+            }
+        }
+
+        self.output.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.output.flush()
+    }
+}
+
+struct OutputBufferState {
+    data: SerialCapture,
+    // The drain thread is writing data out.
+    writing: bool,
+    // The serial device is gone, the drain thread stops once the data is written out.
+    closed: bool,
+}
+
+/// Output buffered between the serial device and its sink, so that a slow sink never
+/// blocks the vCPU writing to the device. The oldest bytes are dropped when it is full.
+pub(crate) struct OutputBuffer {
+    state: Mutex<OutputBufferState>,
+    // Signaled when data is pushed, and when data has been written out.
+    changed: Condvar,
+    // Bytes lost either because the buffer was full, or the sink failed.
+    dropped: AtomicU64,
+}
+
+impl OutputBuffer {
+    /// Create a buffer of `capacity` bytes, written out to `sink` by a dedicated thread.
+    pub fn spawn(mut sink: Box<dyn Write + Send>, capacity: usize) -> Result<Arc<Self>> {
+        let buffer = Arc::new(OutputBuffer {
+            state: Mutex::new(OutputBufferState {
+                data: SerialCapture::new(capacity),
+                writing: false,
+                closed: false,
+            }),
+            changed: Condvar::new(),
+            dropped: AtomicU64::new(0),
+        });
+
+        let drained = buffer.clone();
+        thread::Builder::new()
+            .name("serial-output".to_string())
+            .spawn(move || drained.drain(sink.as_mut()))?;
+
+        Ok(buffer)
+    }
+
+    fn drain(&self, sink: &mut dyn Write) {
+        loop {
+            let data = {
+                let mut state = self.state.lock().unwrap();
+                while state.data.is_empty() && !state.closed {
+                    state = self.changed.wait(state).unwrap();
+                }
+                if state.data.is_empty() {
+                    return;
+                }
+
+                state.writing = true;
+                state.data.take()
+            };
+
+            if sink.write_all(&data).and_then(|_| sink.flush()).is_err() {
+                self.dropped.fetch_add(data.len() as u64, Ordering::Relaxed);
+            }
+
+            self.state.lock().unwrap().writing = false;
+            self.changed.notify_all();
+        }
+    }
+
+    /// Wait for the buffered data to be written out, for at most `timeout`.
+    pub fn flush(&self, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.state.lock().unwrap();
+
+        while !state.data.is_empty() || state.writing {
+            let now = Instant::now();
+            if now >= deadline {
+                return;
+            }
+            state = self.changed.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// Serial output sink filling an [`OutputBuffer`].
+pub(crate) struct BufferedWriter(Arc<OutputBuffer>);
+
+impl Write for BufferedWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let dropped = self.0.state.lock().unwrap().data.push(buf);
+        self.0.dropped.fetch_add(dropped as u64, Ordering::Relaxed);
+        self.0.changed.notify_all();
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        // Writing out is the drain thread's job, the device must not wait for it.
+        Ok(())
+    }
+}
+
+impl Drop for BufferedWriter {
+    fn drop(&mut self) {
+        self.0.state.lock().unwrap().closed = true;
+        self.0.changed.notify_all();
+    }
+}
+
+/// Output shared by the serial device and the one replacing it when the VM reboots.
+#[derive(Clone)]
+struct SharedOutput(Arc<Mutex<Box<dyn Write + Send>>>);
+
+impl Write for SharedOutput {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+}
+
 pub(crate) struct LumperSerial {
     // evenfd allows for the device to send interrupts to the guest.
     eventfd: EventFdTrigger,
+    output: SharedOutput,
 
     // serial is the actual serial device.
     pub serial: Serial<EventFdTrigger, NoEvents, Box<dyn Write + Send>>,
+
+    // Buffer between the device and its output, if any.
+    output_buffer: Option<Arc<OutputBuffer>>,
 }
 
 impl LumperSerial {
     pub fn new(output: Box<dyn Write + Send>) -> Result<Self> {
         let eventfd = EventFdTrigger::new(libc::EFD_NONBLOCK).unwrap();
+        let output = SharedOutput(Arc::new(Mutex::new(output)));
 
         Ok(LumperSerial {
             eventfd: eventfd.try_clone()?,
-            serial: Serial::new(eventfd.try_clone()?, Box::new(output)),
+            serial: Serial::new(eventfd.try_clone()?, Box::new(output.clone())),
+            output,
+            output_buffer: None,
         })
     }
 
+    /// Put the UART back in its power-on state, keeping its output and interrupt.
+    pub fn reset(&mut self) -> Result<()> {
+        self.serial = Serial::new(self.eventfd.try_clone()?, Box::new(self.output.clone()));
+
+        Ok(())
+    }
+
+    /// Create a serial device writing to `output` through a buffer of `buffer_size` bytes,
+    /// recording the guest output to `capture` and signaling `matchers` before it is buffered.
+    ///
+    /// A size of 0 disables the buffer, guest writes then wait for `output`.
+    pub fn buffered(
+        output: Box<dyn Write + Send>,
+        buffer_size: usize,
+        capture: Arc<Mutex<SerialCapture>>,
+        matchers: Vec<(StringMatcher, EventFd)>,
+    ) -> Result<Self> {
+        if buffer_size == 0 {
+            return LumperSerial::new(Box::new(CaptureWriter::new(output, capture, matchers)));
+        }
+
+        let output_buffer = OutputBuffer::spawn(output, buffer_size)?;
+        let output = BufferedWriter(output_buffer.clone());
+        let mut serial = LumperSerial::new(Box::new(CaptureWriter::new(
+            Box::new(output),
+            capture,
+            matchers,
+        )))?;
+        serial.output_buffer = Some(output_buffer);
+
+        Ok(serial)
+    }
+
     pub fn eventfd(&self) -> Result<EventFd> {
         Ok(self.eventfd.try_clone()?.0)
     }
+
+    /// Write out the buffered output, before the VM stops.
+    pub fn flush_output(&self) {
+        if let Some(output_buffer) = self.output_buffer.as_ref() {
+            output_buffer.flush(SERIAL_FLUSH_TIMEOUT);
+        }
+    }
+
+    /// Number of output bytes lost because the output could not keep up.
+    pub fn dropped_bytes(&self) -> u64 {
+        self.output_buffer
+            .as_ref()
+            .map_or(0, |output_buffer| output_buffer.dropped())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capture_keeps_last_bytes() {
+        let mut capture = SerialCapture::new(8);
+
+        capture.push(b"abcd");
+        capture.push(b"efghij");
+        assert_eq!(capture.last_lines(10), "cdefghij");
+
+        capture.push(b"0123456789");
+        assert_eq!(capture.last_lines(10), "23456789");
+    }
+
+    #[test]
+    fn capture_last_lines() {
+        let mut capture = SerialCapture::new(64);
+
+        capture.push(b"one\ntwo\nthree\nfour\n");
+        assert_eq!(capture.last_lines(2), "three\nfour");
+        assert_eq!(capture.last_lines(10), "one\ntwo\nthree\nfour");
+        assert_eq!(capture.last_lines(0), "");
+    }
+
+    #[test]
+    fn capture_tail() {
+        let mut capture = SerialCapture::new(8);
+
+        capture.push(b"abcdef");
+        assert_eq!(capture.tail(4), "cdef");
+        assert_eq!(capture.tail(100), "abcdef");
+
+        // The tail follows the buffer as it wraps around.
+        capture.push(b"ghijkl");
+        assert_eq!(capture.tail(8), "efghijkl");
+        assert_eq!(capture.tail(0), "");
+    }
+
+    #[test]
+    fn capture_tail_utf8() {
+        let mut capture = SerialCapture::new(64);
+
+        // "é" is two bytes long, and "€" three.
+        capture.push("aé€".as_bytes());
+        assert_eq!(capture.tail(6), "aé€");
+        assert_eq!(capture.tail(5), "é€");
+        // Characters cut by the start of the tail are left out.
+        assert_eq!(capture.tail(4), "€");
+        assert_eq!(capture.tail(2), "");
+
+        // Characters cut by the start of the buffer as well.
+        let mut capture = SerialCapture::new(4);
+        capture.push("€ok".as_bytes());
+        assert_eq!(capture.tail(4), "ok");
+
+        // Invalid sequences from the guest are replaced.
+        capture.push(b"\xff");
+        assert_eq!(capture.tail(4), "ok\u{fffd}");
+    }
+
+    #[test]
+    fn capture_take() {
+        let mut capture = SerialCapture::new(4);
+
+        capture.push(b"abcdef");
+        assert_eq!(capture.take(), b"cdef");
+        assert!(capture.take().is_empty());
+    }
+
+    // Sink blocking every write until it is released.
+    struct BlockedWriter {
+        output: Arc<Mutex<Vec<u8>>>,
+        release: Arc<(Mutex<bool>, Condvar)>,
+    }
+
+    impl Write for BlockedWriter {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            let (released, cond) = &*self.release;
+            let mut released = released.lock().unwrap();
+            while !*released {
+                released = cond.wait(released).unwrap();
+            }
+
+            self.output.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn capture_push_dropped() {
+        let mut capture = SerialCapture::new(4);
+
+        assert_eq!(capture.push(b"abc"), 0);
+        assert_eq!(capture.push(b"de"), 1);
+        assert_eq!(capture.push(b"0123456"), 7);
+        assert_eq!(capture.take(), b"3456");
+    }
+
+    #[test]
+    fn buffered_output_never_blocks() {
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let release = Arc::new((Mutex::new(false), Condvar::new()));
+        let sink = BlockedWriter {
+            output: output.clone(),
+            release: release.clone(),
+        };
+
+        let capture = Arc::new(Mutex::new(SerialCapture::new(SERIAL_CAPTURE_SIZE)));
+        let mut serial = LumperSerial::buffered(Box::new(sink), 4, capture, Vec::new()).unwrap();
+
+        // The sink is stuck, the guest keeps writing and the oldest bytes get dropped.
+        for byte in b"abcdefghij" {
+            serial.serial.write(0, *byte).unwrap();
+        }
+
+        *release.0.lock().unwrap() = true;
+        release.1.notify_all();
+        serial.flush_output();
+
+        // The drain thread may have picked the first byte up before the sink got stuck.
+        let output = output.lock().unwrap();
+        assert!(output.ends_with(b"ghij"));
+        assert_eq!(serial.dropped_bytes() as usize, 10 - output.len());
+    }
+
+    #[test]
+    fn unbuffered_output() {
+        let capture = Arc::new(Mutex::new(SerialCapture::new(SERIAL_CAPTURE_SIZE)));
+        let serial =
+            LumperSerial::buffered(Box::new(std::io::sink()), 0, capture, Vec::new()).unwrap();
+
+        assert!(serial.output_buffer.is_none());
+        assert_eq!(serial.dropped_bytes(), 0);
+    }
+
+    #[test]
+    fn reset_keeps_output() {
+        // Line control register, with the divisor latch access bit.
+        const LCR_OFFSET: u8 = 3;
+        const LCR_DLAB: u8 = 0x80;
+
+        let capture = Arc::new(Mutex::new(SerialCapture::new(SERIAL_CAPTURE_SIZE)));
+        let mut serial =
+            LumperSerial::buffered(Box::new(std::io::sink()), 0, capture.clone(), Vec::new())
+                .unwrap();
+
+        // The guest was programming the baud rate when it rebooted.
+        let lcr = serial.serial.read(LCR_OFFSET);
+        serial.serial.write(LCR_OFFSET, lcr | LCR_DLAB).unwrap();
+        serial.reset().unwrap();
+        assert_eq!(serial.serial.read(LCR_OFFSET), lcr);
+
+        // Writes reach the output again, instead of the divisor latch.
+        serial.serial.write(0, b'a').unwrap();
+        assert_eq!(capture.lock().unwrap().tail(1), "a");
+    }
+
+    #[test]
+    fn matcher_single_chunk() {
+        let mut matcher = StringMatcher::new("login:");
+
+        assert!(!matcher.feed(b"Welcome to Linux\n"));
+        assert!(matcher.feed(b"lumper login: "));
+    }
+
+    #[test]
+    fn matcher_across_chunks() {
+        let mut matcher = StringMatcher::new("login:");
+
+        assert!(!matcher.feed(b"lumper lo"));
+        assert!(!matcher.feed(b"g"));
+        assert!(!matcher.feed(b""));
+        assert!(matcher.feed(b"in: "));
+    }
+
+    #[test]
+    fn capture_matchers() {
+        let capture = Arc::new(Mutex::new(SerialCapture::new(SERIAL_CAPTURE_SIZE)));
+        let ready_evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let expect_evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let matchers = vec![
+            (StringMatcher::new("login:"), ready_evt.try_clone().unwrap()),
+            (StringMatcher::new("$ "), expect_evt.try_clone().unwrap()),
+        ];
+        let mut writer = CaptureWriter::new(Box::new(std::io::sink()), capture, matchers);
+
+        writer.write_all(b"lumper login: ").unwrap();
+        assert_eq!(ready_evt.read().unwrap(), 1);
+        assert!(expect_evt.read().is_err());
+
+        // Each matcher is only signaled once.
+        writer.write_all(b"root\nlumper login: $ ").unwrap();
+        assert!(ready_evt.read().is_err());
+        assert_eq!(expect_evt.read().unwrap(), 1);
+    }
+
+    #[test]
+    fn capture_before_buffer() {
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let release = Arc::new((Mutex::new(false), Condvar::new()));
+        let sink = BlockedWriter {
+            output,
+            release: release.clone(),
+        };
+        let capture = Arc::new(Mutex::new(SerialCapture::new(SERIAL_CAPTURE_SIZE)));
+        let ready_evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let matchers = vec![(StringMatcher::new("login:"), ready_evt.try_clone().unwrap())];
+
+        let mut serial =
+            LumperSerial::buffered(Box::new(sink), 4, capture.clone(), matchers).unwrap();
+
+        // The sink is stuck, and the buffer too small for the whole output.
+        for byte in b"lumper login: " {
+            serial.serial.write(0, *byte).unwrap();
+        }
+        assert_eq!(ready_evt.read().unwrap(), 1);
+        assert_eq!(capture.lock().unwrap().tail(64), "lumper login: ");
+
+        *release.0.lock().unwrap() = true;
+        release.1.notify_all();
+        serial.flush_output();
+    }
+
+    // Sink failing every write.
+    struct BrokenWriter;
+
+    impl Write for BrokenWriter {
+        fn write(&mut self, _buf: &[u8]) -> Result<usize> {
+            Err(Error::from_raw_os_error(libc::EPIPE))
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn capture_broken_sink() {
+        let capture = Arc::new(Mutex::new(SerialCapture::new(SERIAL_CAPTURE_SIZE)));
+        let expect_evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let matchers = vec![(StringMatcher::new("$ "), expect_evt.try_clone().unwrap())];
+        let mut writer = CaptureWriter::new(Box::new(BrokenWriter), capture.clone(), matchers);
+
+        assert!(writer.write_all(b"root@lumper:~$ ").is_err());
+        assert_eq!(expect_evt.read().unwrap(), 1);
+        assert_eq!(capture.lock().unwrap().tail(64), "root@lumper:~$ ");
+    }
+
+    #[test]
+    fn matcher_byte_per_byte() {
+        let mut matcher = StringMatcher::new("login:");
+        let input = b"llologin:";
+
+        for (i, byte) in input.iter().enumerate() {
+            assert_eq!(matcher.feed(&[*byte]), i == input.len() - 1);
+        }
+    }
+
+    #[test]
+    fn matcher_no_false_positive() {
+        let mut matcher = StringMatcher::new("login:");
+
+        assert!(!matcher.feed(b"logi"));
+        assert!(!matcher.feed(b"n\n:"));
+        assert!(!matcher.feed(b"log in:"));
+    }
 }
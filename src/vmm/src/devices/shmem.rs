@@ -0,0 +1,404 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Memory shared between the guest and host processes, in the spirit of QEMU `ivshmem`.
+//!
+//! A memfd is mapped in the MMIO gap, out of the guest RAM, along with a page of registers
+//! describing it. Guest writes to the doorbell register signal an eventfd polled by the host, and
+//! the host signals another one to raise the device interrupt. Host processes connecting to the
+//! Unix socket receive the size of the memory, then the memfd and both eventfds in this order.
+//!
+//! Registers, little endian:
+//! - `0x00`: magic (`LSHM`), read-only.
+//! - `0x08`: guest physical address of the shared memory (64 bits), read-only.
+//! - `0x10`: size of the shared memory (64 bits), read-only.
+//! - `0x18`: doorbell, any write signals the host.
+
+use std::fs::File;
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::path::PathBuf;
+use std::ptr;
+use std::result;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use kvm_bindings::kvm_userspace_memory_region;
+use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::sock_ctrl_msg::ScmSocket;
+
+use crate::memory::{parse_size, MMIO_GAP_START, PAGE_SIZE};
+
+/// Guest physical address of the registers.
+pub const SHMEM_REGS_ADDR: u64 = MMIO_GAP_START;
+const SHMEM_REGS_SIZE: u64 = PAGE_SIZE;
+/// Guest physical address of the shared memory.
+pub const SHMEM_ADDR: u64 = MMIO_GAP_START + 0x1000_0000;
+/// Largest shared memory, ending below the IOAPIC.
+pub const SHMEM_MAX_SIZE: u64 = 0x2000_0000;
+/// Interrupt raised when the host signals the guest.
+pub const SHMEM_IRQ: u32 = 5;
+
+const SHMEM_MAGIC: u32 = u32::from_le_bytes(*b"LSHM");
+
+// Register offsets.
+const REG_MAGIC: usize = 0x00;
+const REG_ADDR: usize = 0x08;
+const REG_SIZE: usize = 0x10;
+const REG_DOORBELL: u64 = 0x18;
+const REGS_LEN: usize = 0x20;
+
+/// Shared memory configuration, as a list of `<key>=<value>` (e.g.
+/// `size=16M,socket=/run/shmem.sock`).
+#[derive(Clone, Debug, PartialEq)]
+pub struct ShmemConfig {
+    /// Size of the shared memory, in bytes.
+    pub size: u64,
+    /// Unix socket host processes connect to, to get the memfd and eventfds.
+    pub socket: PathBuf,
+}
+
+impl FromStr for ShmemConfig {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        let invalid = || crate::Error::ShmemConfig(s.to_string());
+
+        let mut size = None;
+        let mut socket = None;
+        for option in s.split(',').filter(|option| !option.is_empty()) {
+            match option.split_once('=') {
+                Some(("size", value)) => size = Some(parse_size(value).ok_or_else(invalid)?),
+                Some(("socket", path)) if !path.is_empty() => socket = Some(PathBuf::from(path)),
+                _ => return Err(invalid()),
+            }
+        }
+
+        let size = size
+            .filter(|size| *size > 0 && size % PAGE_SIZE == 0 && *size <= SHMEM_MAX_SIZE)
+            .ok_or_else(invalid)?;
+
+        Ok(ShmemConfig {
+            size,
+            socket: socket.ok_or_else(invalid)?,
+        })
+    }
+}
+
+/// Registers of the device, accessed by the vCPUs.
+pub(crate) struct ShmemRegisters {
+    size: u64,
+    doorbell_evt: EventFd,
+}
+
+impl ShmemRegisters {
+    /// Whether the guest physical address `addr` belongs to the registers.
+    pub fn contains(&self, addr: u64) -> bool {
+        (SHMEM_REGS_ADDR..SHMEM_REGS_ADDR + SHMEM_REGS_SIZE).contains(&addr)
+    }
+
+    /// Handle a guest read at `offset` from the registers. Unknown registers read as zero.
+    pub fn read(&self, offset: u64, data: &mut [u8]) {
+        let mut regs = [0u8; REGS_LEN];
+        regs[REG_MAGIC..REG_MAGIC + 4].copy_from_slice(&SHMEM_MAGIC.to_le_bytes());
+        regs[REG_ADDR..REG_ADDR + 8].copy_from_slice(&SHMEM_ADDR.to_le_bytes());
+        regs[REG_SIZE..REG_SIZE + 8].copy_from_slice(&self.size.to_le_bytes());
+
+        let start = offset as usize;
+        match regs.get(start..start + data.len()) {
+            Some(bytes) => data.copy_from_slice(bytes),
+            None => data.iter_mut().for_each(|byte| *byte = 0),
+        }
+    }
+
+    /// Drop the doorbell notifications the host did not handle, as the VM reboots.
+    pub fn reset(&self) {
+        let _ = self.doorbell_evt.read();
+    }
+
+    /// Handle a guest write at `offset` to the registers. Only the doorbell is writable.
+    pub fn write(&self, offset: u64, _data: &[u8]) {
+        if offset == REG_DOORBELL {
+            // Only fails while the counter is saturated, the host has notifications pending then.
+            let _ = self.doorbell_evt.write(1);
+        }
+    }
+}
+
+/// Shared memory, and the socket handing it over to host processes.
+pub(crate) struct Shmem {
+    memory: File,
+    // Host mapping of the memory, the guest one is backed by.
+    host_addr: u64,
+    registers: Arc<ShmemRegisters>,
+    interrupt_evt: EventFd,
+    listener: UnixListener,
+    socket_path: PathBuf,
+}
+
+impl Shmem {
+    pub fn new(config: &ShmemConfig) -> io::Result<Self> {
+        // Safe because the name is a valid C string, and the result is checked.
+        let fd = unsafe {
+            libc::memfd_create(
+                b"lumper-shmem\0".as_ptr() as *const libc::c_char,
+                libc::MFD_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Safe because the fd was just created, and is owned by nothing else.
+        let memory = unsafe { File::from_raw_fd(fd) };
+        memory.set_len(config.size)?;
+        let registers = Arc::new(ShmemRegisters {
+            size: config.size,
+            doorbell_evt: EventFd::new(libc::EFD_NONBLOCK)?,
+        });
+        let interrupt_evt = EventFd::new(libc::EFD_NONBLOCK)?;
+
+        // Safe because a new shared mapping of the memfd is created, and the result is checked.
+        let host_addr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                config.size as usize,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                memory.as_raw_fd(),
+                0,
+            )
+        };
+        if host_addr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        // A socket in use is left alone.
+        let listener = UnixListener::bind(&config.socket).inspect_err(|_| {
+            // Safe because the mapping was just created with this size, and is not used.
+            unsafe { libc::munmap(host_addr, config.size as usize) };
+        })?;
+        let shmem = Shmem {
+            memory,
+            host_addr: host_addr as u64,
+            registers,
+            interrupt_evt,
+            listener,
+            socket_path: config.socket.clone(),
+        };
+        shmem.listener.set_nonblocking(true)?;
+
+        Ok(shmem)
+    }
+
+    /// KVM memory region mapping the shared memory in the guest, in `slot`.
+    pub fn memory_region(&self, slot: u32) -> kvm_userspace_memory_region {
+        kvm_userspace_memory_region {
+            slot,
+            guest_phys_addr: SHMEM_ADDR,
+            memory_size: self.registers.size,
+            userspace_addr: self.host_addr,
+            flags: 0,
+        }
+    }
+
+    pub fn registers(&self) -> Arc<ShmemRegisters> {
+        Arc::clone(&self.registers)
+    }
+
+    /// Event raising the device interrupt in the guest.
+    pub fn interrupt_evt(&self) -> &EventFd {
+        &self.interrupt_evt
+    }
+
+    pub fn listener_fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+
+    /// Hand the memory and eventfds over to a host process connecting to the socket.
+    pub fn accept(&self) -> io::Result<()> {
+        let (stream, _) = self.listener.accept()?;
+
+        let fds = [
+            self.memory.as_raw_fd(),
+            self.registers.doorbell_evt.as_raw_fd(),
+            self.interrupt_evt.as_raw_fd(),
+        ];
+        stream
+            .send_with_fds(&[&self.registers.size.to_le_bytes()[..]], &fds)
+            .map_err(|e| io::Error::from_raw_os_error(e.errno()))?;
+
+        Ok(())
+    }
+}
+
+impl Drop for Shmem {
+    fn drop(&mut self) {
+        // Safe because the mapping was created with this size, and is not used anymore.
+        unsafe {
+            libc::munmap(
+                self.host_addr as *mut libc::c_void,
+                self.registers.size as usize,
+            )
+        };
+        let _ = std::fs::remove_file(&self.socket_path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::os::unix::fs::FileExt;
+    use std::os::unix::net::UnixStream;
+
+    use vmm_sys_util::tempdir::TempDir;
+
+    #[test]
+    fn shmem_config() {
+        assert_eq!(
+            "size=16M,socket=/run/shmem.sock"
+                .parse::<ShmemConfig>()
+                .unwrap(),
+            ShmemConfig {
+                size: 16 << 20,
+                socket: PathBuf::from("/run/shmem.sock"),
+            }
+        );
+
+        for invalid in [
+            "size=16M",
+            "socket=/run/shmem.sock",
+            "size=0,socket=/run/shmem.sock",
+            "size=1000,socket=/run/shmem.sock",
+            "size=1G,socket=/run/shmem.sock",
+            "size=16M,socket=",
+            "size=16M,socket=/run/shmem.sock,doorbell=1",
+        ]
+        .iter()
+        {
+            assert!(invalid.parse::<ShmemConfig>().is_err(), "{}", invalid);
+        }
+    }
+
+    fn registers(size: u64) -> ShmemRegisters {
+        ShmemRegisters {
+            size,
+            doorbell_evt: EventFd::new(libc::EFD_NONBLOCK).unwrap(),
+        }
+    }
+
+    #[test]
+    fn register_reads() {
+        let regs = registers(16 << 20);
+
+        let mut data = [0u8; 4];
+        regs.read(REG_MAGIC as u64, &mut data);
+        assert_eq!(&data, b"LSHM");
+
+        let mut data = [0u8; 8];
+        regs.read(REG_ADDR as u64, &mut data);
+        assert_eq!(u64::from_le_bytes(data), SHMEM_ADDR);
+        regs.read(REG_SIZE as u64, &mut data);
+        assert_eq!(u64::from_le_bytes(data), 16 << 20);
+
+        // 32-bit halves of a 64-bit register.
+        let mut data = [0u8; 4];
+        regs.read(REG_SIZE as u64 + 4, &mut data);
+        assert_eq!(u32::from_le_bytes(data), 0);
+        regs.read(REG_SIZE as u64, &mut data);
+        assert_eq!(u32::from_le_bytes(data), 16 << 20);
+
+        // Beyond the registers, or straddling their end.
+        let mut data = [0xffu8; 8];
+        regs.read(0x100, &mut data);
+        assert_eq!(data, [0; 8]);
+        let mut data = [0xffu8; 8];
+        regs.read(REGS_LEN as u64 - 4, &mut data);
+        assert_eq!(data, [0; 8]);
+
+        assert!(regs.contains(SHMEM_REGS_ADDR));
+        assert!(regs.contains(SHMEM_REGS_ADDR + REG_DOORBELL));
+        assert!(!regs.contains(SHMEM_REGS_ADDR + SHMEM_REGS_SIZE));
+        assert!(!regs.contains(SHMEM_ADDR));
+    }
+
+    #[test]
+    fn register_writes() {
+        let regs = registers(16 << 20);
+
+        // Read-only registers ignore writes.
+        regs.write(REG_SIZE as u64, &[0; 8]);
+        let mut data = [0u8; 8];
+        regs.read(REG_SIZE as u64, &mut data);
+        assert_eq!(u64::from_le_bytes(data), 16 << 20);
+        assert!(regs.doorbell_evt.read().is_err());
+
+        // Each doorbell write signals the host.
+        regs.write(REG_DOORBELL, &[0; 4]);
+        regs.write(REG_DOORBELL, &[1]);
+        assert_eq!(regs.doorbell_evt.read().unwrap(), 2);
+
+        // Notifications left by the previous boot are dropped.
+        regs.write(REG_DOORBELL, &[1]);
+        regs.reset();
+        assert!(regs.doorbell_evt.read().is_err());
+    }
+
+    #[test]
+    fn fd_passing() {
+        let dir = TempDir::new_with_prefix(env::temp_dir().join("lumper")).unwrap();
+        let config = ShmemConfig {
+            size: 2 * PAGE_SIZE,
+            socket: dir.as_path().join("shmem.sock"),
+        };
+        let shmem = Shmem::new(&config).unwrap();
+        assert!(Shmem::new(&config).is_err());
+
+        let region = shmem.memory_region(3);
+        assert_eq!(
+            (region.slot, region.guest_phys_addr, region.memory_size),
+            (3, SHMEM_ADDR, 2 * PAGE_SIZE)
+        );
+
+        let peer = UnixStream::connect(&config.socket).unwrap();
+        shmem.accept().unwrap();
+
+        let mut size = [0u8; 8];
+        let mut iovecs = [libc::iovec {
+            iov_base: size.as_mut_ptr() as *mut libc::c_void,
+            iov_len: size.len(),
+        }];
+        let mut fds = [-1; 4];
+        // Safe because the iovec points to the size buffer, which outlives the call.
+        let (count, fd_count) = unsafe { peer.recv_with_fds(&mut iovecs, &mut fds) }.unwrap();
+        assert_eq!((count, fd_count), (8, 3));
+        assert_eq!(u64::from_le_bytes(size), 2 * PAGE_SIZE);
+
+        // Safe because the fds were just received, and are owned by nothing else.
+        let (memory, doorbell_evt, interrupt_evt) = unsafe {
+            (
+                File::from_raw_fd(fds[0]),
+                EventFd::from_raw_fd(fds[1]),
+                EventFd::from_raw_fd(fds[2]),
+            )
+        };
+
+        // The peer shares the VMM memory.
+        assert_eq!(memory.metadata().unwrap().len(), 2 * PAGE_SIZE);
+        memory.write_at(b"ping", PAGE_SIZE).unwrap();
+        let mut data = [0u8; 4];
+        shmem.memory.read_at(&mut data, PAGE_SIZE).unwrap();
+        assert_eq!(&data, b"ping");
+
+        // The guest rings the doorbell.
+        shmem.registers().write(REG_DOORBELL, &[1]);
+        assert_eq!(doorbell_evt.read().unwrap(), 1);
+
+        // The peer raises the guest interrupt.
+        interrupt_evt.write(1).unwrap();
+        assert_eq!(shmem.interrupt_evt().read().unwrap(), 1);
+
+        drop(shmem);
+        assert!(!config.socket.exists());
+    }
+}
@@ -0,0 +1,181 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use std::io::{Read, Result, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex};
+
+use crate::devices::serial::SerialCapture;
+
+/// Number of bytes of output kept while no client is connected.
+pub const TCP_CONSOLE_BUFFER_SIZE: usize = 64 << 10;
+
+/// Output side of the console, shared with the serial device.
+struct TcpConsoleOutput {
+    client: Option<TcpStream>,
+    // Output written while no client is connected, sent once one attaches.
+    pending: SerialCapture,
+}
+
+/// Guest console reachable over TCP, by one client at a time.
+pub(crate) struct TcpConsole {
+    listener: TcpListener,
+    // Input side of the connected client.
+    client: Option<TcpStream>,
+    output: Arc<Mutex<TcpConsoleOutput>>,
+}
+
+impl TcpConsole {
+    pub fn bind(addr: &str) -> Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+
+        Ok(TcpConsole {
+            listener,
+            client: None,
+            output: Arc::new(Mutex::new(TcpConsoleOutput {
+                client: None,
+                pending: SerialCapture::new(TCP_CONSOLE_BUFFER_SIZE),
+            })),
+        })
+    }
+
+    pub fn listener_fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+
+    pub fn client_fd(&self) -> Option<RawFd> {
+        self.client.as_ref().map(|client| client.as_raw_fd())
+    }
+
+    /// Sink for the serial device output.
+    pub fn writer(&self) -> TcpConsoleWriter {
+        TcpConsoleWriter(self.output.clone())
+    }
+
+    /// Accept a pending connection, and return its fd.
+    ///
+    /// Connections are closed right away while another client is attached.
+    pub fn accept(&mut self) -> Result<Option<RawFd>> {
+        let (mut stream, _) = self.listener.accept()?;
+        if self.client.is_some() {
+            return Ok(None);
+        }
+
+        stream.set_nonblocking(false)?;
+
+        let mut output = self.output.lock().unwrap();
+        stream.write_all(&output.pending.take())?;
+        output.client = Some(stream.try_clone()?);
+        self.client = Some(stream);
+
+        Ok(self.client_fd())
+    }
+
+    /// Read input from the client. Returns 0 once the client is gone, and detaches it.
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let count = match self.client.as_mut() {
+            Some(client) => client.read(buf).unwrap_or(0),
+            None => 0,
+        };
+
+        if count == 0 {
+            self.client = None;
+            self.output.lock().unwrap().client = None;
+        }
+
+        Ok(count)
+    }
+}
+
+pub(crate) struct TcpConsoleWriter(Arc<Mutex<TcpConsoleOutput>>);
+
+impl Write for TcpConsoleWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let mut output = self.0.lock().unwrap();
+
+        if let Some(client) = output.client.as_mut() {
+            if client.write_all(buf).is_ok() {
+                return Ok(buf.len());
+            }
+            // The client went away, keep the output until the next one attaches.
+            output.client = None;
+        }
+
+        output.pending.push(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        match self.0.lock().unwrap().client.as_mut() {
+            Some(client) => client.flush().or(Ok(())),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::ErrorKind;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    fn accept(console: &mut TcpConsole) -> Option<RawFd> {
+        // The listener is non blocking, give the connection time to be queued.
+        loop {
+            match console.accept() {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => sleep(Duration::from_millis(10)),
+                result => return result.unwrap(),
+            }
+        }
+    }
+
+    #[test]
+    fn output_buffered_until_connection() {
+        let mut console = TcpConsole::bind("127.0.0.1:0").unwrap();
+        let addr = console.listener.local_addr().unwrap();
+        let mut writer = console.writer();
+
+        writer.write_all(b"early boot\n").unwrap();
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        assert!(accept(&mut console).is_some());
+        writer.write_all(b"login: ").unwrap();
+
+        let mut buf = [0u8; 18];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"early boot\nlogin: ");
+    }
+
+    #[test]
+    fn input_and_reconnection() {
+        let mut console = TcpConsole::bind("127.0.0.1:0").unwrap();
+        let addr = console.listener.local_addr().unwrap();
+        let mut writer = console.writer();
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        accept(&mut console).unwrap();
+
+        // A second client is turned away while the first one is attached.
+        let _second = TcpStream::connect(addr).unwrap();
+        assert!(accept(&mut console).is_none());
+
+        client.write_all(b"root\n").unwrap();
+        let mut buf = [0u8; 16];
+        assert_eq!(console.read(&mut buf).unwrap(), 5);
+        assert_eq!(&buf[..5], b"root\n");
+
+        // Disconnection detaches the client, output is kept for the next one.
+        drop(client);
+        assert_eq!(console.read(&mut buf).unwrap(), 0);
+        assert!(console.client_fd().is_none());
+        writer.write_all(b"bye").unwrap();
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        accept(&mut console).unwrap();
+        let mut buf = [0u8; 3];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"bye");
+    }
+}
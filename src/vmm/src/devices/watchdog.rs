@@ -0,0 +1,268 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Watchdog, through the iBASE IB700 ISA device interface (Linux `ib700wdt` driver).
+//!
+//! The guest writes to the start port to arm the watchdog or postpone its expiry, and to the
+//! stop port to disarm it. The timeout written by the guest is ignored, the configured one is
+//! used instead. The watchdog does not run while the VM is paused (e.g. stopped by a debugger).
+
+use std::result;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// I/O port disarming the watchdog.
+pub const WATCHDOG_STOP_PORT: u16 = 0x441;
+/// I/O port arming the watchdog, or postponing its expiry.
+pub const WATCHDOG_START_PORT: u16 = 0x443;
+
+/// Timeout used when the configuration does not set one.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// What to do when the guest stops pinging the watchdog.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WatchdogAction {
+    /// Stop the VM.
+    Poweroff,
+    /// Reboot the guest.
+    Reset,
+    /// Only report the expiry, the guest keeps running.
+    None,
+}
+
+impl FromStr for WatchdogAction {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s {
+            "poweroff" => Ok(WatchdogAction::Poweroff),
+            "reset" => Ok(WatchdogAction::Reset),
+            "none" => Ok(WatchdogAction::None),
+            _ => Err(crate::Error::WatchdogConfig(s.to_string())),
+        }
+    }
+}
+
+/// Watchdog configuration, as a list of `<key>=<value>` (e.g. `action=poweroff,timeout=30`).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WatchdogConfig {
+    pub action: WatchdogAction,
+    pub timeout: Duration,
+}
+
+impl FromStr for WatchdogConfig {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        let invalid = || crate::Error::WatchdogConfig(s.to_string());
+        let mut config = WatchdogConfig {
+            action: WatchdogAction::Poweroff,
+            timeout: DEFAULT_TIMEOUT,
+        };
+
+        for option in s.split(',').filter(|option| !option.is_empty()) {
+            let mut option = option.splitn(2, '=');
+            match (option.next(), option.next()) {
+                (Some("action"), Some(action)) => config.action = action.parse()?,
+                (Some("timeout"), Some(timeout)) => {
+                    let timeout = timeout.parse().map_err(|_| invalid())?;
+                    if timeout == 0 {
+                        return Err(invalid());
+                    }
+                    config.timeout = Duration::from_secs(timeout);
+                }
+                _ => return Err(invalid()),
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+pub(crate) struct Watchdog {
+    timeout: Duration,
+    // Time at which the watchdog expires, unset while it is disarmed.
+    deadline: Option<Instant>,
+    // Time at which the VM was paused, unset while it runs.
+    paused_at: Option<Instant>,
+}
+
+impl Watchdog {
+    pub fn new(timeout: Duration) -> Self {
+        Watchdog {
+            timeout,
+            deadline: None,
+            paused_at: None,
+        }
+    }
+
+    /// Handle a guest write to `port` at time `now`.
+    pub fn write(&mut self, port: u16, now: Instant) {
+        match port {
+            WATCHDOG_START_PORT => self.deadline = Some(now + self.timeout),
+            WATCHDOG_STOP_PORT => self.deadline = None,
+            _ => {}
+        }
+    }
+
+    /// Disarm the watchdog, as the machine resets.
+    pub fn reset(&mut self) {
+        self.deadline = None;
+        self.paused_at = None;
+    }
+
+    /// Stop counting down at time `now`, as the VM is paused.
+    pub fn pause(&mut self, now: Instant) {
+        if self.paused_at.is_none() {
+            self.paused_at = Some(now);
+        }
+    }
+
+    /// Count down again at time `now`, the expiry being postponed by the pause duration.
+    pub fn resume(&mut self, now: Instant) {
+        if let (Some(paused_at), Some(deadline)) = (self.paused_at.take(), self.deadline) {
+            self.deadline = Some(deadline + now.saturating_duration_since(paused_at));
+        }
+    }
+
+    /// Whether the watchdog expired at time `now`.
+    ///
+    /// An expired watchdog is disarmed, until the guest arms it again.
+    pub fn expired(&mut self, now: Instant) -> bool {
+        match self.deadline {
+            Some(deadline) if now >= deadline && self.paused_at.is_none() => {
+                self.deadline = None;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(30);
+
+    #[test]
+    fn watchdog_config() {
+        assert_eq!(
+            "".parse::<WatchdogConfig>().unwrap(),
+            WatchdogConfig {
+                action: WatchdogAction::Poweroff,
+                timeout: DEFAULT_TIMEOUT,
+            }
+        );
+        assert_eq!(
+            "action=none,timeout=10".parse::<WatchdogConfig>().unwrap(),
+            WatchdogConfig {
+                action: WatchdogAction::None,
+                timeout: Duration::from_secs(10),
+            }
+        );
+
+        assert_eq!(
+            "action=reset".parse::<WatchdogConfig>().unwrap(),
+            WatchdogConfig {
+                action: WatchdogAction::Reset,
+                timeout: DEFAULT_TIMEOUT,
+            }
+        );
+
+        for config in [
+            "action=reboot",
+            "timeout=0",
+            "timeout=-1",
+            "timeout",
+            "interval=30",
+        ]
+        .iter()
+        {
+            assert!(matches!(
+                config.parse::<WatchdogConfig>(),
+                Err(crate::Error::WatchdogConfig(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn disarmed_watchdog() {
+        let start = Instant::now();
+        let mut watchdog = Watchdog::new(TIMEOUT);
+
+        assert!(!watchdog.expired(start + TIMEOUT * 10));
+
+        // Armed, then disarmed by the guest.
+        watchdog.write(WATCHDOG_START_PORT, start);
+        watchdog.write(WATCHDOG_STOP_PORT, start + TIMEOUT / 2);
+        assert!(!watchdog.expired(start + TIMEOUT * 10));
+
+        // Armed, then disarmed by a reboot.
+        watchdog.write(WATCHDOG_START_PORT, start);
+        watchdog.reset();
+        assert!(!watchdog.expired(start + TIMEOUT * 10));
+    }
+
+    #[test]
+    fn watchdog_expiry() {
+        let start = Instant::now();
+        let mut watchdog = Watchdog::new(TIMEOUT);
+
+        watchdog.write(WATCHDOG_START_PORT, start);
+        assert!(!watchdog.expired(start + TIMEOUT / 2));
+        assert!(watchdog.expired(start + TIMEOUT));
+        // The expiry is only reported once.
+        assert!(!watchdog.expired(start + TIMEOUT * 2));
+
+        // The guest arms it again.
+        let start = start + TIMEOUT * 2;
+        watchdog.write(WATCHDOG_START_PORT, start);
+        assert!(watchdog.expired(start + TIMEOUT));
+    }
+
+    #[test]
+    fn watchdog_pings() {
+        let start = Instant::now();
+        let mut watchdog = Watchdog::new(TIMEOUT);
+
+        watchdog.write(WATCHDOG_START_PORT, start);
+        // Each ping postpones the expiry.
+        for ping in 1..4 {
+            let now = start + TIMEOUT / 2 * ping;
+            assert!(!watchdog.expired(now));
+            watchdog.write(WATCHDOG_START_PORT, now);
+        }
+        let last_ping = start + TIMEOUT / 2 * 3;
+        assert!(!watchdog.expired(last_ping + TIMEOUT / 2));
+        assert!(watchdog.expired(last_ping + TIMEOUT));
+
+        // Writes to other ports are ignored.
+        watchdog.write(WATCHDOG_START_PORT, start);
+        watchdog.write(WATCHDOG_START_PORT + 1, start);
+        assert!(watchdog.expired(start + TIMEOUT));
+    }
+
+    #[test]
+    fn paused_watchdog() {
+        let start = Instant::now();
+        let mut watchdog = Watchdog::new(TIMEOUT);
+
+        watchdog.write(WATCHDOG_START_PORT, start);
+        watchdog.pause(start + TIMEOUT / 2);
+        // A paused guest cannot ping the watchdog, it does not expire.
+        assert!(!watchdog.expired(start + TIMEOUT * 10));
+
+        // The time left when paused is left after the pause.
+        let resumed = start + TIMEOUT * 10;
+        watchdog.resume(resumed);
+        assert!(!watchdog.expired(resumed + TIMEOUT / 4));
+        assert!(watchdog.expired(resumed + TIMEOUT / 2));
+
+        // A reboot while paused disarms it.
+        watchdog.write(WATCHDOG_START_PORT, start);
+        watchdog.pause(start);
+        watchdog.reset();
+        watchdog.resume(start + TIMEOUT);
+        assert!(!watchdog.expired(start + TIMEOUT * 10));
+    }
+}
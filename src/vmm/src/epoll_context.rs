@@ -28,6 +28,28 @@ impl EpollContext {
 
         Ok(())
     }
+
+    pub fn add_fd(&self, fd: RawFd) -> result::Result<(), io::Error> {
+        epoll::ctl(
+            self.raw_fd,
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            fd,
+            epoll::Event::new(epoll::Events::EPOLLIN, fd as u64),
+        )?;
+
+        Ok(())
+    }
+
+    pub fn remove_fd(&self, fd: RawFd) -> result::Result<(), io::Error> {
+        epoll::ctl(
+            self.raw_fd,
+            epoll::ControlOptions::EPOLL_CTL_DEL,
+            fd,
+            epoll::Event::new(epoll::Events::empty(), 0),
+        )?;
+
+        Ok(())
+    }
 }
 
 impl AsRawFd for EpollContext {
@@ -0,0 +1,263 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Why the VMM exits, as an exit code and optionally as a JSON file.
+//!
+//! | Reason           | Exit code |
+//! |------------------|-----------|
+//! | `shutdown`       | 0         |
+//! | `expect-string`  | 0         |
+//! | `internal-error` | 1         |
+//! | `config-error`   | 2         |
+//! | `guest-crash`    | 3         |
+//! | `timeout`        | 124       |
+
+use std::fmt::Write;
+use std::fs;
+use std::panic;
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::daemon::Pidfile;
+
+/// Exit code returned when the VMM itself failed.
+pub const INTERNAL_ERROR_EXIT_CODE: i32 = 1;
+
+/// Exit code returned when the VM could not be configured as asked.
+pub const CONFIG_ERROR_EXIT_CODE: i32 = 2;
+
+/// Exit code returned when the guest crashed and crashes are handled, or its watchdog expired.
+pub const CRASH_EXIT_CODE: i32 = 3;
+
+/// Exit code returned when the guest did not complete before the deadline, as timeout(1) does.
+pub const TIMEOUT_EXIT_CODE: i32 = 124;
+
+/// Why the VM stopped.
+#[derive(Clone, Debug, PartialEq)]
+pub enum VmExitReason {
+    /// The guest stopped the machine, as described.
+    Shutdown(String),
+    /// The guest printed the expected string.
+    ExpectedString,
+    /// The guest did not complete before the deadline.
+    Timeout,
+    /// The guest crashed, as described.
+    GuestCrash(String),
+    /// The VM could not be configured, with the error.
+    ConfigError(String),
+    /// The VMM failed, with the error.
+    InternalError(String),
+}
+
+impl VmExitReason {
+    /// Name of the reason, in the exit reason file.
+    pub fn name(&self) -> &'static str {
+        match self {
+            VmExitReason::Shutdown(_) => "shutdown",
+            VmExitReason::ExpectedString => "expect-string",
+            VmExitReason::Timeout => "timeout",
+            VmExitReason::GuestCrash(_) => "guest-crash",
+            VmExitReason::ConfigError(_) => "config-error",
+            VmExitReason::InternalError(_) => "internal-error",
+        }
+    }
+
+    /// Details about the reason, empty if there are none.
+    pub fn details(&self) -> &str {
+        match self {
+            VmExitReason::Shutdown(details)
+            | VmExitReason::GuestCrash(details)
+            | VmExitReason::ConfigError(details)
+            | VmExitReason::InternalError(details) => details,
+            VmExitReason::ExpectedString | VmExitReason::Timeout => "",
+        }
+    }
+
+    /// Exit code of the process, see the table above.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            VmExitReason::Shutdown(_) | VmExitReason::ExpectedString => 0,
+            VmExitReason::Timeout => TIMEOUT_EXIT_CODE,
+            VmExitReason::GuestCrash(_) => CRASH_EXIT_CODE,
+            VmExitReason::ConfigError(_) => CONFIG_ERROR_EXIT_CODE,
+            VmExitReason::InternalError(_) => INTERNAL_ERROR_EXIT_CODE,
+        }
+    }
+
+    /// Describe the reason as a JSON object, the VM having run for `uptime`.
+    pub fn to_json(&self, uptime: Duration) -> String {
+        format!(
+            "{{\"reason\":\"{}\",\"details\":\"{}\",\"uptime_ms\":{}}}\n",
+            self.name(),
+            json_escape(self.details()),
+            uptime.as_millis()
+        )
+    }
+}
+
+/// Escape `s` to be used within a JSON string.
+fn json_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(escaped, "\\u{:04x}", c as u32);
+            }
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+/// Turns the exit reason into the process exit code, writing it to a file if asked to.
+pub struct ExitReporter {
+    // File the exit reason is written to.
+    path: Option<PathBuf>,
+    start: Instant,
+    // Only the first reason is written, e.g. when a panic happens while exiting.
+    reported: AtomicBool,
+    // Removed once the exit is reported.
+    pidfile: Mutex<Option<Pidfile>>,
+}
+
+impl ExitReporter {
+    pub fn new(path: Option<PathBuf>) -> Self {
+        ExitReporter {
+            path,
+            start: Instant::now(),
+            reported: AtomicBool::new(false),
+            pidfile: Mutex::new(None),
+        }
+    }
+
+    /// Remove `pidfile` when the VMM exits, panics included.
+    pub fn remove_on_exit(&self, pidfile: Pidfile) {
+        *self.pidfile.lock().unwrap() = Some(pidfile);
+    }
+
+    /// Record why the VMM exits, and return the process exit code.
+    pub fn report(&self, reason: &VmExitReason) -> i32 {
+        if let Some(path) = &self.path {
+            if !self.reported.swap(true, Ordering::SeqCst) {
+                let json = reason.to_json(self.start.elapsed());
+                if let Err(e) = fs::write(path, json) {
+                    error!(
+                        "Failed to write the exit reason to {}: {}",
+                        path.display(),
+                        e
+                    );
+                }
+            }
+        }
+        self.pidfile.lock().unwrap().take();
+
+        reason.exit_code()
+    }
+
+    /// Report panics, from any thread, as internal errors and exit.
+    pub fn install_panic_hook(self: &Arc<Self>) {
+        let reporter = Arc::clone(self);
+        let default_hook = panic::take_hook();
+
+        panic::set_hook(Box::new(move |info| {
+            default_hook(info);
+
+            let code = reporter.report(&VmExitReason::InternalError(info.to_string()));
+            process::exit(code);
+        }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    use vmm_sys_util::tempdir::TempDir;
+
+    #[test]
+    fn exit_codes() {
+        let reasons = [
+            (VmExitReason::Shutdown("ACPI power off".to_string()), 0),
+            (VmExitReason::ExpectedString, 0),
+            (VmExitReason::InternalError("epoll".to_string()), 1),
+            (VmExitReason::ConfigError("memory".to_string()), 2),
+            (VmExitReason::GuestCrash("panic".to_string()), 3),
+            (VmExitReason::Timeout, 124),
+        ];
+
+        for (reason, code) in reasons.iter() {
+            assert_eq!(reason.exit_code(), *code, "{:?}", reason);
+        }
+    }
+
+    #[test]
+    fn json() {
+        let uptime = Duration::from_millis(1234);
+        let reasons = [
+            (
+                VmExitReason::Shutdown("ACPI power off".to_string()),
+                r#"{"reason":"shutdown","details":"ACPI power off","uptime_ms":1234}"#,
+            ),
+            (
+                VmExitReason::Timeout,
+                r#"{"reason":"timeout","details":"","uptime_ms":1234}"#,
+            ),
+            (
+                VmExitReason::GuestCrash("guest kernel panic".to_string()),
+                r#"{"reason":"guest-crash","details":"guest kernel panic","uptime_ms":1234}"#,
+            ),
+            (
+                VmExitReason::ConfigError("BootProtocol(\"efi\")".to_string()),
+                r#"{"reason":"config-error","details":"BootProtocol(\"efi\")","uptime_ms":1234}"#,
+            ),
+            (
+                VmExitReason::InternalError("a\\b\n\tc\u{1}".to_string()),
+                r#"{"reason":"internal-error","details":"a\\b\n\tc\u0001","uptime_ms":1234}"#,
+            ),
+        ];
+
+        for (reason, json) in reasons.iter() {
+            assert_eq!(reason.to_json(uptime), format!("{}\n", json));
+        }
+    }
+
+    #[test]
+    fn exit_reason_file() {
+        let dir = TempDir::new_with_prefix(env::temp_dir().join("lumper")).unwrap();
+        let path = dir.as_path().join("exit.json");
+        let reporter = ExitReporter::new(Some(path.clone()));
+
+        assert_eq!(reporter.report(&VmExitReason::Timeout), 124);
+        let json = fs::read_to_string(&path).unwrap();
+        assert!(json.starts_with(r#"{"reason":"timeout","details":"","uptime_ms":"#));
+
+        // Only the first reason is kept.
+        assert_eq!(reporter.report(&VmExitReason::ExpectedString), 0);
+        assert_eq!(fs::read_to_string(&path).unwrap(), json);
+
+        // Without a file, only the exit code is returned.
+        let reporter = ExitReporter::new(None);
+        assert_eq!(reporter.report(&VmExitReason::GuestCrash(String::new())), 3);
+    }
+
+    #[test]
+    fn pidfile_removed() {
+        let dir = TempDir::new_with_prefix(env::temp_dir().join("lumper")).unwrap();
+        let path = dir.as_path().join("vm.pid");
+        let reporter = ExitReporter::new(None);
+
+        reporter.remove_on_exit(Pidfile::create(path.clone()).unwrap());
+        assert!(path.exists());
+        reporter.report(&VmExitReason::Shutdown("ACPI power off".to_string()));
+        assert!(!path.exists());
+    }
+}
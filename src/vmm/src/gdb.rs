@@ -0,0 +1,960 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! GDB remote serial protocol server, for debugging the guest kernel.
+//!
+//! The server is driven by the vCPU threads: a vCPU stopping on a debug exit takes the
+//! stub over, and serves the debugger until it is told to continue or single-step.
+//! Other vCPUs keep running in the meantime, and stop as they hit a breakpoint.
+//!
+//! The sockets are non-blocking, and polled along with the VM stop event: a vCPU waiting for the
+//! debugger gives up once the VM stops.
+
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::result;
+
+use kvm_bindings::{kvm_regs, kvm_sregs};
+use vm_memory::{Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
+
+use crate::cpu::control::VcpuControl;
+
+/// Signal reported to the debugger when a vCPU stops, SIGTRAP.
+const STOP_SIGNAL: u8 = 5;
+
+/// Opcode of the `int3` instruction, used for software breakpoints.
+const INT3: u8 = 0xcc;
+
+/// Largest packet we accept, as advertised to the debugger.
+const PACKET_SIZE: usize = 0x1000;
+// Longest memory access, its contents taking two hex digits per byte in a packet.
+const MAX_MEMORY_LEN: usize = PACKET_SIZE / 2;
+
+/// Guest virtual addresses are translated one page at a time.
+const PAGE_SIZE: u64 = 0x1000;
+
+/// Errors encountered while setting the GDB server up.
+#[derive(Debug)]
+pub enum Error {
+    /// The address is not of the `tcp:<host>:<port>` form.
+    InvalidAddress(String),
+    /// Failed to listen on the address.
+    Bind(io::Error),
+}
+
+/// Dedicated Result type.
+pub type Result<T> = result::Result<T, Error>;
+
+/// Parse a QEMU style `tcp:<host>:<port>` address, the host defaulting to the loopback one.
+pub fn parse_address(addr: &str) -> Result<String> {
+    let invalid = || Error::InvalidAddress(addr.to_string());
+
+    let addr = addr.strip_prefix("tcp:").ok_or_else(invalid)?;
+    let (host, port) = addr.rsplit_once(':').ok_or_else(invalid)?;
+    port.parse::<u16>().map_err(|_| invalid())?;
+
+    let host = if host.is_empty() { "127.0.0.1" } else { host };
+    Ok(format!("{}:{}", host, port))
+}
+
+/// vCPU state the debugger operates on.
+pub(crate) trait Target {
+    /// General purpose and segment registers.
+    fn registers(&self) -> io::Result<(kvm_regs, kvm_sregs)>;
+    /// Update the general purpose registers.
+    fn set_registers(&self, regs: &kvm_regs) -> io::Result<()>;
+    /// Translate a guest virtual address with the current paging mode.
+    fn translate(&self, gva: u64) -> Option<u64>;
+    /// Enable software breakpoints, and single-stepping if `step` is set.
+    fn set_single_step(&self, step: bool) -> io::Result<()>;
+}
+
+/// How the stopped vCPU proceeds once the debugger is done.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Resume {
+    Continue,
+    Step,
+    /// The debugger left, the guest runs freely.
+    Detach,
+    /// The debugger asked for the VM to be stopped.
+    Kill,
+}
+
+/// Packet received from the debugger.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Packet {
+    Command(Vec<u8>),
+    /// Ctrl-C, sent outside of any packet.
+    Interrupt,
+}
+
+fn checksum(payload: &[u8]) -> u8 {
+    payload
+        .iter()
+        .fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+fn read_byte<R: Read>(stream: &mut R) -> io::Result<u8> {
+    let mut byte = [0u8; 1];
+    stream.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+/// Read the next packet, acknowledging it, and asking for packets with a bad checksum again.
+pub(crate) fn read_packet<S: Read + Write>(stream: &mut S) -> io::Result<Packet> {
+    loop {
+        match read_byte(stream)? {
+            0x03 => return Ok(Packet::Interrupt),
+            b'$' => {}
+            // Acknowledgments, and noise between packets.
+            _ => continue,
+        }
+
+        let mut payload = Vec::new();
+        loop {
+            match read_byte(stream)? {
+                b'#' => break,
+                _ if payload.len() == PACKET_SIZE => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "packet too long",
+                    ))
+                }
+                byte => payload.push(byte),
+            }
+        }
+
+        let mut expected = [0u8; 2];
+        stream.read_exact(&mut expected)?;
+        let expected = std::str::from_utf8(&expected)
+            .ok()
+            .and_then(|expected| u8::from_str_radix(expected, 16).ok());
+
+        if expected == Some(checksum(&payload)) {
+            stream.write_all(b"+")?;
+            return Ok(Packet::Command(payload));
+        }
+        stream.write_all(b"-")?;
+    }
+}
+
+/// Wait for `fd` to be ready for `events`, failing once the VM stops.
+fn wait_ready(fd: RawFd, events: libc::c_short, control: &VcpuControl) -> io::Result<()> {
+    let mut fds = [
+        libc::pollfd {
+            fd,
+            events,
+            revents: 0,
+        },
+        libc::pollfd {
+            fd: control.stop_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        },
+    ];
+
+    loop {
+        if control.stopping() {
+            return Err(io::Error::new(io::ErrorKind::Other, "VM stopped"));
+        }
+
+        // Safe because the array outlives the call, and its length is passed along.
+        let ret = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) };
+        if ret > 0 && fds[1].revents == 0 {
+            return Ok(());
+        }
+        // Kicked out of the poll, or the VM stopped: checked again above.
+        if ret < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err(e);
+            }
+        }
+    }
+}
+
+/// Connection to the debugger, waited for until the VM stops.
+struct Connection<'a> {
+    stream: &'a mut TcpStream,
+    control: &'a VcpuControl,
+}
+
+impl Read for Connection<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.stream.read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    wait_ready(self.stream.as_raw_fd(), libc::POLLIN, self.control)?
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl Write for Connection<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        loop {
+            match self.stream.write(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    wait_ready(self.stream.as_raw_fd(), libc::POLLOUT, self.control)?
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// Frame and send a packet.
+pub(crate) fn write_packet<W: Write>(stream: &mut W, payload: &[u8]) -> io::Result<()> {
+    let mut packet = Vec::with_capacity(payload.len() + 4);
+    packet.push(b'$');
+    packet.extend_from_slice(payload);
+    packet.extend_from_slice(format!("#{:02x}", checksum(payload)).as_bytes());
+
+    stream.write_all(&packet)?;
+    stream.flush()
+}
+
+fn hex_encode(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn hex_decode(hex: &str) -> Option<Vec<u8>> {
+    // An odd length leaves a truncated last byte.
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn parse_u64(hex: &str) -> Option<u64> {
+    u64::from_str_radix(hex, 16).ok()
+}
+
+/// Parse the `<addr>,<len>` arguments of the memory packets.
+fn parse_range(args: &str) -> Option<(u64, usize)> {
+    let (addr, len) = args.split_once(',')?;
+    Some((parse_u64(addr)?, parse_u64(len)? as usize))
+}
+
+/// Encode the registers in the order of the GDB amd64 target description.
+///
+/// Registers past the segment selectors (x87 and SSE) are not sent, and GDB reports them as
+/// unavailable.
+fn encode_registers(regs: &kvm_regs, sregs: &kvm_sregs) -> String {
+    let gp = [
+        regs.rax, regs.rbx, regs.rcx, regs.rdx, regs.rsi, regs.rdi, regs.rbp, regs.rsp, regs.r8,
+        regs.r9, regs.r10, regs.r11, regs.r12, regs.r13, regs.r14, regs.r15, regs.rip,
+    ];
+    let segments = [
+        regs.rflags as u32,
+        u32::from(sregs.cs.selector),
+        u32::from(sregs.ss.selector),
+        u32::from(sregs.ds.selector),
+        u32::from(sregs.es.selector),
+        u32::from(sregs.fs.selector),
+        u32::from(sregs.gs.selector),
+    ];
+
+    let mut data = Vec::new();
+    gp.iter()
+        .for_each(|value| data.extend_from_slice(&value.to_le_bytes()));
+    segments
+        .iter()
+        .for_each(|value| data.extend_from_slice(&value.to_le_bytes()));
+
+    hex_encode(&data)
+}
+
+/// Update the general purpose registers and flags from a `G` packet payload.
+///
+/// Segment selectors can't be changed.
+fn decode_registers(hex: &str, regs: &mut kvm_regs) -> Option<()> {
+    let data = hex_decode(hex)?;
+    if data.len() < 17 * 8 + 4 {
+        return None;
+    }
+
+    let mut values = data
+        .chunks_exact(8)
+        .map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap()));
+    for reg in [
+        &mut regs.rax,
+        &mut regs.rbx,
+        &mut regs.rcx,
+        &mut regs.rdx,
+        &mut regs.rsi,
+        &mut regs.rdi,
+        &mut regs.rbp,
+        &mut regs.rsp,
+        &mut regs.r8,
+        &mut regs.r9,
+        &mut regs.r10,
+        &mut regs.r11,
+        &mut regs.r12,
+        &mut regs.r13,
+        &mut regs.r14,
+        &mut regs.r15,
+        &mut regs.rip,
+    ] {
+        *reg = values.next()?;
+    }
+
+    // The flags are 32-bit wide.
+    let rflags = &data[17 * 8..17 * 8 + 4];
+    regs.rflags = u64::from(u32::from_le_bytes(rflags.try_into().unwrap()));
+
+    Some(())
+}
+
+/// Software breakpoints, by guest physical address, with the bytes they replaced.
+#[derive(Default)]
+pub(crate) struct Breakpoints(BTreeMap<u64, u8>);
+
+impl Breakpoints {
+    /// Patch an `int3` at `gpa`.
+    pub fn insert(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        gpa: u64,
+    ) -> result::Result<(), GuestMemoryError> {
+        if self.0.contains_key(&gpa) {
+            return Ok(());
+        }
+
+        let shadow: u8 = mem.read_obj(GuestAddress(gpa))?;
+        mem.write_obj(INT3, GuestAddress(gpa))?;
+        self.0.insert(gpa, shadow);
+
+        Ok(())
+    }
+
+    /// Restore the byte a breakpoint replaced.
+    pub fn remove(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        gpa: u64,
+    ) -> result::Result<(), GuestMemoryError> {
+        if let Some(shadow) = self.0.remove(&gpa) {
+            mem.write_obj(shadow, GuestAddress(gpa))?;
+        }
+
+        Ok(())
+    }
+
+    /// Remove all breakpoints.
+    pub fn clear(&mut self, mem: &GuestMemoryMmap) -> result::Result<(), GuestMemoryError> {
+        for (gpa, shadow) in std::mem::take(&mut self.0) {
+            mem.write_obj(shadow, GuestAddress(gpa))?;
+        }
+
+        Ok(())
+    }
+
+    /// Hide breakpoints from guest memory read at `gpa`.
+    pub fn unpatch(&self, gpa: u64, data: &mut [u8]) {
+        // Data read from guest memory cannot wrap around.
+        let end = match gpa.checked_add(data.len() as u64) {
+            Some(end) => end,
+            None => return,
+        };
+        for (addr, shadow) in self.0.range(gpa..end) {
+            data[(addr - gpa) as usize] = *shadow;
+        }
+    }
+
+    /// Write guest memory at `gpa`, updating the bytes breakpoints replaced instead of them.
+    pub fn write(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        gpa: u64,
+        data: &[u8],
+    ) -> result::Result<(), GuestMemoryError> {
+        let end = gpa
+            .checked_add(data.len() as u64)
+            .ok_or(GuestMemoryError::InvalidGuestAddress(GuestAddress(gpa)))?;
+        mem.write_slice(data, GuestAddress(gpa))?;
+        for (addr, shadow) in self.0.range_mut(gpa..end) {
+            *shadow = data[(addr - gpa) as usize];
+            mem.write_obj(INT3, GuestAddress(*addr))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Outcome of a debugger command.
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Packet(String),
+    Resume(Resume),
+}
+
+impl From<&str> for Reply {
+    fn from(payload: &str) -> Self {
+        Reply::Packet(payload.to_string())
+    }
+}
+
+// Replies to failed requests.
+const REPLY_ERROR: &str = "E01";
+const REPLY_FAULT: &str = "E0e";
+
+/// GDB server, shared by the vCPU threads.
+pub(crate) struct GdbStub {
+    listener: TcpListener,
+    client: Option<TcpStream>,
+    guest_memory: GuestMemoryMmap,
+    breakpoints: Breakpoints,
+    num_vcpus: u64,
+}
+
+impl GdbStub {
+    pub fn bind(addr: &str, guest_memory: GuestMemoryMmap, num_vcpus: u64) -> Result<Self> {
+        let listener = TcpListener::bind(parse_address(addr)?).map_err(Error::Bind)?;
+        listener.set_nonblocking(true).map_err(Error::Bind)?;
+
+        Ok(GdbStub {
+            listener,
+            client: None,
+            guest_memory,
+            breakpoints: Breakpoints::default(),
+            num_vcpus,
+        })
+    }
+
+    /// Serve the debugger while vCPU `index` is stopped, until it must run again.
+    ///
+    /// Waits for a debugger to connect if none is. Losing the connection detaches it. Once the VM
+    /// stops, the vCPU is told to stop too.
+    pub fn stopped(&mut self, target: &dyn Target, index: u64, control: &VcpuControl) -> Resume {
+        match self.serve(target, index, control) {
+            Ok(resume) => resume,
+            Err(_) if control.stopping() => Resume::Kill,
+            Err(e) => {
+                warn!("gdb connection lost: {}", e);
+                self.detach();
+                Resume::Detach
+            }
+        }
+    }
+
+    fn serve(
+        &mut self,
+        target: &dyn Target,
+        index: u64,
+        control: &VcpuControl,
+    ) -> io::Result<Resume> {
+        let (mut client, attached) = match self.client.take() {
+            Some(client) => (client, true),
+            None => (self.accept(control)?, false),
+        };
+
+        let mut connection = Connection {
+            stream: &mut client,
+            control,
+        };
+        // A new debugger asks why the vCPU stopped by itself.
+        if attached {
+            write_packet(&mut connection, stop_reply(index).as_bytes())?;
+        }
+        let resume = self.serve_packets(&mut connection, target, index)?;
+
+        if resume != Resume::Detach {
+            self.client = Some(client);
+        }
+        Ok(resume)
+    }
+
+    /// Wait for a debugger to connect.
+    fn accept(&self, control: &VcpuControl) -> io::Result<TcpStream> {
+        warn!("Waiting for gdb on {}", self.listener.local_addr()?);
+
+        loop {
+            match self.listener.accept() {
+                Ok((client, _)) => {
+                    client.set_nonblocking(true)?;
+                    return Ok(client);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    wait_ready(self.listener.as_raw_fd(), libc::POLLIN, control)?
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Handle the debugger commands until the vCPU must run again.
+    fn serve_packets(
+        &mut self,
+        client: &mut Connection,
+        target: &dyn Target,
+        index: u64,
+    ) -> io::Result<Resume> {
+        loop {
+            let packet = match read_packet(client)? {
+                Packet::Command(packet) => packet,
+                // The vCPU is already stopped.
+                Packet::Interrupt => continue,
+            };
+
+            match self.handle(target, index, &String::from_utf8_lossy(&packet)) {
+                Reply::Packet(payload) => write_packet(client, payload.as_bytes())?,
+                Reply::Resume(Resume::Detach) => {
+                    write_packet(client, b"OK")?;
+                    self.detach();
+                    return Ok(Resume::Detach);
+                }
+                Reply::Resume(resume) => return Ok(resume),
+            }
+        }
+    }
+
+    /// Drop the debugger, and the breakpoints it left.
+    fn detach(&mut self) {
+        self.client = None;
+        if let Err(e) = self.breakpoints.clear(&self.guest_memory) {
+            error!("Failed to remove gdb breakpoints: {}", e);
+        }
+    }
+
+    /// Translate `len` bytes from `gva` into guest physical ranges, one per page.
+    fn translate(target: &dyn Target, gva: u64, len: usize) -> Option<Vec<(u64, usize)>> {
+        let mut ranges = Vec::new();
+        let mut addr = gva;
+        let end = gva.checked_add(len as u64)?;
+
+        while addr < end {
+            let chunk = std::cmp::min(end - addr, PAGE_SIZE - addr % PAGE_SIZE);
+            ranges.push((target.translate(addr)?, chunk as usize));
+            addr += chunk;
+        }
+
+        Some(ranges)
+    }
+
+    fn read_memory(&self, target: &dyn Target, gva: u64, len: usize) -> Option<Vec<u8>> {
+        let mut data = Vec::with_capacity(len);
+
+        for (gpa, len) in Self::translate(target, gva, len)? {
+            let mut chunk = vec![0u8; len];
+            self.guest_memory
+                .read_slice(&mut chunk, GuestAddress(gpa))
+                .ok()?;
+            self.breakpoints.unpatch(gpa, &mut chunk);
+            data.extend_from_slice(&chunk);
+        }
+
+        Some(data)
+    }
+
+    fn write_memory(&mut self, target: &dyn Target, gva: u64, data: &[u8]) -> Option<()> {
+        let mut data = data;
+
+        for (gpa, len) in Self::translate(target, gva, data.len())? {
+            let (chunk, rest) = data.split_at(len);
+            self.breakpoints
+                .write(&self.guest_memory, gpa, chunk)
+                .ok()?;
+            data = rest;
+        }
+
+        Some(())
+    }
+
+    /// Resume execution, at `addr` if given.
+    fn resume(target: &dyn Target, addr: &str, resume: Resume) -> Reply {
+        if !addr.is_empty() {
+            let set_rip = parse_u64(addr).and_then(|rip| {
+                let (mut regs, _) = target.registers().ok()?;
+                regs.rip = rip;
+                target.set_registers(&regs).ok()
+            });
+            if set_rip.is_none() {
+                return REPLY_ERROR.into();
+            }
+        }
+
+        Reply::Resume(resume)
+    }
+
+    fn handle(&mut self, target: &dyn Target, index: u64, packet: &str) -> Reply {
+        // Threads are numbered from 1, as 0 means any thread.
+        let thread = index + 1;
+        let num_vcpus = self.num_vcpus;
+        let known_thread = |id: &str| match id {
+            "0" | "-1" => true,
+            id => matches!(parse_u64(id), Some(id) if (1..=num_vcpus).contains(&id)),
+        };
+
+        let (command, args) = split_command(packet);
+        match command {
+            "?" => Reply::Packet(stop_reply(index)),
+            "g" => match target.registers() {
+                Ok((regs, sregs)) => Reply::Packet(encode_registers(&regs, &sregs)),
+                Err(_) => REPLY_ERROR.into(),
+            },
+            "G" => {
+                let written = target.registers().ok().and_then(|(mut regs, _)| {
+                    decode_registers(args, &mut regs)?;
+                    target.set_registers(&regs).ok()
+                });
+                written.map_or(REPLY_ERROR.into(), |_| "OK".into())
+            }
+            "m" => match parse_range(args) {
+                Some((_, len)) if len > MAX_MEMORY_LEN => REPLY_ERROR.into(),
+                range => range
+                    .and_then(|(gva, len)| self.read_memory(target, gva, len))
+                    .map_or(REPLY_FAULT.into(), |data| Reply::Packet(hex_encode(&data))),
+            },
+            "M" => {
+                let (range, data) = args.split_once(':').unwrap_or((args, ""));
+                match parse_range(range) {
+                    Some((_, len)) if len > MAX_MEMORY_LEN => REPLY_ERROR.into(),
+                    range => {
+                        let written = range.and_then(|(gva, len)| {
+                            let data = hex_decode(data).filter(|data| data.len() == len)?;
+                            self.write_memory(target, gva, &data)
+                        });
+                        written.map_or(REPLY_FAULT.into(), |_| "OK".into())
+                    }
+                }
+            }
+            "Z" | "z" => {
+                // Only software breakpoints are supported.
+                let gpa = match args
+                    .strip_prefix("0,")
+                    .and_then(|args| args.split_once(','))
+                {
+                    Some((addr, _kind)) => parse_u64(addr).and_then(|gva| target.translate(gva)),
+                    None => return "".into(),
+                };
+                let done = gpa.and_then(|gpa| {
+                    if command == "Z" {
+                        self.breakpoints.insert(&self.guest_memory, gpa).ok()
+                    } else {
+                        self.breakpoints.remove(&self.guest_memory, gpa).ok()
+                    }
+                });
+                done.map_or(REPLY_FAULT.into(), |_| "OK".into())
+            }
+            "c" => Self::resume(target, args, Resume::Continue),
+            "s" => Self::resume(target, args, Resume::Step),
+            "D" => Reply::Resume(Resume::Detach),
+            "k" => Reply::Resume(Resume::Kill),
+            // Only the stopped vCPU can be inspected, the others keep running.
+            "H" => match split_command(args) {
+                ("g", id) if id == "0" || id == "-1" || parse_u64(id) == Some(thread) => {
+                    "OK".into()
+                }
+                ("c", id) if known_thread(id) => "OK".into(),
+                _ => REPLY_ERROR.into(),
+            },
+            "T" if known_thread(args) => "OK".into(),
+            "T" => REPLY_ERROR.into(),
+            "q" => match args {
+                _ if args.starts_with("Supported") => {
+                    Reply::Packet(format!("PacketSize={:x}", PACKET_SIZE))
+                }
+                "Attached" => "1".into(),
+                "C" => Reply::Packet(format!("QC{:x}", thread)),
+                "fThreadInfo" => Reply::Packet(format!(
+                    "m{}",
+                    (1..=self.num_vcpus)
+                        .map(|id| format!("{:x}", id))
+                        .collect::<Vec<_>>()
+                        .join(",")
+                )),
+                "sThreadInfo" => "l".into(),
+                _ => "".into(),
+            },
+            // Unsupported commands get an empty reply.
+            _ => "".into(),
+        }
+    }
+}
+
+/// Split the one letter command from its arguments.
+fn split_command(packet: &str) -> (&str, &str) {
+    if packet.is_char_boundary(1) {
+        packet.split_at(1)
+    } else {
+        ("", packet)
+    }
+}
+
+/// Report vCPU `index` stopping on a trap.
+fn stop_reply(index: u64) -> String {
+    format!("T{:02x}thread:{:x};", STOP_SIGNAL, index + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+
+    use kvm_bindings::kvm_segment;
+
+    // Byte stream fed with what the debugger sends, recording what it receives.
+    #[derive(Default)]
+    struct Stream {
+        input: VecDeque<u8>,
+        output: Vec<u8>,
+    }
+
+    impl Stream {
+        fn new(input: &[u8]) -> Self {
+            Stream {
+                input: input.iter().copied().collect(),
+                output: Vec::new(),
+            }
+        }
+    }
+
+    impl Read for Stream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Stream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // vCPU with paging enabled, mapping virtual addresses 0x8000_0000 higher than physical ones.
+    #[derive(Default)]
+    struct FakeVcpu {
+        regs: RefCell<kvm_regs>,
+        sregs: kvm_sregs,
+    }
+
+    const VIRT_OFFSET: u64 = 0x8000_0000;
+
+    impl Target for FakeVcpu {
+        fn registers(&self) -> io::Result<(kvm_regs, kvm_sregs)> {
+            Ok((*self.regs.borrow(), self.sregs))
+        }
+
+        fn set_registers(&self, regs: &kvm_regs) -> io::Result<()> {
+            *self.regs.borrow_mut() = *regs;
+            Ok(())
+        }
+
+        fn translate(&self, gva: u64) -> Option<u64> {
+            gva.checked_sub(VIRT_OFFSET)
+        }
+
+        fn set_single_step(&self, _step: bool) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn stub(num_vcpus: u64) -> GdbStub {
+        let guest_memory = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        GdbStub::bind("tcp:127.0.0.1:0", guest_memory, num_vcpus).unwrap()
+    }
+
+    #[test]
+    fn addresses() {
+        assert_eq!(parse_address("tcp::1234").unwrap(), "127.0.0.1:1234");
+        assert_eq!(parse_address("tcp:0.0.0.0:1234").unwrap(), "0.0.0.0:1234");
+        assert!(parse_address("1234").is_err());
+        assert!(parse_address("tcp:localhost").is_err());
+        assert!(parse_address("tcp::gdb").is_err());
+    }
+
+    #[test]
+    fn packet_framing() {
+        let mut stream = Stream::default();
+        write_packet(&mut stream, b"OK").unwrap();
+        assert_eq!(stream.output, b"$OK#9a");
+
+        // Acknowledgments are skipped, and packets acknowledged.
+        let mut stream = Stream::new(b"+$g#67$?#3f");
+        assert_eq!(
+            read_packet(&mut stream).unwrap(),
+            Packet::Command(b"g".to_vec())
+        );
+        assert_eq!(
+            read_packet(&mut stream).unwrap(),
+            Packet::Command(b"?".to_vec())
+        );
+        assert_eq!(stream.output, b"++");
+
+        // Corrupted packets are asked for again.
+        let mut stream = Stream::new(b"$g#00$g#67");
+        assert_eq!(
+            read_packet(&mut stream).unwrap(),
+            Packet::Command(b"g".to_vec())
+        );
+        assert_eq!(stream.output, b"-+");
+
+        let mut stream = Stream::new(b"\x03");
+        assert_eq!(read_packet(&mut stream).unwrap(), Packet::Interrupt);
+
+        // Truncated packets are reported.
+        let mut stream = Stream::new(b"$g#6");
+        assert!(read_packet(&mut stream).is_err());
+    }
+
+    #[test]
+    fn registers() {
+        let vcpu = FakeVcpu {
+            regs: RefCell::new(kvm_regs {
+                rax: 0x1122_3344_5566_7788,
+                rip: 0xffff_ffff_8100_0000,
+                rflags: 0x246,
+                ..Default::default()
+            }),
+            sregs: kvm_sregs {
+                cs: kvm_segment {
+                    selector: 0x10,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        };
+        let mut stub = stub(1);
+
+        let regs = match stub.handle(&vcpu, 0, "g") {
+            Reply::Packet(regs) => regs,
+            reply => panic!("unexpected reply {:?}", reply),
+        };
+        // 17 64-bit registers, then the flags and 6 selectors on 32 bits.
+        assert_eq!(regs.len(), (17 * 8 + 7 * 4) * 2);
+        assert!(regs.starts_with("8877665544332211"));
+        assert_eq!(&regs[16 * 16..17 * 16], "00000081ffffffff");
+        assert_eq!(&regs[17 * 16..17 * 16 + 16], "4602000010000000");
+
+        // Writing the registers back changes the general purpose ones only.
+        let regs = regs.replacen("8877665544332211", "0100000000000000", 1);
+        assert_eq!(stub.handle(&vcpu, 0, &format!("G{}", regs)), "OK".into());
+        assert_eq!(vcpu.regs.borrow().rax, 1);
+        assert_eq!(vcpu.regs.borrow().rflags, 0x246);
+        assert_eq!(stub.handle(&vcpu, 0, "G00"), REPLY_ERROR.into());
+    }
+
+    #[test]
+    fn memory() {
+        let vcpu = FakeVcpu::default();
+        let mut stub = stub(1);
+
+        // Writes may cross pages.
+        assert_eq!(stub.handle(&vcpu, 0, "M80000ffe,4:deadbeef"), "OK".into());
+        assert_eq!(stub.handle(&vcpu, 0, "m80000ffe,4"), "deadbeef".into());
+        let mut data = [0u8; 4];
+        stub.guest_memory
+            .read_slice(&mut data, GuestAddress(0xffe))
+            .unwrap();
+        assert_eq!(data, [0xde, 0xad, 0xbe, 0xef]);
+
+        // Unmapped and out of guest memory addresses are reported.
+        assert_eq!(stub.handle(&vcpu, 0, "m1000,4"), REPLY_FAULT.into());
+        assert_eq!(stub.handle(&vcpu, 0, "m8000fffe,4"), REPLY_FAULT.into());
+        assert_eq!(stub.handle(&vcpu, 0, "M80000000,2:00"), REPLY_FAULT.into());
+
+        // Accesses must fit in a packet.
+        assert_eq!(
+            stub.handle(&vcpu, 0, "m80000000,800"),
+            Reply::Packet("00".repeat(0x800))
+        );
+        assert_eq!(stub.handle(&vcpu, 0, "m80000000,801"), REPLY_ERROR.into());
+        assert_eq!(
+            stub.handle(&vcpu, 0, "mffffffffffffffff,ffffffffffffffff"),
+            REPLY_ERROR.into()
+        );
+        assert_eq!(
+            stub.handle(&vcpu, 0, "M80000000,801:00"),
+            REPLY_ERROR.into()
+        );
+    }
+
+    #[test]
+    fn breakpoints_wrap_around() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut breakpoints = Breakpoints::default();
+
+        let mut data = [0u8; 2];
+        breakpoints.unpatch(u64::MAX, &mut data);
+        assert!(breakpoints.write(&mem, u64::MAX, &data).is_err());
+    }
+
+    #[test]
+    fn breakpoints() {
+        let vcpu = FakeVcpu::default();
+        let mut stub = stub(1);
+        let mem = stub.guest_memory.clone();
+        mem.write_slice(&[0x90, 0x90], GuestAddress(0x2000))
+            .unwrap();
+
+        assert_eq!(stub.handle(&vcpu, 0, "Z0,80002000,1"), "OK".into());
+        assert_eq!(mem.read_obj::<u8>(GuestAddress(0x2000)).unwrap(), INT3);
+        // Inserting twice keeps the original byte around.
+        assert_eq!(stub.handle(&vcpu, 0, "Z0,80002000,1"), "OK".into());
+
+        // The debugger sees the original bytes, and updates them.
+        assert_eq!(stub.handle(&vcpu, 0, "m80002000,2"), "9090".into());
+        assert_eq!(stub.handle(&vcpu, 0, "M80002000,2:c3c3"), "OK".into());
+        assert_eq!(mem.read_obj::<u8>(GuestAddress(0x2000)).unwrap(), INT3);
+        assert_eq!(mem.read_obj::<u8>(GuestAddress(0x2001)).unwrap(), 0xc3);
+
+        assert_eq!(stub.handle(&vcpu, 0, "z0,80002000,1"), "OK".into());
+        assert_eq!(mem.read_obj::<u8>(GuestAddress(0x2000)).unwrap(), 0xc3);
+
+        // Hardware breakpoints and watchpoints are not supported.
+        assert_eq!(stub.handle(&vcpu, 0, "Z1,80002000,1"), "".into());
+
+        // Detaching removes the breakpoints left.
+        assert_eq!(stub.handle(&vcpu, 0, "Z0,80002001,1"), "OK".into());
+        assert_eq!(stub.handle(&vcpu, 0, "D"), Reply::Resume(Resume::Detach));
+        stub.detach();
+        assert_eq!(mem.read_obj::<u8>(GuestAddress(0x2001)).unwrap(), 0xc3);
+    }
+
+    #[test]
+    fn execution_control() {
+        let vcpu = FakeVcpu::default();
+        let mut stub = stub(2);
+
+        assert_eq!(stub.handle(&vcpu, 1, "?"), "T05thread:2;".into());
+        assert_eq!(stub.handle(&vcpu, 1, "c"), Reply::Resume(Resume::Continue));
+        assert_eq!(
+            stub.handle(&vcpu, 1, "s80001000"),
+            Reply::Resume(Resume::Step)
+        );
+        assert_eq!(vcpu.regs.borrow().rip, 0x8000_1000);
+        assert_eq!(stub.handle(&vcpu, 1, "k"), Reply::Resume(Resume::Kill));
+    }
+
+    #[test]
+    fn threads() {
+        let vcpu = FakeVcpu::default();
+        let mut stub = stub(2);
+
+        assert_eq!(stub.handle(&vcpu, 1, "qfThreadInfo"), "m1,2".into());
+        assert_eq!(stub.handle(&vcpu, 1, "qsThreadInfo"), "l".into());
+        assert_eq!(stub.handle(&vcpu, 1, "qC"), "QC2".into());
+        assert_eq!(stub.handle(&vcpu, 1, "T1"), "OK".into());
+        assert_eq!(stub.handle(&vcpu, 1, "T3"), REPLY_ERROR.into());
+
+        // Only the stopped vCPU registers can be read.
+        assert_eq!(stub.handle(&vcpu, 1, "Hg2"), "OK".into());
+        assert_eq!(stub.handle(&vcpu, 1, "Hg0"), "OK".into());
+        assert_eq!(stub.handle(&vcpu, 1, "Hg1"), REPLY_ERROR.into());
+        assert_eq!(stub.handle(&vcpu, 1, "Hc-1"), "OK".into());
+    }
+}
@@ -0,0 +1,612 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Confining the VMM process before the VM is created.
+//!
+//! `/dev/kvm` is opened first, while it is reachable and the process is privileged. The VMM then
+//! moves to new mount, PID, network and UTS namespaces, chroots into a directory holding only the
+//! files it needs (bind-mounted read-only), applies resource limits and drops its privileges.
+//! Paths opened afterwards, e.g. the console file, are inside the jail.
+//!
+//! The process that entered the PID namespace stays outside of the jail. It forwards the
+//! termination signals to the jailed VMM, hands the systemd main process role over to it, and
+//! removes the pidfile once it exits.
+
+use std::ffi::CString;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::ptr;
+use std::result;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+use libc::c_int;
+
+use crate::daemon::Pidfile;
+use crate::notify::SystemdNotifier;
+
+/// Namespaces the VMM moves to.
+const NAMESPACES: c_int =
+    libc::CLONE_NEWNS | libc::CLONE_NEWPID | libc::CLONE_NEWNET | libc::CLONE_NEWUTS;
+
+/// Signals the process outside of the jail forwards to the VMM.
+const FORWARDED_SIGNALS: [c_int; 3] = [libc::SIGTERM, libc::SIGINT, libc::SIGHUP];
+
+/// PID of the jailed VMM, signals are forwarded to.
+static JAILED_PID: AtomicI32 = AtomicI32::new(0);
+
+const KVM_PATH: &str = "/dev/kvm";
+
+/// Errors confining the VMM.
+#[derive(Debug)]
+pub enum Error {
+    /// Invalid resource limits, as given.
+    Rlimit(String),
+    /// The path has no file name to be exposed in the jail under.
+    FileName(PathBuf),
+    /// A step of entering the jail failed.
+    Step(&'static str, io::Error),
+}
+
+/// Dedicated Result type.
+pub type Result<T> = result::Result<T, Error>;
+
+/// Resource limited in the jail, named as by `prlimit(1)`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Rlimit {
+    /// Address space size, in bytes.
+    As,
+    /// Core dump size, in bytes.
+    Core,
+    /// Size of the files written, in bytes.
+    Fsize,
+    /// Locked memory, in bytes.
+    Memlock,
+    /// Number of open files.
+    Nofile,
+    /// Number of processes of the user.
+    Nproc,
+}
+
+impl FromStr for Rlimit {
+    type Err = Error;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s {
+            "as" => Ok(Rlimit::As),
+            "core" => Ok(Rlimit::Core),
+            "fsize" => Ok(Rlimit::Fsize),
+            "memlock" => Ok(Rlimit::Memlock),
+            "nofile" => Ok(Rlimit::Nofile),
+            "nproc" => Ok(Rlimit::Nproc),
+            _ => Err(Error::Rlimit(s.to_string())),
+        }
+    }
+}
+
+/// Resource limits, as a list of `<resource>=<limit>` (e.g. `nofile=1024,fsize=0`).
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Rlimits(Vec<(Rlimit, u64)>);
+
+impl FromStr for Rlimits {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        let invalid = || crate::Error::Jail(Error::Rlimit(s.to_string()));
+
+        s.split(',')
+            .filter(|rlimit| !rlimit.is_empty())
+            .map(|rlimit| {
+                let mut rlimit = rlimit.splitn(2, '=');
+                match (rlimit.next(), rlimit.next()) {
+                    (Some(resource), Some(limit)) => Ok((
+                        resource.parse().map_err(|_| invalid())?,
+                        limit.parse().map_err(|_| invalid())?,
+                    )),
+                    _ => Err(invalid()),
+                }
+            })
+            .collect::<result::Result<_, _>>()
+            .map(Rlimits)
+    }
+}
+
+/// How the VMM is confined.
+pub struct JailConfig {
+    /// Directory the VMM is chrooted into, created if needed.
+    pub root: PathBuf,
+    pub uid: u32,
+    pub gid: u32,
+    /// Files exposed read-only at the root of the jail, under their file name.
+    pub files: Vec<PathBuf>,
+    pub rlimits: Rlimits,
+    /// Stay in the host network namespace, for the VMM to listen on host addresses.
+    pub host_network: bool,
+}
+
+impl JailConfig {
+    /// Namespaces the VMM moves to.
+    fn namespaces(&self) -> c_int {
+        if self.host_network {
+            return NAMESPACES & !libc::CLONE_NEWNET;
+        }
+
+        NAMESPACES
+    }
+
+    /// Path of `file`, one of the exposed files, once in the jail.
+    pub fn jailed_path(file: &Path) -> crate::Result<PathBuf> {
+        let name = file
+            .file_name()
+            .ok_or_else(|| crate::Error::Jail(Error::FileName(file.to_path_buf())))?;
+
+        Ok(Path::new("/").join(name))
+    }
+}
+
+/// What the process left outside of the jail does while the VMM runs.
+#[derive(Default)]
+pub struct JailSupervisor {
+    /// Told the jailed VMM is the main process of the service.
+    pub systemd_notifier: Option<SystemdNotifier>,
+    /// Removed once the jailed VMM exits.
+    pub pidfile: Option<Pidfile>,
+}
+
+/// System calls confining the process.
+pub(crate) trait JailOps {
+    fn open(&mut self, path: &Path) -> io::Result<File>;
+    fn unshare(&mut self, namespaces: c_int) -> io::Result<()>;
+    /// Fork, only returning in the child: the parent waits for it and exits with its status.
+    fn fork_and_wait(&mut self) -> io::Result<()>;
+    fn make_mounts_private(&mut self) -> io::Result<()>;
+    /// Expose `source` at `target` read-only, creating `target` if needed.
+    fn bind_read_only(&mut self, source: &Path, target: &Path) -> io::Result<()>;
+    fn chroot(&mut self, root: &Path) -> io::Result<()>;
+    fn setrlimit(&mut self, resource: Rlimit, limit: u64) -> io::Result<()>;
+    fn clear_groups(&mut self) -> io::Result<()>;
+    fn setgid(&mut self, gid: u32) -> io::Result<()>;
+    fn setuid(&mut self, uid: u32) -> io::Result<()>;
+}
+
+/// Confine the process, returning `/dev/kvm` opened beforehand.
+pub(crate) fn enter<O: JailOps>(ops: &mut O, config: &JailConfig) -> Result<File> {
+    let step = |name| move |e| Error::Step(name, e);
+
+    let kvm = ops
+        .open(Path::new(KVM_PATH))
+        .map_err(step("open /dev/kvm"))?;
+
+    ops.unshare(config.namespaces()).map_err(step("unshare"))?;
+    // Only children of the process enter the new PID namespace.
+    ops.fork_and_wait().map_err(step("fork"))?;
+
+    // Keep the mounts below from showing up in the host mount namespace.
+    ops.make_mounts_private()
+        .map_err(step("make mounts private"))?;
+    for file in config.files.iter() {
+        let name = file
+            .file_name()
+            .ok_or_else(|| Error::FileName(file.clone()))?;
+        ops.bind_read_only(file, &config.root.join(name))
+            .map_err(step("bind mount"))?;
+    }
+    ops.chroot(&config.root).map_err(step("chroot"))?;
+
+    // Limits may only be raised while privileged.
+    for (resource, limit) in config.rlimits.0.iter() {
+        ops.setrlimit(*resource, *limit)
+            .map_err(step("setrlimit"))?;
+    }
+
+    // Groups may only be changed while the process is still root.
+    ops.clear_groups().map_err(step("setgroups"))?;
+    ops.setgid(config.gid).map_err(step("setgid"))?;
+    ops.setuid(config.uid).map_err(step("setuid"))?;
+
+    Ok(kvm)
+}
+
+/// Confine the process as configured, returning `/dev/kvm` opened beforehand.
+///
+/// The process must be privileged, and must not have spawned any thread.
+pub fn enter_jail(config: &JailConfig, supervisor: JailSupervisor) -> crate::Result<File> {
+    let mut syscalls = Syscalls {
+        supervisor: Some(supervisor),
+    };
+
+    enter(&mut syscalls, config).map_err(crate::Error::Jail)
+}
+
+fn cstring(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+fn check(ret: c_int) -> io::Result<()> {
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Close the file descriptors past stdio, so that the children own them alone.
+fn close_inherited_fds() -> io::Result<()> {
+    let fds: Vec<RawFd> = fs::read_dir("/proc/self/fd")?
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+        .filter(|fd| *fd > libc::STDERR_FILENO)
+        .collect();
+
+    for fd in fds {
+        // Safe because no file descriptor is used past this point, and the result is ignored:
+        // one of them was the directory listing, already closed.
+        unsafe { libc::close(fd) };
+    }
+
+    Ok(())
+}
+
+extern "C" fn forward_signal(signum: c_int) {
+    let pid = JAILED_PID.load(Ordering::Relaxed);
+    if pid > 0 {
+        // Safe because kill does not access memory.
+        unsafe { libc::kill(pid, signum) };
+    }
+}
+
+extern "C" fn exit_on_signal(signum: c_int) {
+    // Safe because the process exits right away, as it would have without a handler.
+    unsafe { libc::_exit(128 + signum) };
+}
+
+/// Handle the forwarded signals with `handler`.
+fn handle_signals(handler: extern "C" fn(c_int)) -> io::Result<()> {
+    for signum in FORWARDED_SIGNALS.iter() {
+        // Safe because sigaction is plain data.
+        let mut action: libc::sigaction = unsafe { mem::zeroed() };
+        action.sa_sigaction = handler as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        // Safe because the handlers only make async-signal-safe calls, and the result is
+        // checked.
+        check(unsafe { libc::sigaction(*signum, &action, ptr::null_mut()) })?;
+    }
+
+    Ok(())
+}
+
+struct Syscalls {
+    // Taken by the process left outside of the jail.
+    supervisor: Option<JailSupervisor>,
+}
+
+impl JailOps for Syscalls {
+    fn open(&mut self, path: &Path) -> io::Result<File> {
+        OpenOptions::new().read(true).write(true).open(path)
+    }
+
+    fn unshare(&mut self, namespaces: c_int) -> io::Result<()> {
+        // Safe because unshare does not access memory, and the result is checked.
+        check(unsafe { libc::unshare(namespaces) })
+    }
+
+    fn fork_and_wait(&mut self) -> io::Result<()> {
+        let supervisor = self.supervisor.take().unwrap_or_default();
+        // Set before forking, so that no signal is missed.
+        handle_signals(forward_signal)?;
+
+        // Safe because the process is single-threaded, and the result is checked.
+        let pid = unsafe { libc::fork() };
+        match pid {
+            -1 => return Err(io::Error::last_os_error()),
+            0 => {
+                // The pidfile is left to the parent.
+                mem::forget(supervisor.pidfile);
+                // PID 1 of its namespace, the VMM ignores the signals it does not handle.
+                return handle_signals(exit_on_signal);
+            }
+            _ => JAILED_PID.store(pid, Ordering::Relaxed),
+        }
+
+        if let Some(systemd_notifier) = supervisor.systemd_notifier {
+            // systemd keeps watching this process otherwise, which only works with
+            // `NotifyAccess=all`.
+            if let Err(e) = systemd_notifier.main_pid(pid as u32) {
+                warn!("Failed to tell systemd the main PID: {}", e);
+            }
+        }
+        close_inherited_fds()?;
+        let mut status = 0;
+        loop {
+            // Safe because status is valid for writes, and the result is checked.
+            if unsafe { libc::waitpid(pid, &mut status, 0) } >= 0 {
+                break;
+            }
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err(e);
+            }
+        }
+
+        drop(supervisor.pidfile);
+        if libc::WIFEXITED(status) {
+            process::exit(libc::WEXITSTATUS(status));
+        }
+        process::exit(128 + libc::WTERMSIG(status));
+    }
+
+    fn make_mounts_private(&mut self) -> io::Result<()> {
+        let root = cstring(Path::new("/"))?;
+        // Safe because the path is a valid C string, and the result is checked.
+        check(unsafe {
+            libc::mount(
+                ptr::null(),
+                root.as_ptr(),
+                ptr::null(),
+                libc::MS_REC | libc::MS_PRIVATE,
+                ptr::null(),
+            )
+        })
+    }
+
+    fn bind_read_only(&mut self, source: &Path, target: &Path) -> io::Result<()> {
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        File::create(target)?;
+
+        let source = cstring(source)?;
+        let target = cstring(target)?;
+        // Safe because the paths are valid C strings, and the results are checked. Bind mounts
+        // are only made read-only when remounted.
+        unsafe {
+            check(libc::mount(
+                source.as_ptr(),
+                target.as_ptr(),
+                ptr::null(),
+                libc::MS_BIND,
+                ptr::null(),
+            ))?;
+            check(libc::mount(
+                ptr::null(),
+                target.as_ptr(),
+                ptr::null(),
+                libc::MS_BIND | libc::MS_REMOUNT | libc::MS_RDONLY,
+                ptr::null(),
+            ))
+        }
+    }
+
+    fn chroot(&mut self, root: &Path) -> io::Result<()> {
+        let root = cstring(root)?;
+        // Safe because the path is a valid C string, and the result is checked.
+        check(unsafe { libc::chroot(root.as_ptr()) })?;
+
+        std::env::set_current_dir("/")
+    }
+
+    fn setrlimit(&mut self, resource: Rlimit, limit: u64) -> io::Result<()> {
+        let resource = match resource {
+            Rlimit::As => libc::RLIMIT_AS,
+            Rlimit::Core => libc::RLIMIT_CORE,
+            Rlimit::Fsize => libc::RLIMIT_FSIZE,
+            Rlimit::Memlock => libc::RLIMIT_MEMLOCK,
+            Rlimit::Nofile => libc::RLIMIT_NOFILE,
+            Rlimit::Nproc => libc::RLIMIT_NPROC,
+        };
+        let limits = libc::rlimit {
+            rlim_cur: limit,
+            rlim_max: limit,
+        };
+
+        // Safe because the limits are valid for reads, and the result is checked.
+        check(unsafe { libc::setrlimit(resource, &limits) })
+    }
+
+    fn clear_groups(&mut self) -> io::Result<()> {
+        // Safe because no group is read, and the result is checked.
+        check(unsafe { libc::setgroups(0, ptr::null()) })
+    }
+
+    fn setgid(&mut self, gid: u32) -> io::Result<()> {
+        // Safe because setgid does not access memory, and the result is checked.
+        check(unsafe { libc::setgid(gid) })
+    }
+
+    fn setuid(&mut self, uid: u32) -> io::Result<()> {
+        // Safe because setuid does not access memory, and the result is checked.
+        check(unsafe { libc::setuid(uid) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Debug, PartialEq)]
+    enum Call {
+        Open(PathBuf),
+        Unshare(c_int),
+        Fork,
+        MakeMountsPrivate,
+        BindReadOnly(PathBuf, PathBuf),
+        Chroot(PathBuf),
+        Setrlimit(Rlimit, u64),
+        ClearGroups,
+        Setgid(u32),
+        Setuid(u32),
+    }
+
+    // Records the calls, failing the first one matching `fail`.
+    #[derive(Default)]
+    struct RecordedOps {
+        calls: Vec<Call>,
+        fail: Option<fn(&Call) -> bool>,
+    }
+
+    impl RecordedOps {
+        fn record(&mut self, call: Call) -> io::Result<()> {
+            let failed = matches!(self.fail, Some(fail) if fail(&call));
+            self.calls.push(call);
+            if failed {
+                return Err(io::Error::from_raw_os_error(libc::EPERM));
+            }
+
+            Ok(())
+        }
+    }
+
+    impl JailOps for RecordedOps {
+        fn open(&mut self, path: &Path) -> io::Result<File> {
+            self.record(Call::Open(path.to_path_buf()))?;
+            File::open("/dev/null")
+        }
+
+        fn unshare(&mut self, namespaces: c_int) -> io::Result<()> {
+            self.record(Call::Unshare(namespaces))
+        }
+
+        fn fork_and_wait(&mut self) -> io::Result<()> {
+            self.record(Call::Fork)
+        }
+
+        fn make_mounts_private(&mut self) -> io::Result<()> {
+            self.record(Call::MakeMountsPrivate)
+        }
+
+        fn bind_read_only(&mut self, source: &Path, target: &Path) -> io::Result<()> {
+            self.record(Call::BindReadOnly(
+                source.to_path_buf(),
+                target.to_path_buf(),
+            ))
+        }
+
+        fn chroot(&mut self, root: &Path) -> io::Result<()> {
+            self.record(Call::Chroot(root.to_path_buf()))
+        }
+
+        fn setrlimit(&mut self, resource: Rlimit, limit: u64) -> io::Result<()> {
+            self.record(Call::Setrlimit(resource, limit))
+        }
+
+        fn clear_groups(&mut self) -> io::Result<()> {
+            self.record(Call::ClearGroups)
+        }
+
+        fn setgid(&mut self, gid: u32) -> io::Result<()> {
+            self.record(Call::Setgid(gid))
+        }
+
+        fn setuid(&mut self, uid: u32) -> io::Result<()> {
+            self.record(Call::Setuid(uid))
+        }
+    }
+
+    fn config() -> JailConfig {
+        JailConfig {
+            root: PathBuf::from("/srv/jail/vm1"),
+            uid: 1000,
+            gid: 100,
+            files: vec![PathBuf::from("/var/lib/lumper/vmlinux")],
+            rlimits: "nofile=64,fsize=0".parse().unwrap(),
+            host_network: false,
+        }
+    }
+
+    #[test]
+    fn rlimits() {
+        assert_eq!("".parse::<Rlimits>().unwrap(), Rlimits::default());
+        assert_eq!(
+            "nofile=1024,as=1073741824".parse::<Rlimits>().unwrap(),
+            Rlimits(vec![(Rlimit::Nofile, 1024), (Rlimit::As, 1 << 30)])
+        );
+
+        for rlimits in ["cpu=1", "nofile", "nofile=-1", "nofile=many"].iter() {
+            assert!(matches!(
+                rlimits.parse::<Rlimits>(),
+                Err(crate::Error::Jail(Error::Rlimit(_)))
+            ));
+        }
+    }
+
+    #[test]
+    fn jailed_paths() {
+        assert_eq!(
+            JailConfig::jailed_path(Path::new("/var/lib/lumper/vmlinux")).unwrap(),
+            PathBuf::from("/vmlinux")
+        );
+        assert!(matches!(
+            JailConfig::jailed_path(Path::new("/")),
+            Err(crate::Error::Jail(Error::FileName(_)))
+        ));
+    }
+
+    #[test]
+    fn enter_order() {
+        let mut ops = RecordedOps::default();
+        enter(&mut ops, &config()).unwrap();
+
+        assert_eq!(
+            ops.calls,
+            vec![
+                Call::Open(PathBuf::from("/dev/kvm")),
+                Call::Unshare(NAMESPACES),
+                Call::Fork,
+                Call::MakeMountsPrivate,
+                Call::BindReadOnly(
+                    PathBuf::from("/var/lib/lumper/vmlinux"),
+                    PathBuf::from("/srv/jail/vm1/vmlinux")
+                ),
+                Call::Chroot(PathBuf::from("/srv/jail/vm1")),
+                Call::Setrlimit(Rlimit::Nofile, 64),
+                Call::Setrlimit(Rlimit::Fsize, 0),
+                Call::ClearGroups,
+                Call::Setgid(100),
+                Call::Setuid(1000),
+            ]
+        );
+    }
+
+    #[test]
+    fn host_network() {
+        let config = JailConfig {
+            host_network: true,
+            ..config()
+        };
+        let mut ops = RecordedOps::default();
+        enter(&mut ops, &config).unwrap();
+
+        assert_eq!(
+            ops.calls[1],
+            Call::Unshare(libc::CLONE_NEWNS | libc::CLONE_NEWPID | libc::CLONE_NEWUTS)
+        );
+    }
+
+    #[test]
+    fn enter_stops_at_failure() {
+        // The process must never run unconfined with dropped privileges, or the other way round.
+        let mut ops = RecordedOps {
+            fail: Some(|call| matches!(call, Call::Chroot(_))),
+            ..Default::default()
+        };
+        assert!(matches!(
+            enter(&mut ops, &config()),
+            Err(Error::Step("chroot", _))
+        ));
+        assert_eq!(ops.calls.last(), Some(&Call::Chroot(config().root)));
+
+        let mut ops = RecordedOps {
+            fail: Some(|call| matches!(call, Call::Setgid(_))),
+            ..Default::default()
+        };
+        assert!(matches!(
+            enter(&mut ops, &config()),
+            Err(Error::Step("setgid", _))
+        ));
+        assert!(!ops.calls.contains(&Call::Setuid(1000)));
+    }
+}
@@ -3,15 +3,21 @@
 #![cfg(target_arch = "x86_64")]
 
 use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
 use std::path::PathBuf;
 use std::result;
+use std::str::FromStr;
 
-use linux_loader::bootparam::boot_params;
-use linux_loader::cmdline::Cmdline;
+use linux_loader::bootparam::{boot_params, setup_header};
+use linux_loader::cmdline::{Cmdline, Error as CmdlineError};
 use linux_loader::configurator::{linux::LinuxBootConfigurator, BootConfigurator, BootParams};
-use linux_loader::loader::{elf::Elf, load_cmdline, KernelLoader, KernelLoaderResult};
-use vm_memory::{Address, GuestAddress, GuestMemory, GuestMemoryMmap};
+use linux_loader::loader::bzimage::BzImage;
+use linux_loader::loader::elf::{Elf, PvhBootCapability};
+use linux_loader::loader::{load_cmdline, KernelLoader};
+use vm_memory::{Address, ByteValued, Bytes, GuestAddress, GuestMemory, GuestMemoryMmap};
 
+use crate::acpi::RSDP_START;
+use crate::memory::{MMIO_GAP_END, MMIO_GAP_START};
 use crate::{Error, Result};
 
 // x86_64 boot constants. See https://www.kernel.org/doc/Documentation/x86/boot.txt for the full
@@ -27,6 +33,13 @@ const KERNEL_LOADER_OTHER: u8 = 0xff;
 // Header field: `kernel_alignment`. Alignment unit required by a relocatable kernel.
 const KERNEL_MIN_ALIGNMENT_BYTES: u32 = 0x0100_0000;
 
+// First bytes of ELF images.
+const ELF_MAGIC: [u8; 4] = *b"\x7fELF";
+// Offset of the setup header `header` field in bzImages.
+const BZIMAGE_HDR_OFFSET: u64 = 0x202;
+// The 64-bit entry point of bzImages is 0x200 bytes past their load address.
+const BZIMAGE_64BIT_ENTRY_OFFSET: u64 = 0x200;
+
 // Start address for the EBDA (Extended Bios Data Area). Older computers (like the one this VMM
 // emulates) typically use 1 KiB for the EBDA, starting at 0x9fc00.
 // See https://wiki.osdev.org/Memory_Map_(x86) for more information.
@@ -45,6 +58,102 @@ const HIMEM_START: u64 = 0x0010_0000; // 1 MB
 const CMDLINE_START: u64 = 0x0002_0000;
 // Default command line
 const CMDLINE: &str = "console=ttyS0 i8042.nokbd reboot=k panic=1 pci=off";
+/// Default maximum size of the command line, null terminator included (`COMMAND_LINE_SIZE` on
+/// x86).
+pub(crate) const CMDLINE_MAX_SIZE: usize = 2048;
+/// Largest command line size, the command line ending before the EBDA.
+pub(crate) const CMDLINE_SIZE_LIMIT: usize = (EBDA_START - CMDLINE_START) as usize;
+
+// PVH boot constants. See xen/include/public/arch-x86/hvm/start_info.h for the full documentation.
+// Header field: `magic`. Must contain "xEn3" with the 0x80 bit of the "E" set.
+const XEN_HVM_START_MAGIC_VALUE: u32 = 0x336e_c578;
+// Version 1 of the start info structure has the memory map fields.
+const XEN_HVM_START_INFO_VERSION: u32 = 1;
+
+/// Address of the PVH start info structure, handed over to the kernel in ebx.
+pub(crate) const PVH_INFO_START: u64 = 0x6000;
+// Address of the PVH memory map, right after the start info structure.
+const PVH_MEMMAP_START: u64 = 0x6100;
+
+/// Boot protocol used to enter the kernel.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BootProtocol {
+    /// PVH if the kernel supports it, the Linux boot protocol otherwise.
+    Auto,
+    /// Linux 64-bit boot protocol.
+    Linux,
+    /// PVH boot protocol, entering the kernel in 32-bit protected mode.
+    Pvh,
+}
+
+impl Default for BootProtocol {
+    fn default() -> Self {
+        BootProtocol::Auto
+    }
+}
+
+impl FromStr for BootProtocol {
+    type Err = Error;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(BootProtocol::Auto),
+            "linux" => Ok(BootProtocol::Linux),
+            "pvh" => Ok(BootProtocol::Pvh),
+            _ => Err(Error::BootProtocol(s.to_string())),
+        }
+    }
+}
+
+/// Format of the kernel image.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum KernelFormat {
+    /// Uncompressed `vmlinux`.
+    Elf,
+    /// Compressed kernel, with the Linux setup header.
+    BzImage,
+}
+
+/// Where and how the vCPUs enter the kernel.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KernelEntry {
+    /// 64-bit entry point, with the zero page address in rsi.
+    Linux(GuestAddress),
+    /// 32-bit PVH entry point, with the start info address in ebx.
+    Pvh(GuestAddress),
+}
+
+/// PVH start info, `struct hvm_start_info`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct HvmStartInfo {
+    magic: u32,
+    version: u32,
+    flags: u32,
+    nr_modules: u32,
+    modlist_paddr: u64,
+    cmdline_paddr: u64,
+    rsdp_paddr: u64,
+    memmap_paddr: u64,
+    memmap_entries: u32,
+    reserved: u32,
+}
+
+// Safe because HvmStartInfo only contains plain data.
+unsafe impl ByteValued for HvmStartInfo {}
+
+/// PVH memory map entry, `struct hvm_memmap_table_entry`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct HvmMemmapTableEntry {
+    addr: u64,
+    size: u64,
+    type_: u32,
+    reserved: u32,
+}
+
+// Safe because HvmMemmapTableEntry only contains plain data.
+unsafe impl ByteValued for HvmMemmapTableEntry {}
 
 fn add_e820_entry(
     params: &mut boot_params,
@@ -64,14 +173,57 @@ fn add_e820_entry(
     Ok(())
 }
 
+/// Usable RAM regions of the guest, as (address, size) pairs, whatever the boot protocol.
+///
+/// # Arguments
+///
+/// * `guest_memory` - guest memory
+/// * `himem_start` - address where high memory starts.
+fn ram_regions(
+    guest_memory: &GuestMemoryMmap,
+    himem_start: GuestAddress,
+) -> result::Result<Vec<(u64, u64)>, Error> {
+    // Add an entry for EBDA itself.
+    let mut regions = vec![(0, EBDA_START)];
+
+    // Add entries for the usable RAM regions, on both sides of the MMIO gap.
+    let last_addr = guest_memory.last_addr();
+    let mmio_gap_start = GuestAddress(MMIO_GAP_START);
+    let mmio_gap_end = GuestAddress(MMIO_GAP_END);
+
+    if last_addr < mmio_gap_start {
+        regions.push((
+            himem_start.raw_value() as u64,
+            last_addr
+                .checked_offset_from(himem_start)
+                .ok_or(Error::HimemStartPastMemEnd)?
+                + 1,
+        ));
+    } else {
+        regions.push((
+            himem_start.raw_value(),
+            mmio_gap_start
+                .checked_offset_from(himem_start)
+                .ok_or(Error::HimemStartPastMemEnd)?,
+        ));
+
+        if last_addr > mmio_gap_end {
+            regions.push((
+                mmio_gap_end.raw_value(),
+                last_addr.unchecked_offset_from(mmio_gap_end) + 1,
+            ));
+        }
+    }
+
+    Ok(regions)
+}
+
 /// Build boot parameters for ELF kernels following the Linux boot protocol.
 ///
 /// # Arguments
 ///
 /// * `guest_memory` - guest memory
 /// * `himem_start` - address where high memory starts.
-/// * `mmio_gap_start` - address where the MMIO gap starts.
-/// * `mmio_gap_end` - address where the MMIO gap ends.
 pub fn build_bootparams(
     guest_memory: &GuestMemoryMmap,
     himem_start: GuestAddress,
@@ -83,55 +235,178 @@ pub fn build_bootparams(
     params.hdr.kernel_alignment = KERNEL_MIN_ALIGNMENT_BYTES;
     params.hdr.type_of_loader = KERNEL_LOADER_OTHER;
 
-    // Add an entry for EBDA itself.
-    add_e820_entry(&mut params, 0, EBDA_START, E820_RAM)?;
-
-    // Add entries for the usable RAM regions.
-    let last_addr = guest_memory.last_addr();
-    add_e820_entry(
-        &mut params,
-        himem_start.raw_value() as u64,
-        last_addr
-            .checked_offset_from(himem_start)
-            .ok_or(Error::HimemStartPastMemEnd)?,
-        E820_RAM,
-    )?;
+    for (addr, size) in ram_regions(guest_memory, himem_start)? {
+        add_e820_entry(&mut params, addr, size, E820_RAM)?;
+    }
 
     Ok(params)
 }
 
+/// Build the PVH start info and memory map.
+///
+/// # Arguments
+///
+/// * `guest_memory` - guest memory
+/// * `himem_start` - address where high memory starts.
+fn build_pvh_start_info(
+    guest_memory: &GuestMemoryMmap,
+    himem_start: GuestAddress,
+) -> result::Result<(HvmStartInfo, Vec<HvmMemmapTableEntry>), Error> {
+    let memmap: Vec<HvmMemmapTableEntry> = ram_regions(guest_memory, himem_start)?
+        .into_iter()
+        .map(|(addr, size)| HvmMemmapTableEntry {
+            addr,
+            size,
+            type_: E820_RAM,
+            ..Default::default()
+        })
+        .collect();
+
+    let start_info = HvmStartInfo {
+        magic: XEN_HVM_START_MAGIC_VALUE,
+        version: XEN_HVM_START_INFO_VERSION,
+        cmdline_paddr: CMDLINE_START,
+        rsdp_paddr: RSDP_START,
+        memmap_paddr: PVH_MEMMAP_START,
+        memmap_entries: memmap.len() as u32,
+        ..Default::default()
+    };
+
+    Ok((start_info, memmap))
+}
+
+/// Build the kernel command line: the default one, followed by the `extra` arguments.
+pub fn kernel_cmdline(extra: &[String], capacity: usize) -> Result<Cmdline> {
+    let mut cmdline = Cmdline::new(capacity);
+    insert_cmdline_str(&mut cmdline, CMDLINE, capacity)?;
+    for args in extra {
+        insert_cmdline_str(&mut cmdline, args, capacity)?;
+    }
+
+    Ok(cmdline)
+}
+
+/// Append `args` to `cmdline`, naming them and the space left when they do not fit.
+fn insert_cmdline_str(cmdline: &mut Cmdline, args: &str, capacity: usize) -> Result<()> {
+    cmdline.insert_str(args).map_err(|e| match e {
+        CmdlineError::TooLarge => {
+            // One byte is kept for the null terminator, and one separates the arguments.
+            let line = cmdline.as_str();
+            let used = line.len() + 1 + usize::from(!line.is_empty());
+            Error::CmdlineOverflow(args.to_string(), capacity.saturating_sub(used))
+        }
+        e => Error::Cmdline(e),
+    })
+}
+
+/// Write the Linux boot parameters in the zero page.
+///
+/// bzImages come with their own setup header, which we complete.
+fn write_bootparams(
+    guest_memory: &GuestMemoryMmap,
+    image_header: Option<setup_header>,
+    cmdline: &Cmdline,
+) -> Result<()> {
+    let mut bootparams = build_bootparams(guest_memory, GuestAddress(HIMEM_START))?;
+    if let Some(hdr) = image_header {
+        bootparams.hdr = hdr;
+        bootparams.hdr.type_of_loader = KERNEL_LOADER_OTHER;
+    }
+
+    // Add the kernel command line to the boot parameters.
+    bootparams.hdr.cmd_line_ptr = CMDLINE_START as u32;
+    bootparams.hdr.cmdline_size = cmdline.as_str().len() as u32 + 1;
+
+    LinuxBootConfigurator::write_bootparams::<GuestMemoryMmap>(
+        &BootParams::new::<boot_params>(&bootparams, GuestAddress(ZEROPG_START)),
+        guest_memory,
+    )
+    .map_err(Error::BootConfigure)
+}
+
+/// Write the PVH start info and memory map.
+fn write_pvh_start_info(guest_memory: &GuestMemoryMmap) -> Result<()> {
+    let (start_info, memmap) = build_pvh_start_info(guest_memory, GuestAddress(HIMEM_START))?;
+
+    guest_memory
+        .write_obj(start_info, GuestAddress(PVH_INFO_START))
+        .map_err(Error::PvhStartInfo)?;
+    for (index, entry) in memmap.into_iter().enumerate() {
+        let addr = PVH_MEMMAP_START + (index * std::mem::size_of::<HvmMemmapTableEntry>()) as u64;
+        guest_memory
+            .write_obj(entry, GuestAddress(addr))
+            .map_err(Error::PvhStartInfo)?;
+    }
+
+    Ok(())
+}
+
+/// Tell ELF images from bzImages apart, from their magic numbers.
+fn kernel_format<R: Read + Seek>(kernel_image: &mut R) -> Result<KernelFormat> {
+    let mut magic = [0u8; 4];
+    kernel_image.seek(SeekFrom::Start(0)).map_err(Error::IO)?;
+    // Images too short to hold a magic number are reported with the bytes they have.
+    let len = kernel_image.read(&mut magic).map_err(Error::IO)?;
+    if magic == ELF_MAGIC {
+        return Ok(KernelFormat::Elf);
+    }
+
+    let mut header = [0u8; 4];
+    kernel_image
+        .seek(SeekFrom::Start(BZIMAGE_HDR_OFFSET))
+        .map_err(Error::IO)?;
+    if kernel_image.read_exact(&mut header).is_ok()
+        && u32::from_le_bytes(header) == KERNEL_HDR_MAGIC
+    {
+        return Ok(KernelFormat::BzImage);
+    }
+
+    let magic = magic[..len]
+        .iter()
+        .flat_map(|byte| std::ascii::escape_default(*byte))
+        .map(char::from)
+        .collect();
+    Err(Error::KernelFormat(magic))
+}
+
 /// Set guest kernel up.
 ///
 /// # Arguments
 ///
-/// * `kernel_cfg` - [`KernelConfig`](struct.KernelConfig.html) struct containing kernel
-///                  configurations.
+/// * `guest_memory` - guest memory the kernel is loaded into.
+/// * `kernel_path` - path to the kernel image, either an ELF `vmlinux` or a bzImage.
+/// * `boot_protocol` - protocol used to enter the kernel.
+/// * `cmdline_extra` - arguments appended to the default kernel command line.
+/// * `cmdline_size` - maximum size of the command line, null terminator included.
 pub fn kernel_setup(
     guest_memory: &GuestMemoryMmap,
     kernel_path: PathBuf,
-) -> Result<KernelLoaderResult> {
+    boot_protocol: BootProtocol,
+    cmdline_extra: &[String],
+    cmdline_size: usize,
+) -> Result<KernelEntry> {
     let mut kernel_image = File::open(kernel_path).map_err(Error::IO)?;
-    let zero_page_addr = GuestAddress(ZEROPG_START);
+    let kernel_format = kernel_format(&mut kernel_image)?;
 
     // Load the kernel into guest memory.
-    let kernel_load = Elf::load(
-        guest_memory,
-        None,
-        &mut kernel_image,
-        Some(GuestAddress(HIMEM_START)),
-    )
+    let kernel_load = match kernel_format {
+        KernelFormat::Elf => Elf::load(
+            guest_memory,
+            None,
+            &mut kernel_image,
+            Some(GuestAddress(HIMEM_START)),
+        ),
+        KernelFormat::BzImage => BzImage::load(
+            guest_memory,
+            None,
+            &mut kernel_image,
+            Some(GuestAddress(HIMEM_START)),
+        ),
+    }
     .map_err(Error::KernelLoad)?;
 
-    // Generate boot parameters.
-    let mut bootparams = build_bootparams(guest_memory, GuestAddress(HIMEM_START))?;
-
-    // Add the kernel command line to the boot parameters.
-    bootparams.hdr.cmd_line_ptr = CMDLINE_START as u32;
-    bootparams.hdr.cmdline_size = CMDLINE.len() as u32 + 1;
-
     // Load the kernel command line into guest memory.
-    let mut cmdline = Cmdline::new(CMDLINE.len() + 1);
-    cmdline.insert_str(CMDLINE).map_err(Error::Cmdline)?;
+    let cmdline = kernel_cmdline(cmdline_extra, cmdline_size)?;
     load_cmdline(
         guest_memory,
         GuestAddress(CMDLINE_START),
@@ -140,12 +415,224 @@ pub fn kernel_setup(
     )
     .map_err(Error::KernelLoad)?;
 
-    // Write the boot parameters in the zeropage.
-    LinuxBootConfigurator::write_bootparams::<GuestMemoryMmap>(
-        &BootParams::new::<boot_params>(&bootparams, zero_page_addr),
-        guest_memory,
-    )
-    .map_err(Error::BootConfigure)?;
+    let pvh_entry = match kernel_load.pvh_boot_cap {
+        PvhBootCapability::PvhEntryPresent(entry) => Some(entry),
+        _ => None,
+    };
+
+    match (boot_protocol, pvh_entry) {
+        (BootProtocol::Auto, Some(entry)) | (BootProtocol::Pvh, Some(entry)) => {
+            write_pvh_start_info(guest_memory)?;
+            Ok(KernelEntry::Pvh(entry))
+        }
+        (BootProtocol::Pvh, None) => Err(Error::PvhUnsupported),
+        (BootProtocol::Auto, None) | (BootProtocol::Linux, _) => {
+            write_bootparams(guest_memory, kernel_load.setup_header, &cmdline)?;
+            let entry = match kernel_format {
+                KernelFormat::Elf => kernel_load.kernel_load,
+                KernelFormat::BzImage => kernel_load
+                    .kernel_load
+                    .unchecked_add(BZIMAGE_64BIT_ENTRY_OFFSET),
+            };
+            Ok(KernelEntry::Linux(entry))
+        }
+    }
+}
 
-    Ok(kernel_load)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn guest_memory(size: usize) -> GuestMemoryMmap {
+        GuestMemoryMmap::from_ranges(&[(GuestAddress(0), size)]).unwrap()
+    }
+
+    #[test]
+    fn boot_protocol_names() {
+        assert_eq!("auto".parse::<BootProtocol>().unwrap(), BootProtocol::Auto);
+        assert_eq!(
+            "linux".parse::<BootProtocol>().unwrap(),
+            BootProtocol::Linux
+        );
+        assert_eq!("pvh".parse::<BootProtocol>().unwrap(), BootProtocol::Pvh);
+        assert!(matches!(
+            "multiboot".parse::<BootProtocol>(),
+            Err(Error::BootProtocol(name)) if name == "multiboot"
+        ));
+    }
+
+    // Image of `len` bytes, starting with `magic`, and with `header` at the setup header offset.
+    fn image(len: usize, magic: &[u8], header: &[u8]) -> Cursor<Vec<u8>> {
+        let mut image = vec![0u8; len];
+        image[..magic.len()].copy_from_slice(magic);
+        let offset = BZIMAGE_HDR_OFFSET as usize;
+        image[offset..offset + header.len()].copy_from_slice(header);
+        Cursor::new(image)
+    }
+
+    #[test]
+    fn kernel_formats() {
+        let mut elf = image(0x400, b"\x7fELF\x02\x01\x01", &[]);
+        assert_eq!(kernel_format(&mut elf).unwrap(), KernelFormat::Elf);
+
+        let mut bzimage = image(0x400, b"MZ", b"HdrS");
+        assert_eq!(kernel_format(&mut bzimage).unwrap(), KernelFormat::BzImage);
+
+        // The position in the image does not matter.
+        bzimage.seek(SeekFrom::End(0)).unwrap();
+        assert_eq!(kernel_format(&mut bzimage).unwrap(), KernelFormat::BzImage);
+    }
+
+    #[test]
+    fn unknown_kernel_formats() {
+        let mut gzip = image(0x400, b"\x1f\x8b\x08\x00", &[]);
+        assert!(matches!(
+            kernel_format(&mut gzip),
+            Err(Error::KernelFormat(magic)) if magic == "\\x1f\\x8b\\x08\\x00"
+        ));
+
+        // Too short to hold a setup header.
+        let mut truncated = Cursor::new(b"MZ".to_vec());
+        assert!(matches!(
+            kernel_format(&mut truncated),
+            Err(Error::KernelFormat(magic)) if magic == "MZ"
+        ));
+    }
+
+    #[test]
+    fn bzimage_setup_header() {
+        let mem = guest_memory(128 << 20);
+        let hdr = setup_header {
+            header: KERNEL_HDR_MAGIC,
+            version: 0x020f,
+            ..Default::default()
+        };
+        let cmdline = kernel_cmdline(&[], CMDLINE_MAX_SIZE).unwrap();
+        write_bootparams(&mem, Some(hdr), &cmdline).unwrap();
+
+        let params: boot_params = mem.read_obj(GuestAddress(ZEROPG_START)).unwrap();
+        let version = params.hdr.version;
+        let type_of_loader = params.hdr.type_of_loader;
+        let cmd_line_ptr = params.hdr.cmd_line_ptr;
+        assert_eq!(version, 0x020f);
+        assert_eq!(type_of_loader, KERNEL_LOADER_OTHER);
+        assert_eq!(cmd_line_ptr, CMDLINE_START as u32);
+        let cmdline_size = params.hdr.cmdline_size;
+        assert_eq!(cmdline_size, CMDLINE.len() as u32 + 1);
+    }
+
+    #[test]
+    fn cmdline_extra_args() {
+        let extra = [
+            "quiet".to_string(),
+            "init=/bin/sh root=/dev/vda".to_string(),
+        ];
+        assert_eq!(
+            kernel_cmdline(&extra, CMDLINE_MAX_SIZE).unwrap().as_str(),
+            format!("{} quiet init=/bin/sh root=/dev/vda", CMDLINE)
+        );
+
+        // Too long for the kernel.
+        let extra = ["x".repeat(CMDLINE_MAX_SIZE)];
+        assert!(matches!(
+            kernel_cmdline(&extra, CMDLINE_MAX_SIZE),
+            Err(Error::CmdlineOverflow(_, _))
+        ));
+
+        // Fits in a larger command line.
+        assert!(kernel_cmdline(&extra, 2 * CMDLINE_MAX_SIZE).is_ok());
+    }
+
+    #[test]
+    fn cmdline_overflow() {
+        // Room for the default command line, a space, `quiet` and the null terminator.
+        let capacity = CMDLINE.len() + 7;
+        let extra = ["quiet".to_string(), "init=/bin/sh".to_string()];
+
+        match kernel_cmdline(&extra, capacity) {
+            Err(Error::CmdlineOverflow(args, remaining)) => {
+                assert_eq!(args, "init=/bin/sh");
+                assert_eq!(remaining, 0);
+            }
+            _ => panic!("the command line should overflow"),
+        }
+
+        // The space separating the arguments and the null terminator are not counted as left.
+        let extra = ["x".repeat(16)];
+        match kernel_cmdline(&extra, CMDLINE.len() + 8) {
+            Err(Error::CmdlineOverflow(args, remaining)) => {
+                assert_eq!(args, "x".repeat(16));
+                assert_eq!(remaining, 6);
+            }
+            _ => panic!("the command line should overflow"),
+        }
+    }
+
+    #[test]
+    fn pvh_start_info_layout() {
+        assert_eq!(std::mem::size_of::<HvmStartInfo>(), 56);
+        assert_eq!(std::mem::size_of::<HvmMemmapTableEntry>(), 24);
+
+        let mem = guest_memory(128 << 20);
+        let (start_info, memmap) = build_pvh_start_info(&mem, GuestAddress(HIMEM_START)).unwrap();
+        let bytes = start_info.as_slice();
+
+        assert_eq!(&bytes[0..4], &XEN_HVM_START_MAGIC_VALUE.to_le_bytes());
+        assert_eq!(&bytes[4..8], &1u32.to_le_bytes());
+        assert_eq!(&bytes[24..32], &CMDLINE_START.to_le_bytes());
+        assert_eq!(&bytes[32..40], &RSDP_START.to_le_bytes());
+        assert_eq!(&bytes[40..48], &PVH_MEMMAP_START.to_le_bytes());
+        assert_eq!(&bytes[48..52], &2u32.to_le_bytes());
+
+        // Low memory below the EBDA, then high memory up to the end of the guest memory.
+        assert_eq!(memmap.len(), 2);
+        assert_eq!((memmap[0].addr, memmap[0].size), (0, EBDA_START));
+        assert_eq!(
+            (memmap[1].addr, memmap[1].size),
+            (HIMEM_START, (128 << 20) - HIMEM_START)
+        );
+        assert!(memmap.iter().all(|entry| entry.type_ == E820_RAM));
+    }
+
+    #[test]
+    fn ram_regions_above_4g() {
+        const GIB: u64 = 1 << 30;
+
+        let regions = crate::memory::guest_memory_regions(6 * GIB, u64::MAX).unwrap();
+        let mem = GuestMemoryMmap::from_ranges(&regions).unwrap();
+        let ram = ram_regions(&mem, GuestAddress(HIMEM_START)).unwrap();
+
+        // Low memory below the EBDA, high memory up to the MMIO gap, the rest above 4G.
+        assert_eq!(
+            ram,
+            vec![
+                (0, EBDA_START),
+                (HIMEM_START, MMIO_GAP_START - HIMEM_START),
+                (MMIO_GAP_END, 6 * GIB - MMIO_GAP_START),
+            ]
+        );
+
+        // All the guest memory is usable, but the legacy hole between the EBDA and 1 MB.
+        let usable: u64 = ram.iter().map(|(_, size)| size).sum();
+        assert_eq!(usable, 6 * GIB - (HIMEM_START - EBDA_START));
+    }
+
+    #[test]
+    fn pvh_start_info_in_guest_memory() {
+        let mem = guest_memory(128 << 20);
+        write_pvh_start_info(&mem).unwrap();
+
+        let start_info: HvmStartInfo = mem.read_obj(GuestAddress(PVH_INFO_START)).unwrap();
+        assert_eq!(start_info.magic, XEN_HVM_START_MAGIC_VALUE);
+
+        // The memory map does not overlap the start info structure.
+        assert!(PVH_INFO_START + std::mem::size_of::<HvmStartInfo>() as u64 <= PVH_MEMMAP_START);
+        let entry: HvmMemmapTableEntry = mem
+            .read_obj(GuestAddress(
+                PVH_MEMMAP_START + std::mem::size_of::<HvmMemmapTableEntry>() as u64,
+            ))
+            .unwrap();
+        assert_eq!(entry.addr, HIMEM_START);
+    }
 }
@@ -8,27 +8,83 @@ extern crate linux_loader;
 extern crate vm_memory;
 extern crate vm_superio;
 
-use std::io::stdout;
-use std::os::unix::io::AsRawFd;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{stdout, StdinLock};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
 use std::os::unix::prelude::RawFd;
+use std::sync::mpsc;
 use std::sync::{Arc, Mutex};
-use std::thread;
-use std::{io, path::PathBuf};
-use std::fs::File;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
 
-use kvm_bindings::{kvm_userspace_memory_region, KVM_MAX_CPUID_ENTRIES};
+use kvm_bindings::{
+    kvm_irqchip, kvm_userspace_memory_region, KVM_IRQCHIP_IOAPIC, KVM_IRQCHIP_PIC_MASTER,
+    KVM_IRQCHIP_PIC_SLAVE, KVM_MAX_CPUID_ENTRIES, KVM_MEM_LOG_DIRTY_PAGES,
+};
 use kvm_ioctls::{Kvm, VmFd};
-use linux_loader::loader::{self, KernelLoaderResult};
+use linux_loader::loader;
 use vm_memory::{Address, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
+use vmm_sys_util::eventfd::EventFd;
 use vmm_sys_util::terminal::Terminal;
+use vmm_sys_util::timerfd::TimerFd;
+#[macro_use]
+mod logger;
+pub use logger::{init_logger, LogFilter};
 mod cpu;
-use cpu::{cpuid, mptable, Vcpu};
+use cpu::control::VcpuControl;
+use cpu::cpuid::CpuModel;
+use cpu::{affinity, control, cpuid, mptable, Vcpu, VcpuDevices};
 mod devices;
-use devices::serial::LumperSerial;
+use devices::acpi_pm::AcpiPm;
+use devices::boot_notifier::{self, BootNotifier};
+use devices::cmos::Cmos;
+use devices::i8042::LumperI8042;
+use devices::serial::{
+    CaptureWriter, LumperSerial, SerialCapture, StringMatcher, SERIAL_CAPTURE_SIZE, SERIAL_IRQ,
+    SERIAL_OUTPUT_BUFFER_SIZE,
+};
+use devices::shmem::{Shmem, ShmemConfig, SHMEM_IRQ};
+use devices::tcp_console::TcpConsole;
+use devices::watchdog::{Watchdog, WatchdogAction, WatchdogConfig};
 
+mod acpi;
+mod builder;
+pub use builder::VmConfigBuilder;
+mod cgroup;
+pub use cgroup::{Cgroup, CgroupConfig};
+mod crash;
+use crash::{CrashAction, CrashHandler};
+mod daemon;
+pub use daemon::{daemonize, Pidfile, ReadyNotifier};
 mod epoll_context;
 use epoll_context::{EpollContext, EPOLL_EVENTS_LEN};
+mod exit;
+pub use exit::{ExitReporter, VmExitReason};
+mod gdb;
+use gdb::GdbStub;
+mod jail;
+pub use jail::{enter_jail, JailConfig, JailSupervisor};
 mod kernel;
+use kernel::{BootProtocol, KernelEntry};
+mod manager;
+pub use manager::{ManagedVm, VmmManager};
+mod memory;
+use memory::MemoryOptions;
+pub use memory::MemoryUsage;
+mod msr;
+use msr::MsrPolicy;
+mod notify;
+pub use notify::{ReadyOn, SystemdNotifier};
+mod numa;
+pub use numa::NumaPlacement;
+use numa::{HostNode, NumaConfig};
+mod smbios;
+use smbios::{SmbiosConfig, Uuid};
 
 #[derive(Debug)]
 
@@ -38,8 +94,20 @@ pub enum Error {
     BootConfigure(linux_loader::configurator::Error),
     /// Error configuring the kernel command line.
     Cmdline(linux_loader::cmdline::Error),
+    /// These kernel command line arguments do not fit, this many bytes being left.
+    CmdlineOverflow(String, usize),
+    /// Invalid kernel command line size.
+    CmdlineSize(usize),
     /// Failed to load kernel.
     KernelLoad(loader::Error),
+    /// The kernel is neither an ELF image nor a bzImage, it starts with these bytes.
+    KernelFormat(String),
+    /// Unknown boot protocol.
+    BootProtocol(String),
+    /// The kernel has no PVH entry point.
+    PvhUnsupported,
+    /// Failed to write the PVH start info to guest memory.
+    PvhStartInfo(vm_memory::GuestMemoryError),
     /// Invalid E820 configuration.
     E820Configuration,
     /// Highmem start address is past the guest memory end.
@@ -52,6 +120,8 @@ pub enum Error {
     Vcpu(cpu::Error),
     /// Memory error.
     Memory(vm_memory::Error),
+    /// Invalid guest memory configuration.
+    MemoryConfig(memory::Error),
     /// Serial creation error
     SerialCreation(io::Error),
     /// IRQ registration error
@@ -66,18 +136,207 @@ pub enum Error {
     TerminalConfigure(kvm_ioctls::Error),
     /// Console configuration error
     ConsoleError(io::Error),
+    /// TCP console connection error
+    TcpConsole(io::Error),
+    /// Timer creation or configuration error
+    Timer(io::Error),
+    /// i8042 controller creation error
+    I8042Creation(io::Error),
+    /// Invalid CPU affinity, or failure to apply it
+    Affinity(affinity::Error),
+    /// Invalid CPU model
+    CpuModel(cpuid::Error),
+    /// ACPI power management device creation error
+    AcpiPmCreation(io::Error),
+    /// Failed to write the ACPI tables
+    Acpi(acpi::Error),
+    /// Failed to configure or write the SMBIOS tables
+    Smbios(smbios::Error),
+    /// GDB server setup error
+    Gdb(gdb::Error),
+    /// Unknown crash action
+    CrashAction(String),
+    /// Invalid watchdog configuration
+    WatchdogConfig(String),
+    /// Invalid boot notifier port, or already used by another device
+    BootNotifierPort(String),
+    /// Inconsistent VM configuration
+    VmConfig(builder::Error),
+    /// Failed to confine the VMM
+    Jail(jail::Error),
+    /// Failed to limit the VMM resources
+    Cgroup(cgroup::Error),
+    /// The dirty log was not enabled before the memory was configured
+    DirtyLogDisabled,
+    /// Invalid readiness condition
+    ReadyOn(String),
+    /// Invalid log filter
+    LogFilter(String),
+    /// Invalid shared memory configuration
+    ShmemConfig(String),
+    /// Failed to create the shared memory
+    Shmem(io::Error),
+    /// Invalid guest memory options
+    MemoryOptions(String),
+    /// Failed to place the VM on a host NUMA node
+    Numa(numa::Error),
 }
 
+/// Interval at which the watchdog expiry is checked.
+const WATCHDOG_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Number of console lines printed when the deadline expires.
+const TIMEOUT_CONSOLE_LINES: usize = 20;
+
+/// Why the VM stopped, when the guest powered it off through ACPI.
+const ACPI_POWER_OFF: &str = "ACPI power off";
+
+/// Why the VM stopped, when the guest watchdog expired.
+const WATCHDOG_EXPIRED: &str = "watchdog expired";
+
+/// Why the VM stopped, when a vCPU halted.
+pub(crate) const GUEST_HALTED: &str = "HLT";
+
 /// Dedicated [`Result`](https://doc.rust-lang.org/std/result/) type.
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// Tell the user the VM stopped the way the guest meant it to, the only VMM output on stdout.
+fn bye(message: &str) {
+    println!("{}. Bye!", message);
+}
+
+/// Memory currently available on the host, in bytes, read from `/proc/meminfo`.
+pub fn host_available_memory() -> Result<u64> {
+    memory::host_available_memory().map_err(Error::MemoryConfig)
+}
+
+/// Resolve the NUMA placement configured as `node=<host node>[,srat=on|off]`, reading the host
+/// topology from sysfs, see [`VMM::configure_numa`].
+pub fn numa_placement(numa: &str) -> Result<NumaPlacement> {
+    let config: NumaConfig = numa.parse()?;
+    let host_cpus = affinity::host_cpus().map_err(Error::Affinity)?;
+
+    NumaPlacement::resolve(&config, Path::new(numa::SYSFS_ROOT), &host_cpus).map_err(Error::Numa)
+}
+
+/// Guest console configuration.
+pub struct ConsoleConfig {
+    /// File the console output is written to, instead of stdout.
+    pub path: Option<String>,
+    /// TCP address the console is exposed on, instead of stdin and stdout.
+    pub tcp: Option<String>,
+    /// String printed by the guest once it is up, stopping the VM successfully.
+    pub expect_string: Option<String>,
+    /// Size of the buffer between the serial device and the console output, 0 to disable it.
+    pub buffer_size: usize,
+    /// The VMM runs in the background, console input is not read from stdin.
+    pub detached: bool,
+}
+
+impl Default for ConsoleConfig {
+    fn default() -> Self {
+        ConsoleConfig {
+            path: None,
+            tcp: None,
+            expect_string: None,
+            buffer_size: SERIAL_OUTPUT_BUFFER_SIZE,
+            detached: false,
+        }
+    }
+}
+
 pub struct VMM {
     vm_fd: VmFd,
     kvm: Kvm,
     guest_memory: GuestMemoryMmap,
+    // Guest memory regions, as registered with KVM.
+    memory_slots: Vec<kvm_userspace_memory_region>,
+    // Whether KVM tracks the guest pages written.
+    dirty_log: bool,
+    memory_options: MemoryOptions,
+    // Whether the guest memory may exceed the memory available on the host.
+    allow_overcommit: bool,
+    // Memory available on the host, in bytes, if read beforehand.
+    host_available_memory: Option<u64>,
     vcpus: Vec<Vcpu>,
+    // Brand string and features exposed to the guest.
+    cpu_model: CpuModel,
+    // Inject a #GP on accesses to unknown MSRs, instead of ignoring them.
+    strict_msrs: bool,
+    // TSC frequency exposed to the guest, in kHz, the host one otherwise.
+    tsc_khz: Option<u32>,
+    // Machine identity exposed to the guest, a random UUID is generated if unset.
+    smbios: Option<SmbiosConfig>,
+    // Protocol used to enter the kernel.
+    boot_protocol: BootProtocol,
+    // Kernel loaded again each time the guest reboots.
+    kernel_path: PathBuf,
+    // Arguments appended to the default kernel command line.
+    cmdline_extra: Vec<String>,
+    // Maximum size of the kernel command line, null terminator included.
+    cmdline_size: usize,
+    // Pauses the vCPUs while the guest reboots, created along with them.
+    vcpu_control: Arc<VcpuControl>,
+    // State of the interrupt controllers once created, restored when the guest reboots.
+    boot_irqchips: Vec<kvm_irqchip>,
+    // Action taken when the guest crashes, crashes are ignored if unset.
+    on_crash: Option<CrashAction>,
+    // Crash dumps are written under this directory.
+    crash_dump_dir: PathBuf,
+    // Shared with the vCPUs, writes the guest memory of a crash dump once they are stopped.
+    crash_handler: Option<Arc<CrashHandler>>,
+    // Host CPU each vCPU thread is pinned to, by vCPU index.
+    vcpu_affinity: BTreeMap<u8, usize>,
+    // Host CPU the event loop thread is pinned to.
+    event_loop_cpu: Option<usize>,
+    // Host NUMA node the guest memory and the vCPUs not pinned explicitly are bound to.
+    numa_node: Option<HostNode>,
+    // Whether the guest is told it runs on a single NUMA node.
+    srat: bool,
 
     serial: Arc<Mutex<LumperSerial>>,
+    // Last bytes written by the guest on the serial console.
+    serial_capture: Arc<Mutex<SerialCapture>>,
+    // Signaled by the console once the expected string has been printed.
+    expect_evt: EventFd,
+    // When the VM is ready, for the notifiers.
+    ready_on: ReadyOn,
+    // Signaled by the console once the guest printed the readiness string.
+    ready_evt: EventFd,
+    // Keyboard controller, through which the guest resets the machine.
+    i8042: Arc<Mutex<LumperI8042>>,
+    // Signaled when the guest resets the machine through the i8042 controller.
+    reset_evt: EventFd,
+    // RTC, from which the guest reads the wall-clock time.
+    cmos: Arc<Mutex<Cmos>>,
+    // ACPI fixed hardware, through which the guest powers the machine off.
+    acpi_pm: Arc<Mutex<AcpiPm>>,
+    // Signaled when the guest powers the machine off through ACPI.
+    shutdown_evt: EventFd,
+    // Signaled to stop the VM from another thread.
+    stop_evt: EventFd,
+    // Console exposed over TCP instead of stdin/stdout.
+    tcp_console: Option<TcpConsole>,
+    // Whether console input is read from stdin, put in raw mode while the VM runs.
+    stdin_console: bool,
+    // Armed when the VM must not run longer than a deadline.
+    timer: Option<TimerFd>,
+    // Watchdog pinged by the guest, and the action taken when it expires.
+    watchdog: Option<(Arc<Mutex<Watchdog>>, WatchdogAction)>,
+    // Periodically checks whether the watchdog expired.
+    watchdog_timer: Option<TimerFd>,
+    // Measures the time the guest takes to boot, if it notifies it.
+    boot_notifier: Option<Arc<Mutex<BootNotifier>>>,
+    // Memory shared with host processes, mapped next to the guest RAM.
+    shmem: Option<Shmem>,
+    // Debugger server, the boot vCPU waits for a client before running.
+    gdb: Option<Arc<Mutex<GdbStub>>>,
+    // Told once the VM is ready, when the VMM runs in the background.
+    ready_notifier: Option<Arc<ReadyNotifier>>,
+    // Told how the VM is doing, when the VMM runs as a systemd service.
+    systemd_notifier: Option<SystemdNotifier>,
+    // Periodically tells systemd the VMM is alive, if systemd watches it.
+    systemd_watchdog_timer: Option<TimerFd>,
     epoll: EpollContext,
 }
 
@@ -87,36 +346,157 @@ impl VMM {
         // Open /dev/kvm and get a file descriptor to it.
         let kvm = Kvm::new().map_err(Error::KvmIoctl)?;
 
+        Self::with_kvm(kvm)
+    }
+
+    /// Create a new VMM from `/dev/kvm` opened beforehand, e.g. before entering a jail.
+    pub fn with_kvm_file(kvm: File) -> Result<Self> {
+        // Safe because the file descriptor is handed over from the file, which owned it.
+        let kvm = unsafe { Kvm::from_raw_fd(kvm.into_raw_fd()) };
+
+        Self::with_kvm(kvm)
+    }
+
+    fn with_kvm(kvm: Kvm) -> Result<Self> {
         // Create a KVM VM object.
         // KVM returns a file descriptor to the VM object.
         let vm_fd = kvm.create_vm().map_err(Error::KvmIoctl)?;
 
         let epoll = EpollContext::new().map_err(Error::EpollError)?;
-        epoll.add_stdin().map_err(Error::EpollError)?;
+
+        let expect_evt = EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EpollError)?;
+        epoll
+            .add_fd(expect_evt.as_raw_fd())
+            .map_err(Error::EpollError)?;
+        let ready_evt = EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EpollError)?;
+        epoll
+            .add_fd(ready_evt.as_raw_fd())
+            .map_err(Error::EpollError)?;
+
+        let i8042 = LumperI8042::new().map_err(Error::I8042Creation)?;
+        let reset_evt = i8042.reset_eventfd().map_err(Error::I8042Creation)?;
+        let vcpu_control =
+            VcpuControl::new(0, reset_evt.try_clone().map_err(Error::I8042Creation)?)
+                .map_err(Error::IO)?;
+        epoll
+            .add_fd(reset_evt.as_raw_fd())
+            .map_err(Error::EpollError)?;
+
+        let acpi_pm = AcpiPm::new().map_err(Error::AcpiPmCreation)?;
+        let shutdown_evt = acpi_pm.shutdown_eventfd().map_err(Error::AcpiPmCreation)?;
+        epoll
+            .add_fd(shutdown_evt.as_raw_fd())
+            .map_err(Error::EpollError)?;
+
+        let stop_evt = EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EpollError)?;
+        epoll
+            .add_fd(stop_evt.as_raw_fd())
+            .map_err(Error::EpollError)?;
+
+        let serial_capture = Arc::new(Mutex::new(SerialCapture::new(SERIAL_CAPTURE_SIZE)));
+        let output = CaptureWriter::new(Box::new(stdout()), serial_capture.clone(), Vec::new());
 
         let vmm = VMM {
             vm_fd,
             kvm,
             guest_memory: GuestMemoryMmap::default(),
+            memory_slots: Vec::new(),
+            dirty_log: false,
+            memory_options: MemoryOptions::default(),
+            allow_overcommit: false,
+            host_available_memory: None,
             vcpus: vec![],
+            cpu_model: CpuModel::default(),
+            strict_msrs: false,
+            tsc_khz: None,
+            smbios: None,
+            boot_protocol: BootProtocol::default(),
+            kernel_path: PathBuf::new(),
+            cmdline_extra: Vec::new(),
+            cmdline_size: kernel::CMDLINE_MAX_SIZE,
+            vcpu_control: Arc::new(vcpu_control),
+            boot_irqchips: Vec::new(),
+            on_crash: None,
+            crash_dump_dir: PathBuf::from("."),
+            crash_handler: None,
+            vcpu_affinity: BTreeMap::new(),
+            event_loop_cpu: None,
+            numa_node: None,
+            srat: false,
             serial: Arc::new(Mutex::new(
-                LumperSerial::new(Box::new(stdout())).map_err(Error::SerialCreation)?,
+                LumperSerial::new(Box::new(output)).map_err(Error::SerialCreation)?,
             )),
+            serial_capture,
+            expect_evt,
+            ready_on: ReadyOn::default(),
+            ready_evt,
+            i8042: Arc::new(Mutex::new(i8042)),
+            reset_evt,
+            cmos: Arc::new(Mutex::new(Cmos::new())),
+            acpi_pm: Arc::new(Mutex::new(acpi_pm)),
+            shutdown_evt,
+            stop_evt,
+            tcp_console: None,
+            stdin_console: true,
+            timer: None,
+            watchdog: None,
+            watchdog_timer: None,
+            boot_notifier: None,
+            shmem: None,
+            gdb: None,
+            ready_notifier: None,
+            systemd_notifier: None,
+            systemd_watchdog_timer: None,
             epoll,
         };
 
         Ok(vmm)
     }
 
-    pub fn configure_memory(&mut self, mem_size_mb: u32) -> Result<()> {
-        // Convert memory size from MBytes to bytes.
-        let mem_size = ((mem_size_mb as u64) << 20) as usize;
+    pub fn configure_memory(&mut self, mem_size_mb: u32, mem_limit_mb: Option<u32>) -> Result<()> {
+        // Convert memory sizes from MBytes to bytes.
+        let mem_size = (mem_size_mb as u64) << 20;
+        let mem_limit = match mem_limit_mb {
+            Some(mem_limit_mb) => (mem_limit_mb as u64) << 20,
+            // Never allow more memory than the host has.
+            None => memory::host_memory_size().map_err(Error::MemoryConfig)?,
+        };
+
+        // Create the memory regions, from zero to mem_size, around the MMIO gap.
+        let mem_regions =
+            memory::guest_memory_regions(mem_size, mem_limit).map_err(Error::MemoryConfig)?;
 
-        // Create one single memory region, from zero to mem_size.
-        let mem_regions = vec![(GuestAddress(0), mem_size)];
+        // Fail now rather than have the OOM killer step in once the guest uses its memory.
+        if !self.allow_overcommit {
+            let available = match self.host_available_memory {
+                Some(available) => available,
+                None => host_available_memory()?,
+            };
+            memory::check_overcommit(mem_size, available).map_err(Error::MemoryConfig)?;
+            if let Some(node) = &self.numa_node {
+                node.check_memory(mem_size).map_err(Error::Numa)?;
+            }
+        }
 
         // Allocate the guest memory from the memory region.
         let guest_memory = GuestMemoryMmap::from_ranges(&mem_regions).map_err(Error::Memory)?;
+        // Bind the memory before it is faulted in, when locked.
+        if let Some(node) = &self.numa_node {
+            node.bind_guest_memory(&guest_memory).map_err(Error::Numa)?;
+            info!("Guest memory bound to host NUMA node {}", node.id);
+        }
+        if self.memory_options.mlock {
+            memory::lock_guest_memory(&guest_memory).map_err(Error::MemoryConfig)?;
+        }
+        info!(
+            "Guest memory: {} MiB of anonymous memory, {}",
+            mem_size >> 20,
+            if self.memory_options.mlock {
+                "locked"
+            } else {
+                "not locked"
+            }
+        );
 
         // For each memory region in guest_memory:
         // 1. Create a KVM memory region mapping the memory region guest physical address to the host virtual address.
@@ -128,12 +508,24 @@ impl VMM {
                 memory_size: region.len() as u64,
                 // It's safe to unwrap because the guest address is valid.
                 userspace_addr: guest_memory.get_host_address(region.start_addr()).unwrap() as u64,
-                flags: 0,
+                flags: if self.dirty_log {
+                    KVM_MEM_LOG_DIRTY_PAGES
+                } else {
+                    0
+                },
             };
 
             // Register the KVM memory region with KVM.
             unsafe { self.vm_fd.set_user_memory_region(kvm_memory_region) }
                 .map_err(Error::KvmIoctl)?;
+            self.memory_slots.push(kvm_memory_region);
+        }
+
+        // The shared memory is not guest RAM, its writes are not logged.
+        if let Some(shmem) = &self.shmem {
+            let kvm_memory_region = shmem.memory_region(self.memory_slots.len() as u32);
+            unsafe { self.vm_fd.set_user_memory_region(kvm_memory_region) }
+                .map_err(Error::KvmIoctl)?;
         }
 
         self.guest_memory = guest_memory;
@@ -141,6 +533,70 @@ impl VMM {
         Ok(())
     }
 
+    /// Track the guest pages written, see [`dirty_pages`](Self::dirty_pages).
+    ///
+    /// Must be called before the memory is configured.
+    pub fn configure_dirty_log(&mut self, enabled: bool) {
+        self.dirty_log = enabled;
+    }
+
+    /// How the guest memory is allocated, configured as `mlock=on|off`.
+    ///
+    /// Must be called before the memory is configured.
+    pub fn configure_memory_options(&mut self, options: Option<String>) -> Result<()> {
+        if let Some(options) = options {
+            self.memory_options = options.parse()?;
+        }
+
+        Ok(())
+    }
+
+    /// Allow the guest memory to exceed the memory available on the host, which is refused
+    /// otherwise.
+    ///
+    /// Must be called before the memory is configured.
+    pub fn configure_overcommit(&mut self, allowed: bool) {
+        self.allow_overcommit = allowed;
+    }
+
+    /// Check the guest memory against `available` bytes of host memory, read beforehand with
+    /// [`host_available_memory`] while `/proc` is reachable, e.g. before entering a jail. It is
+    /// read when the memory is configured otherwise.
+    ///
+    /// Must be called before the memory is configured.
+    pub fn configure_host_available_memory(&mut self, available: Option<u64>) {
+        self.host_available_memory = available;
+    }
+
+    /// Guest pages written since the dirty log was last fetched, as `(start address, length in
+    /// bytes)` ranges. Fetching the dirty log resets it.
+    pub fn dirty_pages(&self) -> Result<Vec<(GuestAddress, u64)>> {
+        if !self.dirty_log {
+            return Err(Error::DirtyLogDisabled);
+        }
+
+        let mut ranges = Vec::new();
+        for region in self.memory_slots.iter() {
+            let bitmap = self
+                .vm_fd
+                .get_dirty_log(region.slot, region.memory_size as usize)
+                .map_err(Error::KvmIoctl)?;
+            ranges.extend(memory::dirty_page_ranges(
+                GuestAddress(region.guest_phys_addr),
+                &bitmap,
+            ));
+        }
+
+        Ok(ranges)
+    }
+
+    /// Guest memory usage, sampled on request, including while the VM runs.
+    ///
+    /// Must be called once the memory is configured.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        MemoryUsage::new(self.guest_memory.clone())
+    }
+
     pub fn configure_io(&mut self) -> Result<()> {
         // First, create the irqchip.
         // On `x86_64`, this _must_ be created _before_ the vCPUs.
@@ -149,6 +605,23 @@ impl VMM {
         // https://elixir.bootlin.com/linux/latest/source/arch/x86/kvm/x86.c
         self.vm_fd.create_irq_chip().map_err(Error::KvmIoctl)?;
 
+        for chip_id in [
+            KVM_IRQCHIP_PIC_MASTER,
+            KVM_IRQCHIP_PIC_SLAVE,
+            KVM_IRQCHIP_IOAPIC,
+        ]
+        .iter()
+        {
+            let mut irqchip = kvm_irqchip {
+                chip_id: *chip_id,
+                ..Default::default()
+            };
+            self.vm_fd
+                .get_irqchip(&mut irqchip)
+                .map_err(Error::KvmIoctl)?;
+            self.boot_irqchips.push(irqchip);
+        }
+
         self.vm_fd
             .register_irqfd(
                 &self
@@ -157,44 +630,416 @@ impl VMM {
                     .unwrap()
                     .eventfd()
                     .map_err(Error::IrqRegister)?,
-                4,
+                SERIAL_IRQ,
             )
             .map_err(Error::KvmIoctl)?;
 
+        if let Some(shmem) = &self.shmem {
+            self.vm_fd
+                .register_irqfd(shmem.interrupt_evt(), SHMEM_IRQ)
+                .map_err(Error::KvmIoctl)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn configure_console(&mut self, console: ConsoleConfig) -> Result<()> {
+        self.stdin_console = !console.detached;
+
+        let output: Box<dyn io::Write + Send> = match (console.path, console.tcp) {
+            // Clients connecting to the address take over the console.
+            (_, Some(console_tcp)) => {
+                let tcp_console = TcpConsole::bind(&console_tcp).map_err(Error::ConsoleError)?;
+                self.epoll
+                    .add_fd(tcp_console.listener_fd())
+                    .map_err(Error::EpollError)?;
+
+                let writer = tcp_console.writer();
+                self.tcp_console = Some(tcp_console);
+                Box::new(writer)
+            }
+            // We create the file if it does not exist, else we open
+            (Some(console_path), None) => {
+                Box::new(File::create(&console_path).map_err(Error::ConsoleError)?)
+            }
+            (None, None) => Box::new(stdout()),
+        };
+
+        let mut matchers = Vec::new();
+        if let Some(pattern) = console.expect_string {
+            matchers.push((
+                StringMatcher::new(&pattern),
+                self.expect_evt.try_clone().map_err(Error::ConsoleError)?,
+            ));
+        }
+        if let ReadyOn::ConsoleString(pattern) = &self.ready_on {
+            matchers.push((
+                StringMatcher::new(pattern),
+                self.ready_evt.try_clone().map_err(Error::ConsoleError)?,
+            ));
+        }
+
+        let mut serial = self.serial.lock().unwrap();
+        *serial = LumperSerial::buffered(
+            output,
+            console.buffer_size,
+            self.serial_capture.clone(),
+            matchers,
+        )
+        .map_err(Error::SerialCreation)?;
+
         Ok(())
     }
 
-    pub fn configure_console(
+    /// Tell `ready_notifier` once the VM is ready.
+    pub fn configure_ready_notifier(&mut self, ready_notifier: Option<Arc<ReadyNotifier>>) {
+        self.ready_notifier = ready_notifier;
+    }
+
+    /// Tell systemd how the VM is doing: ready, stopping, and alive if it watches the VMM.
+    pub fn configure_systemd_notifier(
         &mut self,
-        console_path: Option<String>
+        systemd_notifier: Option<SystemdNotifier>,
     ) -> Result<()> {
-        if let Some(console_path) = console_path {
-            // We create the file if it does not exist, else we open
-            let file = File::create(&console_path).map_err(Error::ConsoleError)?;
+        let interval = systemd_notifier
+            .as_ref()
+            .and_then(|notifier| notifier.watchdog_interval());
+        if let Some(interval) = interval {
+            let mut timer = TimerFd::new().map_err(Error::Timer)?;
+            timer
+                .reset(interval, Some(interval))
+                .map_err(Error::Timer)?;
+            self.epoll
+                .add_fd(timer.as_raw_fd())
+                .map_err(Error::EpollError)?;
+            self.systemd_watchdog_timer = Some(timer);
+        }
+
+        self.systemd_notifier = systemd_notifier;
+        Ok(())
+    }
+
+    /// When the VM is ready: `vcpus` once the vCPUs are running (the default), or
+    /// `console-string:<string>` once the guest printed `<string>` on the console.
+    ///
+    /// Must be called before the console is configured.
+    pub fn configure_ready_on(&mut self, ready_on: Option<String>) -> Result<()> {
+        if let Some(ready_on) = ready_on {
+            self.ready_on = ready_on.parse()?;
+        }
+
+        Ok(())
+    }
+
+    /// Tell the notifiers the VM is ready, only the first time.
+    fn notify_ready(&mut self) {
+        // The foreground process may have been killed meanwhile, there is nobody left to tell.
+        if let Some(ready_notifier) = self.ready_notifier.take() {
+            let _ = ready_notifier.ready();
+        }
+        if let Some(systemd_notifier) = &self.systemd_notifier {
+            let _ = systemd_notifier.ready();
+        }
+    }
 
-            let mut serial = self.serial.lock().unwrap();
-            *serial = LumperSerial::new(Box::new(file)).map_err(Error::SerialCreation)?;
+    /// Number of console output bytes lost because the output could not keep up with the guest.
+    pub fn console_dropped_bytes(&self) -> u64 {
+        self.serial.lock().unwrap().dropped_bytes()
+    }
+
+    /// At most the `len` last bytes of console output printed by the guest.
+    pub fn console_tail(&self, len: usize) -> String {
+        self.serial_capture.lock().unwrap().tail(len)
+    }
+
+    /// Stop the VM if it is still running after `timeout` seconds.
+    pub fn configure_timeout(&mut self, timeout: Option<u64>) -> Result<()> {
+        if let Some(timeout) = timeout {
+            let mut timer = TimerFd::new().map_err(Error::Timer)?;
+            timer
+                .reset(Duration::from_secs(timeout), None)
+                .map_err(Error::Timer)?;
+            self.epoll
+                .add_fd(timer.as_raw_fd())
+                .map_err(Error::EpollError)?;
+
+            self.timer = Some(timer);
         }
 
         Ok(())
     }
 
-    pub fn configure_vcpus(
+    /// Customize the CPU exposed to the guest.
+    ///
+    /// Must be called before the vCPUs are configured.
+    ///
+    /// # Arguments
+    ///
+    /// * `brand` - processor brand string.
+    /// * `features` - comma separated list of features to enable (`+x2apic`) or disable
+    ///                (`-avx512f`).
+    pub fn configure_cpu_model(
         &mut self,
-        num_vcpus: u8,
-        kernel_load: KernelLoaderResult,
+        brand: Option<String>,
+        features: Option<String>,
     ) -> Result<()> {
+        self.cpu_model =
+            CpuModel::new(brand.as_deref(), features.as_deref()).map_err(Error::CpuModel)?;
+
+        Ok(())
+    }
+
+    /// Set the machine identity read by the guest from the SMBIOS tables.
+    ///
+    /// # Arguments
+    ///
+    /// * `uuid` - system UUID (`xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx`), a random one if `None`.
+    /// * `strings` - comma separated list of `serial=<serial number>` and
+    ///               `product=<product name>`.
+    pub fn configure_smbios(
+        &mut self,
+        uuid: Option<String>,
+        strings: Option<String>,
+    ) -> Result<()> {
+        let mut smbios = SmbiosConfig::new()?;
+        if let Some(uuid) = uuid {
+            smbios.uuid = uuid.parse::<Uuid>()?.0;
+        }
+        if let Some(strings) = strings {
+            smbios.parse_strings(&strings)?;
+        }
+        self.smbios = Some(smbios);
+
+        Ok(())
+    }
+
+    /// System UUID of the VM, once configured.
+    pub fn uuid(&self) -> Option<String> {
+        self.smbios.as_ref().map(SmbiosConfig::uuid_string)
+    }
+
+    /// Set the TSC frequency of the vCPUs, so that it is the same across hosts.
+    ///
+    /// Must be called before the vCPUs are configured.
+    ///
+    /// # Arguments
+    ///
+    /// * `tsc_khz` - TSC frequency in kHz, the host one if `None`.
+    pub fn configure_tsc_frequency(&mut self, tsc_khz: Option<u32>) {
+        self.tsc_khz = tsc_khz;
+    }
+
+    /// Inject a #GP on guest accesses to the MSRs neither KVM nor the VMM know about, instead of
+    /// reading them as 0 and ignoring writes.
+    ///
+    /// Must be called before the vCPUs are configured.
+    pub fn configure_strict_msrs(&mut self, strict: bool) {
+        self.strict_msrs = strict;
+    }
+
+    /// Select the protocol used to enter the kernel.
+    ///
+    /// Must be called before the kernel is loaded.
+    ///
+    /// # Arguments
+    ///
+    /// * `boot_protocol` - `auto`, `linux` or `pvh`, `auto` if `None`.
+    pub fn configure_boot_protocol(&mut self, boot_protocol: Option<String>) -> Result<()> {
+        if let Some(boot_protocol) = boot_protocol {
+            self.boot_protocol = boot_protocol.parse()?;
+        }
+
+        Ok(())
+    }
+
+    /// Append `args` to the default kernel command line.
+    ///
+    /// Must be called before the kernel is loaded.
+    pub fn configure_cmdline(&mut self, args: Option<String>) -> Result<()> {
+        if let Some(args) = args {
+            self.cmdline_extra.push(args);
+            // Fail now rather than when loading the kernel.
+            kernel::kernel_cmdline(&self.cmdline_extra, self.cmdline_size)?;
+        }
+
+        Ok(())
+    }
+
+    /// Set the maximum size of the kernel command line, null terminator included, 2048 bytes if
+    /// `None`. The guest kernel may support less.
+    ///
+    /// Must be called before the kernel is loaded.
+    pub fn configure_cmdline_size(&mut self, size: Option<usize>) -> Result<()> {
+        if let Some(size) = size {
+            if size == 0 || size > kernel::CMDLINE_SIZE_LIMIT {
+                return Err(Error::CmdlineSize(size));
+            }
+            self.cmdline_size = size;
+            kernel::kernel_cmdline(&self.cmdline_extra, self.cmdline_size)?;
+        }
+
+        Ok(())
+    }
+
+    /// Select what happens when the guest crashes: `dump`, `pause` or `shutdown`.
+    pub fn configure_on_crash(&mut self, on_crash: Option<String>) -> Result<()> {
+        if let Some(on_crash) = on_crash {
+            self.on_crash = Some(on_crash.parse()?);
+        }
+
+        Ok(())
+    }
+
+    /// Expose a watchdog to the guest, configured as `action=<action>,timeout=<seconds>`.
+    ///
+    /// Must be called before the vCPUs are configured.
+    pub fn configure_watchdog(&mut self, watchdog: Option<String>) -> Result<()> {
+        if let Some(watchdog) = watchdog {
+            let config: WatchdogConfig = watchdog.parse()?;
+
+            let mut timer = TimerFd::new().map_err(Error::Timer)?;
+            timer
+                .reset(WATCHDOG_CHECK_INTERVAL, Some(WATCHDOG_CHECK_INTERVAL))
+                .map_err(Error::Timer)?;
+            self.epoll
+                .add_fd(timer.as_raw_fd())
+                .map_err(Error::EpollError)?;
+
+            let watchdog = Arc::new(Mutex::new(Watchdog::new(config.timeout)));
+            self.watchdog = Some((watchdog, config.action));
+            self.watchdog_timer = Some(timer);
+        }
+
+        Ok(())
+    }
+
+    /// Share memory with host processes, configured as `size=<size>,socket=<path>`: processes
+    /// connecting to the socket get the memory and the guest doorbell and interrupt eventfds.
+    ///
+    /// Must be called before the VM is configured.
+    pub fn configure_shmem(&mut self, shmem: Option<String>) -> Result<()> {
+        if let Some(shmem) = shmem {
+            let config: ShmemConfig = shmem.parse()?;
+            let shmem = Shmem::new(&config).map_err(Error::Shmem)?;
+            self.epoll
+                .add_fd(shmem.listener_fd())
+                .map_err(Error::EpollError)?;
+
+            self.shmem = Some(shmem);
+        }
+
+        Ok(())
+    }
+
+    /// Report the guest boot time once it writes the boot completion value to `port` (e.g.
+    /// `0x440`).
+    ///
+    /// Must be called before the vCPUs are configured.
+    pub fn configure_boot_notifier(&mut self, port: Option<String>) -> Result<()> {
+        if let Some(port) = port {
+            let port_number = boot_notifier::parse_port(&port)?;
+            if devices::port_in_use(port_number) {
+                return Err(Error::BootNotifierPort(port));
+            }
+
+            self.boot_notifier = Some(Arc::new(Mutex::new(BootNotifier::new(port_number))));
+        }
+
+        Ok(())
+    }
+
+    /// Time the guest took to boot since the VM started or last rebooted, if it notified it.
+    pub fn boot_time(&self) -> Option<Duration> {
+        self.boot_notifier
+            .as_ref()
+            .and_then(|boot_notifier| boot_notifier.lock().unwrap().boot_time())
+    }
+
+    /// Describe the machine to the guest through ACPI and SMBIOS tables.
+    pub fn configure_acpi(&mut self, num_vcpus: u8) -> Result<()> {
+        acpi::setup_acpi(&self.guest_memory, num_vcpus, self.srat).map_err(Error::Acpi)?;
+
+        if self.smbios.is_none() {
+            self.configure_smbios(None, None)?;
+        }
+        if let Some(smbios) = &self.smbios {
+            smbios::setup_smbios(&self.guest_memory, smbios).map_err(Error::Smbios)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn configure_vcpus(&mut self, num_vcpus: u8, kernel_entry: KernelEntry) -> Result<()> {
         mptable::setup_mptable(&self.guest_memory, num_vcpus)
             .map_err(|e| Error::Vcpu(cpu::Error::Mptable(e)))?;
 
-        let base_cpuid = self
+        let mut base_cpuid = self
             .kvm
             .get_supported_cpuid(KVM_MAX_CPUID_ENTRIES)
             .map_err(Error::KvmIoctl)?;
+        cpuid::set_kvm_leaves(&mut base_cpuid).map_err(Error::CpuModel)?;
+
+        let reset_evt = self.reset_evt.try_clone().map_err(Error::IO)?;
+        self.vcpu_control =
+            Arc::new(VcpuControl::new(num_vcpus.into(), reset_evt).map_err(Error::IO)?);
+
+        self.crash_handler = self.on_crash.map(|action| {
+            Arc::new(CrashHandler::new(
+                action,
+                self.guest_memory.clone(),
+                self.crash_dump_dir.clone(),
+            ))
+        });
+
+        // Older kernels keep handling every MSR, following the KVM `ignore_msrs` parameter.
+        let msr_policy = match msr::enable_msr_exits(&self.vm_fd) {
+            Ok(()) => Some(Arc::new(MsrPolicy::new(self.strict_msrs))),
+            Err(e) => {
+                warn!("Unknown MSR accesses are left to KVM: {}", e);
+                None
+            }
+        };
+
+        let devices = VcpuDevices {
+            serial: Arc::clone(&self.serial),
+            i8042: Arc::clone(&self.i8042),
+            cmos: Arc::clone(&self.cmos),
+            acpi_pm: Arc::clone(&self.acpi_pm),
+        };
 
         for index in 0..num_vcpus {
-            let vcpu = Vcpu::new(&self.vm_fd, index.into(), Arc::clone(&self.serial))
-                .map_err(Error::Vcpu)?;
+            let mut vcpu = Vcpu::new(
+                &self.vm_fd,
+                index.into(),
+                devices.clone(),
+                Arc::clone(&self.vcpu_control),
+                self.guest_memory.clone(),
+            )
+            .map_err(Error::Vcpu)?;
+            self.vcpu_control
+                .add_vcpu(&vcpu.vcpu_fd)
+                .map_err(Error::IO)?;
+
+            if let Some(crash_handler) = &self.crash_handler {
+                vcpu.set_crash_handler(Arc::clone(crash_handler));
+            }
+
+            if let Some((watchdog, _)) = &self.watchdog {
+                vcpu.set_watchdog(Arc::clone(watchdog));
+            }
+
+            if let Some(boot_notifier) = &self.boot_notifier {
+                vcpu.set_boot_notifier(Arc::clone(boot_notifier));
+            }
+
+            if let Some(shmem) = &self.shmem {
+                vcpu.set_shmem(shmem.registers());
+            }
+
+            if let Some(msr_policy) = &msr_policy {
+                vcpu.set_msr_policy(Arc::clone(msr_policy))
+                    .map_err(Error::Vcpu)?;
+            }
 
             // Set CPUID.
             let mut vcpu_cpuid = base_cpuid.clone();
@@ -204,15 +1049,19 @@ impl VMM {
                 num_vcpus as usize,
                 &mut vcpu_cpuid,
             );
+            cpuid::apply_cpu_model(&self.cpu_model, &mut vcpu_cpuid);
             vcpu.configure_cpuid(&vcpu_cpuid).map_err(Error::Vcpu)?;
 
+            if let Some(tsc_khz) = self.tsc_khz {
+                vcpu.configure_tsc_khz(tsc_khz).map_err(Error::Vcpu)?;
+            }
+
             // Configure MSRs (model specific registers).
             vcpu.configure_msrs().map_err(Error::Vcpu)?;
 
             // Configure regs, sregs and fpu.
-            vcpu.configure_regs(kernel_load.kernel_load)
-                .map_err(Error::Vcpu)?;
-            vcpu.configure_sregs(&self.guest_memory)
+            vcpu.configure_regs(kernel_entry).map_err(Error::Vcpu)?;
+            vcpu.configure_sregs(&self.guest_memory, kernel_entry)
                 .map_err(Error::Vcpu)?;
             vcpu.configure_fpu().map_err(Error::Vcpu)?;
 
@@ -225,22 +1074,199 @@ impl VMM {
         Ok(())
     }
 
+    /// Pin vCPU threads and the event loop thread to host CPUs.
+    ///
+    /// Must be called once the vCPUs are configured.
+    ///
+    /// # Arguments
+    ///
+    /// * `vcpu_affinity` - comma separated list of `<vcpu>=<host cpu>` entries.
+    /// * `event_loop_cpu` - host CPU for the thread polling the VMM events.
+    pub fn configure_affinity(
+        &mut self,
+        vcpu_affinity: Option<String>,
+        event_loop_cpu: Option<usize>,
+    ) -> Result<()> {
+        let host_cpus = affinity::host_cpus().map_err(Error::Affinity)?;
+
+        if let Some(vcpu_affinity) = vcpu_affinity {
+            self.vcpu_affinity =
+                affinity::parse_affinity(&vcpu_affinity, self.vcpus.len() as u8, &host_cpus)
+                    .map_err(Error::Affinity)?;
+        }
+
+        if let Some(cpu) = event_loop_cpu {
+            if !host_cpus.contains(&cpu) {
+                return Err(Error::Affinity(affinity::Error::InvalidHostCpu(cpu)));
+            }
+            self.event_loop_cpu = Some(cpu);
+        }
+
+        Ok(())
+    }
+
+    /// Bind the guest memory, and the vCPUs not pinned explicitly, to a host NUMA node, as
+    /// resolved by [`numa_placement`]. With `srat`, the guest is told it runs on a single NUMA
+    /// node.
+    ///
+    /// Must be called before the VM is configured.
+    pub fn configure_numa(&mut self, numa: Option<NumaPlacement>) {
+        if let Some(numa) = numa {
+            self.numa_node = Some(numa.node);
+            self.srat = numa.srat;
+        }
+    }
+
+    /// Serve the GDB remote protocol on `addr` (e.g. `tcp::1234`) to debug the guest.
+    ///
+    /// Must be called once the vCPUs are configured.
+    pub fn configure_gdb(&mut self, addr: Option<String>) -> Result<()> {
+        if let Some(addr) = addr {
+            let gdb = GdbStub::bind(&addr, self.guest_memory.clone(), self.vcpus.len() as u64)
+                .map_err(Error::Gdb)?;
+            let gdb = Arc::new(Mutex::new(gdb));
+
+            for vcpu in self.vcpus.iter_mut() {
+                vcpu.attach_gdb(Arc::clone(&gdb)).map_err(Error::Vcpu)?;
+            }
+            self.gdb = Some(gdb);
+        }
+
+        Ok(())
+    }
+
     // Run all virtual CPUs.
-    pub fn run(&mut self) -> Result<()> {
-        for mut vcpu in self.vcpus.drain(..) {
-            println!("Starting vCPU {:?}", vcpu.index);
-            let _ = thread::Builder::new().spawn(move || loop {
-                vcpu.run();
-            });
+    //
+    // Only returns once the VM must be stopped, with the reason why, its vCPUs being stopped.
+    pub fn run(&mut self) -> Result<VmExitReason> {
+        // vCPU threads hand their failures over to this loop, which stops the VM.
+        let (error_tx, error_rx) = mpsc::channel();
+        let error_evt = EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EpollError)?;
+        self.epoll
+            .add_fd(error_evt.as_raw_fd())
+            .map_err(Error::EpollError)?;
+
+        if let Some(cpu) = self.event_loop_cpu {
+            affinity::pin_current_thread(cpu).map_err(Error::Affinity)?;
         }
 
+        control::register_kick_handler().map_err(Error::IO)?;
+
+        // Only locked when the console reads from it, other VMs of the process may run.
         let stdin = io::stdin();
-        let stdin_lock = stdin.lock();
-        stdin_lock
-            .set_raw_mode()
-            .map_err(Error::TerminalConfigure)?;
+        let stdin_lock = if self.stdin_console {
+            let stdin_lock = stdin.lock();
+            self.epoll.add_stdin().map_err(Error::EpollError)?;
+            stdin_lock
+                .set_raw_mode()
+                .map_err(Error::TerminalConfigure)?;
+            Some(stdin_lock)
+        } else {
+            None
+        };
+
+        if let Some(boot_notifier) = &self.boot_notifier {
+            boot_notifier.lock().unwrap().start(Instant::now());
+        }
+        let vcpus = std::mem::take(&mut self.vcpus);
+        let num_vcpus = vcpus.len();
+        let mut vcpu_threads = Vec::with_capacity(num_vcpus);
+
+        for mut vcpu in vcpus {
+            info!("Starting vCPU {}", vcpu.index);
+            let host_cpus = match self.vcpu_affinity.get(&(vcpu.index as u8)) {
+                Some(cpu) => Some(vec![*cpu]),
+                None => self.numa_node.as_ref().map(|node| node.cpus.clone()),
+            };
+            let error_tx = error_tx.clone();
+            let wait_for_gdb = self.gdb.is_some() && vcpu.index == 0;
+            let vcpu_control = Arc::clone(&self.vcpu_control);
+            let vcpu_thread = error_evt.try_clone().and_then(|error_evt| {
+                thread::Builder::new().spawn(move || {
+                    let report = |e| {
+                        vcpu_control.stopped();
+                        let _ = error_tx.send(e);
+                        let _ = error_evt.write(1);
+                    };
+
+                    if let Some(host_cpus) = host_cpus {
+                        if let Err(e) = affinity::pin_current_thread_to(&host_cpus) {
+                            return report(cpu::Error::Affinity(e));
+                        }
+                    }
+
+                    if wait_for_gdb {
+                        if let Err(e) = vcpu.debug_stop() {
+                            return report(e);
+                        }
+                    }
+
+                    while !vcpu_control.stopping() {
+                        if let Err(e) = vcpu.run() {
+                            return report(e);
+                        }
+                    }
+                    vcpu_control.stopped();
+                })
+            });
+
+            match vcpu_thread {
+                Ok(vcpu_thread) => vcpu_threads.push(vcpu_thread),
+                Err(e) => {
+                    // The vCPUs not started never run, the others must be stopped.
+                    for _ in vcpu_threads.len()..num_vcpus {
+                        self.vcpu_control.stopped();
+                    }
+                    self.stop(stdin_lock.as_ref(), vcpu_threads)?;
+                    return Err(Error::IO(e));
+                }
+            }
+        }
+
+        if self.ready_on == ReadyOn::Vcpus {
+            self.notify_ready();
+        }
+
+        let error_fd = error_evt.as_raw_fd();
+        let reason = self.wait_for_exit(&vcpu_threads, stdin_lock.as_ref(), error_fd, &error_rx);
+
+        // The guest must not run anymore once the VMM returns, whatever stopped the VM.
+        self.stop(stdin_lock.as_ref(), vcpu_threads)?;
+        // The guest memory does not change anymore.
+        if let Some(crash_handler) = &self.crash_handler {
+            if let Err(e) = crash_handler.dump_memory() {
+                error!("Failed to write the crash dump: {:?}", e);
+            }
+        }
+        let reason = reason?;
+        self.report_exit(&reason);
+
+        Ok(reason)
+    }
+
+    /// Handle the VMM events until the VM must be stopped, returning why.
+    fn wait_for_exit(
+        &mut self,
+        vcpu_threads: &[JoinHandle<()>],
+        stdin_lock: Option<&StdinLock>,
+        error_fd: RawFd,
+        error_rx: &mpsc::Receiver<cpu::Error>,
+    ) -> Result<VmExitReason> {
         let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); EPOLL_EVENTS_LEN];
         let epoll_fd = self.epoll.as_raw_fd();
+        let expect_fd = self.expect_evt.as_raw_fd();
+        let ready_fd = self.ready_evt.as_raw_fd();
+        let reset_fd = self.reset_evt.as_raw_fd();
+        let shutdown_fd = self.shutdown_evt.as_raw_fd();
+        let stop_fd = self.stop_evt.as_raw_fd();
+        let tcp_listener_fd = self.tcp_console.as_ref().map(|c| c.listener_fd());
+        let shmem_listener_fd = self.shmem.as_ref().map(|shmem| shmem.listener_fd());
+        let timer_fd = self.timer.as_ref().map(|timer| timer.as_raw_fd());
+        let watchdog_timer_fd = self.watchdog_timer.as_ref().map(|timer| timer.as_raw_fd());
+        let systemd_watchdog_fd = self
+            .systemd_watchdog_timer
+            .as_ref()
+            .map(|timer| timer.as_raw_fd());
 
         // Let's start the STDIN polling thread.
         loop {
@@ -249,8 +1275,9 @@ impl VMM {
 
             for event in events.iter().take(num_events) {
                 let event_data = event.data as RawFd;
+                let tcp_client_fd = self.tcp_console.as_ref().and_then(|c| c.client_fd());
 
-                if let libc::STDIN_FILENO = event_data {
+                if let (libc::STDIN_FILENO, Some(stdin_lock)) = (event_data, stdin_lock) {
                     let mut out = [0u8; 64];
 
                     let count = stdin_lock.read_raw(&mut out).map_err(Error::StdinRead)?;
@@ -261,18 +1288,619 @@ impl VMM {
                         .serial
                         .enqueue_raw_bytes(&out[..count])
                         .map_err(Error::StdinWrite)?;
+                } else if Some(event_data) == tcp_listener_fd {
+                    self.accept_tcp_console()?;
+                } else if Some(event_data) == tcp_client_fd {
+                    self.read_tcp_console()?;
+                } else if Some(event_data) == shmem_listener_fd {
+                    if let Some(shmem) = &self.shmem {
+                        // Failing host processes are dropped, the socket keeps listening.
+                        if let Err(e) = shmem.accept() {
+                            warn!("Failed to share the memory: {}", e);
+                        }
+                    }
+                } else if event_data == expect_fd {
+                    return Ok(VmExitReason::ExpectedString);
+                } else if event_data == ready_fd {
+                    let _ = self.ready_evt.read();
+                    self.notify_ready();
+                } else if Some(event_data) == systemd_watchdog_fd {
+                    if let (Some(timer), Some(systemd_notifier)) = (
+                        self.systemd_watchdog_timer.as_mut(),
+                        self.systemd_notifier.as_ref(),
+                    ) {
+                        timer.wait().map_err(Error::Timer)?;
+                        let _ = systemd_notifier.watchdog();
+                    }
+                } else if event_data == reset_fd {
+                    // Requests are coalesced, the guest reboots once.
+                    let _ = self.reset_evt.read();
+
+                    info!("Guest reset, rebooting");
+                    self.reboot(vcpu_threads)?;
+                } else if event_data == shutdown_fd {
+                    return Ok(VmExitReason::Shutdown(ACPI_POWER_OFF.to_string()));
+                } else if event_data == stop_fd {
+                    return Ok(VmExitReason::Shutdown("stopped by the VMM".to_string()));
+                } else if Some(event_data) == timer_fd {
+                    return Ok(VmExitReason::Timeout);
+                } else if Some(event_data) == watchdog_timer_fd {
+                    match self.watchdog_expired()? {
+                        Some(WatchdogAction::Poweroff) => {
+                            return Ok(VmExitReason::GuestCrash(WATCHDOG_EXPIRED.to_string()));
+                        }
+                        Some(WatchdogAction::Reset) => {
+                            warn!("Guest watchdog expired, rebooting");
+                            self.reboot(vcpu_threads)?;
+                        }
+                        Some(WatchdogAction::None) => warn!("Guest watchdog expired"),
+                        None => {}
+                    }
+                } else if event_data == error_fd {
+                    if let Ok(e) = error_rx.try_recv() {
+                        return Ok(match e {
+                            cpu::Error::Stop(reason) => reason,
+                            e => VmExitReason::InternalError(format!("{:?}", Error::Vcpu(e))),
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    /// Reboot the guest in place: pause the vCPUs, put the devices back in their power-on state,
+    /// load the kernel again and resume the vCPUs from its entry point.
+    fn reboot<T>(&mut self, vcpu_threads: &[JoinHandle<T>]) -> Result<()> {
+        self.vcpu_control.pause(vcpu_threads);
+
+        for irqchip in self.boot_irqchips.iter() {
+            self.vm_fd.set_irqchip(irqchip).map_err(Error::KvmIoctl)?;
+        }
+        self.i8042
+            .lock()
+            .unwrap()
+            .reset()
+            .map_err(Error::I8042Creation)?;
+        self.acpi_pm.lock().unwrap().reset();
+        self.serial
+            .lock()
+            .unwrap()
+            .reset()
+            .map_err(Error::SerialCreation)?;
+        self.cmos.lock().unwrap().reset();
+        if let Some(shmem) = &self.shmem {
+            shmem.registers().reset();
+        }
+        if let Some((watchdog, _)) = &self.watchdog {
+            watchdog.lock().unwrap().reset();
+        }
+        if let Some(boot_notifier) = &self.boot_notifier {
+            boot_notifier.lock().unwrap().start(Instant::now());
+        }
+
+        let num_vcpus = vcpu_threads.len() as u8;
+        let kernel_entry = kernel::kernel_setup(
+            &self.guest_memory,
+            self.kernel_path.clone(),
+            self.boot_protocol,
+            &self.cmdline_extra,
+            self.cmdline_size,
+        )?;
+        self.configure_acpi(num_vcpus)?;
+        mptable::setup_mptable(&self.guest_memory, num_vcpus)
+            .map_err(|e| Error::Vcpu(cpu::Error::Mptable(e)))?;
+
+        self.vcpu_control.resume(Some(kernel_entry));
+
+        Ok(())
+    }
+
+    /// Stop the vCPU `threads`, write the pending console output out and give the terminal back,
+    /// before the VM stops.
+    fn stop(
+        &self,
+        stdin_lock: Option<&StdinLock>,
+        vcpu_threads: Vec<JoinHandle<()>>,
+    ) -> Result<()> {
+        if let Some(systemd_notifier) = &self.systemd_notifier {
+            let _ = systemd_notifier.stopping();
+        }
+
+        // The guest memory and devices may go away once the VMM returns.
+        self.vcpu_control.stop(&vcpu_threads);
+        for thread in vcpu_threads {
+            // A vCPU thread panicking already reported it.
+            let _ = thread.join();
+        }
+        self.serial.lock().unwrap().flush_output();
+
+        match stdin_lock {
+            Some(stdin_lock) => stdin_lock
+                .set_canon_mode()
+                .map_err(Error::TerminalConfigure),
+            None => Ok(()),
+        }
+    }
+
+    /// Tell why the VM stopped, once the terminal is given back.
+    fn report_exit(&self, reason: &VmExitReason) {
+        match reason {
+            VmExitReason::Shutdown(details) if details == ACPI_POWER_OFF => {
+                bye("Guest powered off")
+            }
+            VmExitReason::Shutdown(details) if details == GUEST_HALTED => bye("Guest halted"),
+            VmExitReason::GuestCrash(details) if details == WATCHDOG_EXPIRED => {
+                bye("Guest watchdog expired")
+            }
+            VmExitReason::Timeout => warn!(
+                "VM timed out, last console output:\n{}",
+                self.serial_capture
+                    .lock()
+                    .unwrap()
+                    .last_lines(TIMEOUT_CONSOLE_LINES)
+            ),
+            _ => {}
+        }
+        if let Some(warning) = self.console_dropped_warning() {
+            warn!("{}", warning);
+        }
+    }
+
+    /// Tell how much console output was lost, if any.
+    fn console_dropped_warning(&self) -> Option<String> {
+        match self.console_dropped_bytes() {
+            0 => None,
+            dropped => Some(format!(
+                "{} bytes of console output dropped, the console could not keep up with the guest",
+                dropped
+            )),
+        }
+    }
+
+    /// Event stopping the VM once written to, from any thread.
+    pub fn stop_evt(&self) -> Result<EventFd> {
+        self.stop_evt.try_clone().map_err(Error::IO)
+    }
+
+    /// The action to take if the guest stopped pinging the watchdog.
+    fn watchdog_expired(&mut self) -> Result<Option<WatchdogAction>> {
+        if let (Some(timer), Some((watchdog, action))) =
+            (self.watchdog_timer.as_mut(), self.watchdog.as_ref())
+        {
+            timer.wait().map_err(Error::Timer)?;
+
+            if watchdog.lock().unwrap().expired(Instant::now()) {
+                return Ok(Some(*action));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Attach a client connecting to the TCP console.
+    fn accept_tcp_console(&mut self) -> Result<()> {
+        if let Some(tcp_console) = self.tcp_console.as_mut() {
+            // Failing connections are dropped, the console keeps listening.
+            if let Ok(Some(client_fd)) = tcp_console.accept() {
+                self.epoll.add_fd(client_fd).map_err(Error::EpollError)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Forward input from the TCP console client to the guest.
+    fn read_tcp_console(&mut self) -> Result<()> {
+        if let Some(tcp_console) = self.tcp_console.as_mut() {
+            let client_fd = tcp_console.client_fd();
+            let mut out = [0u8; 64];
+
+            let count = tcp_console.read(&mut out).map_err(Error::TcpConsole)?;
+            if count == 0 {
+                // The client left, the VM keeps running until the next one attaches.
+                if let Some(client_fd) = client_fd {
+                    self.epoll.remove_fd(client_fd).map_err(Error::EpollError)?;
                 }
+                return Ok(());
             }
+
+            self.serial
+                .lock()
+                .unwrap()
+                .serial
+                .enqueue_raw_bytes(&out[..count])
+                .map_err(Error::StdinWrite)?;
         }
+
+        Ok(())
     }
 
-    pub fn configure(&mut self, num_vcpus: u8, mem_size_mb: u32, kernel_path: &str, console: Option<String>) -> Result<()> {
+    pub fn configure(
+        &mut self,
+        num_vcpus: u8,
+        mem_size_mb: u32,
+        mem_limit_mb: Option<u32>,
+        kernel_path: &str,
+        console: ConsoleConfig,
+        timeout: Option<u64>,
+    ) -> Result<()> {
         self.configure_console(console)?;
-        self.configure_memory(mem_size_mb)?;
-        let kernel_load = kernel::kernel_setup(&self.guest_memory, PathBuf::from(kernel_path))?;
+        self.configure_timeout(timeout)?;
+        self.configure_memory(mem_size_mb, mem_limit_mb)?;
+        self.kernel_path = PathBuf::from(kernel_path);
+        let kernel_entry = kernel::kernel_setup(
+            &self.guest_memory,
+            self.kernel_path.clone(),
+            self.boot_protocol,
+            &self.cmdline_extra,
+            self.cmdline_size,
+        )?;
         self.configure_io()?;
-        self.configure_vcpus(num_vcpus, kernel_load)?;
+        self.configure_acpi(num_vcpus)?;
+        self.configure_vcpus(num_vcpus, kernel_entry)?;
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use std::io::Write;
+    use std::{env, fs};
+
+    use vm_memory::Bytes;
+    use vmm_sys_util::tempdir::TempDir;
+    use vmm_sys_util::tempfile::TempFile;
+
+    // Where test kernels are loaded and entered.
+    const TEST_KERNEL_ENTRY: u64 = 0x10_0000;
+
+    /// A VM with 16 MiB of memory and a detached console, `None` without KVM.
+    fn detached_vm() -> Option<VMM> {
+        let mut vmm = match VMM::new() {
+            Ok(vmm) => vmm,
+            Err(Error::KvmIoctl(_)) => return None,
+            Err(e) => panic!("{:?}", e),
+        };
+        vmm.configure_overcommit(true);
+        vmm.configure_console(ConsoleConfig {
+            detached: true,
+            ..Default::default()
+        })
+        .unwrap();
+        vmm.configure_memory(16, None).unwrap();
+
+        Some(vmm)
+    }
+
+    /// A VM whose vCPUs spin in the guest until it stops, `None` without KVM.
+    pub(crate) fn spinning_vm(num_vcpus: u8) -> Option<VMM> {
+        let mut vmm = detached_vm()?;
+
+        // `jmp .`, in place of a kernel.
+        let entry = GuestAddress(TEST_KERNEL_ENTRY);
+        vmm.guest_memory.write_slice(&[0xeb, 0xfe], entry).unwrap();
+        vmm.configure_io().unwrap();
+        vmm.configure_vcpus(num_vcpus, KernelEntry::Linux(entry))
+            .unwrap();
+
+        Some(vmm)
+    }
+
+    /// 64-bit ELF kernel image made of `code`, loaded and entered at `TEST_KERNEL_ENTRY`.
+    fn elf_kernel(code: &[u8]) -> Vec<u8> {
+        const EHDR_SIZE: u16 = 64;
+        const PHDR_SIZE: u16 = 56;
+
+        let mut image = b"\x7fELF\x02\x01\x01".to_vec();
+        image.resize(16, 0);
+        // Executable for x86_64, version 1.
+        image.extend_from_slice(&2u16.to_le_bytes());
+        image.extend_from_slice(&62u16.to_le_bytes());
+        image.extend_from_slice(&1u32.to_le_bytes());
+        image.extend_from_slice(&TEST_KERNEL_ENTRY.to_le_bytes());
+        // Program headers right after the ELF header, and no section headers.
+        image.extend_from_slice(&u64::from(EHDR_SIZE).to_le_bytes());
+        image.extend_from_slice(&0u64.to_le_bytes());
+        image.extend_from_slice(&0u32.to_le_bytes());
+        image.extend_from_slice(&EHDR_SIZE.to_le_bytes());
+        image.extend_from_slice(&PHDR_SIZE.to_le_bytes());
+        image.extend_from_slice(&1u16.to_le_bytes());
+        image.extend_from_slice(&[0; 6]);
+
+        // A single loadable RWX segment, the code following the headers.
+        let len = code.len() as u64;
+        image.extend_from_slice(&1u32.to_le_bytes());
+        image.extend_from_slice(&7u32.to_le_bytes());
+        image.extend_from_slice(&u64::from(EHDR_SIZE + PHDR_SIZE).to_le_bytes());
+        image.extend_from_slice(&TEST_KERNEL_ENTRY.to_le_bytes());
+        image.extend_from_slice(&TEST_KERNEL_ENTRY.to_le_bytes());
+        image.extend_from_slice(&len.to_le_bytes());
+        image.extend_from_slice(&len.to_le_bytes());
+        image.extend_from_slice(&0x1000u64.to_le_bytes());
+
+        image.extend_from_slice(code);
+        image
+    }
+
+    /// A VM booting `code` as its kernel, `None` without KVM. `configure` is called before the
+    /// vCPUs are configured. The kernel file is loaded again when the VM reboots, and must be kept
+    /// around.
+    fn booting_vm<F: FnOnce(&mut VMM)>(
+        num_vcpus: u8,
+        code: &[u8],
+        configure: F,
+    ) -> Option<(VMM, TempFile)> {
+        let mut vmm = detached_vm()?;
+        configure(&mut vmm);
+
+        let kernel = TempFile::new().unwrap();
+        kernel.as_file().write_all(&elf_kernel(code)).unwrap();
+        vmm.kernel_path = kernel.as_path().to_path_buf();
+        let entry = kernel::kernel_setup(
+            &vmm.guest_memory,
+            vmm.kernel_path.clone(),
+            vmm.boot_protocol,
+            &vmm.cmdline_extra,
+            vmm.cmdline_size,
+        )
+        .unwrap();
+        assert_eq!(entry, KernelEntry::Linux(GuestAddress(TEST_KERNEL_ENTRY)));
+        vmm.configure_io().unwrap();
+        vmm.configure_vcpus(num_vcpus, entry).unwrap();
+
+        Some((vmm, kernel))
+    }
+
+    #[test]
+    fn stop_vcpus() {
+        let mut vmm = match spinning_vm(2) {
+            Some(vmm) => vmm,
+            None => return,
+        };
+
+        vmm.stop_evt().unwrap().write(1).unwrap();
+        assert_eq!(
+            vmm.run().unwrap(),
+            VmExitReason::Shutdown("stopped by the VMM".to_string())
+        );
+        // The vCPU threads are gone, along with their control.
+        assert_eq!(Arc::strong_count(&vmm.vcpu_control), 1);
+    }
+
+    #[test]
+    fn stop_while_waiting_for_gdb() {
+        let mut vmm = match spinning_vm(1) {
+            Some(vmm) => vmm,
+            None => return,
+        };
+        vmm.configure_gdb(Some("tcp:127.0.0.1:0".to_string()))
+            .unwrap();
+
+        // The boot vCPU waits for a debugger that never connects.
+        let stop_evt = vmm.stop_evt().unwrap();
+        let stopper = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            stop_evt.write(1).unwrap();
+        });
+        assert_eq!(
+            vmm.run().unwrap(),
+            VmExitReason::Shutdown("stopped by the VMM".to_string())
+        );
+        assert_eq!(Arc::strong_count(&vmm.vcpu_control), 1);
+        stopper.join().unwrap();
+    }
+
+    #[test]
+    fn reboot_twice() {
+        // Boot counter, and an empty IDT descriptor.
+        const BOOTS: u64 = 0x20_0000;
+        #[rustfmt::skip]
+        let code = [
+            0xff, 0x04, 0x25, 0x00, 0x00, 0x20, 0x00, // inc dword [BOOTS]
+            0x8b, 0x04, 0x25, 0x00, 0x00, 0x20, 0x00, // mov eax, [BOOTS]
+            0x83, 0xf8, 0x01,                         // cmp eax, 1
+            0x74, 0x11,                               // je i8042_reset
+            0x83, 0xf8, 0x02,                         // cmp eax, 2
+            0x74, 0x12,                               // je triple_fault
+            // Power off through ACPI, with SLP_TYP 5 and SLP_EN in PM1a control.
+            0x66, 0xba, 0x04, 0x06,                   // mov dx, 0x604
+            0x66, 0xb8, 0x00, 0x34,                   // mov ax, 0x3400
+            0x66, 0xef,                               // out dx, ax
+            0xeb, 0xfe,                               // jmp .
+            // i8042_reset: pulse the CPU reset line.
+            0xb0, 0xfe,                               // mov al, 0xfe
+            0xe6, 0x64,                               // out 0x64, al
+            0xeb, 0xfe,                               // jmp .
+            // triple_fault: no IDT to handle the breakpoint, nor the faults it raises.
+            0x0f, 0x01, 0x1c, 0x25, 0x08, 0x00, 0x20, 0x00, // lidt [BOOTS + 8]
+            0xcc,                                     // int3
+        ];
+        let (mut vmm, _kernel) = match booting_vm(1, &code, |_| {}) {
+            Some(vm) => vm,
+            None => return,
+        };
+
+        assert_eq!(
+            vmm.run().unwrap(),
+            VmExitReason::Shutdown(ACPI_POWER_OFF.to_string())
+        );
+        // The kernel was entered again after each reset.
+        let boots: u32 = vmm.guest_memory.read_obj(GuestAddress(BOOTS)).unwrap();
+        assert_eq!(boots, 3);
+    }
+
+    #[test]
+    fn watchdog_reset() {
+        const BOOTS: u64 = 0x20_0000;
+        #[rustfmt::skip]
+        let code = [
+            0xff, 0x04, 0x25, 0x00, 0x00, 0x20, 0x00, // inc dword [BOOTS]
+            0x8b, 0x04, 0x25, 0x00, 0x00, 0x20, 0x00, // mov eax, [BOOTS]
+            0x83, 0xf8, 0x01,                         // cmp eax, 1
+            0x75, 0x07,                               // jne power_off
+            // Arm the watchdog, and never ping it.
+            0x66, 0xba, 0x43, 0x04,                   // mov dx, 0x443
+            0xee,                                     // out dx, al
+            0xeb, 0xfe,                               // jmp .
+            // power_off: with SLP_TYP 5 and SLP_EN in PM1a control.
+            0x66, 0xba, 0x04, 0x06,                   // mov dx, 0x604
+            0x66, 0xb8, 0x00, 0x34,                   // mov ax, 0x3400
+            0x66, 0xef,                               // out dx, ax
+            0xeb, 0xfe,                               // jmp .
+        ];
+        let (mut vmm, _kernel) = match booting_vm(1, &code, |vmm| {
+            vmm.configure_watchdog(Some("action=reset,timeout=1".to_string()))
+                .unwrap()
+        }) {
+            Some(vm) => vm,
+            None => return,
+        };
+
+        // The VM reboots when the watchdog expires, instead of stopping.
+        assert_eq!(
+            vmm.run().unwrap(),
+            VmExitReason::Shutdown(ACPI_POWER_OFF.to_string())
+        );
+        let boots: u32 = vmm.guest_memory.read_obj(GuestAddress(BOOTS)).unwrap();
+        assert_eq!(boots, 2);
+    }
+
+    /// A VM whose guest panics, handled with the `on_crash` action. The guest powers the VM off if
+    /// it keeps running.
+    fn panicking_vm(on_crash: &str, dump_dir: &TempDir) -> Option<(VMM, TempFile)> {
+        #[rustfmt::skip]
+        let code = [
+            0xb0, 0x01,                               // mov al, 1 (panicked)
+            0x66, 0xba, 0x05, 0x05,                   // mov dx, 0x505
+            0xee,                                     // out dx, al
+            // With SLP_TYP 5 and SLP_EN in PM1a control.
+            0x66, 0xba, 0x04, 0x06,                   // mov dx, 0x604
+            0x66, 0xb8, 0x00, 0x34,                   // mov ax, 0x3400
+            0x66, 0xef,                               // out dx, ax
+            0xeb, 0xfe,                               // jmp .
+        ];
+
+        booting_vm(1, &code, |vmm| {
+            vmm.configure_on_crash(Some(on_crash.to_string())).unwrap();
+            vmm.crash_dump_dir = dump_dir.as_path().to_path_buf();
+        })
+    }
+
+    #[test]
+    fn pvpanic_shutdown() {
+        let dump_dir = TempDir::new_with_prefix(env::temp_dir().join("lumper")).unwrap();
+        let (mut vmm, _kernel) = match panicking_vm("shutdown", &dump_dir) {
+            Some(vm) => vm,
+            None => return,
+        };
+
+        assert_eq!(
+            vmm.run().unwrap(),
+            VmExitReason::GuestCrash("guest kernel panic".to_string())
+        );
+        assert_eq!(fs::read_dir(dump_dir.as_path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn pvpanic_dump() {
+        let dump_dir = TempDir::new_with_prefix(env::temp_dir().join("lumper")).unwrap();
+        let (mut vmm, _kernel) = match panicking_vm("dump", &dump_dir) {
+            Some(vm) => vm,
+            None => return,
+        };
+
+        assert_eq!(
+            vmm.run().unwrap(),
+            VmExitReason::GuestCrash("guest kernel panic".to_string())
+        );
+        let dumps: Vec<PathBuf> = fs::read_dir(dump_dir.as_path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(dumps.len(), 1);
+        assert!(dumps[0].join("vcpu0.txt").is_file());
+        assert!(dumps[0].join("memory.core").is_file());
+    }
+
+    #[test]
+    fn pvpanic_pause() {
+        let dump_dir = TempDir::new_with_prefix(env::temp_dir().join("lumper")).unwrap();
+        let (mut vmm, _kernel) = match panicking_vm("pause", &dump_dir) {
+            Some(vm) => vm,
+            None => return,
+        };
+
+        let stop_evt = vmm.stop_evt().unwrap();
+        let stopper = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            stop_evt.write(1).unwrap();
+        });
+        // The vCPU stays parked, instead of powering the VM off.
+        assert_eq!(
+            vmm.run().unwrap(),
+            VmExitReason::Shutdown("stopped by the VMM".to_string())
+        );
+        assert_eq!(fs::read_dir(dump_dir.as_path()).unwrap().count(), 0);
+        stopper.join().unwrap();
+    }
+
+    // Console output taking a while to write each byte.
+    struct SlowWriter;
+
+    impl Write for SlowWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            thread::sleep(Duration::from_millis(50));
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn console_dropped_warning() {
+        let vmm = match detached_vm() {
+            Some(vmm) => vmm,
+            None => return,
+        };
+        assert_eq!(vmm.console_dropped_warning(), None);
+
+        *vmm.serial.lock().unwrap() = LumperSerial::buffered(
+            Box::new(SlowWriter),
+            4,
+            vmm.serial_capture.clone(),
+            Vec::new(),
+        )
+        .unwrap();
+        for byte in b"abcdefghij" {
+            vmm.serial.lock().unwrap().serial.write(0, *byte).unwrap();
+        }
+
+        let dropped = vmm.console_dropped_bytes();
+        assert!(dropped > 0);
+        assert_eq!(
+            vmm.console_dropped_warning(),
+            Some(format!(
+                "{} bytes of console output dropped, the console could not keep up with the guest",
+                dropped
+            ))
+        );
+    }
+
+    #[test]
+    fn timeout_stops_vcpus() {
+        let mut vmm = match spinning_vm(2) {
+            Some(vmm) => vmm,
+            None => return,
+        };
+
+        // The guest never completes.
+        vmm.configure_timeout(Some(1)).unwrap();
+        assert_eq!(vmm.run().unwrap(), VmExitReason::Timeout);
+        assert_eq!(Arc::strong_count(&vmm.vcpu_control), 1);
+    }
+}
@@ -0,0 +1,216 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Diagnostics of the VMM, written to stderr and filtered per module.
+//!
+//! The filter is a list of `[<module>=]<level>` (e.g. `info,vmm::cpu=trace`), a module level
+//! applying to its submodules too. Only warnings and errors are logged by default.
+//!
+//! Records below the most verbose configured level are dropped after a single atomic load, so
+//! that disabled logging costs next to nothing on the vCPU exit paths.
+
+use std::fmt;
+use std::io::{self, Write};
+use std::result;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::OnceLock;
+
+/// Severity of a record, from the least to the most verbose.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl FromStr for Level {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Level::Off),
+            "error" => Ok(Level::Error),
+            "warn" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            "trace" => Ok(Level::Trace),
+            _ => Err(crate::Error::LogFilter(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Level::Off => "OFF",
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Level of each module, as a list of `[<module>=]<level>`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LogFilter {
+    default: Level,
+    modules: Vec<(String, Level)>,
+}
+
+impl Default for LogFilter {
+    fn default() -> Self {
+        LogFilter {
+            default: Level::Warn,
+            modules: Vec::new(),
+        }
+    }
+}
+
+impl FromStr for LogFilter {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        let mut filter = LogFilter::default();
+
+        for directive in s.split(',').filter(|directive| !directive.is_empty()) {
+            match directive.split_once('=') {
+                Some((module, level)) if !module.is_empty() => {
+                    filter.modules.push((module.to_string(), level.parse()?))
+                }
+                Some(_) => return Err(crate::Error::LogFilter(s.to_string())),
+                None => filter.default = directive.parse()?,
+            }
+        }
+
+        Ok(filter)
+    }
+}
+
+impl LogFilter {
+    /// Level of `module`, set by its closest configured parent module.
+    pub fn level(&self, module: &str) -> Level {
+        self.modules
+            .iter()
+            .filter(|(prefix, _)| {
+                module
+                    .strip_prefix(prefix.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+            })
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    /// Most verbose level of any module.
+    fn max_level(&self) -> Level {
+        self.modules
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, Level::max)
+    }
+}
+
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(Level::Warn as u8);
+static FILTER: OnceLock<LogFilter> = OnceLock::new();
+
+/// Filter the records of the whole process from now on.
+///
+/// Only the first filter set is used.
+pub fn init_logger(filter: LogFilter) {
+    let max_level = filter.max_level();
+    if FILTER.set(filter).is_ok() {
+        MAX_LEVEL.store(max_level as u8, Ordering::Relaxed);
+    }
+}
+
+/// Whether records of `level` from `module` are logged.
+#[doc(hidden)]
+pub fn enabled(level: Level, module: &str) -> bool {
+    if level == Level::Off || level as u8 > MAX_LEVEL.load(Ordering::Relaxed) {
+        return false;
+    }
+
+    match FILTER.get() {
+        Some(filter) => level <= filter.level(module),
+        None => level <= Level::Warn,
+    }
+}
+
+#[doc(hidden)]
+pub fn write(level: Level, module: &str, args: fmt::Arguments) {
+    // A single write, so that records from different threads are not interleaved.
+    let record = format!("[{} {}] {}\n", level, module, args);
+    let _ = io::stderr().write_all(record.as_bytes());
+}
+
+macro_rules! log {
+    ($level:expr, $($arg:tt)+) => {
+        if $crate::logger::enabled($level, module_path!()) {
+            $crate::logger::write($level, module_path!(), format_args!($($arg)+));
+        }
+    };
+}
+
+macro_rules! error {
+    ($($arg:tt)+) => { log!($crate::logger::Level::Error, $($arg)+) };
+}
+
+macro_rules! warn {
+    ($($arg:tt)+) => { log!($crate::logger::Level::Warn, $($arg)+) };
+}
+
+macro_rules! info {
+    ($($arg:tt)+) => { log!($crate::logger::Level::Info, $($arg)+) };
+}
+
+macro_rules! debug {
+    ($($arg:tt)+) => { log!($crate::logger::Level::Debug, $($arg)+) };
+}
+
+macro_rules! trace {
+    ($($arg:tt)+) => { log!($crate::logger::Level::Trace, $($arg)+) };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn log_filter() {
+        assert_eq!("".parse::<LogFilter>().unwrap(), LogFilter::default());
+
+        let filter: LogFilter = "info,vmm::cpu=trace,vmm::cpu::cpuid=off,vmm::devices=error"
+            .parse()
+            .unwrap();
+        assert_eq!(filter.level("vmm"), Level::Info);
+        assert_eq!(filter.level("vmm::cpu"), Level::Trace);
+        assert_eq!(filter.level("vmm::cpu::control"), Level::Trace);
+        assert_eq!(filter.level("vmm::cpu::cpuid"), Level::Off);
+        assert_eq!(filter.level("vmm::cpus"), Level::Info);
+        assert_eq!(filter.level("vmm::devices::serial"), Level::Error);
+        assert_eq!(filter.max_level(), Level::Trace);
+
+        let filter: LogFilter = "vmm::gdb=debug".parse().unwrap();
+        assert_eq!(filter.level("vmm::gdb"), Level::Debug);
+        assert_eq!(filter.level("vmm::jail"), Level::Warn);
+
+        for invalid in ["verbose", "vmm=loud", "=info", "vmm::cpu="].iter() {
+            assert!(invalid.parse::<LogFilter>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn default_level() {
+        // No filter is installed by the tests.
+        assert!(enabled(Level::Error, "vmm::cpu"));
+        assert!(enabled(Level::Warn, "vmm::cpu"));
+        assert!(!enabled(Level::Info, "vmm::cpu"));
+        assert!(!enabled(Level::Trace, "vmm::cpu"));
+        assert!(!enabled(Level::Off, "vmm::cpu"));
+    }
+}
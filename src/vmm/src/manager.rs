@@ -0,0 +1,339 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Several VMs in one process, addressed by name.
+//!
+//! Each VM runs its event loop on its own thread, and owns its KVM VM, guest memory and devices.
+//! Only one of them may read its console input from stdin, the others must have it detached
+//! (see [`ConsoleConfig::detached`](crate::ConsoleConfig::detached)). The manager refuses a
+//! second VM taking stdin, which would otherwise wait for the first one to stop. vCPU threads
+//! are kicked with signals sent to each thread, so VMs never see each other's kicks.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::result;
+use std::thread::{self, JoinHandle};
+
+use vmm_sys_util::eventfd::EventFd;
+
+use crate::{VmExitReason, VMM};
+
+/// Errors managing VMs.
+#[derive(Debug)]
+pub enum Error {
+    /// A VM already has this name.
+    Exists(String),
+    /// No VM has this name.
+    NotFound(String),
+    /// The VM is already running.
+    Running(String),
+    /// This VM already reads its console input from stdin.
+    StdinTaken(String),
+    /// The VM was not started.
+    NotRunning(String),
+    /// Failed to get the event stopping the VM.
+    StopEvent(crate::Error),
+    /// Failed to start the VM thread.
+    Thread(io::Error),
+    /// Failed to signal the VM to stop.
+    Stop(io::Error),
+}
+
+/// Dedicated Result type.
+pub type Result<T> = result::Result<T, Error>;
+
+/// A VM the manager runs on its own thread.
+pub trait ManagedVm: Send + 'static {
+    /// Event stopping the VM once written to, from any thread.
+    fn stop_evt(&self) -> crate::Result<EventFd>;
+
+    /// Whether the VM reads its console input from stdin.
+    fn takes_stdin(&self) -> bool;
+
+    /// Run the VM until it stops.
+    fn run(&mut self) -> crate::Result<VmExitReason>;
+}
+
+impl ManagedVm for VMM {
+    fn stop_evt(&self) -> crate::Result<EventFd> {
+        VMM::stop_evt(self)
+    }
+
+    fn takes_stdin(&self) -> bool {
+        self.stdin_console
+    }
+
+    fn run(&mut self) -> crate::Result<VmExitReason> {
+        VMM::run(self)
+    }
+}
+
+enum VmState<V> {
+    Created(V),
+    Running {
+        stop_evt: EventFd,
+        thread: JoinHandle<VmExitReason>,
+    },
+}
+
+/// VMs of the process, by name.
+pub struct VmmManager<V: ManagedVm = VMM> {
+    vms: BTreeMap<String, VmState<V>>,
+    // Name of the VM reading its console input from stdin, if any.
+    stdin_owner: Option<String>,
+}
+
+impl<V: ManagedVm> Default for VmmManager<V> {
+    fn default() -> Self {
+        VmmManager {
+            vms: BTreeMap::new(),
+            stdin_owner: None,
+        }
+    }
+}
+
+impl<V: ManagedVm> VmmManager<V> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a configured VM under `name`, without starting it.
+    pub fn create(&mut self, name: &str, vm: V) -> Result<()> {
+        if self.vms.contains_key(name) {
+            return Err(Error::Exists(name.to_string()));
+        }
+        if vm.takes_stdin() {
+            if let Some(stdin_owner) = &self.stdin_owner {
+                return Err(Error::StdinTaken(stdin_owner.clone()));
+            }
+            self.stdin_owner = Some(name.to_string());
+        }
+
+        self.vms.insert(name.to_string(), VmState::Created(vm));
+        Ok(())
+    }
+
+    /// Start running the VM `name`, on its own thread.
+    pub fn start(&mut self, name: &str) -> Result<()> {
+        let mut vm = match self.vms.remove(name) {
+            Some(VmState::Created(vm)) => vm,
+            Some(running) => {
+                self.vms.insert(name.to_string(), running);
+                return Err(Error::Running(name.to_string()));
+            }
+            None => return Err(Error::NotFound(name.to_string())),
+        };
+
+        let stop_evt = vm.stop_evt().map_err(Error::StopEvent)?;
+        let thread = thread::Builder::new()
+            .name(format!("vm-{}", name))
+            .spawn(move || {
+                vm.run()
+                    .unwrap_or_else(|e| VmExitReason::InternalError(format!("{:?}", e)))
+            })
+            .map_err(Error::Thread)?;
+
+        self.vms
+            .insert(name.to_string(), VmState::Running { stop_evt, thread });
+        Ok(())
+    }
+
+    /// Stop the VM `name` if it is still running, and remove it.
+    ///
+    /// Returns why the VM stopped, which may have happened before it was asked to.
+    pub fn stop(&mut self, name: &str) -> Result<VmExitReason> {
+        let (stop_evt, thread) = match self.vms.remove(name) {
+            Some(VmState::Running { stop_evt, thread }) => (stop_evt, thread),
+            Some(created) => {
+                self.vms.insert(name.to_string(), created);
+                return Err(Error::NotRunning(name.to_string()));
+            }
+            None => return Err(Error::NotFound(name.to_string())),
+        };
+        self.release_stdin(name);
+
+        stop_evt.write(1).map_err(Error::Stop)?;
+        Ok(thread
+            .join()
+            .unwrap_or_else(|_| VmExitReason::InternalError(format!("VM {} panicked", name))))
+    }
+
+    /// Remove the VM `name`, which must not be running.
+    pub fn remove(&mut self, name: &str) -> Result<V> {
+        match self.vms.remove(name) {
+            Some(VmState::Created(vm)) => {
+                self.release_stdin(name);
+                Ok(vm)
+            }
+            Some(running) => {
+                self.vms.insert(name.to_string(), running);
+                Err(Error::Running(name.to_string()))
+            }
+            None => Err(Error::NotFound(name.to_string())),
+        }
+    }
+
+    fn release_stdin(&mut self, name: &str) {
+        if self.stdin_owner.as_deref() == Some(name) {
+            self.stdin_owner = None;
+        }
+    }
+
+    /// Names of the VMs, with whether they are running.
+    pub fn list(&self) -> Vec<(&str, bool)> {
+        self.vms
+            .iter()
+            .map(|(name, state)| (name.as_str(), matches!(state, VmState::Running { .. })))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    // Runs until stopped, like a VM whose guest never stops.
+    struct MockVm {
+        stop_evt: EventFd,
+        running: Arc<AtomicBool>,
+        stdin: bool,
+    }
+
+    impl MockVm {
+        fn new() -> (Self, Arc<AtomicBool>) {
+            let running = Arc::new(AtomicBool::new(false));
+            let vm = MockVm {
+                stop_evt: EventFd::new(0).unwrap(),
+                running: Arc::clone(&running),
+                stdin: false,
+            };
+
+            (vm, running)
+        }
+
+        fn with_stdin() -> Self {
+            MockVm {
+                stdin: true,
+                ..MockVm::new().0
+            }
+        }
+    }
+
+    impl ManagedVm for MockVm {
+        fn stop_evt(&self) -> crate::Result<EventFd> {
+            self.stop_evt.try_clone().map_err(crate::Error::IO)
+        }
+
+        fn takes_stdin(&self) -> bool {
+            self.stdin
+        }
+
+        fn run(&mut self) -> crate::Result<VmExitReason> {
+            self.running.store(true, Ordering::SeqCst);
+            self.stop_evt.read().map_err(crate::Error::IO)?;
+            self.running.store(false, Ordering::SeqCst);
+
+            Ok(VmExitReason::Shutdown("stopped".to_string()))
+        }
+    }
+
+    fn wait_running(running: &AtomicBool) {
+        while !running.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn two_vms() {
+        let mut manager = VmmManager::new();
+        let (vm1, vm1_running) = MockVm::new();
+        let (vm2, vm2_running) = MockVm::new();
+        manager.create("vm1", vm1).unwrap();
+        manager.create("vm2", vm2).unwrap();
+        assert_eq!(manager.list(), vec![("vm1", false), ("vm2", false)]);
+
+        manager.start("vm1").unwrap();
+        manager.start("vm2").unwrap();
+        wait_running(&vm1_running);
+        wait_running(&vm2_running);
+        assert_eq!(manager.list(), vec![("vm1", true), ("vm2", true)]);
+
+        // Stopping a VM leaves the other one running.
+        assert_eq!(
+            manager.stop("vm1").unwrap(),
+            VmExitReason::Shutdown("stopped".to_string())
+        );
+        assert!(!vm1_running.load(Ordering::SeqCst));
+        assert!(vm2_running.load(Ordering::SeqCst));
+        assert_eq!(manager.list(), vec![("vm2", true)]);
+
+        manager.stop("vm2").unwrap();
+        assert!(!vm2_running.load(Ordering::SeqCst));
+        assert!(manager.list().is_empty());
+    }
+
+    #[test]
+    fn vm_names() {
+        let mut manager = VmmManager::new();
+        manager.create("vm1", MockVm::new().0).unwrap();
+
+        assert!(matches!(
+            manager.create("vm1", MockVm::new().0),
+            Err(Error::Exists(name)) if name == "vm1"
+        ));
+        assert!(matches!(manager.start("vm2"), Err(Error::NotFound(_))));
+        assert!(matches!(manager.stop("vm1"), Err(Error::NotRunning(_))));
+
+        manager.start("vm1").unwrap();
+        assert!(matches!(manager.start("vm1"), Err(Error::Running(_))));
+        assert!(matches!(manager.remove("vm1"), Err(Error::Running(_))));
+        manager.stop("vm1").unwrap();
+        assert!(matches!(manager.stop("vm1"), Err(Error::NotFound(_))));
+
+        // A VM never started can be removed.
+        manager.create("vm1", MockVm::new().0).unwrap();
+        assert!(manager.remove("vm1").is_ok());
+        assert!(manager.list().is_empty());
+    }
+
+    #[test]
+    fn one_vm_takes_stdin() {
+        let mut manager = VmmManager::new();
+        manager.create("vm1", MockVm::with_stdin()).unwrap();
+        manager.create("vm2", MockVm::new().0).unwrap();
+
+        assert!(matches!(
+            manager.create("vm3", MockVm::with_stdin()),
+            Err(Error::StdinTaken(name)) if name == "vm1"
+        ));
+
+        // Stdin is free again once its VM stopped or was removed.
+        manager.start("vm1").unwrap();
+        manager.stop("vm1").unwrap();
+        manager.create("vm3", MockVm::with_stdin()).unwrap();
+        assert!(manager.create("vm4", MockVm::with_stdin()).is_err());
+        manager.remove("vm3").unwrap();
+        manager.create("vm4", MockVm::with_stdin()).unwrap();
+    }
+
+    #[test]
+    fn stop_running_guest() {
+        let vm = match crate::tests::spinning_vm(2) {
+            Some(vm) => vm,
+            None => return,
+        };
+        let vcpu_control = Arc::downgrade(&vm.vcpu_control);
+
+        let mut manager = VmmManager::new();
+        manager.create("vm1", vm).unwrap();
+        manager.start("vm1").unwrap();
+        // Let the guest run.
+        thread::sleep(Duration::from_millis(100));
+
+        // The vCPU threads, which hold the control, exited along with the VM.
+        manager.stop("vm1").unwrap();
+        assert!(vcpu_control.upgrade().is_none());
+    }
+}
@@ -0,0 +1,477 @@
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+#![cfg(target_arch = "x86_64")]
+
+use std::fs;
+use std::io;
+use std::result;
+use std::str::FromStr;
+
+use vm_memory::{Address, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
+
+/// Start of the 32-bit MMIO gap, reserved for devices. Guest RAM never overlaps it.
+pub const MMIO_GAP_START: u64 = 0xc000_0000;
+/// End of the 32-bit MMIO gap, RAM that does not fit below the gap resumes here.
+pub const MMIO_GAP_END: u64 = 1 << 32;
+
+/// Size of the pages tracked by the KVM dirty log.
+pub const PAGE_SIZE: u64 = 0x1000;
+
+/// Estimated memory used by the VMM itself, on top of the guest RAM.
+pub const VMM_OVERHEAD: u64 = 64 << 20;
+
+/// Errors associated with the guest memory configuration.
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// The guest memory size is zero.
+    Empty,
+    /// The guest memory size exceeds the host ceiling, both in bytes.
+    ExceedsLimit(u64, u64),
+    /// The guest memory does not fit in the guest physical address space.
+    Overflow,
+    /// Failed to read the amount of host memory.
+    HostMemory,
+    /// The guest memory and the VMM overhead exceed the available host memory, both in bytes.
+    Overcommit(u64, u64),
+    /// The guest memory exceeds `RLIMIT_MEMLOCK`, both in bytes.
+    MemlockLimit(u64, u64),
+    /// Failed to lock the guest memory, with this errno.
+    Mlock(i32),
+    /// Failed to read which guest pages are resident, with this errno.
+    Mincore(i32),
+}
+
+/// Dedicated Result type.
+pub type Result<T> = result::Result<T, Error>;
+
+/// Amount of physical memory of the host, in bytes.
+pub fn host_memory_size() -> Result<u64> {
+    // Safe because sysconf does not access memory, and the results are checked.
+    let pages = unsafe { libc::sysconf(libc::_SC_PHYS_PAGES) };
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    if pages <= 0 || page_size <= 0 {
+        return Err(Error::HostMemory);
+    }
+
+    Ok(pages as u64 * page_size as u64)
+}
+
+/// Memory available to new processes without swapping, the `MemAvailable` field of `meminfo`
+/// (as in `/proc/meminfo`), in bytes.
+pub fn available_memory(meminfo: &str) -> Result<u64> {
+    meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix("MemAvailable:"))
+        .and_then(|value| value.trim().strip_suffix("kB"))
+        .and_then(|kib| kib.trim().parse::<u64>().ok())
+        .and_then(|kib| kib.checked_mul(1 << 10))
+        .ok_or(Error::HostMemory)
+}
+
+/// Memory currently available on the host, in bytes.
+pub fn host_available_memory() -> Result<u64> {
+    let meminfo = fs::read_to_string("/proc/meminfo").map_err(|_| Error::HostMemory)?;
+    available_memory(&meminfo)
+}
+
+/// Check that `mem_size` bytes of guest RAM and the VMM fit in the `available` host memory, so
+/// that the OOM killer does not step in once the guest touches its memory.
+pub fn check_overcommit(mem_size: u64, available: u64) -> Result<()> {
+    let required = mem_size.saturating_add(VMM_OVERHEAD);
+    if required > available {
+        return Err(Error::Overcommit(required, available));
+    }
+
+    Ok(())
+}
+
+/// How the guest memory is allocated, configured as `mlock=on|off`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MemoryOptions {
+    /// Lock the guest memory in RAM, never swapping it out.
+    pub mlock: bool,
+}
+
+impl FromStr for MemoryOptions {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        let mut options = MemoryOptions::default();
+        for option in s.split(',').filter(|option| !option.is_empty()) {
+            match option.split_once('=') {
+                Some(("mlock", "on")) => options.mlock = true,
+                Some(("mlock", "off")) => options.mlock = false,
+                _ => return Err(crate::Error::MemoryOptions(s.to_string())),
+            }
+        }
+
+        Ok(options)
+    }
+}
+
+/// Amount of memory the VMM may lock, in bytes, `None` if unlimited.
+fn memlock_limit() -> Option<u64> {
+    let mut rlimit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // Safe because the kernel only writes to the rlimit, and the result is checked.
+    if unsafe { libc::getrlimit(libc::RLIMIT_MEMLOCK, &mut rlimit) } < 0
+        || rlimit.rlim_cur == libc::RLIM_INFINITY
+    {
+        return None;
+    }
+
+    Some(rlimit.rlim_cur)
+}
+
+/// Error of `mlock` failing with `errno` while locking `mem_size` bytes, blaming `RLIMIT_MEMLOCK`
+/// only when it is too low. Processes with `CAP_IPC_LOCK` are not bound by it.
+fn mlock_error(errno: i32, mem_size: u64, limit: Option<u64>) -> Error {
+    match limit {
+        Some(limit) if mem_size > limit && (errno == libc::ENOMEM || errno == libc::EPERM) => {
+            Error::MemlockLimit(mem_size, limit)
+        }
+        _ => Error::Mlock(errno),
+    }
+}
+
+/// Lock the whole `guest_memory` in RAM, faulting it in.
+pub fn lock_guest_memory(guest_memory: &GuestMemoryMmap) -> Result<()> {
+    for region in guest_memory.iter() {
+        // It's safe to unwrap because the guest address is valid.
+        let addr = guest_memory.get_host_address(region.start_addr()).unwrap();
+        // Safe because locking the pages of the region does not change its contents.
+        if unsafe { libc::mlock(addr as *const libc::c_void, region.len() as usize) } < 0 {
+            let errno = io::Error::last_os_error().raw_os_error().unwrap_or(0);
+            return Err(mlock_error(
+                errno,
+                guest_memory.memory_size(),
+                memlock_limit(),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+// Pages whose residency is read at once, bounding the buffer used.
+const MINCORE_CHUNK_PAGES: usize = 1 << 16;
+
+/// Host memory actually used by the guest, sampled on request only.
+///
+/// Usable from any thread while the VM runs.
+#[derive(Clone)]
+pub struct MemoryUsage {
+    guest_memory: GuestMemoryMmap,
+}
+
+impl MemoryUsage {
+    pub fn new(guest_memory: GuestMemoryMmap) -> Self {
+        MemoryUsage { guest_memory }
+    }
+
+    /// Size of the guest memory, in bytes.
+    pub fn size(&self) -> u64 {
+        self.guest_memory.memory_size()
+    }
+
+    /// Guest memory resident in host RAM, in bytes. Pages the guest never touched, or swapped
+    /// out, are not counted.
+    pub fn resident(&self) -> Result<u64> {
+        let mut resident_pages = 0;
+        let mut vec = vec![0u8; MINCORE_CHUNK_PAGES];
+
+        for region in self.guest_memory.iter() {
+            // It's safe to unwrap because the guest address is valid.
+            let start = self
+                .guest_memory
+                .get_host_address(region.start_addr())
+                .unwrap() as usize;
+            let end = start + region.len() as usize;
+
+            for chunk in (start..end).step_by(MINCORE_CHUNK_PAGES * PAGE_SIZE as usize) {
+                let len = (end - chunk).min(MINCORE_CHUNK_PAGES * PAGE_SIZE as usize);
+                // Safe because the range is mapped for the lifetime of the guest memory, and the
+                // kernel writes one byte per page of it, which fit in the vector.
+                let ret =
+                    unsafe { libc::mincore(chunk as *mut libc::c_void, len, vec.as_mut_ptr()) };
+                if ret < 0 {
+                    let errno = io::Error::last_os_error().raw_os_error().unwrap_or(0);
+                    return Err(Error::Mincore(errno));
+                }
+
+                let pages = len / PAGE_SIZE as usize;
+                resident_pages += vec[..pages].iter().filter(|page| *page & 1 != 0).count() as u64;
+            }
+        }
+
+        Ok(resident_pages * PAGE_SIZE)
+    }
+}
+
+/// Parse a size in bytes, with an optional `K`, `M`, `G` or `T` suffix.
+pub fn parse_size(s: &str) -> Option<u64> {
+    let (number, shift) = match s.chars().last()? {
+        'K' | 'k' => (&s[..s.len() - 1], 10),
+        'M' | 'm' => (&s[..s.len() - 1], 20),
+        'G' | 'g' => (&s[..s.len() - 1], 30),
+        'T' | 't' => (&s[..s.len() - 1], 40),
+        _ => (s, 0),
+    };
+
+    number.parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
+/// Compute the guest RAM regions for `mem_size` bytes, splitting them around the MMIO gap.
+///
+/// # Arguments
+///
+/// * `mem_size` - amount of guest RAM, in bytes.
+/// * `limit` - maximum amount of guest RAM allowed, in bytes.
+pub fn guest_memory_regions(mem_size: u64, limit: u64) -> Result<Vec<(GuestAddress, usize)>> {
+    if mem_size == 0 {
+        return Err(Error::Empty);
+    }
+    if mem_size > limit {
+        return Err(Error::ExceedsLimit(mem_size, limit));
+    }
+
+    if mem_size <= MMIO_GAP_START {
+        return Ok(vec![(GuestAddress(0), mem_size as usize)]);
+    }
+
+    let high_size = mem_size - MMIO_GAP_START;
+    MMIO_GAP_END.checked_add(high_size).ok_or(Error::Overflow)?;
+
+    Ok(vec![
+        (GuestAddress(0), MMIO_GAP_START as usize),
+        (GuestAddress(MMIO_GAP_END), high_size as usize),
+    ])
+}
+
+/// Turn the KVM dirty `bitmap` of the memory region starting at `region_start` into the ranges
+/// of pages written by the guest, as `(start address, length in bytes)`.
+pub fn dirty_page_ranges(region_start: GuestAddress, bitmap: &[u64]) -> Vec<(GuestAddress, u64)> {
+    let mut ranges: Vec<(GuestAddress, u64)> = Vec::new();
+
+    for (word_index, word) in bitmap.iter().enumerate() {
+        // Skip clean words quickly, most of the memory is not written to between two fetches.
+        let mut word = *word;
+        while word != 0 {
+            let bit = word.trailing_zeros() as u64;
+            word &= word - 1;
+
+            let page = word_index as u64 * 64 + bit;
+            let start = region_start.unchecked_add(page * PAGE_SIZE);
+            match ranges.last_mut() {
+                Some((last_start, len)) if last_start.unchecked_add(*len) == start => {
+                    *len += PAGE_SIZE
+                }
+                _ => ranges.push((start, PAGE_SIZE)),
+            }
+        }
+    }
+
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use vm_memory::Bytes;
+
+    const GIB: u64 = 1 << 30;
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("4096"), Some(4096));
+        assert_eq!(parse_size("16M"), Some(16 << 20));
+        assert_eq!(parse_size("3g"), Some(3 * GIB));
+        assert_eq!(parse_size("M"), None);
+        assert_eq!(parse_size("3X"), None);
+        assert_eq!(parse_size("16777216T"), None);
+    }
+
+    #[test]
+    fn below_gap() {
+        let regions = guest_memory_regions(512 << 20, u64::MAX).unwrap();
+        assert_eq!(regions, vec![(GuestAddress(0), 512 << 20)]);
+
+        let regions = guest_memory_regions(MMIO_GAP_START, u64::MAX).unwrap();
+        assert_eq!(regions, vec![(GuestAddress(0), MMIO_GAP_START as usize)]);
+    }
+
+    #[test]
+    fn split_around_gap() {
+        let regions = guest_memory_regions(4 * GIB, u64::MAX).unwrap();
+        assert_eq!(
+            regions,
+            vec![
+                (GuestAddress(0), MMIO_GAP_START as usize),
+                (GuestAddress(MMIO_GAP_END), GIB as usize),
+            ]
+        );
+
+        // No region overlaps the gap.
+        for (start, size) in regions {
+            let end = start.0 + size as u64;
+            assert!(end <= MMIO_GAP_START || start.0 >= MMIO_GAP_END);
+        }
+    }
+
+    #[test]
+    fn above_4g() {
+        let regions = guest_memory_regions(6 * GIB, u64::MAX).unwrap();
+        assert_eq!(
+            regions,
+            vec![
+                (GuestAddress(0), 3 * GIB as usize),
+                (GuestAddress(4 * GIB), 3 * GIB as usize),
+            ]
+        );
+
+        let total: usize = regions.iter().map(|(_, size)| size).sum();
+        assert_eq!(total as u64, 6 * GIB);
+    }
+
+    #[test]
+    fn invalid_sizes() {
+        assert_eq!(guest_memory_regions(0, u64::MAX), Err(Error::Empty));
+        assert_eq!(
+            guest_memory_regions(2 * GIB, GIB),
+            Err(Error::ExceedsLimit(2 * GIB, GIB))
+        );
+        assert_eq!(
+            guest_memory_regions(u64::MAX, u64::MAX),
+            Err(Error::Overflow)
+        );
+    }
+
+    #[test]
+    fn dirty_pages() {
+        let region_start = GuestAddress(MMIO_GAP_END);
+        assert!(dirty_page_ranges(region_start, &[0; 4]).is_empty());
+
+        // The guest wrote to the first page.
+        assert_eq!(
+            dirty_page_ranges(region_start, &[0x1, 0]),
+            vec![(region_start, PAGE_SIZE)]
+        );
+
+        // Pages 1 to 3, and 63 to 65 across two words, then the last one.
+        let bitmap = [0xe | 1 << 63, 0x3 | 1 << 63];
+        assert_eq!(
+            dirty_page_ranges(region_start, &bitmap),
+            vec![
+                (GuestAddress(MMIO_GAP_END + PAGE_SIZE), 3 * PAGE_SIZE),
+                (GuestAddress(MMIO_GAP_END + 63 * PAGE_SIZE), 3 * PAGE_SIZE),
+                (GuestAddress(MMIO_GAP_END + 127 * PAGE_SIZE), PAGE_SIZE),
+            ]
+        );
+    }
+
+    #[test]
+    fn host_memory() {
+        assert!(host_memory_size().unwrap() > 0);
+        assert!(host_available_memory().unwrap() > 0);
+    }
+
+    #[test]
+    fn resident_memory() {
+        let size = 64 * PAGE_SIZE;
+        let guest_memory =
+            GuestMemoryMmap::from_ranges(&[(GuestAddress(0), size as usize)]).unwrap();
+        let usage = MemoryUsage::new(guest_memory.clone());
+        assert_eq!(usage.size(), size);
+        assert_eq!(usage.resident().unwrap(), 0);
+
+        // Touch one page out of four.
+        for page in (0..64).step_by(4) {
+            guest_memory
+                .write_obj(1u8, GuestAddress(page * PAGE_SIZE))
+                .unwrap();
+        }
+        let resident = usage.resident().unwrap();
+        assert!(
+            (16 * PAGE_SIZE..=size).contains(&resident),
+            "{} bytes resident",
+            resident
+        );
+    }
+
+    #[test]
+    fn meminfo() {
+        let meminfo = "MemTotal:       16314020 kB\n\
+                       MemFree:         1203452 kB\n\
+                       MemAvailable:    8157012 kB\n\
+                       Buffers:          402136 kB\n";
+        assert_eq!(available_memory(meminfo), Ok(8157012 << 10));
+
+        for invalid in ["", "MemTotal: 16314020 kB\n", "MemAvailable: 8157012\n"].iter() {
+            assert_eq!(
+                available_memory(invalid),
+                Err(Error::HostMemory),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn overcommit() {
+        let available = available_memory("MemAvailable: 4194304 kB\n").unwrap();
+        assert_eq!(available, 4 * GIB);
+
+        assert_eq!(check_overcommit(2 * GIB, available), Ok(()));
+        assert_eq!(check_overcommit(4 * GIB - VMM_OVERHEAD, available), Ok(()));
+        assert_eq!(
+            check_overcommit(4 * GIB, available),
+            Err(Error::Overcommit(4 * GIB + VMM_OVERHEAD, available))
+        );
+        assert_eq!(
+            check_overcommit(u64::MAX, available),
+            Err(Error::Overcommit(u64::MAX, available))
+        );
+    }
+
+    #[test]
+    fn memory_options() {
+        assert_eq!(
+            "".parse::<MemoryOptions>().unwrap(),
+            MemoryOptions::default()
+        );
+        assert!("mlock=on".parse::<MemoryOptions>().unwrap().mlock);
+        assert!(!"mlock=off".parse::<MemoryOptions>().unwrap().mlock);
+
+        for invalid in ["mlock", "mlock=yes", "hugepages=on"].iter() {
+            assert!(invalid.parse::<MemoryOptions>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn mlock_errors() {
+        // Blamed on the limit only when it is too low.
+        assert_eq!(
+            mlock_error(libc::ENOMEM, 2 * GIB, Some(64 << 20)),
+            Error::MemlockLimit(2 * GIB, 64 << 20)
+        );
+        assert_eq!(
+            mlock_error(libc::EPERM, 2 * GIB, Some(64 << 20)),
+            Error::MemlockLimit(2 * GIB, 64 << 20)
+        );
+        assert_eq!(
+            mlock_error(libc::ENOMEM, 2 * GIB, Some(4 * GIB)),
+            Error::Mlock(libc::ENOMEM)
+        );
+        assert_eq!(
+            mlock_error(libc::ENOMEM, 2 * GIB, None),
+            Error::Mlock(libc::ENOMEM)
+        );
+        assert_eq!(
+            mlock_error(libc::EAGAIN, 2 * GIB, Some(64 << 20)),
+            Error::Mlock(libc::EAGAIN)
+        );
+    }
+}
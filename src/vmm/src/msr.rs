@@ -0,0 +1,227 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Guest accesses to the MSRs KVM does not handle.
+//!
+//! KVM hands these accesses over to the VMM, which follows a small table of known MSRs. Other
+//! MSRs read as 0 and ignore writes, each of them logged once, or raise a #GP in strict mode.
+//!
+//! kvm-ioctls does not decode these exits, so the `kvm_run` structure of each vCPU is mapped a
+//! second time to read and complete them.
+
+use std::collections::HashSet;
+use std::io;
+use std::ptr;
+use std::sync::Mutex;
+
+use kvm_bindings::kvm_enable_cap;
+use kvm_ioctls::{VcpuFd, VmFd};
+
+use crate::cpu::KvmRun;
+
+// From linux/kvm.h.
+const KVM_CAP_X86_USER_SPACE_MSR: u32 = 188;
+const KVM_MSR_EXIT_REASON_UNKNOWN: u64 = 1 << 1;
+pub const KVM_EXIT_X86_RDMSR: u32 = 29;
+pub const KVM_EXIT_X86_WRMSR: u32 = 30;
+
+/// What a guest access to an MSR does.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MsrAction {
+    /// Reads return 0, writes are dropped.
+    Ignore,
+    /// A #GP is injected into the guest.
+    Fault,
+}
+
+// MSRs probed by common guests, as `(first, last, action)`.
+const MSR_TABLE: &[(u32, u32, MsrAction)] = &[
+    // PPIN, which would identify the host CPU.
+    (0x0000_004e, 0x0000_004f, MsrAction::Fault),
+    // C1E promotion, set by intel_idle, meaningless in a VM.
+    (0x0000_01fc, 0x0000_01fc, MsrAction::Ignore),
+    // RAPL power limits and energy counters, the guest skips power capping on #GP.
+    (0x0000_0606, 0x0000_0641, MsrAction::Fault),
+];
+
+/// How the vCPUs handle accesses to the MSRs KVM does not handle, shared by all vCPUs.
+pub struct MsrPolicy {
+    // Inject a #GP for the MSRs missing from the table.
+    strict: bool,
+    // MSRs already logged.
+    logged: Mutex<HashSet<u32>>,
+}
+
+impl MsrPolicy {
+    pub fn new(strict: bool) -> Self {
+        MsrPolicy {
+            strict,
+            logged: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// What an access to the `index` MSR does.
+    pub fn action(&self, index: u32) -> MsrAction {
+        MSR_TABLE
+            .iter()
+            .find(|(first, last, _)| (*first..=*last).contains(&index))
+            .map(|(_, _, action)| *action)
+            .unwrap_or(if self.strict {
+                MsrAction::Fault
+            } else {
+                MsrAction::Ignore
+            })
+    }
+
+    // Whether the `index` MSR is accessed for the first time.
+    fn first_access(&self, index: u32) -> bool {
+        self.logged.lock().unwrap().insert(index)
+    }
+
+    /// Handle the guest reading the `index` MSR, or writing `data` to it, logging the first
+    /// access only.
+    pub fn access(&self, vcpu: u64, index: u32, write: Option<u64>) -> MsrAction {
+        let action = self.action(index);
+        if self.first_access(index) {
+            match write {
+                Some(data) => warn!(
+                    "vCPU {}: unhandled write of {:#x} to MSR {:#x}, {:?}",
+                    vcpu, data, index, action
+                ),
+                None => warn!(
+                    "vCPU {}: unhandled read of MSR {:#x}, {:?}",
+                    vcpu, index, action
+                ),
+            }
+        }
+
+        action
+    }
+}
+
+/// Have KVM hand over to the VMM the accesses to the MSRs it does not handle, instead of
+/// following its `ignore_msrs` module parameter.
+pub fn enable_msr_exits(vm_fd: &VmFd) -> io::Result<()> {
+    let mut cap = kvm_enable_cap {
+        cap: KVM_CAP_X86_USER_SPACE_MSR,
+        ..Default::default()
+    };
+    cap.args[0] = KVM_MSR_EXIT_REASON_UNKNOWN;
+
+    vm_fd
+        .enable_cap(&cap)
+        .map_err(|e| io::Error::from_raw_os_error(e.errno()))
+}
+
+// `kvm_run.msr`, filled by KVM on MSR exits.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct MsrExit {
+    // Set to inject a #GP.
+    error: u8,
+    pad: [u8; 7],
+    reason: u32,
+    index: u32,
+    data: u64,
+}
+
+impl MsrExit {
+    /// Complete the access following `action`.
+    fn complete(&mut self, action: MsrAction, write: bool) {
+        match action {
+            MsrAction::Ignore => {
+                self.error = 0;
+                if !write {
+                    self.data = 0;
+                }
+            }
+            MsrAction::Fault => self.error = 1,
+        }
+    }
+}
+
+/// The `kvm_run` structure of a vCPU, mapped to handle its MSR exits.
+pub struct MsrExits(KvmRun);
+
+impl MsrExits {
+    pub fn new(vcpu_fd: &VcpuFd) -> io::Result<Self> {
+        KvmRun::new(vcpu_fd).map(MsrExits)
+    }
+
+    /// Complete the MSR access the vCPU exited on, following `policy`.
+    pub fn handle(&self, policy: &MsrPolicy, vcpu: u64, write: bool) {
+        let exit = self.0.exit::<MsrExit>();
+        // Safe because the vCPU exited on an MSR access, so KVM filled the exit structure, which
+        // lies within the mapping, and only reads it back once the vCPU runs again.
+        let mut msr = unsafe { ptr::read_volatile(exit) };
+
+        let action = policy.access(vcpu, msr.index, write.then_some(msr.data));
+        msr.complete(action, write);
+
+        // Safe for the same reasons.
+        unsafe { ptr::write_volatile(exit, msr) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn msr_table() {
+        let policy = MsrPolicy::new(false);
+        assert_eq!(policy.action(0x4e), MsrAction::Fault);
+        assert_eq!(policy.action(0x1fc), MsrAction::Ignore);
+        assert_eq!(policy.action(0x606), MsrAction::Fault);
+        assert_eq!(policy.action(0x641), MsrAction::Fault);
+        // Unknown MSRs.
+        assert_eq!(policy.action(0x642), MsrAction::Ignore);
+        assert_eq!(policy.action(0xc001_0064), MsrAction::Ignore);
+
+        let strict = MsrPolicy::new(true);
+        assert_eq!(strict.action(0x1fc), MsrAction::Ignore);
+        assert_eq!(strict.action(0x642), MsrAction::Fault);
+        assert_eq!(strict.action(0xc001_0064), MsrAction::Fault);
+    }
+
+    #[test]
+    fn logged_once() {
+        let policy = MsrPolicy::new(false);
+        assert!(policy.first_access(0x642));
+        assert!(!policy.first_access(0x642));
+        assert!(policy.first_access(0x643));
+
+        // Reads and writes, from any vCPU, share the log.
+        assert_eq!(policy.access(0, 0x1fc, None), MsrAction::Ignore);
+        assert_eq!(policy.access(1, 0x1fc, Some(1)), MsrAction::Ignore);
+        assert!(!policy.first_access(0x1fc));
+        assert_eq!(policy.logged.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn complete_exits() {
+        let exit = MsrExit {
+            index: 0x642,
+            data: 0xdead,
+            ..Default::default()
+        };
+
+        let mut read = exit;
+        read.complete(MsrAction::Ignore, false);
+        assert_eq!((read.error, read.data), (0, 0));
+
+        // The written value is left alone.
+        let mut write = exit;
+        write.complete(MsrAction::Ignore, true);
+        assert_eq!((write.error, write.data), (0, 0xdead));
+
+        let mut fault = exit;
+        fault.complete(MsrAction::Fault, false);
+        assert_eq!(fault.error, 1);
+    }
+
+    #[test]
+    fn exit_layout() {
+        // As in linux/kvm.h.
+        assert_eq!(std::mem::size_of::<MsrExit>(), 24);
+    }
+}
@@ -0,0 +1,203 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Telling systemd how the VM is doing, with the `sd_notify(3)` protocol.
+//!
+//! A service of `Type=notify` is told `READY=1` once the VM is ready, `STOPPING=1` when it stops
+//! and, with `WatchdogSec=` set, `WATCHDOG=1` as long as the VMM event loop is alive.
+
+use std::env;
+use std::io;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::path::Path;
+use std::process;
+use std::result;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// When the VM is considered ready.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum ReadyOn {
+    /// Once the vCPUs are running.
+    #[default]
+    Vcpus,
+    /// Once the guest printed this string on the console (e.g. `login:`).
+    ConsoleString(String),
+}
+
+impl FromStr for ReadyOn {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "vcpus" => Ok(ReadyOn::Vcpus),
+            Some(("console-string", marker)) if !marker.is_empty() => {
+                Ok(ReadyOn::ConsoleString(marker.to_string()))
+            }
+            _ => Err(crate::Error::ReadyOn(s.to_string())),
+        }
+    }
+}
+
+/// Interval at which the watchdog must be pinged, half its timeout, when the watchdog is set up
+/// for this process.
+fn watchdog_interval(usec: Option<&str>, pid: Option<&str>) -> Option<Duration> {
+    // The watchdog is meant for another process.
+    if let Some(pid) = pid {
+        if pid.parse::<u32>().ok()? != process::id() {
+            return None;
+        }
+    }
+
+    match usec?.parse::<u64>().ok()? {
+        0 => None,
+        usec => Some(Duration::from_micros(usec / 2)),
+    }
+}
+
+/// Sends notifications to systemd, dropped if systemd is gone.
+pub struct SystemdNotifier {
+    // Connected when created, so that it still reaches systemd from a jail.
+    socket: UnixDatagram,
+    watchdog_interval: Option<Duration>,
+}
+
+impl SystemdNotifier {
+    /// Notify the socket at `path`, `@` standing for the abstract namespace.
+    pub fn new(path: &str, watchdog_interval: Option<Duration>) -> io::Result<Self> {
+        let addr = match path.strip_prefix('@') {
+            Some(name) => SocketAddr::from_abstract_name(name)?,
+            None => SocketAddr::from_pathname(Path::new(path))?,
+        };
+
+        let socket = UnixDatagram::unbound()?;
+        socket.connect_addr(&addr)?;
+
+        Ok(SystemdNotifier {
+            socket,
+            watchdog_interval,
+        })
+    }
+
+    /// The notifier systemd set up for the service through `NOTIFY_SOCKET`, if any.
+    pub fn from_env() -> io::Result<Option<Self>> {
+        let path = match env::var("NOTIFY_SOCKET") {
+            Ok(path) => path,
+            Err(_) => return Ok(None),
+        };
+        let watchdog_interval = watchdog_interval(
+            env::var("WATCHDOG_USEC").ok().as_deref(),
+            env::var("WATCHDOG_PID").ok().as_deref(),
+        );
+
+        Self::new(&path, watchdog_interval).map(Some)
+    }
+
+    /// Another notifier, sending to the same socket.
+    pub fn try_clone(&self) -> io::Result<Self> {
+        Ok(SystemdNotifier {
+            socket: self.socket.try_clone()?,
+            watchdog_interval: self.watchdog_interval,
+        })
+    }
+
+    /// Interval at which [`watchdog`](Self::watchdog) must be called, if systemd watches the
+    /// service.
+    pub fn watchdog_interval(&self) -> Option<Duration> {
+        self.watchdog_interval
+    }
+
+    fn notify(&self, state: &str) -> io::Result<()> {
+        self.socket.send(state.as_bytes()).map(|_| ())
+    }
+
+    /// The VM is ready.
+    pub fn ready(&self) -> io::Result<()> {
+        self.notify("READY=1")
+    }
+
+    /// The VM is stopping.
+    pub fn stopping(&self) -> io::Result<()> {
+        self.notify("STOPPING=1")
+    }
+
+    /// The VMM is alive.
+    pub fn watchdog(&self) -> io::Result<()> {
+        self.notify("WATCHDOG=1")
+    }
+
+    /// Process `pid` is the main process of the service from now on.
+    pub fn main_pid(&self, pid: u32) -> io::Result<()> {
+        self.notify(&format!("MAINPID={}", pid))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use vmm_sys_util::tempdir::TempDir;
+
+    #[test]
+    fn ready_on() {
+        assert_eq!("vcpus".parse::<ReadyOn>().unwrap(), ReadyOn::Vcpus);
+        assert_eq!(
+            "console-string:login:".parse::<ReadyOn>().unwrap(),
+            ReadyOn::ConsoleString("login:".to_string())
+        );
+
+        for invalid in ["", "console-string:", "console:login", "vcpus:1"].iter() {
+            assert!(invalid.parse::<ReadyOn>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn watchdog() {
+        let pid = process::id().to_string();
+
+        assert_eq!(
+            watchdog_interval(Some("30000000"), None),
+            Some(Duration::from_secs(15))
+        );
+        assert_eq!(
+            watchdog_interval(Some("30000000"), Some(&pid)),
+            Some(Duration::from_secs(15))
+        );
+        assert_eq!(watchdog_interval(Some("30000000"), Some("1")), None);
+        assert_eq!(watchdog_interval(Some("0"), None), None);
+        assert_eq!(watchdog_interval(None, Some(&pid)), None);
+    }
+
+    #[test]
+    fn notifications() {
+        let dir = TempDir::new_with_prefix(env::temp_dir().join("lumper")).unwrap();
+        let path = dir.as_path().join("notify");
+        let systemd = UnixDatagram::bind(&path).unwrap();
+
+        let notifier = SystemdNotifier::new(path.to_str().unwrap(), None).unwrap();
+        notifier.ready().unwrap();
+        notifier.watchdog().unwrap();
+        notifier.stopping().unwrap();
+        notifier.try_clone().unwrap().main_pid(42).unwrap();
+
+        let mut buf = [0; 64];
+        for expected in ["READY=1", "WATCHDOG=1", "STOPPING=1", "MAINPID=42"].iter() {
+            let count = systemd.recv(&mut buf).unwrap();
+            assert_eq!(&buf[..count], expected.as_bytes());
+        }
+    }
+
+    #[test]
+    fn abstract_socket() {
+        let name = format!("lumper-notify-{}", process::id());
+        let addr = SocketAddr::from_abstract_name(&name).unwrap();
+        let systemd = UnixDatagram::bind_addr(&addr).unwrap();
+
+        let notifier = SystemdNotifier::new(&format!("@{}", name), None).unwrap();
+        notifier.ready().unwrap();
+
+        let mut buf = [0; 64];
+        let count = systemd.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..count], b"READY=1");
+    }
+}
@@ -0,0 +1,397 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Placing the VM on a single host NUMA node.
+//!
+//! The guest memory is bound to the node with `mbind(2)`, and the vCPUs not pinned explicitly run
+//! on the CPUs of the node. The node topology is read from sysfs, before the VMM is jailed. The
+//! guest may also be told it runs on a single NUMA node, through the ACPI SRAT.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::result;
+use std::str::FromStr;
+
+use vm_memory::{GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
+
+/// Where sysfs is mounted.
+pub const SYSFS_ROOT: &str = "/sys";
+
+// Memory policy of mbind(2), from linux/mempolicy.h.
+const MPOL_BIND: libc::c_int = 2;
+const MPOL_MF_STRICT: libc::c_uint = 1 << 0;
+const MPOL_MF_MOVE: libc::c_uint = 1 << 1;
+
+/// Errors placing the VM on a NUMA node.
+#[derive(Debug)]
+pub enum Error {
+    /// Invalid NUMA configuration, as given.
+    Config(String),
+    /// The host node does not exist.
+    NodeNotFound(u32),
+    /// The host node has no CPU the VMM may run on.
+    NoCpus(u32),
+    /// The guest memory exceeds the memory of the host node, both in bytes.
+    NodeMemory(u32, u64, u64),
+    /// Failed to read the node topology.
+    Io(PathBuf, io::Error),
+    /// Invalid node topology file.
+    Topology(PathBuf),
+    /// Failed to bind the guest memory to the node.
+    Mbind(io::Error),
+}
+
+/// Dedicated Result type.
+pub type Result<T> = result::Result<T, Error>;
+
+/// NUMA placement of the VM, as `node=<host node>[,srat=on|off]`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NumaConfig {
+    /// Host node the guest memory and vCPUs are bound to.
+    pub node: u32,
+    /// Describe a single NUMA node to the guest through the ACPI SRAT.
+    pub srat: bool,
+}
+
+impl FromStr for NumaConfig {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        let invalid = || crate::Error::Numa(Error::Config(s.to_string()));
+
+        let mut node = None;
+        let mut srat = false;
+        for option in s.split(',').filter(|option| !option.is_empty()) {
+            match option.split_once('=') {
+                Some(("node", id)) => node = Some(id.parse().map_err(|_| invalid())?),
+                Some(("srat", "on")) => srat = true,
+                Some(("srat", "off")) => srat = false,
+                _ => return Err(invalid()),
+            }
+        }
+
+        Ok(NumaConfig {
+            node: node.ok_or_else(invalid)?,
+            srat,
+        })
+    }
+}
+
+/// Parse a list of CPUs as written by the kernel, such as `0-3,8,10-11`.
+fn parse_cpu_list(s: &str) -> Option<Vec<usize>> {
+    let mut cpus = Vec::new();
+
+    for range in s.trim().split(',').filter(|range| !range.is_empty()) {
+        let (first, last): (usize, usize) = match range.split_once('-') {
+            Some((first, last)) => (first.parse().ok()?, last.parse().ok()?),
+            None => {
+                let cpu = range.parse().ok()?;
+                (cpu, cpu)
+            }
+        };
+        if first > last {
+            return None;
+        }
+        cpus.extend(first..=last);
+    }
+
+    Some(cpus)
+}
+
+/// Parse the `MemTotal` field of a node `meminfo`, in bytes.
+fn parse_node_memory(meminfo: &str) -> Option<u64> {
+    // Lines read `Node <id> MemTotal:       16314020 kB`.
+    meminfo
+        .lines()
+        .find_map(|line| line.split_once("MemTotal:"))
+        .and_then(|(_, value)| value.trim().strip_suffix("kB"))
+        .and_then(|kib| kib.trim().parse::<u64>().ok())
+        .and_then(|kib| kib.checked_mul(1 << 10))
+}
+
+/// A NUMA node of the host.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HostNode {
+    pub id: u32,
+    /// CPUs of the node.
+    pub cpus: Vec<usize>,
+    /// Memory of the node, in bytes.
+    pub mem_size: u64,
+}
+
+impl HostNode {
+    /// Read the topology of node `id` from sysfs, mounted at `sysfs`.
+    pub fn read(sysfs: &Path, id: u32) -> Result<Self> {
+        let dir = sysfs.join(format!("devices/system/node/node{}", id));
+        if !dir.is_dir() {
+            return Err(Error::NodeNotFound(id));
+        }
+
+        let read = |name: &str| {
+            let path = dir.join(name);
+            fs::read_to_string(&path).map_err(|e| Error::Io(path.clone(), e))
+        };
+        let cpus = parse_cpu_list(&read("cpulist")?)
+            .ok_or_else(|| Error::Topology(dir.join("cpulist")))?;
+        let mem_size = parse_node_memory(&read("meminfo")?)
+            .ok_or_else(|| Error::Topology(dir.join("meminfo")))?;
+
+        Ok(HostNode { id, cpus, mem_size })
+    }
+
+    /// Keep the CPUs of the node the VMM may run on, `host_cpus`.
+    pub fn restrict_cpus(&mut self, host_cpus: &[usize]) -> Result<()> {
+        self.cpus.retain(|cpu| host_cpus.contains(cpu));
+        if self.cpus.is_empty() {
+            return Err(Error::NoCpus(self.id));
+        }
+
+        Ok(())
+    }
+
+    /// Check that `mem_size` bytes of guest memory fit in the node.
+    pub fn check_memory(&self, mem_size: u64) -> Result<()> {
+        if mem_size > self.mem_size {
+            return Err(Error::NodeMemory(self.id, mem_size, self.mem_size));
+        }
+
+        Ok(())
+    }
+
+    /// Allocate the whole `guest_memory` on the node only.
+    pub fn bind_guest_memory(&self, guest_memory: &GuestMemoryMmap) -> Result<()> {
+        for region in guest_memory.iter() {
+            // It's safe to unwrap because the guest address is valid.
+            let addr = guest_memory.get_host_address(region.start_addr()).unwrap();
+            self.bind_memory(addr, region.len() as usize)?;
+        }
+
+        Ok(())
+    }
+
+    /// Allocate the `len` bytes mapped at `addr` on the node only, moving the pages already
+    /// allocated elsewhere.
+    fn bind_memory(&self, addr: *mut u8, len: usize) -> Result<()> {
+        let bits = u64::BITS as usize;
+        let mut nodemask = vec![0u64; self.id as usize / bits + 1];
+        nodemask[self.id as usize / bits] |= 1 << (self.id as usize % bits);
+
+        // Safe because the kernel only reads the node mask, within the given number of nodes,
+        // and changing the policy of the mapping does not change its contents.
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_mbind,
+                addr,
+                len,
+                MPOL_BIND,
+                nodemask.as_ptr(),
+                // The kernel reads one node less than given.
+                nodemask.len() * bits + 1,
+                MPOL_MF_MOVE | MPOL_MF_STRICT,
+            )
+        };
+        if ret < 0 {
+            return Err(Error::Mbind(io::Error::last_os_error()));
+        }
+
+        Ok(())
+    }
+}
+
+/// NUMA placement of the VM, resolved against the host topology.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NumaPlacement {
+    /// Host node, restricted to the CPUs the VMM may run on.
+    pub node: HostNode,
+    /// Describe a single NUMA node to the guest through the ACPI SRAT.
+    pub srat: bool,
+}
+
+impl NumaPlacement {
+    /// Read the node of `config` from sysfs, mounted at `sysfs`, keeping its CPUs the VMM may run
+    /// on, `host_cpus`.
+    pub fn resolve(config: &NumaConfig, sysfs: &Path, host_cpus: &[usize]) -> Result<Self> {
+        let mut node = HostNode::read(sysfs, config.node)?;
+        node.restrict_cpus(host_cpus)?;
+
+        Ok(NumaPlacement {
+            node,
+            srat: config.srat,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    use vmm_sys_util::tempdir::TempDir;
+
+    #[test]
+    fn config() {
+        assert_eq!(
+            "node=1".parse::<NumaConfig>().unwrap(),
+            NumaConfig {
+                node: 1,
+                srat: false
+            }
+        );
+        assert_eq!(
+            "node=0,srat=on".parse::<NumaConfig>().unwrap(),
+            NumaConfig {
+                node: 0,
+                srat: true
+            }
+        );
+
+        for invalid in [
+            "",
+            "srat=on",
+            "node=-1",
+            "node=1,srat=yes",
+            "node=1,cpus=0-3",
+        ]
+        .iter()
+        {
+            assert!(invalid.parse::<NumaConfig>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn cpu_lists() {
+        assert_eq!(parse_cpu_list("0-3\n"), Some(vec![0, 1, 2, 3]));
+        assert_eq!(parse_cpu_list("0-1,8,10-11"), Some(vec![0, 1, 8, 10, 11]));
+        // A node without CPUs.
+        assert_eq!(parse_cpu_list("\n"), Some(vec![]));
+
+        for invalid in ["3-1", "0-", "a", "0,,-1"].iter() {
+            assert_eq!(parse_cpu_list(invalid), None, "{}", invalid);
+        }
+    }
+
+    // A sysfs tree recorded on a two socket host, with 8 CPUs and about 16 GiB per node.
+    fn recorded_sysfs() -> TempDir {
+        let sysfs = TempDir::new_with_prefix(env::temp_dir().join("lumper")).unwrap();
+        for (id, cpulist, mem_kib) in
+            [(0, "0-3,8-11", 16_777_216), (1, "4-7,12-15", 16_515_072)].iter()
+        {
+            let dir = sysfs
+                .as_path()
+                .join(format!("devices/system/node/node{}", id));
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("cpulist"), format!("{}\n", cpulist)).unwrap();
+            fs::write(
+                dir.join("meminfo"),
+                format!(
+                    "Node {id} MemTotal:       {mem} kB\n\
+                     Node {id} MemFree:        {free} kB\n\
+                     Node {id} MemUsed:        {free} kB\n",
+                    id = id,
+                    mem = mem_kib,
+                    free = mem_kib / 2
+                ),
+            )
+            .unwrap();
+        }
+
+        sysfs
+    }
+
+    #[test]
+    fn read_topology() {
+        let sysfs = recorded_sysfs();
+
+        assert_eq!(
+            HostNode::read(sysfs.as_path(), 1).unwrap(),
+            HostNode {
+                id: 1,
+                cpus: vec![4, 5, 6, 7, 12, 13, 14, 15],
+                mem_size: 16_515_072 << 10,
+            }
+        );
+        assert!(matches!(
+            HostNode::read(sysfs.as_path(), 2),
+            Err(Error::NodeNotFound(2))
+        ));
+
+        let node0 = sysfs.as_path().join("devices/system/node/node0");
+        fs::write(node0.join("meminfo"), "Node 0 MemFree: 1024 kB\n").unwrap();
+        assert!(matches!(
+            HostNode::read(sysfs.as_path(), 0),
+            Err(Error::Topology(path)) if path == node0.join("meminfo")
+        ));
+    }
+
+    #[test]
+    fn node_resources() {
+        let mut node = HostNode::read(recorded_sysfs().as_path(), 0).unwrap();
+
+        // The VMM is restricted to some CPUs of each node.
+        node.restrict_cpus(&[2, 3, 4, 5, 10]).unwrap();
+        assert_eq!(node.cpus, vec![2, 3, 10]);
+        assert!(matches!(node.restrict_cpus(&[4, 5]), Err(Error::NoCpus(0))));
+
+        assert!(node.check_memory(16 << 30).is_ok());
+        assert!(matches!(
+            node.check_memory(32 << 30),
+            Err(Error::NodeMemory(0, size, node_size)) if size == 32 << 30 && node_size == 16 << 30
+        ));
+    }
+
+    #[test]
+    fn placement() {
+        let sysfs = recorded_sysfs();
+        let config = NumaConfig {
+            node: 1,
+            srat: true,
+        };
+
+        let placement = NumaPlacement::resolve(&config, sysfs.as_path(), &[0, 4, 5, 12]).unwrap();
+        assert_eq!(placement.node.cpus, vec![4, 5, 12]);
+        assert_eq!(placement.node.mem_size, 16_515_072 << 10);
+        assert!(placement.srat);
+
+        // The topology is not read again: sysfs may be gone, as in the jail.
+        drop(sysfs);
+        assert!(placement.node.check_memory(1 << 30).is_ok());
+
+        assert!(matches!(
+            NumaPlacement::resolve(&config, Path::new("/nonexistent"), &[4]),
+            Err(Error::NodeNotFound(1))
+        ));
+    }
+
+    #[test]
+    fn bind_memory() {
+        // Every host has a node 0.
+        let node = match HostNode::read(Path::new(SYSFS_ROOT), 0) {
+            Ok(node) => node,
+            // No NUMA support on this host.
+            Err(Error::NodeNotFound(_)) => return,
+            Err(e) => panic!("{:?}", e),
+        };
+
+        let len = 0x10000;
+        // Safe because the mapping is checked, and unmapped once bound.
+        let addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(addr, libc::MAP_FAILED);
+
+        let result = node.bind_memory(addr as *mut u8, len);
+        unsafe { libc::munmap(addr, len) };
+        match result {
+            // Memory policies are not allowed in some sandboxes.
+            Err(Error::Mbind(e)) if e.raw_os_error() == Some(libc::EPERM) => {}
+            result => result.unwrap(),
+        }
+    }
+}
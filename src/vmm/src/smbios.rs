@@ -0,0 +1,492 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! SMBIOS tables identifying the machine to the guest, read by `dmidecode` and exposed in
+//! `/sys/class/dmi/id`.
+//!
+//! The 64-bit entry point is written at the start of the BIOS area the guest scans, and the
+//! structure table right after it. See the SMBIOS specification 3.0.0.
+
+use std::io;
+use std::result;
+use std::str::FromStr;
+
+use vm_memory::{Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
+
+use crate::acpi::checksum;
+
+/// Address of the entry point, in the BIOS area scanned by the guest.
+pub const SMBIOS_START: u64 = 0x000f_0000;
+// The structure table must fit below the end of the BIOS area.
+const SMBIOS_END: u64 = 0x0010_0000;
+
+// SMBIOS 3.0 entry point, specification table 2.
+const ENTRY_POINT_LEN: usize = 24;
+const ENTRY_POINT_CHECKSUM_OFFSET: usize = 5;
+const ENTRY_POINT_REVISION: u8 = 1;
+const SMBIOS_MAJOR: u8 = 3;
+const SMBIOS_MINOR: u8 = 0;
+// The structure table follows the entry point, aligned on a paragraph.
+const TABLE_OFFSET: u64 = 0x20;
+
+// Structure types.
+const BIOS_INFORMATION: u8 = 0;
+const SYSTEM_INFORMATION: u8 = 1;
+const BASEBOARD_INFORMATION: u8 = 2;
+const SYSTEM_ENCLOSURE: u8 = 3;
+const END_OF_TABLE: u8 = 127;
+
+// Structure lengths, without the strings.
+const BIOS_INFORMATION_LEN: u8 = 0x18;
+const SYSTEM_INFORMATION_LEN: u8 = 0x1b;
+const BASEBOARD_INFORMATION_LEN: u8 = 0x0f;
+const SYSTEM_ENCLOSURE_LEN: u8 = 0x15;
+const END_OF_TABLE_LEN: u8 = 4;
+
+// BIOS characteristics: none is supported, the firmware being the VMM itself. The second
+// extension byte flags a virtual machine.
+const BIOS_CHARACTERISTICS_NOT_SUPPORTED: u64 = 1 << 3;
+const BIOS_CHARACTERISTICS_EXT2_VIRTUAL_MACHINE: u8 = 1 << 4;
+// The VM boots when the VMM starts, like when the power switch is pressed.
+const WAKE_UP_POWER_SWITCH: u8 = 6;
+// The baseboard is a motherboard hosting the other components.
+const BASEBOARD_HOSTING_BOARD: u8 = 1 << 0;
+const BASEBOARD_MOTHERBOARD: u8 = 0x0a;
+const ENCLOSURE_OTHER: u8 = 1;
+const ENCLOSURE_STATE_SAFE: u8 = 3;
+const ENCLOSURE_SECURITY_UNKNOWN: u8 = 2;
+
+const MANUFACTURER: &str = "Lumper";
+const DEFAULT_PRODUCT: &str = "Lumper VM";
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Errors associated with the SMBIOS tables.
+#[derive(Debug)]
+pub enum Error {
+    /// Invalid SMBIOS configuration, as given.
+    Config(String),
+    /// Failed to generate the default UUID.
+    Uuid(io::Error),
+    /// The tables do not fit in the BIOS area.
+    NotEnoughMemory,
+    /// Failed to write the tables to guest memory.
+    WriteTable(GuestMemoryError),
+}
+
+/// Dedicated Result type.
+pub type Result<T> = result::Result<T, Error>;
+
+/// Machine identity exposed to the guest.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SmbiosConfig {
+    /// System UUID, in its canonical byte order.
+    pub uuid: [u8; 16],
+    /// System serial number.
+    pub serial: Option<String>,
+    /// System product name.
+    pub product: Option<String>,
+}
+
+impl SmbiosConfig {
+    /// Identity of a machine with a random UUID.
+    pub fn new() -> crate::Result<Self> {
+        Ok(SmbiosConfig {
+            uuid: random_uuid().map_err(|e| crate::Error::Smbios(Error::Uuid(e)))?,
+            serial: None,
+            product: None,
+        })
+    }
+
+    /// Set the system serial number and product name from a list of `<key>=<value>` (e.g.
+    /// `serial=ABC123,product=Lumper VM`).
+    pub fn parse_strings(&mut self, s: &str) -> crate::Result<()> {
+        let invalid = || crate::Error::Smbios(Error::Config(s.to_string()));
+
+        for option in s.split(',').filter(|option| !option.is_empty()) {
+            match option.split_once('=') {
+                Some(("serial", serial)) if !serial.is_empty() => {
+                    self.serial = Some(serial.to_string())
+                }
+                Some(("product", product)) if !product.is_empty() => {
+                    self.product = Some(product.to_string())
+                }
+                _ => return Err(invalid()),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The UUID, formatted as `xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx`.
+    pub fn uuid_string(&self) -> String {
+        let hex: String = self.uuid.iter().map(|b| format!("{:02x}", b)).collect();
+        format!(
+            "{}-{}-{}-{}-{}",
+            &hex[0..8],
+            &hex[8..12],
+            &hex[12..16],
+            &hex[16..20],
+            &hex[20..32]
+        )
+    }
+}
+
+/// A UUID, as `xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx`.
+pub struct Uuid(pub [u8; 16]);
+
+impl FromStr for Uuid {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        let invalid = || crate::Error::Smbios(Error::Config(s.to_string()));
+
+        let groups: Vec<&str> = s.split('-').collect();
+        let lengths: Vec<usize> = groups.iter().map(|group| group.len()).collect();
+        if lengths != [8, 4, 4, 4, 12]
+            || !groups
+                .iter()
+                .all(|g| g.bytes().all(|b| b.is_ascii_hexdigit()))
+        {
+            return Err(invalid());
+        }
+
+        let hex = groups.concat();
+        let mut uuid = [0u8; 16];
+        for (index, byte) in uuid.iter_mut().enumerate() {
+            *byte =
+                u8::from_str_radix(&hex[2 * index..2 * index + 2], 16).map_err(|_| invalid())?;
+        }
+
+        Ok(Uuid(uuid))
+    }
+}
+
+/// A random (version 4) UUID.
+fn random_uuid() -> io::Result<[u8; 16]> {
+    let mut uuid = [0u8; 16];
+    // Safe because the buffer is large enough for the bytes asked for, and the result is checked.
+    let count = unsafe { libc::getrandom(uuid.as_mut_ptr() as *mut libc::c_void, uuid.len(), 0) };
+    if count != uuid.len() as isize {
+        return Err(io::Error::last_os_error());
+    }
+
+    uuid[6] = (uuid[6] & 0x0f) | 0x40;
+    uuid[8] = (uuid[8] & 0x3f) | 0x80;
+
+    Ok(uuid)
+}
+
+/// A structure: the formatted area, followed by its string set.
+struct Structure {
+    data: Vec<u8>,
+    strings: Vec<u8>,
+    count: u8,
+}
+
+impl Structure {
+    fn new(type_: u8, length: u8, handle: u16) -> Self {
+        let mut structure = Structure {
+            data: vec![0u8; length as usize],
+            strings: Vec::new(),
+            count: 0,
+        };
+
+        structure.data[0] = type_;
+        structure.data[1] = length;
+        structure.data[2..4].copy_from_slice(&handle.to_le_bytes());
+
+        structure
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) {
+        self.data[offset..offset + data.len()].copy_from_slice(data);
+    }
+
+    /// Add `s` to the string set, referenced at `offset`. Empty strings are left unset.
+    fn write_string(&mut self, offset: usize, s: &str) {
+        if s.is_empty() {
+            return;
+        }
+
+        self.strings.extend_from_slice(s.as_bytes());
+        self.strings.push(0);
+        self.count += 1;
+        self.data[offset] = self.count;
+    }
+
+    fn into_bytes(mut self) -> Vec<u8> {
+        // The string set ends with an extra null byte, and has two when empty.
+        if self.strings.is_empty() {
+            self.strings.push(0);
+        }
+        self.strings.push(0);
+
+        self.data.extend_from_slice(&self.strings);
+        self.data
+    }
+}
+
+/// BIOS Information (type 0).
+fn create_bios_information(handle: u16) -> Structure {
+    let mut bios = Structure::new(BIOS_INFORMATION, BIOS_INFORMATION_LEN, handle);
+
+    bios.write_string(0x04, MANUFACTURER);
+    bios.write_string(0x05, VERSION);
+    bios.write(0x0a, &BIOS_CHARACTERISTICS_NOT_SUPPORTED.to_le_bytes());
+    bios.write(0x13, &[BIOS_CHARACTERISTICS_EXT2_VIRTUAL_MACHINE]);
+    // No BIOS and embedded controller release.
+    bios.write(0x14, &[0xff; 4]);
+
+    bios
+}
+
+/// System Information (type 1).
+fn create_system_information(handle: u16, config: &SmbiosConfig) -> Structure {
+    let mut system = Structure::new(SYSTEM_INFORMATION, SYSTEM_INFORMATION_LEN, handle);
+
+    system.write_string(0x04, MANUFACTURER);
+    system.write_string(0x05, config.product.as_deref().unwrap_or(DEFAULT_PRODUCT));
+    system.write_string(0x06, VERSION);
+    system.write_string(0x07, config.serial.as_deref().unwrap_or_default());
+    // The first three UUID fields are little endian, the last two are kept as is.
+    let mut uuid = config.uuid;
+    uuid[0..4].reverse();
+    uuid[4..6].reverse();
+    uuid[6..8].reverse();
+    system.write(0x08, &uuid);
+    system.write(0x18, &[WAKE_UP_POWER_SWITCH]);
+
+    system
+}
+
+/// Baseboard Information (type 2), in the enclosure with handle `enclosure_handle`.
+fn create_baseboard_information(handle: u16, enclosure_handle: u16) -> Structure {
+    let mut baseboard = Structure::new(BASEBOARD_INFORMATION, BASEBOARD_INFORMATION_LEN, handle);
+
+    baseboard.write_string(0x04, MANUFACTURER);
+    baseboard.write_string(0x05, DEFAULT_PRODUCT);
+    baseboard.write_string(0x06, VERSION);
+    baseboard.write(0x09, &[BASEBOARD_HOSTING_BOARD]);
+    baseboard.write(0x0b, &enclosure_handle.to_le_bytes());
+    baseboard.write(0x0d, &[BASEBOARD_MOTHERBOARD]);
+
+    baseboard
+}
+
+/// System Enclosure (type 3).
+fn create_system_enclosure(handle: u16) -> Structure {
+    let mut enclosure = Structure::new(SYSTEM_ENCLOSURE, SYSTEM_ENCLOSURE_LEN, handle);
+
+    enclosure.write_string(0x04, MANUFACTURER);
+    enclosure.write(0x05, &[ENCLOSURE_OTHER]);
+    enclosure.write_string(0x06, VERSION);
+    // Boot-up, power supply and thermal states.
+    enclosure.write(0x09, &[ENCLOSURE_STATE_SAFE; 3]);
+    enclosure.write(0x0c, &[ENCLOSURE_SECURITY_UNKNOWN]);
+
+    enclosure
+}
+
+/// The structure table, in the order the structures are listed.
+fn create_structure_table(config: &SmbiosConfig) -> Vec<u8> {
+    let structures = vec![
+        create_bios_information(0),
+        create_system_information(1, config),
+        create_baseboard_information(2, 3),
+        create_system_enclosure(3),
+        Structure::new(END_OF_TABLE, END_OF_TABLE_LEN, 4),
+    ];
+
+    structures
+        .into_iter()
+        .flat_map(Structure::into_bytes)
+        .collect()
+}
+
+/// 64-bit entry point, pointing to the structure table.
+fn create_entry_point(table_addr: u64, table_len: u32) -> [u8; ENTRY_POINT_LEN] {
+    let mut entry_point = [0u8; ENTRY_POINT_LEN];
+
+    entry_point[0..5].copy_from_slice(b"_SM3_");
+    entry_point[6] = ENTRY_POINT_LEN as u8;
+    entry_point[7] = SMBIOS_MAJOR;
+    entry_point[8] = SMBIOS_MINOR;
+    entry_point[10] = ENTRY_POINT_REVISION;
+    entry_point[12..16].copy_from_slice(&table_len.to_le_bytes());
+    entry_point[16..24].copy_from_slice(&table_addr.to_le_bytes());
+    entry_point[ENTRY_POINT_CHECKSUM_OFFSET] = checksum(&entry_point);
+
+    entry_point
+}
+
+/// Write the SMBIOS tables describing the machine `config`, returning the entry point address.
+pub fn setup_smbios(mem: &GuestMemoryMmap, config: &SmbiosConfig) -> Result<GuestAddress> {
+    let entry_point_addr = GuestAddress(SMBIOS_START);
+    let table_addr = SMBIOS_START + TABLE_OFFSET;
+
+    let table = create_structure_table(config);
+    if table_addr + table.len() as u64 > SMBIOS_END {
+        return Err(Error::NotEnoughMemory);
+    }
+    mem.write_slice(&table, GuestAddress(table_addr))
+        .map_err(Error::WriteTable)?;
+
+    let entry_point = create_entry_point(table_addr, table.len() as u32);
+    mem.write_slice(&entry_point, entry_point_addr)
+        .map_err(Error::WriteTable)?;
+
+    Ok(entry_point_addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+
+    const UUID: &str = "6e8a2f3c-1d4b-4a5e-9f60-7b8c9d0e1f2a";
+
+    fn config() -> SmbiosConfig {
+        SmbiosConfig {
+            uuid: UUID.parse::<Uuid>().unwrap().0,
+            serial: Some("ABC123".to_string()),
+            product: None,
+        }
+    }
+
+    // Split the structure table into the structures, with their string sets.
+    fn split_structures(table: &[u8]) -> Vec<&[u8]> {
+        let mut structures = Vec::new();
+        let mut start = 0;
+        while start < table.len() {
+            let strings = start + table[start + 1] as usize;
+            let mut end = strings;
+            while table[end] != 0 || table[end + 1] != 0 {
+                end += 1;
+            }
+            structures.push(&table[start..end + 2]);
+            start = end + 2;
+        }
+        structures
+    }
+
+    #[test]
+    fn uuid() {
+        let config = config();
+        assert_eq!(
+            config.uuid,
+            [
+                0x6e, 0x8a, 0x2f, 0x3c, 0x1d, 0x4b, 0x4a, 0x5e, 0x9f, 0x60, 0x7b, 0x8c, 0x9d, 0x0e,
+                0x1f, 0x2a
+            ]
+        );
+        assert_eq!(config.uuid_string(), UUID);
+
+        for invalid in [
+            "",
+            "6e8a2f3c1d4b4a5e9f607b8c9d0e1f2a",
+            "6e8a2f3c-1d4b-4a5e-9f60-7b8c9d0e1f2",
+            "6e8a2f3c-1d4b-4a5e-9f60-7b8c9d0e1f2ab",
+            "6e8a2f3c-1d4b-4a5e-9f60-7b8c9d0e1fzz",
+            "+e8a2f3c-1d4b-4a5e-9f60-7b8c9d0e1f2a",
+        ]
+        .iter()
+        {
+            assert!(invalid.parse::<Uuid>().is_err(), "{}", invalid);
+        }
+
+        // Random UUIDs are version 4 ones.
+        let random = SmbiosConfig::new().unwrap();
+        assert_eq!(random.uuid[6] >> 4, 4);
+        assert_eq!(random.uuid[8] >> 6, 0b10);
+        assert_ne!(random.uuid, SmbiosConfig::new().unwrap().uuid);
+    }
+
+    #[test]
+    fn strings_config() {
+        let mut config = config();
+        config
+            .parse_strings("serial=XYZ,product=Build runner")
+            .unwrap();
+        assert_eq!(config.serial.as_deref(), Some("XYZ"));
+        assert_eq!(config.product.as_deref(), Some("Build runner"));
+
+        for invalid in ["serial=", "product", "uuid=1", "vendor=ACME"].iter() {
+            assert!(config.parse_strings(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn entry_point_layout() {
+        let expected = [
+            0x5f, 0x53, 0x4d, 0x33, 0x5f, 0x84, 0x18, 0x03, 0x00, 0x00, 0x01, 0x00, //
+            0xa0, 0x00, 0x00, 0x00, 0x20, 0x00, 0x0f, 0x00, 0x00, 0x00, 0x00, 0x00, //
+        ];
+
+        let entry_point = create_entry_point(0xf_0020, 0xa0);
+        assert_eq!(entry_point, expected);
+        assert_eq!(checksum(&entry_point), 0);
+    }
+
+    #[test]
+    fn system_information_layout() {
+        let mut expected = vec![
+            0x01, 0x1b, 0x01, 0x00, 0x01, 0x02, 0x03, 0x04, 0x3c, 0x2f, 0x8a, 0x6e, //
+            0x4b, 0x1d, 0x5e, 0x4a, 0x9f, 0x60, 0x7b, 0x8c, 0x9d, 0x0e, 0x1f, 0x2a, //
+            0x06, 0x00, 0x00, //
+        ];
+        for string in [MANUFACTURER, DEFAULT_PRODUCT, VERSION, "ABC123"].iter() {
+            expected.extend_from_slice(string.as_bytes());
+            expected.push(0);
+        }
+        expected.push(0);
+
+        assert_eq!(
+            create_system_information(1, &config()).into_bytes(),
+            expected
+        );
+    }
+
+    #[test]
+    fn structure_table() {
+        let table = create_structure_table(&config());
+        let structures = split_structures(&table);
+
+        let types: Vec<u8> = structures.iter().map(|s| s[0]).collect();
+        assert_eq!(types, [0, 1, 2, 3, 127]);
+        // Unique handles, in order.
+        for (handle, structure) in structures.iter().enumerate() {
+            assert_eq!(
+                u16::from_le_bytes([structure[2], structure[3]]),
+                handle as u16
+            );
+        }
+        // The baseboard is in the enclosure.
+        assert_eq!(&structures[2][0x0b..0x0d], &[3, 0]);
+        // Structures without strings end with two null bytes.
+        assert_eq!(structures[4], [127, 4, 4, 0, 0, 0]);
+        // Without a serial number, the string is left unset.
+        let table = create_structure_table(&SmbiosConfig {
+            serial: None,
+            ..config()
+        });
+        assert_eq!(split_structures(&table)[1][0x07], 0);
+    }
+
+    #[test]
+    fn setup() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), SMBIOS_END as usize)]).unwrap();
+        let entry_point_addr = setup_smbios(&mem, &config()).unwrap();
+        assert_eq!(entry_point_addr, GuestAddress(SMBIOS_START));
+
+        let mut entry_point = [0u8; ENTRY_POINT_LEN];
+        mem.read_slice(&mut entry_point, entry_point_addr).unwrap();
+        assert_eq!(&entry_point[0..5], b"_SM3_");
+        assert_eq!(checksum(&entry_point), 0);
+
+        let table_len = u32::from_le_bytes(entry_point[12..16].try_into().unwrap());
+        let table_addr = u64::from_le_bytes(entry_point[16..24].try_into().unwrap());
+        let mut table = vec![0u8; table_len as usize];
+        mem.read_slice(&mut table, GuestAddress(table_addr))
+            .unwrap();
+        assert_eq!(table, create_structure_table(&config()));
+    }
+}